@@ -4,7 +4,7 @@ fn main() -> Result<(), tonic_buf_build::error::TonicBufBuildError> {
     };
     tonic_buf_build::compile_from_buf_with_config(
         tonic_build::configure()
-            .build_client(false)
+            .build_client(true)
             .type_attribute(
                 "Location",
                 "#[derive(serde::Serialize, serde::Deserialize)]",
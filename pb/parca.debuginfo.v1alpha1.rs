@@ -80,17 +80,7 @@ pub struct UploadInstructions {
 /// Nested message and enum types in `UploadInstructions`.
 pub mod upload_instructions {
     /// The strategy to use for uploading.
-    #[derive(
-        Clone,
-        Copy,
-        Debug,
-        PartialEq,
-        Eq,
-        Hash,
-        PartialOrd,
-        Ord,
-        ::prost::Enumeration
-    )]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
     #[repr(i32)]
     pub enum UploadStrategy {
         /// The upload is not allowed.
@@ -204,21 +194,14 @@ pub struct Debuginfo {
     /// The type of debuginfo.
     #[prost(enumeration = "DebuginfoType", tag = "6")]
     pub r#type: i32,
+    /// The type of the build ID, as given when the upload was initiated.
+    #[prost(enumeration = "BuildIdType", tag = "7")]
+    pub build_id_type: i32,
 }
 /// Nested message and enum types in `Debuginfo`.
 pub mod debuginfo {
     /// Source is the source of the debuginfo.
-    #[derive(
-        Clone,
-        Copy,
-        Debug,
-        PartialEq,
-        Eq,
-        Hash,
-        PartialOrd,
-        Ord,
-        ::prost::Enumeration
-    )]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
     #[repr(i32)]
     pub enum Source {
         /// To understand when no source is set we have the unknown source.
@@ -269,21 +252,16 @@ pub struct DebuginfoUpload {
     /// FinishedAt is the time the debuginfo upload was finished.
     #[prost(message, optional, tag = "5")]
     pub finished_at: ::core::option::Option<::prost_types::Timestamp>,
+    /// Strategy is how the debuginfo is uploaded. Uploads through the Upload RPC
+    /// are verified as they are stored, signed URL uploads once they are marked
+    /// finished.
+    #[prost(enumeration = "upload_instructions::UploadStrategy", tag = "6")]
+    pub strategy: i32,
 }
 /// Nested message and enum types in `DebuginfoUpload`.
 pub mod debuginfo_upload {
     /// The state of the debuginfo upload.
-    #[derive(
-        Clone,
-        Copy,
-        Debug,
-        PartialEq,
-        Eq,
-        Hash,
-        PartialOrd,
-        Ord,
-        ::prost::Enumeration
-    )]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
     #[repr(i32)]
     pub enum State {
         /// To understand when no upload state is set we have the unknown state.
@@ -349,6 +327,12 @@ pub enum DebuginfoType {
     /// multiple source files that debuginfo references. It is meant to show code
     /// with profiling data inline.
     Sources = 2,
+    /// The type to identify the DWARF package (.dwp) of an executable built with
+    /// split DWARF. It is uploaded for the build ID of the executable.
+    DwarfPackage = 3,
+    /// The type to identify a split DWARF object (.dwo). It is uploaded for the
+    /// hex encoded DWO ID of its unit in place of a build ID.
+    SplitDwarf = 4,
 }
 impl DebuginfoType {
     /// String value of the enum field names used in the ProtoBuf definition.
@@ -360,6 +344,8 @@ impl DebuginfoType {
             Self::DebuginfoUnspecified => "DEBUGINFO_TYPE_DEBUGINFO_UNSPECIFIED",
             Self::Executable => "DEBUGINFO_TYPE_EXECUTABLE",
             Self::Sources => "DEBUGINFO_TYPE_SOURCES",
+            Self::DwarfPackage => "DEBUGINFO_TYPE_DWARF_PACKAGE",
+            Self::SplitDwarf => "DEBUGINFO_TYPE_SPLIT_DWARF",
         }
     }
     /// Creates an enum from field names used in the ProtoBuf definition.
@@ -368,6 +354,8 @@ impl DebuginfoType {
             "DEBUGINFO_TYPE_DEBUGINFO_UNSPECIFIED" => Some(Self::DebuginfoUnspecified),
             "DEBUGINFO_TYPE_EXECUTABLE" => Some(Self::Executable),
             "DEBUGINFO_TYPE_SOURCES" => Some(Self::Sources),
+            "DEBUGINFO_TYPE_DWARF_PACKAGE" => Some(Self::DwarfPackage),
+            "DEBUGINFO_TYPE_SPLIT_DWARF" => Some(Self::SplitDwarf),
             _ => None,
         }
     }
@@ -409,6 +397,178 @@ impl BuildIdType {
         }
     }
 }
+/// Generated client implementations.
+pub mod debuginfo_service_client {
+    #![allow(
+        unused_variables,
+        dead_code,
+        missing_docs,
+        clippy::wildcard_imports,
+        clippy::let_unit_value
+    )]
+    use tonic::codegen::http::Uri;
+    use tonic::codegen::*;
+    /// DebuginfoService is a service that allows storage of debug info
+    #[derive(Debug, Clone)]
+    pub struct DebuginfoServiceClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl DebuginfoServiceClient<tonic::transport::Channel> {
+        /// Attempt to create a new client by connecting to a given endpoint.
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> DebuginfoServiceClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::Error: Into<StdError>,
+        T::ResponseBody: Body<Data = Bytes> + std::marker::Send + 'static,
+        <T::ResponseBody as Body>::Error: Into<StdError> + std::marker::Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_origin(inner: T, origin: Uri) -> Self {
+            let inner = tonic::client::Grpc::with_origin(inner, origin);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> DebuginfoServiceClient<InterceptedService<T, F>>
+        where
+            F: tonic::service::Interceptor,
+            T::ResponseBody: Default,
+            T: tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as tonic::codegen::Service<http::Request<tonic::body::BoxBody>>>::Error:
+                Into<StdError> + std::marker::Send + std::marker::Sync,
+        {
+            DebuginfoServiceClient::new(InterceptedService::new(inner, interceptor))
+        }
+        /// Compress requests with the given encoding.
+        ///
+        /// This requires the server to support it otherwise it might respond with an
+        /// error.
+        #[must_use]
+        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.inner = self.inner.send_compressed(encoding);
+            self
+        }
+        /// Enable decompressing responses.
+        #[must_use]
+        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.inner = self.inner.accept_compressed(encoding);
+            self
+        }
+        /// Limits the maximum size of a decoded message.
+        ///
+        /// Default: `4MB`
+        #[must_use]
+        pub fn max_decoding_message_size(mut self, limit: usize) -> Self {
+            self.inner = self.inner.max_decoding_message_size(limit);
+            self
+        }
+        /// Limits the maximum size of an encoded message.
+        ///
+        /// Default: `usize::MAX`
+        #[must_use]
+        pub fn max_encoding_message_size(mut self, limit: usize) -> Self {
+            self.inner = self.inner.max_encoding_message_size(limit);
+            self
+        }
+        /// Upload ingests debug info for a given build_id
+        pub async fn upload(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::UploadRequest>,
+        ) -> std::result::Result<tonic::Response<super::UploadResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/parca.debuginfo.v1alpha1.DebuginfoService/Upload",
+            );
+            let mut req = request.into_streaming_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "parca.debuginfo.v1alpha1.DebuginfoService",
+                "Upload",
+            ));
+            self.inner.client_streaming(req, path, codec).await
+        }
+        /// ShouldInitiateUpload returns whether an upload for a given build_id should be initiated or not.
+        pub async fn should_initiate_upload(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ShouldInitiateUploadRequest>,
+        ) -> std::result::Result<tonic::Response<super::ShouldInitiateUploadResponse>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/parca.debuginfo.v1alpha1.DebuginfoService/ShouldInitiateUpload",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "parca.debuginfo.v1alpha1.DebuginfoService",
+                "ShouldInitiateUpload",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+        /// InitiateUpload returns a strategy and information to upload debug info for a given build_id.
+        pub async fn initiate_upload(
+            &mut self,
+            request: impl tonic::IntoRequest<super::InitiateUploadRequest>,
+        ) -> std::result::Result<tonic::Response<super::InitiateUploadResponse>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/parca.debuginfo.v1alpha1.DebuginfoService/InitiateUpload",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "parca.debuginfo.v1alpha1.DebuginfoService",
+                "InitiateUpload",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+        /// MarkUploadFinished marks the upload as finished for a given build_id.
+        pub async fn mark_upload_finished(
+            &mut self,
+            request: impl tonic::IntoRequest<super::MarkUploadFinishedRequest>,
+        ) -> std::result::Result<tonic::Response<super::MarkUploadFinishedResponse>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/parca.debuginfo.v1alpha1.DebuginfoService/MarkUploadFinished",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "parca.debuginfo.v1alpha1.DebuginfoService",
+                "MarkUploadFinished",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+    }
+}
 /// Generated server implementations.
 pub mod debuginfo_service_server {
     #![allow(
@@ -416,7 +576,7 @@ pub mod debuginfo_service_server {
         dead_code,
         missing_docs,
         clippy::wildcard_imports,
-        clippy::let_unit_value,
+        clippy::let_unit_value
     )]
     use tonic::codegen::*;
     /// Generated trait containing gRPC methods that should be implemented for use with DebuginfoServiceServer.
@@ -431,26 +591,17 @@ pub mod debuginfo_service_server {
         async fn should_initiate_upload(
             &self,
             request: tonic::Request<super::ShouldInitiateUploadRequest>,
-        ) -> std::result::Result<
-            tonic::Response<super::ShouldInitiateUploadResponse>,
-            tonic::Status,
-        >;
+        ) -> std::result::Result<tonic::Response<super::ShouldInitiateUploadResponse>, tonic::Status>;
         /// InitiateUpload returns a strategy and information to upload debug info for a given build_id.
         async fn initiate_upload(
             &self,
             request: tonic::Request<super::InitiateUploadRequest>,
-        ) -> std::result::Result<
-            tonic::Response<super::InitiateUploadResponse>,
-            tonic::Status,
-        >;
+        ) -> std::result::Result<tonic::Response<super::InitiateUploadResponse>, tonic::Status>;
         /// MarkUploadFinished marks the upload as finished for a given build_id.
         async fn mark_upload_finished(
             &self,
             request: tonic::Request<super::MarkUploadFinishedRequest>,
-        ) -> std::result::Result<
-            tonic::Response<super::MarkUploadFinishedResponse>,
-            tonic::Status,
-        >;
+        ) -> std::result::Result<tonic::Response<super::MarkUploadFinishedResponse>, tonic::Status>;
     }
     /// DebuginfoService is a service that allows storage of debug info
     #[derive(Debug)]
@@ -474,10 +625,7 @@ pub mod debuginfo_service_server {
                 max_encoding_message_size: None,
             }
         }
-        pub fn with_interceptor<F>(
-            inner: T,
-            interceptor: F,
-        ) -> InterceptedService<Self, F>
+        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
         where
             F: tonic::service::Interceptor,
         {
@@ -532,20 +680,15 @@ pub mod debuginfo_service_server {
                 "/parca.debuginfo.v1alpha1.DebuginfoService/Upload" => {
                     #[allow(non_camel_case_types)]
                     struct UploadSvc<T: DebuginfoService>(pub Arc<T>);
-                    impl<
-                        T: DebuginfoService,
-                    > tonic::server::ClientStreamingService<super::UploadRequest>
-                    for UploadSvc<T> {
+                    impl<T: DebuginfoService>
+                        tonic::server::ClientStreamingService<super::UploadRequest>
+                        for UploadSvc<T>
+                    {
                         type Response = super::UploadResponse;
-                        type Future = BoxFuture<
-                            tonic::Response<Self::Response>,
-                            tonic::Status,
-                        >;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<
-                                tonic::Streaming<super::UploadRequest>,
-                            >,
+                            request: tonic::Request<tonic::Streaming<super::UploadRequest>>,
                         ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
                             let fut = async move {
@@ -579,25 +722,19 @@ pub mod debuginfo_service_server {
                 "/parca.debuginfo.v1alpha1.DebuginfoService/ShouldInitiateUpload" => {
                     #[allow(non_camel_case_types)]
                     struct ShouldInitiateUploadSvc<T: DebuginfoService>(pub Arc<T>);
-                    impl<
-                        T: DebuginfoService,
-                    > tonic::server::UnaryService<super::ShouldInitiateUploadRequest>
-                    for ShouldInitiateUploadSvc<T> {
+                    impl<T: DebuginfoService>
+                        tonic::server::UnaryService<super::ShouldInitiateUploadRequest>
+                        for ShouldInitiateUploadSvc<T>
+                    {
                         type Response = super::ShouldInitiateUploadResponse;
-                        type Future = BoxFuture<
-                            tonic::Response<Self::Response>,
-                            tonic::Status,
-                        >;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
                             request: tonic::Request<super::ShouldInitiateUploadRequest>,
                         ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
                             let fut = async move {
-                                <T as DebuginfoService>::should_initiate_upload(
-                                        &inner,
-                                        request,
-                                    )
+                                <T as DebuginfoService>::should_initiate_upload(&inner, request)
                                     .await
                             };
                             Box::pin(fut)
@@ -628,23 +765,19 @@ pub mod debuginfo_service_server {
                 "/parca.debuginfo.v1alpha1.DebuginfoService/InitiateUpload" => {
                     #[allow(non_camel_case_types)]
                     struct InitiateUploadSvc<T: DebuginfoService>(pub Arc<T>);
-                    impl<
-                        T: DebuginfoService,
-                    > tonic::server::UnaryService<super::InitiateUploadRequest>
-                    for InitiateUploadSvc<T> {
+                    impl<T: DebuginfoService>
+                        tonic::server::UnaryService<super::InitiateUploadRequest>
+                        for InitiateUploadSvc<T>
+                    {
                         type Response = super::InitiateUploadResponse;
-                        type Future = BoxFuture<
-                            tonic::Response<Self::Response>,
-                            tonic::Status,
-                        >;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
                             request: tonic::Request<super::InitiateUploadRequest>,
                         ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
                             let fut = async move {
-                                <T as DebuginfoService>::initiate_upload(&inner, request)
-                                    .await
+                                <T as DebuginfoService>::initiate_upload(&inner, request).await
                             };
                             Box::pin(fut)
                         }
@@ -674,26 +807,19 @@ pub mod debuginfo_service_server {
                 "/parca.debuginfo.v1alpha1.DebuginfoService/MarkUploadFinished" => {
                     #[allow(non_camel_case_types)]
                     struct MarkUploadFinishedSvc<T: DebuginfoService>(pub Arc<T>);
-                    impl<
-                        T: DebuginfoService,
-                    > tonic::server::UnaryService<super::MarkUploadFinishedRequest>
-                    for MarkUploadFinishedSvc<T> {
+                    impl<T: DebuginfoService>
+                        tonic::server::UnaryService<super::MarkUploadFinishedRequest>
+                        for MarkUploadFinishedSvc<T>
+                    {
                         type Response = super::MarkUploadFinishedResponse;
-                        type Future = BoxFuture<
-                            tonic::Response<Self::Response>,
-                            tonic::Status,
-                        >;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
                             request: tonic::Request<super::MarkUploadFinishedRequest>,
                         ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
                             let fut = async move {
-                                <T as DebuginfoService>::mark_upload_finished(
-                                        &inner,
-                                        request,
-                                    )
-                                    .await
+                                <T as DebuginfoService>::mark_upload_finished(&inner, request).await
                             };
                             Box::pin(fut)
                         }
@@ -720,23 +846,19 @@ pub mod debuginfo_service_server {
                     };
                     Box::pin(fut)
                 }
-                _ => {
-                    Box::pin(async move {
-                        let mut response = http::Response::new(empty_body());
-                        let headers = response.headers_mut();
-                        headers
-                            .insert(
-                                tonic::Status::GRPC_STATUS,
-                                (tonic::Code::Unimplemented as i32).into(),
-                            );
-                        headers
-                            .insert(
-                                http::header::CONTENT_TYPE,
-                                tonic::metadata::GRPC_CONTENT_TYPE,
-                            );
-                        Ok(response)
-                    })
-                }
+                _ => Box::pin(async move {
+                    let mut response = http::Response::new(empty_body());
+                    let headers = response.headers_mut();
+                    headers.insert(
+                        tonic::Status::GRPC_STATUS,
+                        (tonic::Code::Unimplemented as i32).into(),
+                    );
+                    headers.insert(
+                        http::header::CONTENT_TYPE,
+                        tonic::metadata::GRPC_CONTENT_TYPE,
+                    );
+                    Ok(response)
+                }),
             }
         }
     }
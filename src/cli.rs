@@ -0,0 +1,352 @@
+use crate::debuginfo_store::buildid;
+use crate::debuginfopb::{
+    debuginfo_service_client::DebuginfoServiceClient, upload_instructions::UploadStrategy,
+    upload_request, BuildIdType, DebuginfoType, InitiateUploadRequest, MarkUploadFinishedRequest,
+    ShouldInitiateUploadRequest, UploadInfo, UploadRequest,
+};
+use crate::symbols::elfutils;
+use anyhow::{bail, Context};
+use std::sync::Arc;
+use tonic::{codec::CompressionEncoding, transport::Channel};
+use xxhash_rust::xxh64::Xxh64;
+
+const DEFAULT_SERVER: &str = "http://[::1]:3333";
+
+/// Uploads are streamed to the server in chunks of this size.
+const UPLOAD_CHUNK_SIZE: usize = 1 << 20;
+
+const USAGE: &str = "\
+Usage: evprofiler debuginfo upload [OPTIONS] <FILES>...
+
+Uploads debuginfo to an evprofiler server, so that binaries deployed without
+it can be symbolized.
+
+Options:
+  --server <URL>      Server to upload to [default: http://[::1]:3333]
+  --type <TYPE>       executable, debuginfo, sources, dwp or dwo [default:
+                      debuginfo]
+  --build-id <ID>     Build ID to upload the files as, instead of the GNU or
+                      Go build ID of each file, or the DWO ID of a .dwo.
+                      Required for sources and for the .dwp of an executable,
+                      which are uploaded for the build ID of the executable.
+  --build-id-type <TYPE>
+                      gnu, go or hash, the type of --build-id [default:
+                      inferred from its format]
+  --force             Upload even if the server already has the debuginfo
+";
+
+#[derive(Debug, PartialEq)]
+struct UploadArgs {
+    server: String,
+    req_type: DebuginfoType,
+    build_id: Option<String>,
+    build_id_type: Option<BuildIdType>,
+    force: bool,
+    files: Vec<String>,
+}
+
+/// Runs the command given on the command line.
+pub async fn run(args: &[String]) -> anyhow::Result<()> {
+    match args {
+        [group, cmd, rest @ ..] if group == "debuginfo" && cmd == "upload" => {
+            let args = parse_upload_args(rest)?;
+            let mut client = connect(&args.server).await?;
+            for file in args.files.iter() {
+                upload(&mut client, &args, file)
+                    .await
+                    .with_context(|| format!("Failed to upload {}", file))?;
+            }
+            Ok(())
+        }
+        _ => bail!("{}", USAGE),
+    }
+}
+
+fn parse_upload_args(args: &[String]) -> anyhow::Result<UploadArgs> {
+    let mut parsed = UploadArgs {
+        server: DEFAULT_SERVER.into(),
+        req_type: DebuginfoType::DebuginfoUnspecified,
+        build_id: None,
+        build_id_type: None,
+        force: false,
+        files: vec![],
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .with_context(|| format!("{} needs a value\n\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--server" => parsed.server = value()?,
+            "--type" => {
+                parsed.req_type = match value()?.as_str() {
+                    "executable" => DebuginfoType::Executable,
+                    "debuginfo" => DebuginfoType::DebuginfoUnspecified,
+                    "sources" => DebuginfoType::Sources,
+                    "dwp" => DebuginfoType::DwarfPackage,
+                    "dwo" => DebuginfoType::SplitDwarf,
+                    other => bail!("Unknown type {}\n\n{}", other, USAGE),
+                }
+            }
+            "--build-id" => parsed.build_id = Some(value()?),
+            "--build-id-type" => {
+                parsed.build_id_type = Some(match value()?.as_str() {
+                    "gnu" => BuildIdType::Gnu,
+                    "go" => BuildIdType::Go,
+                    "hash" => BuildIdType::Hash,
+                    other => bail!("Unknown build ID type {}\n\n{}", other, USAGE),
+                })
+            }
+            "--force" => parsed.force = true,
+            "-h" | "--help" => bail!("{}", USAGE),
+            flag if flag.starts_with("--") => bail!("Unknown option {}\n\n{}", flag, USAGE),
+            file => parsed.files.push(file.into()),
+        }
+    }
+
+    if parsed.files.is_empty() {
+        bail!("No files to upload\n\n{}", USAGE);
+    }
+    if parsed.req_type == DebuginfoType::Sources && parsed.build_id.is_none() {
+        bail!("Sources need a --build-id\n\n{}", USAGE);
+    }
+    if parsed.req_type == DebuginfoType::DwarfPackage && parsed.build_id.is_none() {
+        bail!(
+            "DWARF packages need the --build-id of their executable\n\n{}",
+            USAGE
+        );
+    }
+    // The type of a build ID read from the file is known.
+    if parsed.build_id_type.is_some() && parsed.build_id.is_none() {
+        bail!("--build-id-type needs a --build-id\n\n{}", USAGE);
+    }
+    Ok(parsed)
+}
+
+async fn connect(server: &str) -> anyhow::Result<DebuginfoServiceClient<Channel>> {
+    let client = DebuginfoServiceClient::connect(server.to_string())
+        .await
+        .with_context(|| format!("Failed to connect to {}", server))?;
+    Ok(client.send_compressed(CompressionEncoding::Gzip))
+}
+
+/// Uploads a file the way the agent does: it asks whether the server needs
+/// the file, initiates the upload, sends it over gRPC or to the signed URL the
+/// server hands out, and marks it finished.
+async fn upload(
+    client: &mut DebuginfoServiceClient<Channel>,
+    args: &UploadArgs,
+    path: &str,
+) -> anyhow::Result<()> {
+    let file = std::fs::File::open(path)?;
+    // SAFETY: The file is only read, and is expected not to change while it
+    // is uploaded.
+    let data = Arc::new(unsafe { memmap2::Mmap::map(&file)? });
+
+    let (build_id, build_id_type) = match &args.build_id {
+        Some(build_id) => (
+            build_id.clone(),
+            args.build_id_type
+                .unwrap_or_else(|| buildid::infer_type(build_id)),
+        ),
+        None if args.req_type == DebuginfoType::SplitDwarf => {
+            // DWO IDs are hashes of the unit, debuginfod doesn't know them.
+            let elf = object::File::parse(&**data).context("Not an object file")?;
+            match elfutils::split_dwo_ids(&elf)[..] {
+                [dwo_id] => (format!("{:016x}", dwo_id), BuildIdType::Hash),
+                [] => bail!("The file has no split DWARF unit"),
+                _ => bail!("The file has several split DWARF units, pass the --build-id of one"),
+            }
+        }
+        None => {
+            let elf = object::File::parse(&**data).context("Not an object file")?;
+            elfutils::build_id(&elf)
+                .map(|id| (id, BuildIdType::Gnu))
+                .or_else(|| elfutils::go_build_id(&elf).map(|id| (id, BuildIdType::Go)))
+                .context("The file has neither a GNU nor a Go build ID")?
+        }
+    };
+    let mut hasher = Xxh64::new(0);
+    hasher.update(&data);
+    let hash = format!("{:016x}", hasher.digest());
+
+    let should_initiate = client
+        .should_initiate_upload(ShouldInitiateUploadRequest {
+            build_id: build_id.clone(),
+            hash: hash.clone(),
+            force: args.force,
+            r#type: args.req_type.into(),
+            build_id_type: build_id_type.into(),
+        })
+        .await?
+        .into_inner();
+    if !should_initiate.should_initiate_upload {
+        log::info!(
+            "Skipping {} ({}): {}",
+            path,
+            build_id,
+            should_initiate.reason
+        );
+        return Ok(());
+    }
+
+    let instructions = client
+        .initiate_upload(InitiateUploadRequest {
+            build_id: build_id.clone(),
+            size: data.len() as i64,
+            hash,
+            force: args.force,
+            r#type: args.req_type.into(),
+            build_id_type: build_id_type.into(),
+        })
+        .await?
+        .into_inner()
+        .upload_instructions
+        .context("The server sent no upload instructions")?;
+
+    match instructions.upload_strategy() {
+        UploadStrategy::Grpc => {
+            let info = UploadRequest {
+                data: Some(upload_request::Data::Info(UploadInfo {
+                    build_id: build_id.clone(),
+                    upload_id: instructions.upload_id.clone(),
+                    r#type: args.req_type.into(),
+                })),
+            };
+            let chunks = Arc::clone(&data);
+            let requests = async_stream::stream! {
+                yield info;
+                for chunk in chunks.chunks(UPLOAD_CHUNK_SIZE) {
+                    yield UploadRequest {
+                        data: Some(upload_request::Data::ChunkData(chunk.to_vec())),
+                    };
+                }
+            };
+            client.upload(requests).await?;
+        }
+        UploadStrategy::SignedUrl => {
+            let url = instructions.signed_url.clone();
+            let body = Arc::clone(&data);
+            tokio::task::spawn_blocking(move || {
+                ureq::put(&url)
+                    .send_bytes(&body)
+                    .map(drop)
+                    .map_err(anyhow::Error::from)
+            })
+            .await??;
+        }
+        UploadStrategy::Unspecified => bail!("The server sent no upload strategy"),
+    }
+
+    client
+        .mark_upload_finished(MarkUploadFinishedRequest {
+            build_id: build_id.clone(),
+            upload_id: instructions.upload_id,
+            r#type: args.req_type.into(),
+        })
+        .await?;
+    log::info!("Uploaded {} ({})", path, build_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        debuginfo_store::{DebugInfod, DebuginfoStore, MetadataStore},
+        debuginfopb::debuginfo_service_server::DebuginfoServiceServer,
+        storage,
+    };
+    use tonic::transport::{server::TcpIncoming, Server};
+
+    const BUILD_ID: &str = "126cf12e76810726c76d87ab8a6a57e9a1d4c815";
+    const TESTDATA: &str = "src/symbols/addr_to_line/testdata";
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_upload_args() {
+        assert_eq!(
+            parse_upload_args(&args(&["--type", "executable", "--force", "a", "b"])).unwrap(),
+            UploadArgs {
+                server: DEFAULT_SERVER.into(),
+                req_type: DebuginfoType::Executable,
+                build_id: None,
+                build_id_type: None,
+                force: true,
+                files: args(&["a", "b"]),
+            }
+        );
+        assert!(parse_upload_args(&args(&["--type", "bogus", "a"])).is_err());
+        assert!(parse_upload_args(&args(&["--type", "sources", "a"])).is_err());
+        assert!(parse_upload_args(&args(&["--type", "dwp", "a"])).is_err());
+        assert!(parse_upload_args(&args(&["--build-id-type", "hash", "a"])).is_err());
+        assert!(parse_upload_args(&args(&["--server"])).is_err());
+        assert!(parse_upload_args(&[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let store = Arc::new(DebuginfoStore {
+            metadata: MetadataStore::new(),
+            debuginfod: DebugInfod::default().with_upstream_servers(vec![]),
+            max_upload_duration: chrono::Duration::minutes(15),
+            max_upload_size: 1 << 30,
+            bucket: Arc::new(storage::new_memory_bucket()),
+            signer: None,
+            uploads: Default::default(),
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let service = DebuginfoServiceServer::from_arc(Arc::clone(&store))
+            .accept_compressed(CompressionEncoding::Gzip);
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+
+        let file = format!("{}/basic-cpp-no-fp.debug", TESTDATA);
+        let cmd = args(&["debuginfo", "upload", "--server", &server, &file]);
+        run(&cmd).await.unwrap();
+
+        let dbginfo = store
+            .metadata
+            .fetch(BUILD_ID, &DebuginfoType::DebuginfoUnspecified)
+            .unwrap();
+        assert_eq!(
+            dbginfo.upload.unwrap().state(),
+            crate::debuginfopb::debuginfo_upload::State::Uploaded
+        );
+        assert!(dbginfo.quality.unwrap().has_dwarf);
+
+        // The server already has it, so uploading again is skipped.
+        run(&cmd).await.unwrap();
+
+        // Split DWARF objects are uploaded for the DWO ID of their unit.
+        let file = format!("{}/basic-cpp-split-dwarf-main.dwo", TESTDATA);
+        let data = std::fs::read(&file).unwrap();
+        let dwo_id = elfutils::split_dwo_ids(&object::File::parse(&*data).unwrap())[0];
+        run(&args(&[
+            "debuginfo",
+            "upload",
+            "--server",
+            &server,
+            "--type",
+            "dwo",
+            &file,
+        ]))
+        .await
+        .unwrap();
+        assert!(store
+            .metadata
+            .fetch(&format!("{:016x}", dwo_id), &DebuginfoType::SplitDwarf)
+            .is_some_and(|dbginfo| dbginfo.upload.is_some()));
+    }
+}
@@ -1,42 +1,94 @@
 mod pprof_writer;
 mod record_reader;
-use crate::{dal::DataAccessLayer, pprofpb, profile};
+mod source_report;
+use crate::{dal::DataAccessLayer, debuginfo_store::SourceStore, pprofpb, profile};
+use anyhow::Context;
 use flate2::write::GzDecoder;
 use pprof_writer::PprofWriter;
 use prost::Message;
-use std::{io::Write, sync::Arc};
+pub use source_report::{SourceReference, SourceReport};
+use std::{collections::BTreeMap, io::Write, sync::Arc};
 
 pub struct ColumnQuery {
     dal: Arc<DataAccessLayer>,
+    sources: Option<SourceStore>,
 }
 
 pub enum ColumnQueryRequest {
     GeneratePprof,
+    Source(SourceReference),
 }
 
 pub enum ColumnQueryResponse {
     Pprof(Vec<u8>),
+    Source(SourceReport),
 }
 
 impl ColumnQuery {
     pub fn new(dal: &Arc<DataAccessLayer>) -> Self {
         Self {
             dal: Arc::clone(dal),
+            sources: None,
         }
     }
 
+    /// Serves source reports from the uploaded source archives.
+    pub fn with_sources(mut self, sources: SourceStore) -> Self {
+        self.sources = Some(sources);
+        self
+    }
+
     pub async fn query(
         &self,
         query_type: ColumnQueryRequest,
         query_string: &str,
         timestamp: i64,
     ) -> anyhow::Result<ColumnQueryResponse> {
-        let p: profile::Profile = self.dal.select_single(query_string, timestamp).await?;
         match query_type {
-            ColumnQueryRequest::GeneratePprof => self.generate_pprof(p),
+            ColumnQueryRequest::GeneratePprof => {
+                let p: profile::Profile = self.dal.select_single(query_string, timestamp).await?;
+                self.generate_pprof(p)
+            }
+            ColumnQueryRequest::Source(reference) => {
+                self.source_report(&reference, query_string, timestamp)
+                    .await
+            }
         }
     }
 
+    async fn source_report(
+        &self,
+        reference: &SourceReference,
+        query_string: &str,
+        timestamp: i64,
+    ) -> anyhow::Result<ColumnQueryResponse> {
+        let sources = self
+            .sources
+            .as_ref()
+            .context("Source reports need source archives")?;
+        let source = sources
+            .file(&reference.build_id, &reference.filename)
+            .await?
+            .with_context(|| {
+                format!(
+                    "No source of {} was uploaded for {}",
+                    reference.filename, reference.build_id
+                )
+            })?;
+
+        let lines = if reference.source_only {
+            BTreeMap::new()
+        } else {
+            let p = self.dal.select_single(query_string, timestamp).await?;
+            source_report::line_values(&p.samples, reference)?
+        };
+
+        Ok(ColumnQueryResponse::Source(SourceReport {
+            source: String::from_utf8_lossy(&source).into_owned(),
+            lines,
+        }))
+    }
+
     pub fn generate_pprof(&self, profile: profile::Profile) -> anyhow::Result<ColumnQueryResponse> {
         let mut w = PprofWriter::new(profile.meta);
         for rec in profile.samples {
@@ -60,10 +112,82 @@ mod tests {
     use super::*;
     use crate::{
         debuginfo_store::{self, DebuginfoFetcher},
+        debuginfopb::{upload_instructions::UploadStrategy, BuildIdType, DebuginfoType},
+        stacktrace_store::StacktraceStore,
         storage, symbolizer,
     };
+    use chrono::Utc;
     use object_store::ObjectStore;
 
+    #[tokio::test]
+    async fn test_source_only() {
+        let metadata = debuginfo_store::MetadataStore::new();
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let build_id = "126cf12e76810726c76d87ab8a6a57e9a1d4c815";
+        let upload_id = ulid::Ulid::new().to_string();
+        let req_type = DebuginfoType::Sources;
+        metadata
+            .mark_as_uploading(
+                build_id,
+                &upload_id,
+                "hash",
+                &req_type,
+                BuildIdType::Gnu,
+                UploadStrategy::Grpc,
+                Utc::now(),
+            )
+            .unwrap();
+        let archive = debuginfo_store::sources::tests::archive(&[("src/main.c", "int main() {}")]);
+        bucket
+            .put(&upload_id.as_str().into(), archive.into())
+            .await
+            .unwrap();
+        metadata
+            .mark_as_uploaded(build_id, &upload_id, &req_type, Utc::now())
+            .unwrap();
+        let sources = SourceStore::new(
+            debuginfo_store::MetadataStore::with_store(metadata.store.clone()),
+            bucket,
+        );
+
+        let symbolizer = Arc::new(symbolizer::Symbolizer::new(
+            metadata,
+            DebuginfoFetcher::new(
+                Arc::new(storage::new_memory_bucket()),
+                debuginfo_store::DebugInfod::default(),
+            ),
+        ));
+        let stacktraces = Arc::new(StacktraceStore::new(Arc::new(storage::new_memory_bucket())));
+        let dir = tempfile::tempdir().unwrap();
+        let dal = Arc::new(
+            DataAccessLayer::try_new(
+                dir.path().to_str().unwrap(),
+                5000,
+                &symbolizer,
+                &stacktraces,
+            )
+            .await
+            .unwrap(),
+        );
+        let column_query = ColumnQuery::new(&dal).with_sources(sources);
+
+        let reference = SourceReference {
+            build_id: build_id.into(),
+            filename: "/home/ci/src/main.c".into(),
+            source_only: true,
+        };
+        let report = match column_query
+            .query(ColumnQueryRequest::Source(reference), "", 0)
+            .await
+            .unwrap()
+        {
+            ColumnQueryResponse::Source(report) => report,
+            _ => panic!("expected a source report"),
+        };
+        assert_eq!(report.source, "int main() {}");
+        assert!(report.lines.is_empty());
+    }
+
     #[tokio::test]
     async fn test_generate_pprof() {
         let metadata_store = debuginfo_store::MetadataStore::new();
@@ -74,13 +198,15 @@ mod tests {
             DebuginfoFetcher::new(Arc::clone(&debuginfod_bucket), debuginfod.clone()),
         ));
 
+        let stacktraces = Arc::new(StacktraceStore::new(Arc::new(storage::new_memory_bucket())));
+
         let dal = Arc::new(
-            DataAccessLayer::try_new("evprofiler-data", 5000, &symbolizer)
+            DataAccessLayer::try_new("evprofiler-data", 5000, &symbolizer, &stacktraces)
                 .await
                 .unwrap(),
         );
         let column_query = ColumnQuery::new(&dal);
-        let qs = "arch=aarch64,node=focal|parca_agent_cpu:samples:count:cpu:nanoseconds";
+        let qs = "arch=aarch64,node=focal|parca_agent_cpu:samples:count:cpu:nanoseconds:delta";
         let x = column_query
             .query(ColumnQueryRequest::GeneratePprof, qs, 0)
             .await
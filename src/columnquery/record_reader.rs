@@ -69,7 +69,7 @@ impl RecordReader {
         let lines = lines_col.as_list_opt::<i32>().unwrap();
 
         let line_col = Arc::clone(lines.values());
-        let line = lines_col.as_struct_opt().unwrap();
+        let line = line_col.as_struct_opt().unwrap();
 
         let line_number_col = Arc::clone(line.column(0));
         let line_function_name_col = Arc::clone(line.column(1));
@@ -0,0 +1,262 @@
+use super::record_reader::RecordReader;
+use datafusion::arrow::{
+    array::{Array, AsArray, RecordBatch},
+    compute::cast,
+    datatypes::{DataType, Int64Type},
+};
+use std::collections::{BTreeMap, HashSet};
+
+/// SourceReference refers to a source file of a build ID, as recorded in its
+/// DWARF.
+#[derive(Debug, Clone)]
+pub struct SourceReference {
+    pub build_id: String,
+    pub filename: String,
+    /// Only the source is returned, without querying the profile.
+    pub source_only: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LineValues {
+    pub flat: i64,
+    pub cumulative: i64,
+}
+
+#[derive(Debug)]
+pub struct SourceReport {
+    pub source: String,
+    /// The values of the samples attributed to each line of the source.
+    pub lines: BTreeMap<i64, LineValues>,
+}
+
+/// Attributes the values of the samples to the lines of the referenced
+/// source file. A sample counts towards the flat value of the line it was
+/// taken at, and towards the cumulative value of every line of the file in
+/// its stack, once.
+pub(crate) fn line_values(
+    records: &[RecordBatch],
+    reference: &SourceReference,
+) -> anyhow::Result<BTreeMap<i64, LineValues>> {
+    let mut lines: BTreeMap<i64, LineValues> = BTreeMap::new();
+    for record in records.iter() {
+        let rr = RecordReader::new(record);
+        let locations = rr.locations_col.as_list::<i32>();
+        let values = rr.value_col.as_primitive::<Int64Type>();
+        let location_lines = rr.lines_col.as_list::<i32>();
+        let line_numbers = rr.line_number_col.as_primitive::<Int64Type>();
+        // The dictionaries are unpacked, so that they can be compared
+        // whatever their key type.
+        let build_ids = cast(&rr.mapping_buildid_col, &DataType::Binary)?;
+        let build_ids = build_ids.as_binary::<i32>();
+        let filenames = cast(&rr.line_function_filename_col, &DataType::Binary)?;
+        let filenames = filenames.as_binary::<i32>();
+
+        for i in 0..record.num_rows() {
+            if !locations.is_valid(i) || !values.is_valid(i) {
+                continue;
+            }
+            let value = values.value(i);
+            let offsets = locations.value_offsets();
+            let mut seen = HashSet::new();
+            for j in offsets[i] as usize..offsets[i + 1] as usize {
+                if !build_ids.is_valid(j) || build_ids.value(j) != reference.build_id.as_bytes() {
+                    continue;
+                }
+                let line_offsets = location_lines.value_offsets();
+                let line_start = line_offsets[j] as usize;
+                for k in line_start..line_offsets[j + 1] as usize {
+                    if !filenames.is_valid(k) || filenames.value(k) != reference.filename.as_bytes()
+                    {
+                        continue;
+                    }
+                    let line = line_numbers.value(k);
+                    let entry = lines.entry(line).or_default();
+                    // The first line of the first location is the leaf,
+                    // the others are its inlined callers and callers.
+                    if j == offsets[i] as usize && k == line_start {
+                        entry.flat += value;
+                    }
+                    if seen.insert(line) {
+                        entry.cumulative += value;
+                    }
+                }
+            }
+        }
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema_builder::symbolized_record_schema;
+    use datafusion::arrow::{
+        array::{
+            ArrayBuilder, BinaryDictionaryBuilder, Int64Array, Int64Builder, ListBuilder,
+            StructBuilder, UInt64Builder,
+        },
+        datatypes::{Field, FieldRef, Fields, UInt32Type},
+    };
+    use std::sync::Arc;
+
+    const BUILD_ID: &str = "126cf12e76810726c76d87ab8a6a57e9a1d4c815";
+
+    /// A location, by the build ID of its mapping and its lines.
+    type Location<'a> = (&'a str, &'a [(i64, &'a str)]);
+
+    fn record(samples: &[(&[Location], i64)]) -> RecordBatch {
+        // Dictionaries with UInt32 keys aren't supported by make_builder, so
+        // the builders are made by hand.
+        fn dictionary() -> Box<dyn ArrayBuilder> {
+            Box::new(BinaryDictionaryBuilder::<UInt32Type>::new())
+        }
+        fn inner(field: &Field) -> (FieldRef, Fields) {
+            match field.data_type() {
+                DataType::List(inner) => match inner.data_type() {
+                    DataType::Struct(fields) => (Arc::clone(inner), fields.clone()),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            }
+        }
+
+        let schema = symbolized_record_schema();
+        let (location_field, location_fields) = inner(schema.field(0));
+        let (line_field, line_fields) = inner(&location_fields[6]);
+        let line: Box<dyn ArrayBuilder> = Box::new(StructBuilder::new(
+            line_fields,
+            vec![
+                Box::new(Int64Builder::new()),
+                dictionary(),
+                dictionary(),
+                dictionary(),
+                Box::new(Int64Builder::new()),
+            ],
+        ));
+        let mut locations = ListBuilder::new(StructBuilder::new(
+            location_fields,
+            vec![
+                Box::new(UInt64Builder::new()),
+                Box::new(UInt64Builder::new()),
+                Box::new(UInt64Builder::new()),
+                Box::new(UInt64Builder::new()),
+                dictionary(),
+                dictionary(),
+                Box::new(ListBuilder::new(line).with_field(line_field)),
+            ],
+        ))
+        .with_field(location_field);
+        for (stack, _) in samples.iter() {
+            for (build_id, lines) in stack.iter() {
+                let location = locations.values();
+                for i in 0..4 {
+                    location
+                        .field_builder::<UInt64Builder>(i)
+                        .unwrap()
+                        .append_value(0);
+                }
+                for (i, value) in [(4, "/bin/app"), (5, *build_id)] {
+                    location
+                        .field_builder::<BinaryDictionaryBuilder<UInt32Type>>(i)
+                        .unwrap()
+                        .append_value(value);
+                }
+                let location_lines = location
+                    .field_builder::<ListBuilder<Box<dyn ArrayBuilder>>>(6)
+                    .unwrap();
+                for (number, filename) in lines.iter() {
+                    let line = location_lines
+                        .values()
+                        .as_any_mut()
+                        .downcast_mut::<StructBuilder>()
+                        .unwrap();
+                    line.field_builder::<Int64Builder>(0)
+                        .unwrap()
+                        .append_value(*number);
+                    for (i, value) in [(1, "fn"), (2, "fn"), (3, *filename)] {
+                        line.field_builder::<BinaryDictionaryBuilder<UInt32Type>>(i)
+                            .unwrap()
+                            .append_value(value);
+                    }
+                    line.field_builder::<Int64Builder>(4)
+                        .unwrap()
+                        .append_value(1);
+                    line.append(true);
+                }
+                location_lines.append(true);
+                location.append(true);
+            }
+            locations.append(true);
+        }
+
+        let values: Int64Array = samples.iter().map(|(_, value)| Some(*value)).collect();
+        let diffs = Int64Array::from(vec![0; samples.len()]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(locations.finish()),
+                Arc::new(values),
+                Arc::new(diffs),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_line_values() {
+        let other = "0000000000000000000000000000000000000000";
+        let record = record(&[
+            // main.c:10 inlined into main.c:20, called from main.c:30.
+            (
+                &[
+                    (BUILD_ID, &[(10, "main.c"), (20, "main.c")]),
+                    (BUILD_ID, &[(30, "main.c")]),
+                ],
+                5,
+            ),
+            // Recursion only counts once towards the cumulative value.
+            (
+                &[(BUILD_ID, &[(30, "main.c")]), (BUILD_ID, &[(30, "main.c")])],
+                3,
+            ),
+            // Lines of other files and build IDs are ignored.
+            (
+                &[(BUILD_ID, &[(1, "util.c")]), (other, &[(20, "main.c")])],
+                7,
+            ),
+        ]);
+
+        let reference = SourceReference {
+            build_id: BUILD_ID.into(),
+            filename: "main.c".into(),
+            source_only: false,
+        };
+        let lines = line_values(&[record], &reference).unwrap();
+        assert_eq!(
+            lines.into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    10,
+                    LineValues {
+                        flat: 5,
+                        cumulative: 5
+                    }
+                ),
+                (
+                    20,
+                    LineValues {
+                        flat: 0,
+                        cumulative: 5
+                    }
+                ),
+                (
+                    30,
+                    LineValues {
+                        flat: 3,
+                        cumulative: 8
+                    }
+                ),
+            ]
+        );
+    }
+}
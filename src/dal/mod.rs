@@ -2,21 +2,24 @@ use crate::{
     profile::{
         self,
         schema::{
-            COLUMN_NAME, COLUMN_PERIOD_TYPE, COLUMN_PERIOD_UNIT, COLUMN_SAMPLE_TYPE,
-            COLUMN_SAMPLE_UNIT, COLUMN_STACKTRACE, COLUMN_TIMESTAMP, COLUMN_VALUE,
+            COLUMN_DELTA, COLUMN_NAME, COLUMN_PERIOD_TYPE, COLUMN_PERIOD_UNIT, COLUMN_SAMPLE_TYPE,
+            COLUMN_SAMPLE_UNIT, COLUMN_STACKTRACE_ID, COLUMN_TIMESTAMP, COLUMN_VALUE,
         },
         utils,
     },
     schema_builder::{self, symbolized_record_schema},
+    stacktrace_store::StacktraceStore,
     symbolizer::Symbolizer,
 };
 use datafusion::{
     arrow::{
         array::{
-            Array, ArrayBuilder, AsArray, BinaryDictionaryBuilder, GenericListBuilder,
-            Int64Builder, ListBuilder, NullArray, RecordBatch, StructBuilder, UInt64Builder,
+            new_null_array, Array, ArrayBuilder, AsArray, BinaryArray, BinaryDictionaryBuilder,
+            GenericListBuilder, Int64Builder, ListArray, ListBuilder, NullArray, RecordBatch,
+            StringArray, StructBuilder, UInt64Builder,
         },
-        datatypes::{DataType, Field, Fields, Int32Type},
+        compute::cast,
+        datatypes::{DataType, Field, Fields, Int32Type, Schema},
     },
     catalog::TableProvider,
     datasource::{
@@ -27,6 +30,7 @@ use datafusion::{
     prelude::*,
 };
 use std::{
+    collections::HashMap,
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
@@ -45,12 +49,20 @@ impl CachedProvider {
     }
 }
 
+/// Label that kernel frames are symbolized against.
+const COLUMN_KERNEL_RELEASE: &str = "labels.kernel_release";
+
+/// Column that held the encoded locations of every sample, in files written
+/// before stacktraces were kept in the stacktrace store.
+const COLUMN_LEGACY_STACKTRACE: &str = "stacktrace";
+
 pub struct DataAccessLayer {
     path_prefix: String,
     max_cache_stale_duration: Duration,
     config: ListingTableConfig,
     cached_provider: Mutex<CachedProvider>,
     symbolizer: Arc<Symbolizer>,
+    stacktraces: Arc<StacktraceStore>,
 }
 
 #[derive(Debug)]
@@ -64,6 +76,7 @@ impl DataAccessLayer {
         path: &str,
         cache_stale_duration: u64,
         symbolizer: &Arc<Symbolizer>,
+        stacktraces: &Arc<StacktraceStore>,
     ) -> anyhow::Result<Self> {
         let ctx = SessionContext::new();
         let session_state = ctx.state();
@@ -76,6 +89,16 @@ impl DataAccessLayer {
         let resolved_schema = listing_options
             .infer_schema(&session_state, &table_path)
             .await?;
+        // Files written with older layouts lack some of the columns, which
+        // then read as null.
+        let resolved_schema = Arc::new(Schema::new_with_metadata(
+            resolved_schema
+                .fields()
+                .iter()
+                .map(|field| field.as_ref().clone().with_nullable(true))
+                .collect::<Vec<_>>(),
+            resolved_schema.metadata().clone(),
+        ));
 
         let config = ListingTableConfig::new(table_path)
             .with_listing_options(listing_options)
@@ -89,6 +112,7 @@ impl DataAccessLayer {
             cached_provider: Mutex::new(CachedProvider::new(provider)),
             config,
             symbolizer: Arc::clone(symbolizer),
+            stacktraces: Arc::clone(stacktraces),
         })
     }
 
@@ -139,6 +163,20 @@ impl DataAccessLayer {
         let (mut meta, mut filter_expr) = qs_to_meta_and_filter_expr(qs)?;
         filter_expr.push(col(COLUMN_TIMESTAMP).eq(lit(time)));
 
+        // Files of every layout are read together, so any of the columns
+        // can be missing from some of them, or from all.
+        let provider = self.get_provider().await?;
+        let schema = provider.schema();
+        // Files written before the delta column don't say whether their
+        // profiles are delta, so they are matched either way.
+        if schema.column_with_name(COLUMN_DELTA).is_some() {
+            filter_expr.push(
+                col(COLUMN_DELTA)
+                    .eq(lit(meta.delta))
+                    .or(col(COLUMN_DELTA).is_null()),
+            );
+        }
+
         let filter_expr = filter_expr
             .into_iter()
             .reduce(|acc, pred| and(acc, pred))
@@ -147,8 +185,16 @@ impl DataAccessLayer {
         let ctx = SessionContext::new();
         let value_column = "sum(value)";
         let aggr_expr = vec![sum(col(COLUMN_VALUE)).alias(value_column)];
-        let group_expr = vec![col(COLUMN_STACKTRACE)];
-        let df = ctx.read_table(self.get_provider().await?)?;
+        let group_expr = [
+            COLUMN_STACKTRACE_ID,
+            COLUMN_LEGACY_STACKTRACE,
+            COLUMN_KERNEL_RELEASE,
+        ]
+        .into_iter()
+        .filter(|name| schema.column_with_name(name).is_some())
+        .map(|name| col(format!(r#""{}""#, name)))
+        .collect();
+        let df = ctx.read_table(provider)?;
         let df = df.filter(filter_expr)?;
         let df = df.aggregate(group_expr, aggr_expr)?;
         let record = df.collect().await?;
@@ -158,6 +204,63 @@ impl DataAccessLayer {
         Ok((record, value_column, meta))
     }
 
+    /// Returns every distinct profile type that has been ingested.
+    pub async fn profile_types(&self) -> anyhow::Result<Vec<profile::ProfileType>> {
+        let ctx = SessionContext::new();
+        let provider = self.get_provider().await?;
+        // Profiles of files written before the delta column are listed as
+        // not delta.
+        let delta = match provider.schema().column_with_name(COLUMN_DELTA) {
+            Some(_) => coalesce(vec![col(COLUMN_DELTA), lit(false)]),
+            None => lit(false),
+        };
+        let df = ctx.read_table(provider)?;
+        let df = df.aggregate(
+            vec![
+                col(COLUMN_NAME),
+                col(COLUMN_SAMPLE_TYPE),
+                col(COLUMN_SAMPLE_UNIT),
+                col(COLUMN_PERIOD_TYPE),
+                col(COLUMN_PERIOD_UNIT),
+                delta.alias(COLUMN_DELTA),
+            ],
+            vec![],
+        )?;
+
+        let mut profile_types = vec![];
+        for record in df.collect().await? {
+            let string_column = |name: &str| -> anyhow::Result<Arc<dyn Array>> {
+                match record.column_by_name(name) {
+                    Some(c) => Ok(cast(c, &DataType::Utf8)?),
+                    None => anyhow::bail!("Missing column: {}", name),
+                }
+            };
+            let names = string_column(COLUMN_NAME)?;
+            let sample_types = string_column(COLUMN_SAMPLE_TYPE)?;
+            let sample_units = string_column(COLUMN_SAMPLE_UNIT)?;
+            let period_types = string_column(COLUMN_PERIOD_TYPE)?;
+            let period_units = string_column(COLUMN_PERIOD_UNIT)?;
+            let deltas = match record.column_by_name(COLUMN_DELTA) {
+                Some(c) => c.as_boolean(),
+                None => anyhow::bail!("Missing column: {}", COLUMN_DELTA),
+            };
+
+            for i in 0..record.num_rows() {
+                profile_types.push(profile::ProfileType {
+                    name: names.as_string::<i32>().value(i).to_string(),
+                    sample_type: sample_types.as_string::<i32>().value(i).to_string(),
+                    sample_unit: sample_units.as_string::<i32>().value(i).to_string(),
+                    period_type: period_types.as_string::<i32>().value(i).to_string(),
+                    period_unit: period_units.as_string::<i32>().value(i).to_string(),
+                    delta: deltas.is_valid(i) && deltas.value(i),
+                });
+            }
+        }
+
+        profile_types.sort_by_key(|pt| pt.to_string());
+        Ok(profile_types)
+    }
+
     async fn symbolize_records(
         &self,
         records: Vec<RecordBatch>,
@@ -167,16 +270,23 @@ impl DataAccessLayer {
         let mut res = Vec::with_capacity(records.len());
 
         for record in records.iter() {
-            let stacktrace_col = Arc::clone(match record.column_by_name(COLUMN_STACKTRACE) {
-                Some(sc) => sc,
-                None => anyhow::bail!("Missing column: {}", COLUMN_STACKTRACE),
-            });
+            let stacktrace_id_col = record.column_by_name(COLUMN_STACKTRACE_ID).cloned();
+            let legacy_stacktrace_col = record.column_by_name(COLUMN_LEGACY_STACKTRACE).cloned();
+            if stacktrace_id_col.is_none() && legacy_stacktrace_col.is_none() {
+                anyhow::bail!("Missing column: {}", COLUMN_STACKTRACE_ID);
+            }
             let value_column = Arc::clone(match record.column_by_name(value_col) {
                 Some(sc) => sc,
                 None => anyhow::bail!("Missing column: {}", value_col),
             });
+            let kernel_release_col = match record.column_by_name(COLUMN_KERNEL_RELEASE) {
+                Some(c) => cast(c, &DataType::Utf8)?,
+                None => new_null_array(&DataType::Utf8, record.num_rows()),
+            };
             let values_per_second = Arc::new(NullArray::new(value_col.len()));
-            let locations_record = self.resolve_stacks(stacktrace_col).await?;
+            let locations_record = self
+                .resolve_stacks(stacktrace_id_col, legacy_stacktrace_col, kernel_release_col)
+                .await?;
 
             let records = vec![
                 Arc::clone(locations_record.column(0)),
@@ -191,150 +301,299 @@ impl DataAccessLayer {
         Ok(res)
     }
 
-    async fn resolve_stacks(&self, stacktrace_col: Arc<dyn Array>) -> anyhow::Result<RecordBatch> {
-        let stacktrace_col = match stacktrace_col.as_list_opt::<i32>() {
-            Some(sc) => sc,
-            None => anyhow::bail!("stacktrace column couldnot be downcasted to binary array."),
+    /// Resolves the stack of every row, from its stacktrace ID, or from the
+    /// locations stored inline by files written before stacktrace IDs.
+    async fn resolve_stacks(
+        &self,
+        stacktrace_id_col: Option<Arc<dyn Array>>,
+        legacy_stacktrace_col: Option<Arc<dyn Array>>,
+        kernel_release_col: Arc<dyn Array>,
+    ) -> anyhow::Result<RecordBatch> {
+        let stacktrace_ids = match stacktrace_id_col {
+            Some(c) => Some(cast(&c, &DataType::Utf8)?),
+            None => None,
         };
-        let stacktrace_col = match stacktrace_col.values().as_binary_opt::<i32>() {
-            Some(sc) => sc,
-            None => anyhow::bail!("stacktrace column couldnot be downcasted to binary array."),
+        let stacktrace_ids = match stacktrace_ids.as_ref() {
+            Some(c) => match c.as_string_opt::<i32>() {
+                Some(sc) => Some(sc),
+                None => {
+                    anyhow::bail!("stacktrace_id column couldnot be downcasted to string array.")
+                }
+            },
+            None => None,
+        };
+        let legacy_stacktraces = match legacy_stacktrace_col.as_ref() {
+            Some(c) => match c.as_list_opt::<i32>() {
+                Some(sc) => Some(sc),
+                None => anyhow::bail!("stacktrace column couldnot be downcasted to list array."),
+            },
+            None => None,
         };
 
-        let symbolized_locations =
-            utils::symbolize_locations(stacktrace_col, Arc::clone(&self.symbolizer)).await?;
+        let kernel_releases = kernel_release_col.as_string::<i32>();
+
+        // Every unique location is decoded and symbolized once, no matter how
+        // many stacktraces reference it. Kernel frames resolve differently
+        // depending on the kernel, so for them the release is part of what
+        // makes a location unique.
+        let mut location_index: HashMap<(String, Option<String>), usize> = HashMap::new();
+        let mut encoded_locations: Vec<Arc<Vec<u8>>> = vec![];
+        // Lines of locations that were symbolized after ingestion.
+        let mut presymbolized: Vec<Option<Arc<Vec<profile::LocationLine>>>> = vec![];
+        let mut location_releases: Vec<Option<String>> = vec![];
+        let mut stacks: Vec<Option<Vec<usize>>> = Vec::with_capacity(kernel_releases.len());
+
+        for row in 0..kernel_releases.len() {
+            let release = match kernel_releases.is_null(row) {
+                true => None,
+                false => Some(kernel_releases.value(row).to_string()),
+            };
+            let frames = match self
+                .stack_frames(stacktrace_ids, legacy_stacktraces, row)
+                .await?
+            {
+                Some(frames) => frames,
+                None => {
+                    stacks.push(None);
+                    continue;
+                }
+            };
+
+            let mut stack = Vec::with_capacity(frames.len());
+            for (location_id, encoded) in frames {
+                let key = (location_id.clone(), release.clone());
+                if let Some(idx) = location_index.get(&key) {
+                    stack.push(*idx);
+                    continue;
+                }
+
+                let encoded = match encoded {
+                    Some(encoded) => encoded,
+                    None => match self.stacktraces.location(&location_id).await? {
+                        Some(encoded) => encoded,
+                        None => {
+                            log::warn!("Location {} not found in stacktrace store", location_id);
+                            continue;
+                        }
+                    },
+                };
+                location_index.insert(key, encoded_locations.len());
+                stack.push(encoded_locations.len());
+                encoded_locations.push(encoded);
+                location_releases.push(release.clone());
+                presymbolized.push(
+                    self.stacktraces
+                        .symbolized(&location_id, release.as_deref()),
+                );
+            }
+            stacks.push(Some(stack));
+        }
+
+        let locations = BinaryArray::from_iter(
+            encoded_locations
+                .iter()
+                .zip(presymbolized.iter())
+                .map(|(l, lines)| lines.is_none().then_some(l.as_slice())),
+        );
+        let mut symbolized_locations = utils::symbolize_locations(
+            &locations,
+            &location_releases,
+            Arc::clone(&self.symbolizer),
+        )
+        .await?;
+        log::debug!(
+            "Symbolizer cache metrics: {:?}",
+            self.symbolizer.cache_metrics()
+        );
+
+        for (idx, lines) in presymbolized.iter().enumerate() {
+            let lines = match lines {
+                Some(lines) => lines,
+                None => continue,
+            };
+            match profile::PprofLocations::decode(&encoded_locations[idx]) {
+                Ok(location) => {
+                    let mut location = location.to_location();
+                    location.lines = lines.as_ref().clone();
+                    symbolized_locations[idx] = Some(location);
+                }
+                Err(e) => log::warn!("Failed to decode location: {}", e),
+            }
+        }
 
         let mut locations_list = locations_array_builder();
-        for (indx, stacktrace) in stacktrace_col.iter().enumerate() {
-            if stacktrace.is_none() {
-                locations_list.append_null();
-                continue;
+        for stack in stacks.iter() {
+            match stack {
+                Some(stack) => {
+                    for idx in stack.iter() {
+                        append_location(
+                            locations_list.values(),
+                            symbolized_locations[*idx].as_ref(),
+                        );
+                    }
+                    locations_list.append(true);
+                }
+                None => locations_list.append_null(),
             }
-            locations_list.append(true);
+        }
+
+        let locations_array = locations_list.finish();
+        Ok(RecordBatch::try_new(
+            Arc::new(schema_builder::locations_arrow_schema()),
+            vec![Arc::new(locations_array)],
+        )?)
+    }
+
+    /// Returns the location IDs of the stack of the row, innermost frame
+    /// first, along with the encoded locations if they're stored inline.
+    async fn stack_frames(
+        &self,
+        stacktrace_ids: Option<&StringArray>,
+        legacy_stacktraces: Option<&ListArray>,
+        row: usize,
+    ) -> anyhow::Result<Option<Vec<(String, Option<Arc<Vec<u8>>>)>>> {
+        if let Some(id) = stacktrace_ids.filter(|ids| ids.is_valid(row)) {
+            let location_ids = self.stacktraces.stacktrace(id.value(row)).await?;
+            return Ok(location_ids
+                .map(|location_ids| location_ids.iter().map(|id| (id.clone(), None)).collect()));
+        }
+
+        let stacktrace = match legacy_stacktraces.filter(|stacktraces| stacktraces.is_valid(row)) {
+            Some(stacktraces) => stacktraces.value(row),
+            None => return Ok(None),
+        };
+        let locations = match stacktrace.as_binary_opt::<i32>() {
+            Some(locations) => locations,
+            None => anyhow::bail!("stacktrace column couldnot be downcasted to binary array."),
+        };
+        Ok(Some(
+            locations
+                .iter()
+                .flatten()
+                .map(|location| {
+                    (
+                        StacktraceStore::location_id(location),
+                        Some(Arc::new(location.to_vec())),
+                    )
+                })
+                .collect(),
+        ))
+    }
+}
 
-            let locations: &mut StructBuilder = locations_list.values();
+fn append_location(locations: &mut StructBuilder, location: Option<&profile::Location>) {
+    let location = match location {
+        Some(location) => location,
+        None => {
+            locations
+                .field_builder::<UInt64Builder>(0)
+                .unwrap()
+                .append_value(0);
+            for i in 1..4 {
+                locations
+                    .field_builder::<UInt64Builder>(i)
+                    .unwrap()
+                    .append_null();
+            }
+            for i in 4..6 {
+                locations
+                    .field_builder::<BinaryDictionaryBuilder<Int32Type>>(i)
+                    .unwrap()
+                    .append_null();
+            }
+            locations
+                .field_builder::<ListBuilder<Box<dyn ArrayBuilder>>>(6)
+                .unwrap()
+                .append_null();
             locations.append(true);
+            return;
+        }
+    };
 
-            let addresses = locations.field_builder::<UInt64Builder>(0).unwrap();
-            if let Some(symbolized_location) = &symbolized_locations[indx] {
-                addresses.append_value(symbolized_location.address);
+    let addresses = locations.field_builder::<UInt64Builder>(0).unwrap();
+    addresses.append_value(location.address);
 
-                if let Some(mapping) = &symbolized_location.mapping {
-                    let mapping_build_id = locations
-                        .field_builder::<BinaryDictionaryBuilder<Int32Type>>(5)
-                        .unwrap();
-                    if !mapping.build_id.is_empty() {
-                        mapping_build_id.append_value(mapping.build_id.as_bytes());
-                    } else {
-                        mapping_build_id.append_value("".as_bytes());
-                    }
+    let (build_id, file, start, limit, offset) = match &location.mapping {
+        Some(mapping) => (
+            mapping.build_id.as_str(),
+            mapping.file.as_str(),
+            mapping.start,
+            mapping.limit,
+            mapping.offset,
+        ),
+        None => ("", "", 0, 0, 0),
+    };
 
-                    let mapping_file = locations
-                        .field_builder::<BinaryDictionaryBuilder<Int32Type>>(4)
-                        .unwrap();
-                    if !mapping.file.is_empty() {
-                        mapping_file.append_value(mapping.file.as_bytes());
-                    } else {
-                        mapping_file.append_value("".as_bytes());
-                    }
+    let mapping_start = locations.field_builder::<UInt64Builder>(1).unwrap();
+    mapping_start.append_value(start);
 
-                    let mapping_start = locations.field_builder::<UInt64Builder>(1).unwrap();
-                    mapping_start.append_value(mapping.start);
+    let mapping_limit = locations.field_builder::<UInt64Builder>(2).unwrap();
+    mapping_limit.append_value(limit);
 
-                    let mapping_limit = locations.field_builder::<UInt64Builder>(2).unwrap();
-                    mapping_limit.append_value(mapping.limit);
+    let mapping_offset = locations.field_builder::<UInt64Builder>(3).unwrap();
+    mapping_offset.append_value(offset);
 
-                    let mapping_offset = locations.field_builder::<UInt64Builder>(3).unwrap();
-                    mapping_offset.append_value(mapping.offset);
-                } else {
-                    let mapping_build_id = locations
-                        .field_builder::<BinaryDictionaryBuilder<Int32Type>>(5)
-                        .unwrap();
-                    mapping_build_id.append_value("".as_bytes());
+    let mapping_file = locations
+        .field_builder::<BinaryDictionaryBuilder<Int32Type>>(4)
+        .unwrap();
+    mapping_file.append_value(file.as_bytes());
+
+    let mapping_build_id = locations
+        .field_builder::<BinaryDictionaryBuilder<Int32Type>>(5)
+        .unwrap();
+    mapping_build_id.append_value(build_id.as_bytes());
+
+    let lines = locations
+        .field_builder::<ListBuilder<Box<dyn ArrayBuilder>>>(6)
+        .unwrap();
+    if location.lines.is_empty() {
+        lines.append(false);
+    } else {
+        for ln in location.lines.iter() {
+            let line = lines
+                .values()
+                .as_any_mut()
+                .downcast_mut::<StructBuilder>()
+                .unwrap();
+
+            let line_number = line.field_builder::<Int64Builder>(0).unwrap();
+            line_number.append_value(ln.line);
 
-                    let mapping_file = locations
-                        .field_builder::<BinaryDictionaryBuilder<Int32Type>>(4)
+            match &ln.function {
+                Some(func) => {
+                    let function_name = line
+                        .field_builder::<BinaryDictionaryBuilder<Int32Type>>(1)
                         .unwrap();
-                    mapping_file.append_value("".as_bytes());
+                    function_name.append_value(func.name.as_bytes());
 
-                    let mapping_start = locations.field_builder::<UInt64Builder>(1).unwrap();
-                    mapping_start.append_value(0);
+                    let function_system_name = line
+                        .field_builder::<BinaryDictionaryBuilder<Int32Type>>(2)
+                        .unwrap();
+                    function_system_name.append_value(func.system_name.as_bytes());
 
-                    let mapping_limit = locations.field_builder::<UInt64Builder>(2).unwrap();
-                    mapping_limit.append_value(0);
+                    let function_filename = line
+                        .field_builder::<BinaryDictionaryBuilder<Int32Type>>(3)
+                        .unwrap();
+                    function_filename.append_value(func.filename.as_bytes());
 
-                    let mapping_offset = locations.field_builder::<UInt64Builder>(3).unwrap();
-                    mapping_offset.append_value(0);
+                    let function_start_line = line.field_builder::<Int64Builder>(4).unwrap();
+                    function_start_line.append_value(func.start_line);
                 }
-
-                let lines = locations
-                    .field_builder::<ListBuilder<Box<dyn ArrayBuilder>>>(6)
-                    .unwrap();
-                if symbolized_location.lines.len() > 0 {
-                    lines.append(true);
-                    for ln in symbolized_location.lines.iter() {
-                        let line = lines
-                            .values()
-                            .as_any_mut()
-                            .downcast_mut::<StructBuilder>()
-                            .unwrap();
-                        line.append(true);
-
-                        let line_number = line.field_builder::<Int64Builder>(0).unwrap();
-                        line_number.append_value(ln.line);
-
-                        if let Some(func) = &ln.function {
-                            let function_name = line
-                                .field_builder::<BinaryDictionaryBuilder<Int32Type>>(1)
-                                .unwrap();
-                            if !func.name.is_empty() {
-                                function_name.append_value(func.name.as_bytes());
-                            } else {
-                                function_name.append_value("".as_bytes());
-                            }
-
-                            let function_system_name = line
-                                .field_builder::<BinaryDictionaryBuilder<Int32Type>>(2)
-                                .unwrap();
-                            if !func.system_name.is_empty() {
-                                function_system_name.append_value(func.system_name.as_bytes());
-                            } else {
-                                function_system_name.append_value("".as_bytes());
-                            }
-
-                            let function_filename = line
-                                .field_builder::<BinaryDictionaryBuilder<Int32Type>>(3)
-                                .unwrap();
-                            if !func.filename.is_empty() {
-                                function_filename.append_value(func.filename.as_bytes());
-                            } else {
-                                function_filename.append_value("".as_bytes());
-                            }
-
-                            let function_start_line =
-                                line.field_builder::<Int64Builder>(4).unwrap();
-                            function_start_line.append_value(func.start_line);
-                        }
+                None => {
+                    for i in 1..4 {
+                        line.field_builder::<BinaryDictionaryBuilder<Int32Type>>(i)
+                            .unwrap()
+                            .append_null();
                     }
-                } else {
-                    lines.append(false);
+                    line.field_builder::<Int64Builder>(4).unwrap().append_null();
                 }
-            } else {
-                addresses.append_value(0);
-
-                let lines = locations
-                    .field_builder::<ListBuilder<Box<dyn ArrayBuilder>>>(6)
-                    .unwrap();
-                lines.append_null();
             }
+            line.append(true);
         }
-
-        let locations_array = locations_list.finish();
-        Ok(RecordBatch::try_new(
-            Arc::new(schema_builder::locations_arrow_schema()),
-            vec![Arc::new(locations_array)],
-        )?)
+        lines.append(true);
     }
+
+    locations.append(true);
 }
 
 fn locations_array_builder() -> GenericListBuilder<i32, StructBuilder> {
@@ -397,7 +656,7 @@ fn locations_array_builder() -> GenericListBuilder<i32, StructBuilder> {
 fn qs_to_meta_and_filter_expr(qs: &str) -> anyhow::Result<(profile::Meta, Vec<Expr>)> {
     let parsed_query: Vec<&str> = qs.trim().split("|").collect();
     if parsed_query.len() != 2 {
-        anyhow::bail!("Expected 2 part query but received {}. Make sure it is in this format: <labels_name>=xx,<labels_name>=yy,...|<name>:<sample-type>:<sample-unit>:<period-type>:<period-unit>", parsed_query.len());
+        anyhow::bail!("Expected 2 part query but received {}. Make sure it is in this format: <labels_name>=xx,<labels_name>=yy,...|<name>:<sample-type>:<sample-unit>:<period-type>:<period-unit>[:delta]", parsed_query.len());
     }
 
     let labels_matcher: Vec<&str> = parsed_query[0].split(",").map(|lm| lm.trim()).collect();
@@ -412,9 +671,13 @@ fn qs_to_meta_and_filter_expr(qs: &str) -> anyhow::Result<(profile::Meta, Vec<Ex
         filter_expressions.push(col(col_name).eq(lit(parsed_lm[1])));
     }
 
-    let meta_fields: Vec<&str> = parsed_query[1].trim().split(":").collect();
+    let mut meta_fields: Vec<&str> = parsed_query[1].trim().split(":").collect();
+    let delta = meta_fields.len() == 6 && meta_fields[5] == "delta";
+    if delta {
+        meta_fields.pop();
+    }
     if meta_fields.len() != 5 {
-        anyhow::bail!("Expected 5 meta fields but received {}. Make sure it is in this format: <name>:<sample-type>:<sample-unit>:<period-type>:<period-unit> ", meta_fields.len());
+        anyhow::bail!("Expected 5 meta fields but received {}. Make sure it is in this format: <name>:<sample-type>:<sample-unit>:<period-type>:<period-unit>[:delta] ", meta_fields.len());
     }
 
     let meta = profile::Meta {
@@ -430,6 +693,7 @@ fn qs_to_meta_and_filter_expr(qs: &str) -> anyhow::Result<(profile::Meta, Vec<Ex
         timestamp: 0,
         duration: 0,
         period: 0,
+        delta,
     };
 
     filter_expressions.push(col(COLUMN_NAME).eq(lit(meta_fields[0])));
@@ -589,3 +853,234 @@ fn qs_to_meta_and_filter_expr(qs: &str) -> anyhow::Result<(profile::Meta, Vec<Ex
 //    Ok(())
 //}
 //}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        debuginfo_store::{self, DebuginfoFetcher},
+        profile::{schema, PprofLocations},
+        storage,
+    };
+    use datafusion::{
+        arrow::array::{ArrayRef, BinaryBuilder, BooleanArray, Int64Array},
+        parquet::arrow::ArrowWriter,
+    };
+    use object_store::ObjectStore;
+
+    fn record(profile_types: &[(&str, &str, bool)]) -> RecordBatch {
+        let schema = Arc::new(schema::create_schema());
+        let len = profile_types.len();
+        let columns: Vec<ArrayRef> = schema
+            .fields()
+            .iter()
+            .map(|field| {
+                let values: Option<Vec<&str>> = match field.name().as_str() {
+                    COLUMN_NAME => Some(profile_types.iter().map(|pt| pt.0).collect()),
+                    COLUMN_SAMPLE_TYPE => Some(profile_types.iter().map(|pt| pt.1).collect()),
+                    COLUMN_SAMPLE_UNIT | COLUMN_PERIOD_TYPE | COLUMN_PERIOD_UNIT => {
+                        Some(vec!["x"; len])
+                    }
+                    _ => None,
+                };
+                match (field.name().as_str(), values) {
+                    (COLUMN_DELTA, _) => Arc::new(BooleanArray::from(
+                        profile_types.iter().map(|pt| pt.2).collect::<Vec<_>>(),
+                    )) as ArrayRef,
+                    (_, Some(values)) => {
+                        cast(&StringArray::from(values), field.data_type()).unwrap()
+                    }
+                    (_, None) if field.is_nullable() => new_null_array(field.data_type(), len),
+                    (_, None) => Arc::new(Int64Array::from(vec![0; len])),
+                }
+            })
+            .collect();
+
+        RecordBatch::try_new(schema, columns).unwrap()
+    }
+
+    #[test]
+    fn test_query_delta() {
+        let (meta, filters) =
+            qs_to_meta_and_filter_expr("node=a|parca_agent:samples:count:cpu:nanoseconds:delta")
+                .unwrap();
+        assert!(meta.delta);
+        assert_eq!(meta.period_type.unit, "nanoseconds");
+        assert!(filters.contains(&col(COLUMN_PERIOD_UNIT).eq(lit("nanoseconds"))));
+
+        let (meta, _) =
+            qs_to_meta_and_filter_expr("node=a|memory:inuse_space:bytes:space:bytes").unwrap();
+        assert!(!meta.delta);
+
+        assert!(qs_to_meta_and_filter_expr("node=a|memory:inuse_space:bytes:space").is_err());
+    }
+
+    fn write_parquet(path: &std::path::Path, batch: &RecordBatch) {
+        let file = std::fs::File::create(path).unwrap();
+        let mut writer = ArrowWriter::try_new(file, batch.schema(), None).unwrap();
+        writer.write(batch).unwrap();
+        writer.close().unwrap();
+    }
+
+    fn with_column(batch: &RecordBatch, name: &str, column: Option<ArrayRef>) -> RecordBatch {
+        let mut fields = vec![];
+        let mut columns = vec![];
+        for (field, c) in batch.schema().fields().iter().zip(batch.columns()) {
+            if field.name() != name {
+                fields.push(Arc::clone(field));
+                columns.push(Arc::clone(c));
+            }
+        }
+        if let Some(column) = column {
+            fields.push(Arc::new(Field::new(name, column.data_type().clone(), true)));
+            columns.push(column);
+        }
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).unwrap()
+    }
+
+    async fn dal(path: &str, stacktraces: &Arc<StacktraceStore>) -> DataAccessLayer {
+        let metadata_store = debuginfo_store::MetadataStore::new();
+        let debuginfod = debuginfo_store::DebugInfod::default();
+        let debuginfod_bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let symbolizer = Arc::new(Symbolizer::new(
+            debuginfo_store::MetadataStore::with_store(metadata_store.store.clone()),
+            DebuginfoFetcher::new(Arc::clone(&debuginfod_bucket), debuginfod.clone()),
+        ));
+
+        DataAccessLayer::try_new(path, 5000, &symbolizer, stacktraces)
+            .await
+            .unwrap()
+    }
+
+    fn location(address: u64) -> PprofLocations {
+        PprofLocations {
+            address,
+            number_of_lines: 0,
+            build_id: String::new(),
+            file_name: String::new(),
+            mapping_memory_start: 0,
+            mapping_memory_end: 0,
+            mapping_file_offset: 0,
+            functions: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_legacy_stacktraces() {
+        let dir = tempfile::tempdir().unwrap();
+        let stacktraces = Arc::new(StacktraceStore::new(Arc::new(storage::new_memory_bucket())));
+        let node: ArrayRef = cast(
+            &StringArray::from(vec!["a"]),
+            &DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+        )
+        .unwrap();
+
+        // Files written before stacktrace IDs hold the bincode encoded
+        // locations inline.
+        let mut legacy = ListBuilder::new(BinaryBuilder::new());
+        legacy
+            .values()
+            .append_value(bincode::serialize(&location(0x10)).unwrap());
+        legacy
+            .values()
+            .append_value(bincode::serialize(&location(0x20)).unwrap());
+        legacy.append(true);
+        let batch = record(&[("parca_agent", "samples", true)]);
+        let batch = with_column(&batch, "labels.node", Some(Arc::clone(&node)));
+        let batch = with_column(&batch, COLUMN_DELTA, None);
+        let batch = with_column(&batch, COLUMN_STACKTRACE_ID, None);
+        let batch = with_column(
+            &batch,
+            COLUMN_LEGACY_STACKTRACE,
+            Some(Arc::new(legacy.finish())),
+        );
+        write_parquet(&dir.path().join("0.parquet"), &batch);
+
+        let id = stacktraces.insert(&[location(0x30).encode().unwrap()]);
+        let batch = record(&[("parca_agent", "samples", true)]);
+        let batch = with_column(&batch, "labels.node", Some(node));
+        let batch = with_column(
+            &batch,
+            COLUMN_STACKTRACE_ID,
+            Some(Arc::new(StringArray::from(vec![id.as_str()]))),
+        );
+        write_parquet(&dir.path().join("1.parquet"), &batch);
+
+        let dal = dal(&format!("{}/", dir.path().display()), &stacktraces).await;
+        let (records, _, _) = dal
+            .find_single("node=a|parca_agent:samples:x:x:x:delta", 0)
+            .await
+            .unwrap();
+
+        let mut stacks = vec![];
+        for record in records.iter() {
+            let stacktrace_ids = cast(
+                record.column_by_name(COLUMN_STACKTRACE_ID).unwrap(),
+                &DataType::Utf8,
+            )
+            .unwrap();
+            let legacy_stacktraces = record.column_by_name(COLUMN_LEGACY_STACKTRACE).unwrap();
+            for row in 0..record.num_rows() {
+                let frames = dal
+                    .stack_frames(
+                        Some(stacktrace_ids.as_string()),
+                        Some(legacy_stacktraces.as_list()),
+                        row,
+                    )
+                    .await
+                    .unwrap()
+                    .unwrap();
+                let mut addresses = vec![];
+                for (id, encoded) in frames {
+                    let encoded = match encoded {
+                        Some(encoded) => encoded,
+                        None => stacktraces.location(&id).await.unwrap().unwrap(),
+                    };
+                    addresses.push(PprofLocations::decode(&encoded).unwrap().address);
+                }
+                stacks.push(addresses);
+            }
+        }
+        stacks.sort();
+        assert_eq!(stacks, vec![vec![0x10, 0x20], vec![0x30]]);
+    }
+
+    #[tokio::test]
+    async fn test_profile_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let batch = record(&[
+            ("parca_agent", "samples", true),
+            ("memory", "inuse_space", false),
+            ("parca_agent", "samples", true),
+            ("memory", "alloc_space", true),
+        ]);
+        write_parquet(&dir.path().join("0.parquet"), &batch);
+
+        // Files written before the delta column.
+        let batch = record(&[("memory", "inuse_space", false), ("cpu", "samples", true)]);
+        write_parquet(
+            &dir.path().join("1.parquet"),
+            &with_column(&batch, COLUMN_DELTA, None),
+        );
+
+        let stacktraces = Arc::new(StacktraceStore::new(Arc::new(storage::new_memory_bucket())));
+        let dal = dal(&format!("{}/", dir.path().display()), &stacktraces).await;
+
+        let profile_types: Vec<String> = dal
+            .profile_types()
+            .await
+            .unwrap()
+            .iter()
+            .map(|pt| pt.to_string())
+            .collect();
+        assert_eq!(
+            profile_types,
+            vec![
+                "cpu:samples:x:x:x",
+                "memory:alloc_space:x:x:x:delta",
+                "memory:inuse_space:x:x:x",
+                "parca_agent:samples:x:x:x:delta",
+            ]
+        );
+    }
+}
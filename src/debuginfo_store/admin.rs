@@ -0,0 +1,618 @@
+use super::{buildid, DebuginfoStore};
+use crate::debuginfopb::{debuginfo::Source, Debuginfo, DebuginfoQuality, DebuginfoType};
+use crate::symbolizer::kernel::{self, Kallsyms};
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, FromRef, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Router,
+};
+use chrono::{TimeZone, Utc};
+use prost_types::Timestamp;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::{sync::Semaphore, task::JoinSet};
+use tonic::async_trait;
+
+/// Purger drops what was derived from the debuginfo of a build ID, like the
+/// lines its addresses were symbolized to, once the debuginfo is deleted or
+/// invalidated.
+#[async_trait]
+pub trait Purger: Send + Sync {
+    async fn purge(&self, build_id: &str) -> anyhow::Result<()>;
+
+    /// Drops the kallsyms snapshot of the kernel release, once it is replaced.
+    async fn purge_kallsyms(&self, release: &str) -> anyhow::Result<()>;
+}
+
+/// Kallsyms snapshots are a few MB, the bound only guards against mistakes.
+const MAX_KALLSYMS_SIZE: usize = 64 << 20;
+
+/// How many entries list looks the size of up in the bucket at once.
+const LIST_CONCURRENCY: usize = 32;
+
+#[derive(Clone)]
+struct Admin {
+    store: Arc<DebuginfoStore>,
+    purger: Option<Arc<dyn Purger>>,
+}
+
+impl FromRef<Admin> for Arc<DebuginfoStore> {
+    fn from_ref(admin: &Admin) -> Self {
+        Arc::clone(&admin.store)
+    }
+}
+
+impl Admin {
+    async fn purge(&self, build_id: &str) -> Result<(), Response> {
+        let purger = match &self.purger {
+            Some(purger) => purger,
+            None => return Ok(()),
+        };
+        purger.purge(build_id).await.map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to purge build_id {}: {:#}", build_id, e),
+            )
+                .into_response()
+        })
+    }
+
+    async fn purge_kallsyms(&self, release: &str) -> Result<(), Response> {
+        let purger = match &self.purger {
+            Some(purger) => purger,
+            None => return Ok(()),
+        };
+        purger.purge_kallsyms(release).await.map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to purge kallsyms of {}: {:#}", release, e),
+            )
+                .into_response()
+        })
+    }
+}
+
+/// Returns the routes of the admin API, which lists, inspects and repairs the
+/// debuginfo the store holds. Deleting or invalidating debuginfo purges what
+/// was symbolized with it. The API isn't authenticated, so it must be served
+/// on its own listener, where only operators can reach it.
+///
+/// - GET /admin/debuginfo lists all debuginfo.
+/// - GET /admin/debuginfo/:build_id shows the debuginfo of a build ID.
+/// - DELETE /admin/debuginfo/:build_id/:type deletes the metadata and the
+///   uploaded file.
+/// - POST /admin/debuginfo/:build_id/:type/invalidate marks the debuginfo
+///   invalid, so that agents upload it again.
+/// - POST /admin/debuginfo/:build_id/:type/debuginfod looks the build ID up on
+///   the debuginfod servers, and records them as its source if they have it.
+///   Uploaded debuginfo has to be deleted first.
+/// - PUT /admin/kernel/:release/kallsyms stores the /proc/kallsyms snapshot
+///   kernel frames of the release are symbolized with, replacing any
+///   previous one.
+///
+/// type is one of debuginfo, executable or sources.
+pub fn router(store: Arc<DebuginfoStore>, purger: Option<Arc<dyn Purger>>) -> Router {
+    Router::new()
+        .route("/admin/debuginfo", get(list))
+        .route("/admin/debuginfo/:build_id", get(show))
+        .route(
+            "/admin/debuginfo/:build_id/:type",
+            axum::routing::delete(delete),
+        )
+        .route(
+            "/admin/debuginfo/:build_id/:type/invalidate",
+            post(invalidate),
+        )
+        .route(
+            "/admin/debuginfo/:build_id/:type/debuginfod",
+            post(debuginfod),
+        )
+        .route(
+            "/admin/kernel/:release/kallsyms",
+            put(upload_kallsyms).layer(DefaultBodyLimit::max(MAX_KALLSYMS_SIZE)),
+        )
+        .with_state(Admin { store, purger })
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    build_id: String,
+    r#type: &'static str,
+    source: &'static str,
+    upload_id: Option<String>,
+    state: Option<&'static str>,
+    size: Option<usize>,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    quality: Option<Quality>,
+    debuginfod_servers: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Quality {
+    not_valid_elf: bool,
+    has_dwarf: bool,
+    has_go_pclntab: bool,
+    has_symtab: bool,
+    has_dynsym: bool,
+}
+
+impl From<DebuginfoQuality> for Quality {
+    fn from(q: DebuginfoQuality) -> Self {
+        Self {
+            not_valid_elf: q.not_valid_elf,
+            has_dwarf: q.has_dwarf,
+            has_go_pclntab: q.has_go_pclntab,
+            has_symtab: q.has_symtab,
+            has_dynsym: q.has_dynsym,
+        }
+    }
+}
+
+const TYPES: [(&str, DebuginfoType); 5] = [
+    ("debuginfo", DebuginfoType::DebuginfoUnspecified),
+    ("executable", DebuginfoType::Executable),
+    ("sources", DebuginfoType::Sources),
+    ("dwp", DebuginfoType::DwarfPackage),
+    ("dwo", DebuginfoType::SplitDwarf),
+];
+
+fn parse_type(name: &str) -> Option<DebuginfoType> {
+    TYPES.iter().find(|(n, _)| *n == name).map(|(_, t)| *t)
+}
+
+fn type_name(req_type: DebuginfoType) -> &'static str {
+    TYPES
+        .iter()
+        .find(|(_, t)| *t == req_type)
+        .map_or("debuginfo", |(n, _)| n)
+}
+
+fn timestamp(ts: Option<Timestamp>) -> Option<String> {
+    let ts = ts?;
+    Some(
+        Utc.timestamp_opt(ts.seconds, ts.nanos as u32)
+            .single()?
+            .to_rfc3339(),
+    )
+}
+
+async fn entry(store: &DebuginfoStore, dbginfo: Debuginfo) -> Entry {
+    let source = match dbginfo.source() {
+        Source::Upload => "upload",
+        Source::Debuginfod => "debuginfod",
+        _ => "unknown",
+    };
+    let r#type = type_name(dbginfo.r#type());
+    let upload = dbginfo.upload;
+
+    let size = match &upload {
+        Some(upload) => store
+            .bucket
+            .head(&object_store::path::Path::from(upload.id.as_str()))
+            .await
+            .ok()
+            .map(|meta| meta.size),
+        None => None,
+    };
+
+    Entry {
+        build_id: dbginfo.build_id,
+        r#type,
+        source,
+        state: upload.as_ref().map(|u| match u.state() {
+            super::State::Uploading => "uploading",
+            super::State::Uploaded => "uploaded",
+            _ => "unknown",
+        }),
+        started_at: upload.as_ref().and_then(|u| timestamp(u.started_at)),
+        finished_at: upload.as_ref().and_then(|u| timestamp(u.finished_at)),
+        upload_id: upload.map(|u| u.id),
+        size,
+        quality: dbginfo.quality.map(Quality::from),
+        debuginfod_servers: dbginfo.debuginfod_servers,
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Response {
+    match serde_json::to_vec(value) {
+        Ok(body) => ([(header::CONTENT_TYPE, "application/json")], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn list(State(store): State<Arc<DebuginfoStore>>) -> Response {
+    let permits = Arc::new(Semaphore::new(LIST_CONCURRENCY));
+    let mut tasks = JoinSet::new();
+    for dbginfo in store.metadata.list() {
+        let store = Arc::clone(&store);
+        let permits = Arc::clone(&permits);
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            entry(&store, dbginfo).await
+        });
+    }
+
+    let mut entries = Vec::with_capacity(tasks.len());
+    while let Some(res) = tasks.join_next().await {
+        match res {
+            Ok(entry) => entries.push(entry),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        }
+    }
+    entries.sort_by(|a, b| (&a.build_id, a.r#type).cmp(&(&b.build_id, b.r#type)));
+    json(&entries)
+}
+
+async fn show(State(store): State<Arc<DebuginfoStore>>, Path(build_id): Path<String>) -> Response {
+    let mut entries = vec![];
+    for (_, req_type) in TYPES {
+        if let Some(dbginfo) = store.metadata.fetch(&build_id, &req_type) {
+            entries.push(entry(&store, dbginfo).await);
+        }
+    }
+
+    if entries.is_empty() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    json(&entries)
+}
+
+async fn delete(
+    State(admin): State<Admin>,
+    Path((build_id, r#type)): Path<(String, String)>,
+) -> Response {
+    let store = &admin.store;
+    let req_type = match parse_type(&r#type) {
+        Some(t) => t,
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    let dbginfo = match store.metadata.delete(&build_id, &req_type) {
+        Some(dbginfo) => dbginfo,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    if let Some(upload) = &dbginfo.upload {
+        if let Err(e) = store.delete_upload_object(&upload.id).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)).into_response();
+        }
+    }
+    if let Err(response) = admin.purge(&build_id).await {
+        return response;
+    }
+
+    log::info!("Deleted {} of build_id {}", r#type, build_id);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn invalidate(
+    State(admin): State<Admin>,
+    Path((build_id, r#type)): Path<(String, String)>,
+) -> Response {
+    let store = &admin.store;
+    let req_type = match parse_type(&r#type) {
+        Some(t) => t,
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    let dbginfo = match store.metadata.fetch(&build_id, &req_type) {
+        Some(dbginfo) => dbginfo,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let quality = DebuginfoQuality {
+        not_valid_elf: true,
+        ..dbginfo.quality.unwrap_or_default()
+    };
+    if let Err(e) = store.metadata.set_quality(&build_id, &quality, &req_type) {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)).into_response();
+    }
+    if let Err(response) = admin.purge(&build_id).await {
+        return response;
+    }
+
+    log::info!("Marked {} of build_id {} invalid", r#type, build_id);
+    match store.metadata.fetch(&build_id, &req_type) {
+        Some(dbginfo) => json(&entry(store, dbginfo).await),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn debuginfod(
+    State(store): State<Arc<DebuginfoStore>>,
+    Path((build_id, r#type)): Path<(String, String)>,
+) -> Response {
+    let req_type = match parse_type(&r#type) {
+        Some(t) => t,
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    let build_id_type = store.metadata.build_id_type(&build_id);
+    if !buildid::is_debuginfod_compatible(build_id_type) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Debuginfod can't be asked for {} build IDs",
+                build_id_type.as_str_name()
+            ),
+        )
+            .into_response();
+    }
+
+    // Recording debuginfod as the source would orphan the uploaded file.
+    if store
+        .metadata
+        .fetch(&build_id, &req_type)
+        .is_some_and(|dbginfo| dbginfo.source() == Source::Upload)
+    {
+        return (
+            StatusCode::CONFLICT,
+            format!(
+                "{} of build_id {} was uploaded, delete it first",
+                r#type, build_id
+            ),
+        )
+            .into_response();
+    }
+
+    let servers = store.debuginfod.exists(&build_id, build_id_type).await;
+    if servers.is_empty() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if let Err(e) =
+        store
+            .metadata
+            .mark_as_debuginfod_source(servers, &build_id, &req_type, build_id_type)
+    {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)).into_response();
+    }
+
+    match store.metadata.fetch(&build_id, &req_type) {
+        Some(dbginfo) => json(&entry(&store, dbginfo).await),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn upload_kallsyms(
+    State(admin): State<Admin>,
+    Path(release): Path<String>,
+    body: Bytes,
+) -> Response {
+    if let Err(e) = kernel::validate_kernel_release(&release) {
+        return (StatusCode::BAD_REQUEST, format!("{:#}", e)).into_response();
+    }
+    // Only snapshots that symbolize anything are kept.
+    let parsed = tokio::task::spawn_blocking({
+        let body = body.clone();
+        move || match std::str::from_utf8(&body) {
+            Ok(data) => Kallsyms::parse(data).map(|_| ()),
+            Err(e) => Err(e.into()),
+        }
+    })
+    .await;
+    match parsed {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => return (StatusCode::BAD_REQUEST, format!("{:#}", e)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+
+    let path = object_store::path::Path::from(kernel::kallsyms_path(&release));
+    if let Err(e) = admin.store.bucket.put(&path, body.into()).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    if let Err(response) = admin.purge_kallsyms(&release).await {
+        return response;
+    }
+
+    log::info!("Stored kallsyms of kernel release {}", release);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        debuginfo_store::{DebugInfod, MetadataStore},
+        debuginfopb::{
+            debuginfo_service_server::DebuginfoService, upload_instructions::UploadStrategy,
+            BuildIdType, ShouldInitiateUploadRequest,
+        },
+        storage,
+    };
+    use object_store::ObjectStore;
+
+    const BUILD_ID: &str = "126cf12e76810726c76d87ab8a6a57e9a1d4c815";
+
+    #[derive(Default)]
+    struct Purged(std::sync::Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl Purger for Purged {
+        async fn purge(&self, build_id: &str) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(build_id.to_string());
+            Ok(())
+        }
+
+        async fn purge_kallsyms(&self, release: &str) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(format!("kallsyms {}", release));
+            Ok(())
+        }
+    }
+
+    async fn call(method: &'static str, url: String) -> (u16, String) {
+        tokio::task::spawn_blocking(move || match ureq::request(method, &url).call() {
+            Ok(response) => (response.status(), response.into_string().unwrap()),
+            Err(ureq::Error::Status(status, _)) => (status, String::new()),
+            Err(e) => panic!("{}", e),
+        })
+        .await
+        .unwrap()
+    }
+
+    fn store() -> Arc<DebuginfoStore> {
+        Arc::new(DebuginfoStore {
+            metadata: MetadataStore::new(),
+            debuginfod: DebugInfod::default().with_upstream_servers(vec![]),
+            max_upload_duration: chrono::Duration::minutes(15),
+            max_upload_size: 1 << 30,
+            bucket: Arc::new(storage::new_memory_bucket()),
+            signer: None,
+            uploads: Default::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let store = store();
+        let upload_id = ulid::Ulid::new().to_string();
+        let req_type = DebuginfoType::Executable;
+        store
+            .metadata
+            .mark_as_uploading(
+                BUILD_ID,
+                &upload_id,
+                "hash",
+                &req_type,
+                BuildIdType::Gnu,
+                UploadStrategy::Grpc,
+                Utc::now(),
+            )
+            .unwrap();
+        store
+            .bucket
+            .put(
+                &object_store::path::Path::from(upload_id.as_str()),
+                b"ELF".to_vec().into(),
+            )
+            .await
+            .unwrap();
+        store
+            .metadata
+            .mark_as_uploaded(BUILD_ID, &upload_id, &req_type, Utc::now())
+            .unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/admin/debuginfo", listener.local_addr().unwrap());
+        let purged = Arc::new(Purged::default());
+        let app = router(Arc::clone(&store), Some(purged.clone()));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (status, body) = call("GET", url.clone()).await;
+        assert_eq!(status, 200);
+        let entries: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(entries[0]["build_id"], BUILD_ID);
+        assert_eq!(entries[0]["type"], "executable");
+        assert_eq!(entries[0]["source"], "upload");
+        assert_eq!(entries[0]["state"], "uploaded");
+        assert_eq!(entries[0]["size"], 3);
+
+        // Invalidated debuginfo is uploaded again.
+        let should_initiate = || {
+            store.should_initiate_upload(tonic::Request::new(ShouldInitiateUploadRequest {
+                build_id: BUILD_ID.into(),
+                hash: "other hash".into(),
+                r#type: req_type.into(),
+                ..Default::default()
+            }))
+        };
+        assert!(
+            !should_initiate()
+                .await
+                .unwrap()
+                .into_inner()
+                .should_initiate_upload
+        );
+        let (status, body) = call(
+            "POST",
+            format!("{}/{}/executable/invalidate", url, BUILD_ID),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""not_valid_elf":true"#), "{}", body);
+        assert_eq!(*purged.0.lock().unwrap(), vec![BUILD_ID]);
+        assert!(
+            should_initiate()
+                .await
+                .unwrap()
+                .into_inner()
+                .should_initiate_upload
+        );
+
+        // The uploaded file isn't replaced by debuginfod.
+        assert_eq!(
+            call(
+                "POST",
+                format!("{}/{}/executable/debuginfod", url, BUILD_ID)
+            )
+            .await
+            .0,
+            409
+        );
+
+        let (status, _) = call("DELETE", format!("{}/{}/executable", url, BUILD_ID)).await;
+        assert_eq!(status, 204);
+        assert_eq!(purged.0.lock().unwrap().len(), 2);
+        assert_eq!(call("GET", format!("{}/{}", url, BUILD_ID)).await.0, 404);
+        assert!(store
+            .bucket
+            .head(&object_store::path::Path::from(upload_id.as_str()))
+            .await
+            .is_err());
+        assert_eq!(
+            call("DELETE", format!("{}/{}/bogus", url, BUILD_ID))
+                .await
+                .0,
+            400
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_kallsyms() {
+        let store = store();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/admin/kernel", listener.local_addr().unwrap());
+        let purged = Arc::new(Purged::default());
+        let app = router(Arc::clone(&store), Some(purged.clone()));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let put = |release: &str, body: &'static str| {
+            let url = format!("{}/{}/kallsyms", url, release);
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    match ureq::put(&url).send_bytes(body.as_bytes()) {
+                        Ok(response) => response.status(),
+                        Err(ureq::Error::Status(status, _)) => status,
+                        Err(e) => panic!("{}", e),
+                    }
+                })
+                .await
+                .unwrap()
+            }
+        };
+        let kallsyms = "ffffffff81000000 T _stext\nffffffff81001000 T schedule\n";
+
+        assert_eq!(put("6.8.0-45-generic", kallsyms).await, 204);
+        let path = object_store::path::Path::from(kernel::kallsyms_path("6.8.0-45-generic"));
+        let stored = store
+            .bucket
+            .get(&path)
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        assert_eq!(stored, kallsyms.as_bytes());
+        assert_eq!(*purged.0.lock().unwrap(), vec!["kallsyms 6.8.0-45-generic"]);
+
+        // Invalid releases and snapshots that symbolize nothing are rejected.
+        assert_eq!(put(".config", kallsyms).await, 400);
+        assert_eq!(
+            put("6.8.0-46-generic", "0000000000000000 T _stext\n").await,
+            400
+        );
+        assert_eq!(put("6.8.0-46-generic", "not kallsyms\n").await, 400);
+        assert_eq!(purged.0.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::debuginfopb::BuildIdType;
+use anyhow::bail;
+use std::borrow::Cow;
+
+/// Returns the type of the build ID as far as its format tells. Most build
+/// IDs are GNU build IDs, the hex encoded .note.gnu.build-id of the binary.
+/// Go binaries without one are identified by the Go build ID in
+/// .note.go.buildid, up to four base64url encoded hashes separated by '/'.
+/// Binaries that have neither are identified by a hex encoded hash of their
+/// contents, which can't be told apart from a GNU build ID, so the type of hex
+/// build IDs is unknown until it is stated or confirmed by the
+/// .note.gnu.build-id of an upload.
+pub fn infer_type(build_id: &str) -> BuildIdType {
+    if is_go(build_id) {
+        BuildIdType::Go
+    } else {
+        BuildIdType::UnknownUnspecified
+    }
+}
+
+/// Checks that the build ID is of the given type, and returns its type. The
+/// type of build IDs of unknown type is inferred from their format, hex build
+/// IDs stay of unknown type and those of any other format are rejected.
+pub fn validate(build_id: &str, build_id_type: BuildIdType) -> anyhow::Result<BuildIdType> {
+    if build_id.len() <= 2 {
+        bail!("unexpectedly short input");
+    }
+
+    let valid = match build_id_type {
+        BuildIdType::UnknownUnspecified => {
+            if is_go(build_id) {
+                return Ok(BuildIdType::Go);
+            }
+            is_hex(build_id)
+        }
+        BuildIdType::Gnu | BuildIdType::Hash => is_hex(build_id),
+        // Go build IDs before Go 1.10 were a single hex encoded hash.
+        BuildIdType::Go => is_go(build_id) || is_hex(build_id),
+    };
+    if !valid {
+        bail!(
+            "{} is not a valid {} build ID",
+            build_id,
+            build_id_type.as_str_name()
+        );
+    }
+    Ok(build_id_type)
+}
+
+/// Whether debuginfod servers can be asked for the debuginfo of build IDs of
+/// the type. They only index binaries by their GNU build ID, so build IDs of
+/// unknown type can't be looked up until they are known to be one.
+pub fn is_debuginfod_compatible(build_id_type: BuildIdType) -> bool {
+    build_id_type == BuildIdType::Gnu
+}
+
+/// Returns a name for the build ID that is safe to use in object paths and
+/// as a file name. Hex build IDs are used as is, the '/' of Go build IDs is
+/// replaced by '~', which base64url never uses. Any other character is
+/// replaced by '_', which only build IDs that wouldn't validate contain.
+pub fn object_name(build_id: &str) -> Cow<'_, str> {
+    if build_id.chars().all(is_base64url) {
+        return Cow::Borrowed(build_id);
+    }
+    Cow::Owned(
+        build_id
+            .chars()
+            .map(|c| match c {
+                '/' => '~',
+                c if is_base64url(c) => c,
+                _ => '_',
+            })
+            .collect(),
+    )
+}
+
+fn is_hex(build_id: &str) -> bool {
+    !build_id.is_empty() && build_id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_go(build_id: &str) -> bool {
+    let parts = build_id.split('/');
+    let count = parts.clone().count();
+    (2..=4).contains(&count)
+        && parts
+            .into_iter()
+            .all(|p| !p.is_empty() && p.chars().all(is_base64url))
+}
+
+fn is_base64url(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GNU: &str = "126cf12e76810726c76d87ab8a6a57e9a1d4c815";
+    const GO: &str =
+        "GBQARYFuCXw4zGISXIvG/KjNR3sbcTb46XVfHcKCJ/G5iOmSaPKQvCL6SeuXIh/8m1xd8RXcywCH0gKwCcy";
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(infer_type(GNU), BuildIdType::UnknownUnspecified);
+        assert_eq!(infer_type(GO), BuildIdType::Go);
+        assert_eq!(infer_type("../etc"), BuildIdType::UnknownUnspecified);
+
+        assert_eq!(
+            validate(GNU, BuildIdType::UnknownUnspecified).unwrap(),
+            BuildIdType::UnknownUnspecified
+        );
+        assert_eq!(validate(GNU, BuildIdType::Gnu).unwrap(), BuildIdType::Gnu);
+        assert_eq!(
+            validate(GO, BuildIdType::UnknownUnspecified).unwrap(),
+            BuildIdType::Go
+        );
+        assert_eq!(validate(GNU, BuildIdType::Hash).unwrap(), BuildIdType::Hash);
+        assert!(validate(GO, BuildIdType::Gnu).is_err());
+        assert!(validate(GO, BuildIdType::Hash).is_err());
+        assert!(validate("a/b/c/d/e", BuildIdType::Go).is_err());
+        assert!(validate("../etc/passwd", BuildIdType::UnknownUnspecified).is_err());
+        assert!(validate("ab", BuildIdType::Gnu).is_err());
+    }
+
+    #[test]
+    fn test_object_name() {
+        assert_eq!(object_name(GNU), GNU);
+        assert_eq!(
+            object_name(GO),
+            "GBQARYFuCXw4zGISXIvG~KjNR3sbcTb46XVfHcKCJ~G5iOmSaPKQvCL6SeuXIh~8m1xd8RXcywCH0gKwCcy"
+        );
+        assert_eq!(object_name("../etc"), "__~etc");
+    }
+}
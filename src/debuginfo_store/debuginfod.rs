@@ -1,14 +1,37 @@
+use super::buildid;
+use crate::debuginfopb::BuildIdType;
 use anyhow::{bail, Context};
-use object_store::ObjectStore;
+use moka::sync::Cache;
+use object_store::{GetResult, ObjectMeta, ObjectStore, WriteMultipart};
+use reqwest::{header, StatusCode};
 use std::{sync::Arc, time::Duration};
+use tokio_stream::StreamExt;
 use tonic::Status;
 use url::Url;
 
+/// Downloads are streamed into the bucket with up to this many parts in
+/// flight.
+const DOWNLOAD_BUFFERED_PARTS: usize = 8;
+
+const DEBUGINFO: &str = "debuginfo";
+
+/// How long a server is assumed to still not have a build ID it didn't have.
+const NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Bounds of the cache of downloaded artifacts, unless configured with
+/// with_cache_limits. The oldest artifacts are removed first.
+const CACHE_SIZE: u64 = 1 << 30;
+const CACHE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
 #[derive(Debug)]
 pub struct DebugInfod {
     pub upstream_servers: Vec<Url>,
     bucket: Arc<dyn ObjectStore>,
-    client: ureq::Agent,
+    client: reqwest::Client,
+    /// Build IDs that servers answered 404 for, keyed by server and build ID.
+    not_found: Cache<(String, String), ()>,
+    max_cache_size: u64,
+    cache_ttl: Duration,
 }
 
 impl Clone for DebugInfod {
@@ -17,6 +40,9 @@ impl Clone for DebugInfod {
             upstream_servers: self.upstream_servers.clone(),
             bucket: Arc::clone(&self.bucket),
             client: self.client.clone(),
+            not_found: self.not_found.clone(),
+            max_cache_size: self.max_cache_size,
+            cache_ttl: self.cache_ttl,
         }
     }
 }
@@ -28,72 +54,631 @@ impl Default for DebugInfod {
         Self {
             upstream_servers: vec![url],
             bucket: Arc::new(crate::storage::new_memory_bucket()),
-            client: ureq::AgentBuilder::new()
-                .timeout_read(Duration::from_secs(5))
-                .timeout_write(Duration::from_secs(5))
-                .redirects(2)
-                .build(),
+            client: reqwest::Client::builder()
+                .connect_timeout(Duration::from_secs(5))
+                .read_timeout(Duration::from_secs(5))
+                .redirect(reqwest::redirect::Policy::limited(2))
+                .build()
+                .unwrap(),
+            not_found: Self::not_found_cache(NEGATIVE_CACHE_TTL),
+            max_cache_size: CACHE_SIZE,
+            cache_ttl: CACHE_TTL,
         }
     }
 }
 
 impl DebugInfod {
-    pub async fn exists(&self, build_id: &str) -> Vec<String> {
+    /// Uses the servers listed in DEBUGINFOD_URLS, like the elfutils client
+    /// does, falling back to the default server if it isn't set.
+    pub fn from_env() -> Self {
+        let debuginfod = Self::default();
+        match std::env::var("DEBUGINFOD_URLS") {
+            Ok(urls) if !urls.trim().is_empty() => {
+                debuginfod.with_upstream_servers(parse_urls(&urls))
+            }
+            _ => debuginfod,
+        }
+    }
+
+    pub fn with_upstream_servers(mut self, upstream_servers: Vec<Url>) -> Self {
+        self.upstream_servers = upstream_servers;
+        self
+    }
+
+    /// Sets how long a 404 from a server is remembered for.
+    pub fn with_negative_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.not_found = Self::not_found_cache(ttl);
+        self
+    }
+
+    /// Sets the bucket downloaded artifacts are cached in, eg. one on disk
+    /// rather than the default in-memory one.
+    pub fn with_bucket(mut self, bucket: Arc<dyn ObjectStore>) -> Self {
+        self.bucket = bucket;
+        self
+    }
+
+    /// Sets how large the cache of downloaded artifacts may grow, and how long
+    /// they are kept for.
+    pub fn with_cache_limits(mut self, max_size: u64, ttl: Duration) -> Self {
+        self.max_cache_size = max_size;
+        self.cache_ttl = ttl;
+        self
+    }
+
+    fn not_found_cache(ttl: Duration) -> Cache<(String, String), ()> {
+        Cache::builder()
+            .max_capacity(100_000)
+            .time_to_live(ttl)
+            .build()
+    }
+
+    /// Returns the servers that have debuginfo for the build ID, in the
+    /// order they are configured in.
+    pub async fn exists(&self, build_id: &str, build_id_type: BuildIdType) -> Vec<String> {
         let mut available_servers = vec![];
 
-        let vec = self.upstream_servers.clone();
-        for server in vec {
-            if self.get(&server, build_id).await.is_ok() {
-                available_servers.push(server.to_string());
+        for server in self.upstream_servers.iter() {
+            match self.head(server, build_id, build_id_type).await {
+                Ok(true) => available_servers.push(server.to_string()),
+                Ok(false) => (),
+                Err(e) => log::debug!(
+                    "Failed to check debuginfod server {} for {}: {:#}",
+                    server,
+                    build_id,
+                    e
+                ),
             }
         }
         available_servers
     }
 
-    pub async fn get(&self, upstream_server: &Url, build_id: &str) -> anyhow::Result<Vec<u8>> {
-        self.debuginfo_request(upstream_server, build_id).await
+    /// Fetches debuginfo from the server. It is streamed from the cache.
+    pub async fn get(
+        &self,
+        upstream_server: &Url,
+        build_id: &str,
+        build_id_type: BuildIdType,
+    ) -> anyhow::Result<GetResult> {
+        let path = self
+            .artifact_request(upstream_server, build_id, build_id_type, DEBUGINFO)
+            .await?;
+        Ok(self.bucket.get(&path).await?)
+    }
+
+    /// Fetches debuginfo from the first of the servers that has it. It is
+    /// streamed from the cache.
+    pub async fn get_from(
+        &self,
+        servers: &[Url],
+        build_id: &str,
+        build_id_type: BuildIdType,
+    ) -> anyhow::Result<GetResult> {
+        self.get_stream(servers, build_id, build_id_type, DEBUGINFO)
+            .await
+    }
+
+    /// Fetches an artifact of the build ID, as named in the debuginfod
+    /// protocol, eg. "executable" or "source/usr/src/foo.c", from the first
+    /// of the servers that has it. The artifact is streamed from the cache.
+    pub async fn get_stream(
+        &self,
+        servers: &[Url],
+        build_id: &str,
+        build_id_type: BuildIdType,
+        artifact: &str,
+    ) -> anyhow::Result<object_store::GetResult> {
+        let mut last_err = None;
+        for server in servers {
+            match self
+                .artifact_request(server, build_id, build_id_type, artifact)
+                .await
+            {
+                Ok(path) => return Ok(self.bucket.get(&path).await?),
+                Err(e) => {
+                    log::debug!(
+                        "Failed to fetch {} of {} from debuginfod server {}: {:#}",
+                        artifact,
+                        build_id,
+                        server,
+                        e
+                    );
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Status::not_found(format!("No debuginfod server has build_id {}", build_id)).into()
+        }))
+    }
+
+    /// Returns the servers to fetch a build ID from: the ones recorded to have
+    /// it first, then the configured ones.
+    pub fn servers_for(&self, recorded: &[String]) -> Vec<Url> {
+        let mut servers: Vec<Url> = recorded
+            .iter()
+            .filter_map(|server| Url::parse(server).ok())
+            .collect();
+        for server in self.upstream_servers.iter() {
+            if !servers.contains(server) {
+                servers.push(server.clone());
+            }
+        }
+        servers
+    }
+
+    /// Checks whether the server has debuginfo for the build ID without
+    /// downloading it. Servers that don't support HEAD are asked for the
+    /// first byte instead.
+    async fn head(
+        &self,
+        upstream_server: &Url,
+        build_id: &str,
+        build_id_type: BuildIdType,
+    ) -> anyhow::Result<bool> {
+        if !buildid::is_debuginfod_compatible(build_id_type) {
+            return Ok(false);
+        }
+        let key = (
+            upstream_server.to_string(),
+            artifact_path(build_id, DEBUGINFO),
+        );
+        if self.not_found.contains_key(&key) {
+            return Ok(false);
+        }
+
+        let url = upstream_server.join(&key.1)?;
+        let mut response = self.client.head(url.clone()).send().await?;
+        if matches!(
+            response.status(),
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+        ) {
+            response = self
+                .client
+                .get(url)
+                .header(header::RANGE, "bytes=0-0")
+                .send()
+                .await?;
+        }
+        let found = match response.status() {
+            StatusCode::NOT_FOUND => false,
+            _ => response.error_for_status().map(|_| true)?,
+        };
+
+        if !found {
+            self.not_found.insert(key, ());
+        }
+        Ok(found)
     }
 
-    async fn debuginfo_request(
+    /// Makes sure the artifact is cached, and returns where.
+    async fn artifact_request(
         &self,
         upstream_server: &Url,
         build_id: &str,
-    ) -> anyhow::Result<Vec<u8>> {
-        let url = upstream_server.join(format!("buildid/{}/debuginfo", build_id).as_str())?;
+        build_id_type: BuildIdType,
+        artifact: &str,
+    ) -> anyhow::Result<object_store::path::Path> {
+        // Debuginfod servers only index GNU build IDs, anything else would at
+        // best be a wasted request.
+        if !buildid::is_debuginfod_compatible(build_id_type) {
+            bail!(Status::not_found(format!(
+                "Debuginfod servers don't index build IDs like {}",
+                build_id
+            )));
+        }
 
-        self.request(url).await
+        let key = (
+            upstream_server.to_string(),
+            artifact_path(build_id, artifact),
+        );
+        if self.not_found.contains_key(&key) {
+            bail!(Status::not_found(format!(
+                "{} recently had no {} for {}",
+                upstream_server, artifact, build_id
+            )));
+        }
+
+        let res = self.request(upstream_server.join(&key.1)?).await;
+        if res.as_ref().is_err_and(is_not_found) {
+            self.not_found.insert(key, ());
+        }
+        res
     }
 
-    async fn request(&self, url: Url) -> anyhow::Result<Vec<u8>> {
+    /// Makes sure the response to the request is cached, and returns where.
+    async fn request(&self, url: Url) -> anyhow::Result<object_store::path::Path> {
         let path = object_store::path::Path::from(url.as_str());
-        let res = self.bucket.get(&path).await?.bytes().await?;
-        if res.is_empty() {
-            let response =
-                self.client.get(url.as_str()).call().map_err(|err| {
-                    Status::internal(format!("Failed to fetch debuginfo: {}", err))
-                })?;
-
-            if response.status() == 200 {
-                let mut content = Vec::new();
-                response
-                    .into_reader()
-                    .read_to_end(&mut content)
-                    .with_context(|| "Failed to read response from the debuginfod server")?;
-
-                std::mem::drop(self.bucket.put(&path, content.clone().into()));
-                Ok(content)
-            } else {
-                bail!("Failed to fetch debuginfo: {}", response.status());
+        match self.bucket.head(&path).await {
+            Ok(_) => return Ok(path),
+            Err(object_store::Error::NotFound { .. }) => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        self.download(url, &path).await?;
+        Ok(path)
+    }
+
+    /// Streams the response body into the bucket. The object only becomes
+    /// visible once the whole body was written, a download that fails or is
+    /// cancelled halfway is aborted.
+    async fn download(&self, url: Url, path: &object_store::path::Path) -> anyhow::Result<()> {
+        let mut response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("Failed to fetch debuginfo")?;
+
+        let mut upload = AbortOnDrop(Some(WriteMultipart::new(
+            self.bucket.put_multipart(path).await?,
+        )));
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .context("Failed to read response from the debuginfod server")?
+        {
+            let upload = upload.0.as_mut().unwrap();
+            upload.wait_for_capacity(DOWNLOAD_BUFFERED_PARTS).await?;
+            upload.write(&chunk);
+        }
+
+        upload.0.take().unwrap().finish().await?;
+
+        let debuginfod = self.clone();
+        tokio::spawn(async move {
+            match debuginfod.evict_cache().await {
+                Ok(0) => (),
+                Ok(n) => log::info!("Evicted {} artifacts from the debuginfod cache", n),
+                Err(e) => log::warn!("Failed to evict from the debuginfod cache: {:#}", e),
+            }
+        });
+        Ok(())
+    }
+
+    /// Removes the cached artifacts that are older than the TTL, then the
+    /// oldest ones until the cache fits in its size bound. Artifacts that are
+    /// being read when they are removed stay readable. Returns how many were
+    /// removed.
+    pub async fn evict_cache(&self) -> anyhow::Result<usize> {
+        let mut objects: Vec<ObjectMeta> = self
+            .bucket
+            .list(None)
+            .collect::<object_store::Result<_>>()
+            .await?;
+        objects.sort_by_key(|meta| meta.last_modified);
+
+        let expired = chrono::Utc::now() - self.cache_ttl;
+        let mut size: u64 = objects.iter().map(|meta| meta.size as u64).sum();
+        let mut removed = 0;
+        for meta in objects {
+            if meta.last_modified > expired && size <= self.max_cache_size {
+                break;
             }
-        } else {
-            Ok(res.to_vec())
+            match self.bucket.delete(&meta.location).await {
+                Ok(()) | Err(object_store::Error::NotFound { .. }) => (),
+                Err(e) => return Err(e.into()),
+            }
+            size -= meta.size as u64;
+            removed += 1;
         }
+        Ok(removed)
     }
 }
 
+/// AbortOnDrop aborts a multipart upload that is dropped before it was
+/// finished, so that no parts are left behind in the bucket.
+struct AbortOnDrop(Option<WriteMultipart>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        let upload = match self.0.take() {
+            Some(upload) => upload,
+            None => return,
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    if let Err(e) = upload.abort().await {
+                        log::warn!("Failed to abort debuginfod download: {}", e);
+                    }
+                });
+            }
+            Err(_) => log::warn!("Failed to abort debuginfod download: no runtime"),
+        }
+    }
+}
+
+/// Returns whether the error is a server answering 404.
+pub(crate) fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        .is_some_and(|status| status == StatusCode::NOT_FOUND)
+}
+
+/// Parses a DEBUGINFOD_URLS value, a whitespace separated list of server
+/// URLs. Invalid URLs are skipped.
+pub fn parse_urls(urls: &str) -> Vec<Url> {
+    urls.split_whitespace()
+        .filter_map(|url| {
+            // Without a trailing slash, joining would replace the last
+            // path segment of the server URL.
+            let url = if url.ends_with('/') {
+                url.to_string()
+            } else {
+                format!("{}/", url)
+            };
+            Url::parse(&url)
+                .map_err(|e| log::warn!("Ignoring invalid debuginfod URL {}: {}", url, e))
+                .ok()
+        })
+        .collect()
+}
+
+fn artifact_path(build_id: &str, artifact: &str) -> String {
+    format!("buildid/{}/{}", build_id, artifact)
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::Mutex;
+
+    type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+    /// Serves HTTP on a local port with the handler, which gets the method,
+    /// path and Range header of each request. Returns the server URL and the
+    /// requests it received.
+    pub(crate) fn serve<F>(handler: F) -> (Url, Requests)
+    where
+        F: Fn(&str, &str, Option<&str>) -> (u16, Vec<u8>) + Send + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let requests: Requests = Default::default();
+        let log = Arc::clone(&requests);
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut parts = request_line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_string();
+                let path = parts.next().unwrap_or_default().to_string();
+
+                let mut range = None;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("range") {
+                            range = Some(value.trim().to_string());
+                        }
+                    }
+                }
+
+                let (status, body) = handler(&method, &path, range.as_deref());
+                log.lock().unwrap().push((method.clone(), path));
+                let mut response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                )
+                .into_bytes();
+                if method != "HEAD" {
+                    response.extend_from_slice(&body);
+                }
+                let _ = stream.write_all(&response);
+            }
+        });
+
+        (url, requests)
+    }
+
+    const BUILD_ID: &str = "252f7dc22ca9d935e8334f04a0232f35359b5880";
+
+    #[test]
+    fn test_parse_urls() {
+        let urls = parse_urls(" https://a.example/debuginfod  https://b.example/\tnot a url ");
+        assert_eq!(
+            urls.iter().map(|u| u.as_str()).collect::<Vec<_>>(),
+            vec!["https://a.example/debuginfod/", "https://b.example/"]
+        );
+        assert_eq!(
+            urls[0]
+                .join(&artifact_path(BUILD_ID, DEBUGINFO))
+                .unwrap()
+                .as_str(),
+            format!(
+                "https://a.example/debuginfod/buildid/{}/debuginfo",
+                BUILD_ID
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exists_without_download() {
+        let path = format!("/buildid/{}/debuginfo", BUILD_ID);
+        let (has_head, has_head_requests) = serve(move |_, p, _| match p == path {
+            true => (200, b"ELF".to_vec()),
+            false => (404, vec![]),
+        });
+        // A server without HEAD support is asked for the first byte.
+        let (no_head, no_head_requests) = serve(|method, _, range| match (method, range) {
+            ("HEAD", _) => (405, vec![]),
+            ("GET", Some("bytes=0-0")) => (206, b"E".to_vec()),
+            _ => (200, b"ELF".to_vec()),
+        });
+        let debuginfod = DebugInfod::default().with_upstream_servers(vec![has_head, no_head]);
+
+        assert_eq!(debuginfod.exists(BUILD_ID, BuildIdType::Gnu).await.len(), 2);
+        assert!(has_head_requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(method, _)| method == "HEAD"));
+        assert_eq!(no_head_requests.lock().unwrap().len(), 2);
+
+        // 404s are remembered.
+        assert_eq!(debuginfod.exists("123", BuildIdType::Gnu).await.len(), 1);
+        assert_eq!(debuginfod.exists("123", BuildIdType::Gnu).await.len(), 1);
+        assert_eq!(has_head_requests.lock().unwrap().len(), 2);
+
+        // Hashes look like GNU build IDs, but servers don't index them.
+        assert!(debuginfod
+            .exists(BUILD_ID, BuildIdType::Hash)
+            .await
+            .is_empty());
+        assert_eq!(has_head_requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_streams_into_cache() {
+        // Large enough to be uploaded in several parts.
+        let body: Vec<u8> = (0..12 << 20).map(|i| i as u8).collect();
+        let expected = body.clone();
+        let (server, requests) = serve(move |_, _, _| (200, body.clone()));
+        let debuginfod = DebugInfod::default();
+
+        assert!(
+            debuginfod
+                .get(&server, BUILD_ID, BuildIdType::Gnu)
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap()
+                == expected
+        );
+        assert!(
+            debuginfod
+                .get(&server, BUILD_ID, BuildIdType::Gnu)
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap()
+                == expected
+        );
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_from_disk_cache() {
+        let (server, _) = serve(|_, _, _| (200, b"ELF".to_vec()));
+        let dir = tempfile::tempdir().unwrap();
+        let debuginfod = DebugInfod::default().with_bucket(Arc::new(
+            object_store::local::LocalFileSystem::new_with_prefix(dir.path()).unwrap(),
+        ));
+
+        let res = debuginfod
+            .get(&server, BUILD_ID, BuildIdType::Gnu)
+            .await
+            .unwrap();
+        // Callers get the cached file rather than a copy of it.
+        match res.payload {
+            object_store::GetResultPayload::File(_, path) => {
+                assert_eq!(std::fs::read(path).unwrap(), b"ELF")
+            }
+            _ => panic!("Expected a file"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_truncated_isnt_cached() {
+        // The server promises more than it sends, and hangs up.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let _ = std::io::Read::read(&mut stream, &mut request);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\nELF");
+            }
+        });
+        let debuginfod = DebugInfod::default();
+
+        assert!(debuginfod
+            .get(&server, BUILD_ID, BuildIdType::Gnu)
+            .await
+            .is_err());
+        let path = object_store::path::Path::from(
+            server
+                .join(&artifact_path(BUILD_ID, DEBUGINFO))
+                .unwrap()
+                .as_str(),
+        );
+        assert!(debuginfod.bucket.head(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_evict_cache() {
+        let (server, _) = serve(|_, _, _| (200, b"ELF".to_vec()));
+        let debuginfod = DebugInfod::default().with_cache_limits(4, CACHE_TTL);
+        let build_ids = ["aa", "bb"];
+        for build_id in build_ids {
+            debuginfod
+                .get(&server, build_id, BuildIdType::Gnu)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        debuginfod.evict_cache().await.unwrap();
+        let bucket = Arc::clone(&debuginfod.bucket);
+        let cached = |build_id| {
+            let path = object_store::path::Path::from(
+                server
+                    .join(&artifact_path(build_id, DEBUGINFO))
+                    .unwrap()
+                    .as_str(),
+            );
+            let bucket = Arc::clone(&bucket);
+            async move { bucket.head(&path).await.is_ok() }
+        };
+        // Only the newest artifact fits.
+        assert!(!cached("aa").await);
+        assert!(cached("bb").await);
+
+        let debuginfod = debuginfod.with_cache_limits(u64::MAX, Duration::ZERO);
+        assert_eq!(debuginfod.evict_cache().await.unwrap(), 1);
+        assert!(!cached("bb").await);
+    }
+
+    #[tokio::test]
+    async fn test_get_from_fails_over() {
+        let (missing, missing_requests) = serve(|_, _, _| (404, vec![]));
+        let (broken, _) = serve(|_, _, _| (500, vec![]));
+        let (found, _) = serve(|_, _, _| (200, b"ELF".to_vec()));
+        let debuginfod = DebugInfod::default();
+        let servers = [missing, broken, found];
+
+        assert_eq!(
+            debuginfod
+                .get_from(&servers, BUILD_ID, BuildIdType::Gnu)
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap(),
+            b"ELF"[..]
+        );
+        assert!(debuginfod
+            .get_from(&servers[..2], BUILD_ID, BuildIdType::Gnu)
+            .await
+            .is_err());
+        // The server that didn't have it isn't asked again.
+        assert_eq!(missing_requests.lock().unwrap().len(), 1);
+    }
 
     #[tokio::test]
     async fn test_debuginfod_get() {
@@ -102,7 +687,14 @@ mod tests {
 
         // testing for linux's clear exec build id
         let debug_ = debuginfod
-            .get(&srv, "252f7dc22ca9d935e8334f04a0232f35359b5880")
+            .get(
+                &srv,
+                "252f7dc22ca9d935e8334f04a0232f35359b5880",
+                BuildIdType::Gnu,
+            )
+            .await
+            .unwrap()
+            .bytes()
             .await
             .unwrap();
 
@@ -113,12 +705,15 @@ mod tests {
     async fn test_debuginfod_exists() {
         let debuginfod = DebugInfod::default();
         // testing for a random buildid
-        assert_eq!(debuginfod.exists("123").await.is_empty(), true);
+        assert_eq!(
+            debuginfod.exists("123", BuildIdType::Gnu).await.is_empty(),
+            true
+        );
 
         // testing for linux's clear exec build id
         assert_eq!(
             debuginfod
-                .exists("252f7dc22ca9d935e8334f04a0232f35359b5880")
+                .exists("252f7dc22ca9d935e8334f04a0232f35359b5880", BuildIdType::Gnu)
                 .await
                 .is_empty(),
             false,
@@ -1,9 +1,14 @@
 use super::DebugInfod;
-use crate::debuginfopb::{debuginfo::Source, Debuginfo};
+use crate::debuginfopb::{debuginfo::Source, debuginfo_upload::State, BuildIdType, Debuginfo};
+use crate::symbolizer::kernel;
 use anyhow::bail;
-use object_store::ObjectStore;
+use object_store::{GetResult, ObjectStore};
 use std::sync::Arc;
 
+/// The debuginfod artifact of a build ID's DWARF package. It isn't part of
+/// the protocol, evprofiler serves it so that instances can be chained.
+pub(crate) const DWP: &str = "dwp";
+
 #[derive(Debug)]
 pub struct DebuginfoFetcher {
     bucket: Arc<dyn ObjectStore>,
@@ -15,7 +20,10 @@ impl DebuginfoFetcher {
         Self { bucket, debuginfod }
     }
 
-    pub async fn fetch_raw_elf(&self, dbginfo: &Debuginfo) -> anyhow::Result<Vec<u8>> {
+    /// Fetches the file the debuginfo was recorded with. It is streamed, or
+    /// handed over as a file if the bucket keeps it on disk, so that callers
+    /// don't have to hold it in memory.
+    pub async fn fetch_raw_elf(&self, dbginfo: &Debuginfo) -> anyhow::Result<GetResult> {
         let source = dbginfo.source();
         match source {
             Source::Debuginfod => self.fetch_debuginfod(dbginfo).await,
@@ -26,25 +34,155 @@ impl DebuginfoFetcher {
         }
     }
 
-    async fn fetch_debuginfod(&self, dbginfo: &Debuginfo) -> anyhow::Result<Vec<u8>> {
-        let rc = self
+    /// Fetches the DWARF package (.dwp) of an executable built with split
+    /// DWARF, as uploaded for its build ID. Packages that weren't uploaded are
+    /// asked for from the debuginfod servers, which only evprofiler serves.
+    /// Returns None if there is no package for the build ID.
+    pub async fn fetch_dwp(
+        &self,
+        build_id: &str,
+        build_id_type: BuildIdType,
+        dbginfo: Option<&Debuginfo>,
+    ) -> anyhow::Result<Option<GetResult>> {
+        if let Some(dbginfo) = dbginfo.filter(|dbginfo| is_uploaded(dbginfo)) {
+            return Ok(Some(self.fetch_bucket(dbginfo).await?));
+        }
+
+        match self
             .debuginfod
-            .get(
-                &self.debuginfod.upstream_servers[0],
-                dbginfo.build_id.as_str(),
+            .get_stream(
+                &self.debuginfod.upstream_servers,
+                build_id,
+                build_id_type,
+                DWP,
             )
-            .await?;
-        Ok(rc.to_vec())
+            .await
+        {
+            Ok(res) => Ok(Some(res)),
+            Err(e) => {
+                log::debug!("DWARF package for {} not found: {:#}", build_id, e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Fetches a split DWARF object (.dwo), as uploaded for the DWO ID of its
+    /// unit. Returns None if it wasn't uploaded.
+    pub async fn fetch_dwo(
+        &self,
+        dbginfo: Option<&Debuginfo>,
+    ) -> anyhow::Result<Option<GetResult>> {
+        match dbginfo.filter(|dbginfo| is_uploaded(dbginfo)) {
+            Some(dbginfo) => Ok(Some(self.fetch_bucket(dbginfo).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Fetches the supplementary file referenced by an executable's
+    /// .gnu_debugaltlink section, as produced by dwz. Supplementary files
+    /// have a build ID of their own, so they are uploaded and served by
+    /// debuginfod like any other debuginfo.
+    pub async fn fetch_supplementary(
+        &self,
+        build_id: &str,
+        dbginfo: Option<&Debuginfo>,
+    ) -> anyhow::Result<Option<GetResult>> {
+        if let Some(dbginfo) = dbginfo.filter(|dbginfo| is_uploaded(dbginfo)) {
+            return Ok(Some(self.fetch_bucket(dbginfo).await?));
+        }
+
+        // .gnu_debugaltlink always refers to the GNU build ID of the file.
+        let recorded = dbginfo.map_or(&[][..], |dbginfo| &dbginfo.debuginfod_servers[..]);
+        match self
+            .debuginfod
+            .get_from(
+                &self.debuginfod.servers_for(recorded),
+                build_id,
+                BuildIdType::Gnu,
+            )
+            .await
+        {
+            Ok(data) => Ok(Some(data)),
+            Err(e) => {
+                log::debug!("Supplementary file {} not found: {:#}", build_id, e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Fetches the /proc/kallsyms snapshot of a kernel release.
+    pub async fn fetch_kallsyms(&self, kernel_release: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        self.fetch_optional(&kernel::kallsyms_path(kernel_release))
+            .await
+    }
+
+    async fn fetch_optional(&self, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.bucket.get(&object_store::path::Path::from(path)).await {
+            Ok(rc) => Ok(Some(rc.bytes().await?.to_vec())),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Fetches from the servers that had the debuginfo when its metadata was
+    /// recorded, in order, falling back to the configured ones.
+    async fn fetch_debuginfod(&self, dbginfo: &Debuginfo) -> anyhow::Result<GetResult> {
+        let servers = self.debuginfod.servers_for(&dbginfo.debuginfod_servers);
+        self.debuginfod
+            .get_from(&servers, &dbginfo.build_id, dbginfo.build_id_type())
+            .await
     }
 
-    async fn fetch_bucket(&self, dbginfo: &Debuginfo) -> anyhow::Result<Vec<u8>> {
+    async fn fetch_bucket(&self, dbginfo: &Debuginfo) -> anyhow::Result<GetResult> {
         let path: &str = &dbginfo.upload.as_ref().unwrap().id;
 
-        let rc = self
+        Ok(self
             .bucket
             .get(&object_store::path::Path::from(path))
-            .await?;
+            .await?)
+    }
+}
+
+/// Whether the file was uploaded in full and not found to be invalid since.
+fn is_uploaded(dbginfo: &Debuginfo) -> bool {
+    dbginfo.source() == Source::Upload
+        && dbginfo
+            .upload
+            .as_ref()
+            .is_some_and(|upload| upload.state() == State::Uploaded)
+        && dbginfo.quality.is_none_or(|q| !q.not_valid_elf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{debuginfo_store::debuginfod::tests::serve, storage};
+
+    const BUILD_ID: &str = "58e86b9789342302bc7849e31d412b7e531240ac";
+
+    #[tokio::test]
+    async fn test_fetch_dwp_from_debuginfod() {
+        let (upstream, requests) = serve(|_, path, _| match path {
+            "/buildid/58e86b9789342302bc7849e31d412b7e531240ac/dwp" => (200, b"dwp".to_vec()),
+            _ => (404, vec![]),
+        });
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let fetcher = DebuginfoFetcher::new(
+            Arc::clone(&bucket),
+            DebugInfod::default().with_upstream_servers(vec![upstream]),
+        );
 
-        Ok(rc.bytes().await?.to_vec())
+        let dwp = fetcher
+            .fetch_dwp(BUILD_ID, BuildIdType::Gnu, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(dwp.bytes().await.unwrap(), b"dwp"[..]);
+        assert!(fetcher
+            .fetch_dwp("aabbccdd", BuildIdType::Gnu, None)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(requests.lock().unwrap().len(), 2);
     }
 }
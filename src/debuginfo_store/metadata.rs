@@ -1,8 +1,14 @@
-use self::debuginfopb::{debuginfo::Source, debuginfo_upload, DebuginfoUpload};
-use crate::debuginfopb::{self, Debuginfo, DebuginfoType};
+use self::debuginfopb::{
+    debuginfo::Source, debuginfo_upload, upload_instructions::UploadStrategy, DebuginfoUpload,
+};
+use super::buildid;
+use crate::debuginfopb::{self, BuildIdType, Debuginfo, DebuginfoType};
 use anyhow::bail;
 use chrono::{DateTime, Utc};
-use moka::sync::Cache;
+use moka::{
+    ops::compute::{CompResult, Op},
+    sync::Cache,
+};
 use prost_types::Timestamp;
 
 #[derive(Debug)]
@@ -18,9 +24,7 @@ impl Default for MetadataStore {
 
 impl MetadataStore {
     pub fn new() -> Self {
-        Self {
-            store: Cache::new(10_000),
-        }
+        Self::with_store(Cache::new(10_000))
     }
 
     pub fn with_store(store: Cache<String, Debuginfo>) -> Self {
@@ -32,10 +36,32 @@ impl MetadataStore {
         self.store.get(&path)
     }
 
+    /// Returns the type of the build ID, as recorded with its metadata, or as
+    /// inferred from its format if none was recorded. Hashes can't be told
+    /// apart from GNU build IDs by their format.
+    pub fn build_id_type(&self, build_id: &str) -> BuildIdType {
+        [
+            DebuginfoType::DebuginfoUnspecified,
+            DebuginfoType::Executable,
+            DebuginfoType::DwarfPackage,
+            DebuginfoType::Sources,
+        ]
+        .iter()
+        .filter_map(|req_type| self.fetch(build_id, req_type))
+        .map(|dbginfo| dbginfo.build_id_type())
+        .find(|build_id_type| *build_id_type != BuildIdType::UnknownUnspecified)
+        .unwrap_or_else(|| buildid::infer_type(build_id))
+    }
+
     fn get_object_path(build_id: &str, req_type: &DebuginfoType) -> String {
+        let build_id = buildid::object_name(build_id);
         match req_type {
             DebuginfoType::Executable => format!("{}/executable.metadata", build_id),
             DebuginfoType::Sources => format!("{}/sources.metadata", build_id),
+            DebuginfoType::DwarfPackage => format!("{}/dwp.metadata", build_id),
+            // DWO IDs identify a unit rather than a binary, so they are kept
+            // apart from build IDs.
+            DebuginfoType::SplitDwarf => format!("dwo/{}.metadata", build_id),
             _ => format!("{}/metadata", build_id),
         }
     }
@@ -59,15 +85,37 @@ impl MetadataStore {
         Ok(())
     }
 
+    /// Records the type of the build ID once an upload confirmed it.
+    pub fn set_build_id_type(
+        &self,
+        build_id: &str,
+        req_type: &DebuginfoType,
+        build_id_type: BuildIdType,
+    ) -> anyhow::Result<()> {
+        let path = Self::get_object_path(build_id, req_type);
+        let mut entry = match self.store.get(&path) {
+            Some(e) => e,
+            None => {
+                bail!("Debuginfo not found");
+            }
+        };
+
+        entry.build_id_type = build_id_type.into();
+        self.store.insert(path, entry);
+        Ok(())
+    }
+
     pub fn mark_as_debuginfod_source(
         &self,
         servers: Vec<String>,
         build_id: &str,
         req_type: &DebuginfoType,
+        build_id_type: BuildIdType,
     ) -> anyhow::Result<()> {
         self.write(Debuginfo {
             build_id: build_id.to_string(),
             r#type: (*req_type).into(),
+            build_id_type: build_id_type.into(),
             source: Source::Debuginfod.into(),
             upload: None,
             quality: None,
@@ -75,17 +123,21 @@ impl MetadataStore {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn mark_as_uploading(
         &self,
         build_id: &str,
         upload_id: &str,
         hash: &str,
         req_type: &DebuginfoType,
+        build_id_type: BuildIdType,
+        strategy: UploadStrategy,
         started_at: DateTime<Utc>,
     ) -> anyhow::Result<()> {
         self.write(Debuginfo {
             build_id: build_id.to_string(),
             r#type: (*req_type).into(),
+            build_id_type: build_id_type.into(),
             source: Source::Upload.into(),
             upload: Some(DebuginfoUpload {
                 id: upload_id.to_string(),
@@ -96,6 +148,7 @@ impl MetadataStore {
                 }),
                 finished_at: None,
                 state: debuginfo_upload::State::Uploading.into(),
+                strategy: strategy.into(),
             }),
             quality: None,
             debuginfod_servers: vec![],
@@ -137,6 +190,38 @@ impl MetadataStore {
         self.write(debug_info)
     }
 
+    /// Returns the metadata of all build IDs, in no particular order.
+    pub fn list(&self) -> Vec<Debuginfo> {
+        self.store.iter().map(|(_, dbginfo)| dbginfo).collect()
+    }
+
+    pub fn delete(&self, build_id: &str, req_type: &DebuginfoType) -> Option<Debuginfo> {
+        self.store
+            .remove(&Self::get_object_path(build_id, req_type))
+    }
+
+    /// Removes the metadata of the build ID if it is still that of the
+    /// upload. Returns whether it was removed.
+    pub fn remove_upload(&self, build_id: &str, upload_id: &str, req_type: &DebuginfoType) -> bool {
+        let path = Self::get_object_path(build_id, req_type);
+        let res = self
+            .store
+            .entry(path)
+            .and_compute_with(|entry| match entry {
+                Some(entry)
+                    if entry
+                        .value()
+                        .upload
+                        .as_ref()
+                        .is_some_and(|u| u.id == upload_id) =>
+                {
+                    Op::Remove
+                }
+                _ => Op::Nop,
+            });
+        matches!(res, CompResult::Removed(_))
+    }
+
     pub fn write(&self, debuginfo: Debuginfo) -> anyhow::Result<()> {
         if debuginfo.build_id.is_empty() {
             bail!("build_id is empty. REQUIRED to write debuginfo metadata");
@@ -1,7 +1,12 @@
+pub mod admin;
+pub mod buildid;
 mod debuginfod;
 mod fetcher;
 mod metadata;
 mod reasons;
+mod server;
+mod signer;
+pub(crate) mod sources;
 
 use self::debuginfopb::{
     debuginfo_upload::State, upload_instructions::UploadStrategy, upload_request, DebuginfoType,
@@ -9,19 +14,34 @@ use self::debuginfopb::{
 };
 use crate::debuginfopb::{
     self, debuginfo::Source, debuginfo_service_server::DebuginfoService, BuildIdType, Debuginfo,
-    InitiateUploadRequest, InitiateUploadResponse, MarkUploadFinishedRequest,
+    DebuginfoQuality, InitiateUploadRequest, InitiateUploadResponse, MarkUploadFinishedRequest,
     MarkUploadFinishedResponse, ShouldInitiateUploadResponse, UploadRequest, UploadResponse,
 };
+use crate::symbols::elfutils;
 use chrono::{DateTime, Duration, TimeZone, Utc};
 pub use debuginfod::DebugInfod;
 pub use fetcher::DebuginfoFetcher;
 pub use metadata::MetadataStore;
-use object_store::ObjectStore;
+use object_store::{ObjectStore, WriteMultipart};
 use reasons::DebugInfoUploadReason;
+pub use server::DebuginfodServer;
+#[cfg(feature = "cloud")]
+pub use signer::ObjectStoreSigner;
+pub use signer::UploadSigner;
+pub use sources::{SourceArchive, SourceStore};
+use std::collections::HashMap;
+use std::io::Write;
 use std::result::Result;
-use std::sync::Arc;
-use tokio_stream::StreamExt;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_stream::{Stream, StreamExt};
 use tonic::{async_trait, Request, Response, Status, Streaming};
+use xxhash_rust::xxh64::Xxh64;
+
+/// Accepted uploads are written to the bucket in parts of this size, with up
+/// to UPLOAD_CONCURRENCY of them in flight.
+const UPLOAD_PART_SIZE: usize = 8 << 20;
+const UPLOAD_CONCURRENCY: usize = 4;
 
 pub struct UploadRequestInfo {
     buildid: String,
@@ -52,6 +72,139 @@ pub struct DebuginfoStore {
     pub(crate) max_upload_duration: Duration,
     pub(crate) max_upload_size: i64,
     pub(crate) bucket: Arc<dyn ObjectStore>,
+    /// Signs upload URLs for the bucket. Buckets that can't sign URLs, like
+    /// the local filesystem, are uploaded to through the Upload RPC.
+    pub(crate) signer: Option<Arc<dyn UploadSigner>>,
+    pub(crate) uploads: TrackedUploads,
+}
+
+/// TrackedUpload is an upload whose object may have to be deleted.
+#[derive(Debug, Clone)]
+struct TrackedUpload {
+    build_id: String,
+    req_type: DebuginfoType,
+    since: DateTime<Utc>,
+}
+
+/// TrackedUploadRecord is how a TrackedUpload is kept in the bucket.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct TrackedUploadRecord {
+    build_id: String,
+    req_type: i32,
+    since_millis: i64,
+}
+
+/// Objects that record the tracked uploads, so that uploads abandoned before
+/// a restart are still cleaned up after it.
+const TRACKED_UPLOADS_PREFIX: &str = "tracked-uploads";
+
+/// TrackedUploads are the uploads whose objects the janitor may have to
+/// delete: those that were initiated but not finished yet, and those that
+/// were replaced by another upload. Unlike the metadata, they aren't
+/// evicted, and they are recorded in the bucket, so an upload object that
+/// isn't tracked is never deleted, and one that is isn't forgotten.
+#[derive(Debug, Default)]
+pub struct TrackedUploads(Mutex<HashMap<String, TrackedUpload>>);
+
+impl TrackedUploads {
+    async fn track(
+        &self,
+        bucket: &dyn ObjectStore,
+        upload_id: &str,
+        build_id: &str,
+        req_type: DebuginfoType,
+        since: DateTime<Utc>,
+    ) {
+        self.0.lock().unwrap().insert(
+            upload_id.to_string(),
+            TrackedUpload {
+                build_id: build_id.to_string(),
+                req_type,
+                since,
+            },
+        );
+
+        let record = TrackedUploadRecord {
+            build_id: build_id.to_string(),
+            req_type: req_type.into(),
+            since_millis: since.timestamp_millis(),
+        };
+        let res = match serde_json::to_vec(&record) {
+            Ok(data) => bucket
+                .put(&Self::record_path(upload_id), data.into())
+                .await
+                .map(drop)
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            log::warn!("Failed to record tracked upload {}: {:#}", upload_id, e);
+        }
+    }
+
+    async fn untrack(&self, bucket: &dyn ObjectStore, upload_id: &str) {
+        self.0.lock().unwrap().remove(upload_id);
+        match bucket.delete(&Self::record_path(upload_id)).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => (),
+            Err(e) => log::warn!("Failed to remove tracked upload {}: {}", upload_id, e),
+        }
+    }
+
+    /// Loads the uploads that were tracked before a restart. Records that
+    /// can't be read are skipped.
+    async fn load(&self, bucket: &dyn ObjectStore) -> anyhow::Result<usize> {
+        let prefix = object_store::path::Path::from(TRACKED_UPLOADS_PREFIX);
+        let records: Vec<_> = bucket
+            .list(Some(&prefix))
+            .collect::<object_store::Result<_>>()
+            .await?;
+
+        let mut loaded = 0;
+        for meta in records {
+            let upload_id = match meta.location.filename() {
+                Some(upload_id) => upload_id.to_string(),
+                None => continue,
+            };
+            let record = match bucket.get(&meta.location).await {
+                Ok(res) => res.bytes().await.map_err(anyhow::Error::from),
+                Err(e) => Err(e.into()),
+            }
+            .and_then(|data| Ok(serde_json::from_slice::<TrackedUploadRecord>(&data)?));
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("Skipping tracked upload {}: {:#}", upload_id, e);
+                    continue;
+                }
+            };
+            let tracked = TrackedUpload {
+                build_id: record.build_id,
+                req_type: DebuginfoType::try_from(record.req_type).unwrap_or_default(),
+                since: Utc
+                    .timestamp_millis_opt(record.since_millis)
+                    .single()
+                    .unwrap_or_else(Utc::now),
+            };
+            self.0.lock().unwrap().insert(upload_id, tracked);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// Returns the uploads tracked since before the cutoff.
+    fn before(&self, cutoff: DateTime<Utc>) -> Vec<(String, TrackedUpload)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, upload)| upload.since < cutoff)
+            .map(|(id, upload)| (id.clone(), upload.clone()))
+            .collect()
+    }
+
+    fn record_path(upload_id: &str) -> object_store::path::Path {
+        object_store::path::Path::from(format!("{}/{}", TRACKED_UPLOADS_PREFIX, upload_id))
+    }
 }
 
 #[async_trait]
@@ -80,7 +233,10 @@ impl DebuginfoService for DebuginfoStore {
             .data
             .ok_or_else(|| Status::invalid_argument("Missing data"))?;
         let upload_info = UploadRequestInfo::try_from(data)?;
-        let _ = self.validate_buildid(&upload_info.buildid)?;
+        let _ = self.validate_buildid(
+            &upload_info.buildid,
+            self.metadata.build_id_type(&upload_info.buildid),
+        )?;
 
         let dbginfo = self
             .metadata
@@ -104,39 +260,9 @@ impl DebuginfoService for DebuginfoStore {
         ));
         }
 
-        let mut chunks = Vec::new();
-        while let Some(req) = stream.next().await {
-            let req = req?;
-            match req.data {
-                Some(upload_request::Data::ChunkData(chunk)) => {
-                    chunks.extend(chunk);
-                }
-                _ => {
-                    return Err(Status::invalid_argument(
-                        "provided no value or invalid data",
-                    ))
-                }
-            }
-        }
-
-        let size = chunks.len() as u64;
-
-        match self
-            .bucket
-            .put(
-                &object_store::path::Path::from(upload_info.upload_id),
-                chunks.into(),
-            )
-            .await
-        {
-            Ok(_) => {}
-            Err(e) => {
-                return Err(Status::internal(format!(
-                    "Failed to store debuginfo: {}",
-                    e
-                )))
-            }
-        };
+        let size = self
+            .store_upload(&upload_info, &upload.hash, &mut stream)
+            .await?;
 
         Ok(Response::new(UploadResponse {
             build_id: upload_info.buildid,
@@ -153,13 +279,13 @@ impl DebuginfoService for DebuginfoStore {
     ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
         // log::info!("ShouldInitiateUpload request received");
         let request = request.into_inner();
-        let _ = self.validate_buildid(&request.build_id)?;
+        let build_id_type = self.validate_buildid(&request.build_id, request.build_id_type())?;
 
         let debuginfo = self.metadata.fetch(&request.build_id, &request.r#type());
 
         match debuginfo {
             Some(info) => self.handle_existing_debuginfo(&request, &info),
-            None => Box::pin(self.handle_new_build_id(&request)).await,
+            None => Box::pin(self.handle_new_build_id(&request, build_id_type)).await,
         }
     }
 
@@ -208,9 +334,19 @@ impl DebuginfoService for DebuginfoStore {
             )));
         }
 
+        let build_id_type = self.validate_buildid(&request.build_id, request.build_id_type())?;
         let upload_id = ulid::Ulid::new().to_string();
         let upload_started = self.time_now();
-        // let upload_expired = upload_started + self.max_upload_duration;
+        let replaced = self
+            .metadata
+            .fetch(&request.build_id, &request.r#type())
+            .and_then(|dbginfo| dbginfo.upload);
+        // Agents upload straight to the bucket if it can sign URLs, otherwise
+        // through the Upload RPC.
+        let upload_strategy = match &self.signer {
+            Some(_) => UploadStrategy::SignedUrl,
+            None => UploadStrategy::Grpc,
+        };
 
         {
             let _ = self
@@ -220,6 +356,9 @@ impl DebuginfoService for DebuginfoStore {
                     &upload_id,
                     &request.hash,
                     &request.r#type(),
+                    // The upload is checked against the build ID of this type.
+                    build_id_type,
+                    upload_strategy,
                     upload_started,
                 )
                 .map_err(|e| {
@@ -228,13 +367,47 @@ impl DebuginfoService for DebuginfoStore {
                     ))
                 })?;
         }
+        self.uploads
+            .track(
+                &*self.bucket,
+                &upload_id,
+                &request.build_id,
+                request.r#type(),
+                upload_started,
+            )
+            .await;
+        // The object of a replaced upload is deleted once the new upload had
+        // the time to finish, or it is abandoned too.
+        if let Some(replaced) = replaced {
+            self.uploads
+                .track(
+                    &*self.bucket,
+                    &replaced.id,
+                    &request.build_id,
+                    request.r#type(),
+                    upload_started,
+                )
+                .await;
+        }
+
+        let signed_url = match &self.signer {
+            Some(signer) => signer
+                .signed_put_url(
+                    &object_store::path::Path::from(upload_id.as_str()),
+                    self.max_upload_duration.to_std().unwrap_or_default(),
+                )
+                .await
+                .map_err(|e| Status::internal(format!("Failed to sign upload URL: {e:#}")))?
+                .to_string(),
+            None => String::new(),
+        };
 
         Ok(Response::new(InitiateUploadResponse {
             upload_instructions: Some(UploadInstructions {
                 upload_id,
                 build_id: request.build_id,
-                upload_strategy: UploadStrategy::Grpc.into(),
-                signed_url: "".into(),
+                upload_strategy: upload_strategy.into(),
+                signed_url,
                 r#type: request.r#type,
             }),
         }))
@@ -247,7 +420,87 @@ impl DebuginfoService for DebuginfoStore {
         // log::info!("MarkUploadFinished request received");
 
         let request = request.into_inner();
-        let _ = self.validate_buildid(&request.build_id)?;
+        let _ = self.validate_buildid(
+            &request.build_id,
+            self.metadata.build_id_type(&request.build_id),
+        )?;
+
+        let upload = self
+            .metadata
+            .fetch(&request.build_id, &request.r#type())
+            .and_then(|dbginfo| dbginfo.upload)
+            .filter(|upload| upload.id == request.upload_id)
+            .ok_or_else(|| {
+                Status::failed_precondition(
+                    "upload metadata not found, this indicates that the upload was not previously initiated",
+                )
+            })?;
+        if upload.state() == State::Uploading && self.is_upload_stale(&upload) {
+            let _ = self.delete_upload_object(&upload.id).await;
+            return Err(Status::failed_precondition(format!(
+                "Upload {} expired before it was marked finished",
+                upload.id
+            )));
+        }
+
+        // Signed URL uploads bypass the server, so the upload is only known
+        // to have arrived if it is in the bucket.
+        let path = object_store::path::Path::from(request.upload_id.as_str());
+        let meta = match self.bucket.head(&path).await {
+            Ok(meta) => meta,
+            Err(object_store::Error::NotFound { .. }) => {
+                return Err(Status::failed_precondition(format!(
+                    "Upload {} was not found, it has to be uploaded before it is marked finished",
+                    request.upload_id
+                )))
+            }
+            Err(e) => {
+                return Err(Status::internal(format!(
+                    "Failed to check upload {}: {}",
+                    request.upload_id, e
+                )))
+            }
+        };
+        if meta.size as i64 > self.max_upload_size {
+            let _ = self.bucket.delete(&path).await;
+            return Err(Status::invalid_argument(format!(
+                "Upload size {} exceeds the maximum allowed size {}",
+                meta.size, self.max_upload_size
+            )));
+        }
+
+        // Uploads through the Upload RPC were checked as they were stored.
+        // Signed URL uploads bypass the server, they are checked like one
+        // through the server would be, and deleted if they don't pass.
+        if upload.strategy() != UploadStrategy::Grpc {
+            let (file, digest) = self.spool_object(&path).await.map_err(|e| {
+                Status::internal(format!(
+                    "Failed to read upload {}: {:#}",
+                    request.upload_id, e
+                ))
+            })?;
+            let info = UploadRequestInfo {
+                buildid: request.build_id.clone(),
+                upload_id: request.upload_id.clone(),
+                debuginfo_type: request.r#type(),
+            };
+            let quality = match self
+                .verify_upload(&info, &upload.hash, &digest, &file, meta.size as u64)
+                .await
+            {
+                Ok((_, quality)) => quality,
+                Err(status) => {
+                    let _ = self.bucket.delete(&path).await;
+                    return Err(status);
+                }
+            };
+            if let Some(quality) = quality {
+                self.metadata
+                    .set_quality(&request.build_id, &quality, &request.r#type())
+                    .map_err(|e| Status::internal(format!("Failed to set quality: {e}")))?;
+            }
+        }
+
         let _ = self
             .metadata
             .mark_as_uploaded(
@@ -259,17 +512,356 @@ impl DebuginfoService for DebuginfoStore {
             .map_err(|e| {
                 Status::internal(format!("Failed to mark metadata as uploaded. details: {e}"))
             })?;
+        self.uploads
+            .untrack(&*self.bucket, &request.upload_id)
+            .await;
         Ok(Response::new(MarkUploadFinishedResponse::default()))
     }
 }
 
 impl DebuginfoStore {
-    fn validate_buildid(&self, id: &str) -> anyhow::Result<(), Status> {
-        if id.len() <= 2 {
-            return Err(Status::invalid_argument("unexpectedly short input"));
+    /// Stores the chunks of an upload. They are hashed and spooled to a
+    /// temporary file as they come in, so the upload can be checked before it
+    /// is committed to the bucket. Uploads that exceed the size limit, don't
+    /// match the hash given when they were initiated, or are the ELF file of
+    /// another build ID are rejected, as are sources that aren't a tar archive.
+    /// The quality of accepted ELF files is recorded right away.
+    async fn store_upload<S>(
+        &self,
+        info: &UploadRequestInfo,
+        hash: &str,
+        stream: &mut S,
+    ) -> Result<u64, Status>
+    where
+        S: Stream<Item = Result<UploadRequest, Status>> + Unpin,
+    {
+        let internal = |e: std::io::Error| Status::internal(format!("Failed to spool upload: {e}"));
+
+        let mut file = tempfile::tempfile().map_err(internal)?;
+        let mut hasher = Xxh64::new(0);
+        let mut size: u64 = 0;
+        while let Some(req) = stream.next().await {
+            let chunk = match req?.data {
+                Some(upload_request::Data::ChunkData(chunk)) => chunk,
+                _ => {
+                    return Err(Status::invalid_argument(
+                        "provided no value or invalid data",
+                    ))
+                }
+            };
+
+            size += chunk.len() as u64;
+            if size > self.max_upload_size as u64 {
+                return Err(Status::invalid_argument(format!(
+                    "Upload exceeds the maximum allowed size {}",
+                    self.max_upload_size
+                )));
+            }
+            hasher.update(&chunk);
+            file.write_all(&chunk).map_err(internal)?;
+        }
+
+        let digest = format!("{:016x}", hasher.digest());
+        let (data, quality) = self.verify_upload(info, hash, &digest, &file, size).await?;
+
+        let path = object_store::path::Path::from(info.upload_id.as_str());
+        let store =
+            |e: object_store::Error| Status::internal(format!("Failed to store debuginfo: {}", e));
+        let mut upload =
+            WriteMultipart::new(self.bucket.put_multipart(&path).await.map_err(store)?);
+        for chunk in data.chunks(UPLOAD_PART_SIZE) {
+            upload
+                .wait_for_capacity(UPLOAD_CONCURRENCY)
+                .await
+                .map_err(store)?;
+            upload.write(chunk);
+        }
+        upload.finish().await.map_err(store)?;
+
+        if let Some(quality) = quality {
+            self.metadata
+                .set_quality(&info.buildid, &quality, &info.debuginfo_type)
+                .map_err(|e| Status::internal(format!("Failed to set quality: {e}")))?;
+        }
+
+        Ok(size)
+    }
+
+    /// Checks an upload spooled to file. It has to match the hash it was
+    /// initiated with, and be what it was uploaded as. Returns the mapped
+    /// upload, along with the quality of ELF files.
+    async fn verify_upload(
+        &self,
+        info: &UploadRequestInfo,
+        hash: &str,
+        digest: &str,
+        file: &std::fs::File,
+        size: u64,
+    ) -> Result<(memmap2::Mmap, Option<DebuginfoQuality>), Status> {
+        if size == 0 {
+            return Err(Status::invalid_argument("Upload is empty"));
+        }
+        if !digest.eq_ignore_ascii_case(hash) {
+            return Err(Status::invalid_argument(format!(
+                "Upload hash {} does not match the hash {} it was initiated with",
+                digest, hash
+            )));
+        }
+
+        // SAFETY: The file is anonymous, nothing else can modify it.
+        let data = unsafe { memmap2::Mmap::map(file) }
+            .map_err(|e| Status::internal(format!("Failed to map upload: {e}")))?;
+        let build_id_type = self.metadata.build_id_type(&info.buildid);
+        let build_id = info.buildid.clone();
+        let debuginfo_type = info.debuginfo_type;
+        // Parsing is CPU bound, and uploads can be large.
+        let (data, assessed) = tokio::task::spawn_blocking(move || {
+            let assessed = match debuginfo_type {
+                DebuginfoType::Sources => SourceArchive::parse(&data)
+                    .map(|_| (None, build_id_type))
+                    .map_err(|e| {
+                        Status::invalid_argument(format!("Upload is not a source archive: {e:#}"))
+                    }),
+                DebuginfoType::DwarfPackage | DebuginfoType::SplitDwarf => {
+                    Self::assess_split_dwarf(&build_id, debuginfo_type, &data)
+                        .map(|quality| (Some(quality), build_id_type))
+                        .map_err(|e| Status::invalid_argument(e.to_string()))
+                }
+                _ => Self::assess_upload(&build_id, build_id_type, &data)
+                    .map(|(quality, build_id_type)| (Some(quality), build_id_type))
+                    .map_err(|e| Status::invalid_argument(e.to_string())),
+            };
+            (data, assessed)
+        })
+        .await
+        .map_err(|e| Status::internal(format!("Failed to assess upload: {e}")))?;
+        let (quality, confirmed_type) = assessed?;
+        if confirmed_type != build_id_type {
+            let _ = self.metadata.set_build_id_type(
+                &info.buildid,
+                &info.debuginfo_type,
+                confirmed_type,
+            );
+        }
+        if let Some(quality) = quality.filter(|q| q.not_valid_elf) {
+            let _ = self
+                .metadata
+                .set_quality(&info.buildid, &quality, &info.debuginfo_type);
+            return Err(Status::invalid_argument("Upload is not a valid ELF file"));
+        }
+
+        Ok((data, quality))
+    }
+
+    /// Copies an upload that was uploaded to the bucket directly to a
+    /// temporary file, hashing it on the way, so it can be verified.
+    async fn spool_object(
+        &self,
+        path: &object_store::path::Path,
+    ) -> anyhow::Result<(std::fs::File, String)> {
+        let mut file = tempfile::tempfile()?;
+        let mut hasher = Xxh64::new(0);
+        let mut stream = self.bucket.get(path).await?.into_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            hasher.update(&chunk);
+            file.write_all(&chunk)?;
+        }
+        Ok((file, format!("{:016x}", hasher.digest())))
+    }
+
+    /// Checks that the upload is the ELF file of the build ID, and assesses
+    /// its quality. Returns the type of the build ID too, which for a hex
+    /// build ID of unknown type is GNU if the upload has it in its
+    /// .note.gnu.build-id.
+    fn assess_upload(
+        build_id: &str,
+        build_id_type: BuildIdType,
+        data: &[u8],
+    ) -> anyhow::Result<(DebuginfoQuality, BuildIdType)> {
+        let file = match object::File::parse(data) {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!("Received an upload that can't be parsed: {}", e);
+                let quality = DebuginfoQuality {
+                    not_valid_elf: true,
+                    ..Default::default()
+                };
+                return Ok((quality, build_id_type));
+            }
+        };
+
+        // GNU and Go build IDs are read from the notes of the file, which an
+        // upload of that build ID must have. Hash build IDs are computed by the
+        // agent from parts of the executable that debuginfo files may not
+        // contain, so they can't be checked.
+        let (file_build_id, note) = match build_id_type {
+            BuildIdType::Gnu => (elfutils::build_id(&file), ".note.gnu.build-id"),
+            BuildIdType::Go => (elfutils::go_build_id(&file), ".note.go.buildid"),
+            BuildIdType::UnknownUnspecified
+                if elfutils::build_id(&file) == Some(build_id.to_ascii_lowercase()) =>
+            {
+                return Ok((elfutils::quality(&file), BuildIdType::Gnu));
+            }
+            _ => return Ok((elfutils::quality(&file), build_id_type)),
+        };
+        // GNU build IDs are hex encoded in lower case, Go build IDs are case
+        // sensitive.
+        let expected = match build_id_type {
+            BuildIdType::Gnu => build_id.to_ascii_lowercase(),
+            _ => build_id.to_string(),
+        };
+        match file_build_id {
+            Some(file_build_id) if file_build_id != expected => anyhow::bail!(
+                "Upload has build ID {}, expected {}",
+                file_build_id,
+                build_id
+            ),
+            Some(_) => (),
+            None => anyhow::bail!("Upload has no {} with build ID {}", note, build_id),
+        }
+
+        Ok((elfutils::quality(&file), build_id_type))
+    }
+
+    /// Checks that the upload holds split units, and for a .dwo that one of
+    /// them is the unit of the DWO ID it was uploaded for.
+    fn assess_split_dwarf(
+        dwo_id: &str,
+        req_type: DebuginfoType,
+        data: &[u8],
+    ) -> anyhow::Result<DebuginfoQuality> {
+        let file = match object::File::parse(data) {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!("Received a split DWARF upload that can't be parsed: {}", e);
+                return Ok(DebuginfoQuality {
+                    not_valid_elf: true,
+                    ..Default::default()
+                });
+            }
+        };
+
+        let ids = elfutils::split_dwo_ids(&file);
+        if ids.is_empty() {
+            anyhow::bail!("Upload has no split DWARF units");
+        }
+        if req_type == DebuginfoType::SplitDwarf
+            && !u64::from_str_radix(dwo_id, 16).is_ok_and(|id| ids.contains(&id))
+        {
+            anyhow::bail!("Upload has no split unit with DWO ID {}", dwo_id);
+        }
+
+        Ok(elfutils::quality(&file))
+    }
+
+    /// Removes uploads that were never finished. Their metadata is removed so
+    /// the build ID can be uploaded again, and their objects are deleted,
+    /// along with the objects of uploads that were replaced by another one.
+    /// Returns how many uploads were removed.
+    pub async fn expire_uploads(&self) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        let mut expired = vec![];
+        for (_, dbginfo) in self.metadata.store.iter() {
+            match &dbginfo.upload {
+                Some(upload)
+                    if upload.state() == State::Uploading && self.is_upload_stale(upload) =>
+                {
+                    expired.push(dbginfo.clone())
+                }
+                _ => (),
+            }
         }
+        for dbginfo in expired {
+            let upload_id = &dbginfo.upload.as_ref().unwrap().id;
+            if self
+                .metadata
+                .remove_upload(&dbginfo.build_id, upload_id, &dbginfo.r#type())
+            {
+                log::info!(
+                    "Expired upload {} of build_id {}",
+                    upload_id,
+                    dbginfo.build_id
+                );
+                self.delete_upload_object(upload_id).await?;
+                self.uploads.untrack(&*self.bucket, upload_id).await;
+                removed += 1;
+            }
+        }
+
+        // Tracked uploads that are past their deadline are abandoned, unless
+        // the metadata says they were finished after all. Their metadata may
+        // have been evicted since.
+        let cutoff = self.time_now() - (self.max_upload_duration + Duration::minutes(2));
+        for (upload_id, tracked) in self.uploads.before(cutoff) {
+            let current = self
+                .metadata
+                .fetch(&tracked.build_id, &tracked.req_type)
+                .and_then(|dbginfo| dbginfo.upload)
+                .filter(|upload| upload.id == upload_id);
+            if current.is_some_and(|upload| upload.state() == State::Uploaded) {
+                self.uploads.untrack(&*self.bucket, &upload_id).await;
+                continue;
+            }
+
+            self.metadata
+                .remove_upload(&tracked.build_id, &upload_id, &tracked.req_type);
+            log::info!(
+                "Deleting abandoned upload {} of build_id {}",
+                upload_id,
+                tracked.build_id
+            );
+            self.delete_upload_object(&upload_id).await?;
+            self.uploads.untrack(&*self.bucket, &upload_id).await;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    /// Expires abandoned uploads every interval, until the runtime shuts down.
+    /// The uploads that were tracked before a restart are loaded first.
+    pub fn spawn_janitor(self: Arc<Self>, interval: std::time::Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            match self.uploads.load(&*self.bucket).await {
+                Ok(0) => (),
+                Ok(n) => log::info!("Loaded {} tracked uploads", n),
+                Err(e) => log::error!("Failed to load tracked uploads: {:#}", e),
+            }
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match self.expire_uploads().await {
+                    Ok(0) => (),
+                    Ok(n) => log::info!("Removed {} abandoned uploads", n),
+                    Err(e) => log::error!("Failed to expire uploads: {:#}", e),
+                }
+            }
+        })
+    }
+
+    async fn delete_upload_object(&self, upload_id: &str) -> anyhow::Result<()> {
+        match self
+            .bucket
+            .delete(&object_store::path::Path::from(upload_id))
+            .await
+        {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
 
-        Ok(())
+    fn validate_buildid(
+        &self,
+        id: &str,
+        build_id_type: BuildIdType,
+    ) -> anyhow::Result<BuildIdType, Status> {
+        match buildid::validate(id, build_id_type) {
+            // An upload may have confirmed the type of a hex build ID.
+            Ok(BuildIdType::UnknownUnspecified) => Ok(self.metadata.build_id_type(id)),
+            Ok(build_id_type) => Ok(build_id_type),
+            Err(e) => Err(Status::invalid_argument(e.to_string())),
+        }
     }
 
     fn is_upload_stale(&self, upload: &DebuginfoUpload) -> bool {
@@ -343,8 +935,9 @@ impl DebuginfoStore {
         request: &ShouldInitiateUploadRequest,
         debuginfo: &Debuginfo,
     ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
-        if !self.is_valid_elf(debuginfo) {
-            return self.handle_invalid_elf(request);
+        // Only debuginfo that was marked invalid is replaced, unless forced.
+        if !self.is_marked_invalid(debuginfo) {
+            return self.handle_valid_elf(request);
         }
 
         if request.hash.is_empty() {
@@ -357,14 +950,13 @@ impl DebuginfoStore {
         self.compare_hash(request, debuginfo)
     }
 
-    fn is_valid_elf(&self, debuginfo: &Debuginfo) -> bool {
-        debuginfo
-            .quality
-            .as_ref()
-            .map_or(false, |q| !q.not_valid_elf)
+    /// Debuginfo is marked invalid when it turned out not to be an ELF file,
+    /// or by an admin, so that it is uploaded again.
+    fn is_marked_invalid(&self, debuginfo: &Debuginfo) -> bool {
+        debuginfo.quality.as_ref().is_some_and(|q| q.not_valid_elf)
     }
 
-    fn handle_invalid_elf(
+    fn handle_valid_elf(
         &self,
         request: &ShouldInitiateUploadRequest,
     ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
@@ -405,12 +997,13 @@ impl DebuginfoStore {
         &self,
         debuginfo: &Debuginfo,
     ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
+        let invalid = self.is_marked_invalid(debuginfo);
         Ok(Response::new(ShouldInitiateUploadResponse {
-            should_initiate_upload: true,
-            reason: if !self.is_valid_elf(debuginfo) {
-                DebugInfoUploadReason::DebugInfodSource.to_string()
-            } else {
+            should_initiate_upload: invalid,
+            reason: if invalid {
                 DebugInfoUploadReason::DebugInfodInvalid.to_string()
+            } else {
+                DebugInfoUploadReason::DebugInfodSource.to_string()
             },
         }))
     }
@@ -418,11 +1011,17 @@ impl DebuginfoStore {
     async fn handle_new_build_id(
         &self,
         request: &ShouldInitiateUploadRequest,
+        build_id_type: BuildIdType,
     ) -> anyhow::Result<Response<ShouldInitiateUploadResponse>, Status> {
-        if !matches!(
-            request.build_id_type(),
-            BuildIdType::Gnu | BuildIdType::UnknownUnspecified
-        ) {
+        // Debuginfod can't be asked for build IDs it doesn't index, and only
+        // knows whether it has the debuginfo of a build ID, not whether it
+        // has its split DWARF.
+        if !buildid::is_debuginfod_compatible(build_id_type)
+            || matches!(
+                request.r#type(),
+                DebuginfoType::DwarfPackage | DebuginfoType::SplitDwarf
+            )
+        {
             return Ok(Response::new(ShouldInitiateUploadResponse {
                 should_initiate_upload: true,
                 reason: DebugInfoUploadReason::FirstTimeSeen.to_string(),
@@ -431,12 +1030,15 @@ impl DebuginfoStore {
 
         // Check existence outside of the lock
         let build_id = request.build_id.clone();
-        let exists = self.debuginfod.exists(&build_id).await;
+        let exists = self.debuginfod.exists(&build_id, build_id_type).await;
 
         if !exists.is_empty() {
-            let _ = self
-                .metadata
-                .mark_as_debuginfod_source(exists, &build_id, &request.r#type());
+            let _ = self.metadata.mark_as_debuginfod_source(
+                exists,
+                &build_id,
+                &request.r#type(),
+                build_id_type,
+            );
             Ok(Response::new(ShouldInitiateUploadResponse {
                 should_initiate_upload: false,
                 reason: DebugInfoUploadReason::DebugInfoInDebugInfod.to_string(),
@@ -449,3 +1051,578 @@ impl DebuginfoStore {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage;
+
+    const BUILD_ID: &str = "126cf12e76810726c76d87ab8a6a57e9a1d4c815";
+    const TESTDATA: &str = "src/symbols/addr_to_line/testdata";
+
+    fn store(max_upload_size: i64) -> DebuginfoStore {
+        DebuginfoStore {
+            metadata: MetadataStore::new(),
+            debuginfod: DebugInfod::default().with_upstream_servers(vec![]),
+            max_upload_duration: Duration::minutes(15),
+            max_upload_size,
+            bucket: Arc::new(storage::new_memory_bucket()),
+            signer: None,
+            uploads: Default::default(),
+        }
+    }
+
+    async fn upload(
+        store: &DebuginfoStore,
+        build_id: &str,
+        hash: &str,
+        data: &[u8],
+    ) -> Result<u64, Status> {
+        upload_as(
+            store,
+            build_id,
+            hash,
+            data,
+            DebuginfoType::DebuginfoUnspecified,
+            buildid::infer_type(build_id),
+        )
+        .await
+    }
+
+    async fn upload_as(
+        store: &DebuginfoStore,
+        build_id: &str,
+        hash: &str,
+        data: &[u8],
+        req_type: DebuginfoType,
+        build_id_type: BuildIdType,
+    ) -> Result<u64, Status> {
+        let upload_id = ulid::Ulid::new().to_string();
+        store
+            .metadata
+            .mark_as_uploading(
+                build_id,
+                &upload_id,
+                hash,
+                &req_type,
+                build_id_type,
+                UploadStrategy::Grpc,
+                Utc::now(),
+            )
+            .unwrap();
+
+        let info = UploadRequestInfo {
+            buildid: build_id.into(),
+            upload_id: upload_id.clone(),
+            debuginfo_type: req_type,
+        };
+        let mut requests: Vec<Result<UploadRequest, Status>> = vec![];
+        for chunk in data.chunks(1000) {
+            requests.push(Ok(UploadRequest {
+                data: Some(upload_request::Data::ChunkData(chunk.to_vec())),
+            }));
+        }
+        let mut chunks = tokio_stream::iter(requests);
+        let res = store.store_upload(&info, hash, &mut chunks).await;
+
+        let stored = store
+            .bucket
+            .head(&object_store::path::Path::from(upload_id))
+            .await
+            .is_ok();
+        assert_eq!(stored, res.is_ok());
+        res
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let store = store(1 << 30);
+        let data = std::fs::read(format!("{}/basic-cpp-no-fp.debug", TESTDATA)).unwrap();
+        let hash = format!("{:016x}", xxhash_rust::xxh64::xxh64(&data, 0));
+
+        assert_eq!(
+            store.metadata.build_id_type(BUILD_ID),
+            BuildIdType::UnknownUnspecified
+        );
+        assert_eq!(
+            upload(&store, BUILD_ID, &hash, &data).await.unwrap(),
+            data.len() as u64
+        );
+        // The .note.gnu.build-id of the upload confirms it's a GNU build ID.
+        assert_eq!(store.metadata.build_id_type(BUILD_ID), BuildIdType::Gnu);
+        let quality = store
+            .metadata
+            .fetch(BUILD_ID, &DebuginfoType::DebuginfoUnspecified)
+            .unwrap()
+            .quality
+            .unwrap();
+        assert!(!quality.not_valid_elf);
+        assert!(quality.has_dwarf);
+    }
+
+    #[tokio::test]
+    async fn test_upload_rejected() {
+        let data = std::fs::read(format!("{}/basic-cpp-no-fp.debug", TESTDATA)).unwrap();
+        let hash = format!("{:016x}", xxhash_rust::xxh64::xxh64(&data, 0));
+
+        let status = upload(&store(1 << 30), BUILD_ID, "0123456789abcdef", &data)
+            .await
+            .unwrap_err();
+        assert!(status.message().contains("hash"), "{}", status);
+
+        let status = upload(&store(1000), BUILD_ID, &hash, &data)
+            .await
+            .unwrap_err();
+        assert!(status.message().contains("maximum"), "{}", status);
+
+        // A hex build ID of unknown type may be a hash, only a stated GNU
+        // build ID must match the note of the upload.
+        let other = "0000000000000000000000000000000000000000";
+        let status = upload_as(
+            &store(1 << 30),
+            other,
+            &hash,
+            &data,
+            DebuginfoType::DebuginfoUnspecified,
+            BuildIdType::Gnu,
+        )
+        .await
+        .unwrap_err();
+        assert!(status.message().contains("build ID"), "{}", status);
+
+        let store = store(1 << 30);
+        let text = b"not an elf file";
+        let hash = format!("{:016x}", xxhash_rust::xxh64::xxh64(text, 0));
+        assert!(upload(&store, BUILD_ID, &hash, text).await.is_err());
+        let quality = store
+            .metadata
+            .fetch(BUILD_ID, &DebuginfoType::DebuginfoUnspecified)
+            .unwrap()
+            .quality
+            .unwrap();
+        assert!(quality.not_valid_elf);
+    }
+
+    #[tokio::test]
+    async fn test_upload_sources() {
+        let store = store(1 << 30);
+        let data = sources::tests::archive(&[("src/main.c", "int main() {}")]);
+        let hash = format!("{:016x}", xxhash_rust::xxh64::xxh64(&data, 0));
+        upload_as(
+            &store,
+            BUILD_ID,
+            &hash,
+            &data,
+            DebuginfoType::Sources,
+            BuildIdType::Gnu,
+        )
+        .await
+        .unwrap();
+
+        // Sources are never parsed as ELF files, but have to be an archive.
+        let text = b"int main() {}";
+        let hash = format!("{:016x}", xxhash_rust::xxh64::xxh64(text, 0));
+        let status = upload_as(
+            &store,
+            BUILD_ID,
+            &hash,
+            text,
+            DebuginfoType::Sources,
+            BuildIdType::Gnu,
+        )
+        .await
+        .unwrap_err();
+        assert!(status.message().contains("source archive"), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn test_non_gnu_build_ids() {
+        let (upstream, requests) = debuginfod::tests::serve(|_, _, _| (200, vec![]));
+        let mut store = store(1 << 30);
+        store.debuginfod = DebugInfod::default().with_upstream_servers(vec![upstream]);
+
+        let go_build_id =
+            "GBQARYFuCXw4zGISXIvG/KjNR3sbcTb46XVfHcKCJ/G5iOmSaPKQvCL6SeuXIh/8m1xd8RXcywCH0gKwCcy";
+        let hash = "0123456789abcdef";
+        for (build_id, build_id_type) in [
+            (go_build_id, BuildIdType::Go),
+            (go_build_id, BuildIdType::UnknownUnspecified),
+            (hash, BuildIdType::Hash),
+            // Hex build IDs may be hashes until an upload confirms them.
+            (hash, BuildIdType::UnknownUnspecified),
+        ] {
+            let res = store
+                .should_initiate_upload(Request::new(ShouldInitiateUploadRequest {
+                    build_id: build_id.into(),
+                    hash: "hash".into(),
+                    force: false,
+                    r#type: DebuginfoType::Executable.into(),
+                    build_id_type: build_id_type.into(),
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(res.should_initiate_upload);
+        }
+        // Debuginfod is never asked for build IDs it doesn't index.
+        assert!(requests.lock().unwrap().is_empty());
+
+        let status = store
+            .should_initiate_upload(Request::new(ShouldInitiateUploadRequest {
+                build_id: go_build_id.into(),
+                hash: "hash".into(),
+                force: false,
+                r#type: DebuginfoType::Executable.into(),
+                build_id_type: BuildIdType::Gnu.into(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_upload_go_build_id() {
+        let data = std::fs::read(format!("{}/basic-go-with-debuginfo", TESTDATA)).unwrap();
+        let hash = format!("{:016x}", xxhash_rust::xxh64::xxh64(&data, 0));
+
+        let store = store(1 << 30);
+        let go_build_id =
+            "GBQARYFuCXw4zGISXIvG/KjNR3sbcTb46XVfHcKCJ/G5iOmSaPKQvCL6SeuXIh/8m1xd8RXcywCH0gKwCcy";
+        upload(&store, go_build_id, &hash, &data).await.unwrap();
+        assert_eq!(store.metadata.build_id_type(go_build_id), BuildIdType::Go);
+
+        let other =
+            "GBQARYFuCXw4zGISXIvG/KjNR3sbcTb46XVfHcKCJ/G5iOmSaPKQvCL6SeuXIh/AAAAAAAAAAAAAAAAAAAA";
+        let status = upload(&store, other, &hash, &data).await.unwrap_err();
+        assert!(status.message().contains("build ID"), "{}", status);
+
+        // Go binaries have no GNU build ID note.
+        let status = upload_as(
+            &store,
+            BUILD_ID,
+            &hash,
+            &data,
+            DebuginfoType::DebuginfoUnspecified,
+            BuildIdType::Gnu,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            status.message().contains(".note.gnu.build-id"),
+            "{}",
+            status
+        );
+
+        // Hash build IDs can't be checked against the file.
+        let hash_build_id = "0123456789abcdef";
+        upload_as(
+            &store,
+            hash_build_id,
+            &hash,
+            &data,
+            DebuginfoType::DebuginfoUnspecified,
+            BuildIdType::Hash,
+        )
+        .await
+        .unwrap();
+        // The type is kept with the metadata, it can't be told from the ID.
+        assert_eq!(
+            store.metadata.build_id_type(hash_build_id),
+            BuildIdType::Hash
+        );
+
+        // Without a matching .note.gnu.build-id the type stays unknown.
+        let unknown_build_id = "fedcba9876543210";
+        upload(&store, unknown_build_id, &hash, &data)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.metadata.build_id_type(unknown_build_id),
+            BuildIdType::UnknownUnspecified
+        );
+    }
+
+    /// Signs URLs for the in-memory bucket, which can't be uploaded to, the
+    /// test puts the object itself.
+    #[derive(Debug)]
+    struct FakeSigner;
+
+    #[tonic::async_trait]
+    impl UploadSigner for FakeSigner {
+        async fn signed_put_url(
+            &self,
+            path: &object_store::path::Path,
+            expires_in: std::time::Duration,
+        ) -> anyhow::Result<url::Url> {
+            Ok(url::Url::parse(&format!(
+                "https://bucket.example/{}?expires={}",
+                path,
+                expires_in.as_secs()
+            ))?)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signed_url_upload() {
+        let mut store = store(1 << 30);
+        store.signer = Some(Arc::new(FakeSigner));
+        let data = std::fs::read(format!("{}/basic-cpp-no-fp.debug", TESTDATA)).unwrap();
+        let hash = format!("{:016x}", xxhash_rust::xxh64::xxh64(&data, 0));
+
+        let initiate = |hash: &str| {
+            store.initiate_upload(Request::new(InitiateUploadRequest {
+                build_id: BUILD_ID.into(),
+                size: data.len() as i64,
+                hash: hash.into(),
+                ..Default::default()
+            }))
+        };
+        let instructions = initiate(&hash)
+            .await
+            .unwrap()
+            .into_inner()
+            .upload_instructions
+            .unwrap();
+        assert_eq!(instructions.upload_strategy(), UploadStrategy::SignedUrl);
+        assert_eq!(
+            instructions.signed_url,
+            format!(
+                "https://bucket.example/{}?expires=900",
+                instructions.upload_id
+            )
+        );
+
+        let finished = MarkUploadFinishedRequest {
+            build_id: BUILD_ID.into(),
+            upload_id: instructions.upload_id.clone(),
+            ..Default::default()
+        };
+        let status = store
+            .mark_upload_finished(Request::new(finished.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        let path = object_store::path::Path::from(instructions.upload_id.as_str());
+        store.bucket.put(&path, data.clone().into()).await.unwrap();
+        store
+            .mark_upload_finished(Request::new(finished))
+            .await
+            .unwrap();
+        let dbginfo = store
+            .metadata
+            .fetch(BUILD_ID, &DebuginfoType::DebuginfoUnspecified)
+            .unwrap();
+        assert_eq!(dbginfo.upload.unwrap().state(), State::Uploaded);
+        assert!(dbginfo.quality.unwrap().has_dwarf);
+    }
+
+    #[tokio::test]
+    async fn test_signed_url_upload_verified() {
+        let mut store = store(1 << 30);
+        store.signer = Some(Arc::new(FakeSigner));
+        let data = std::fs::read(format!("{}/basic-cpp-no-fp.debug", TESTDATA)).unwrap();
+        let hash = format!("{:016x}", xxhash_rust::xxh64::xxh64(&data, 0));
+
+        // An upload that doesn't match its hash, and one that isn't ELF.
+        for (hash, body) in [("0123456789abcdef", data.clone()), (&hash, b"ELF".to_vec())] {
+            let upload_id = ulid::Ulid::new().to_string();
+            store
+                .metadata
+                .mark_as_uploading(
+                    BUILD_ID,
+                    &upload_id,
+                    hash,
+                    &DebuginfoType::DebuginfoUnspecified,
+                    BuildIdType::Gnu,
+                    UploadStrategy::SignedUrl,
+                    Utc::now(),
+                )
+                .unwrap();
+            let path = object_store::path::Path::from(upload_id.as_str());
+            store.bucket.put(&path, body.into()).await.unwrap();
+
+            let status = store
+                .mark_upload_finished(Request::new(MarkUploadFinishedRequest {
+                    build_id: BUILD_ID.into(),
+                    upload_id: upload_id.clone(),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", status);
+            assert!(store.bucket.head(&path).await.is_err());
+            let upload = store
+                .metadata
+                .fetch(BUILD_ID, &DebuginfoType::DebuginfoUnspecified)
+                .unwrap()
+                .upload
+                .unwrap();
+            assert_eq!(upload.state(), State::Uploading);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_grpc_upload_without_signer() {
+        let store = store(1 << 30);
+        let data = std::fs::read(format!("{}/basic-cpp-no-fp.debug", TESTDATA)).unwrap();
+        let hash = format!("{:016x}", xxhash_rust::xxh64::xxh64(&data, 0));
+        let instructions = store
+            .initiate_upload(Request::new(InitiateUploadRequest {
+                build_id: BUILD_ID.into(),
+                size: data.len() as i64,
+                hash: hash.clone(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .upload_instructions
+            .unwrap();
+        assert_eq!(instructions.upload_strategy(), UploadStrategy::Grpc);
+        assert!(instructions.signed_url.is_empty());
+
+        let info = UploadRequestInfo {
+            buildid: BUILD_ID.into(),
+            upload_id: instructions.upload_id.clone(),
+            debuginfo_type: DebuginfoType::DebuginfoUnspecified,
+        };
+        let mut chunks = tokio_stream::iter(vec![Ok(UploadRequest {
+            data: Some(upload_request::Data::ChunkData(data)),
+        })]);
+        store.store_upload(&info, &hash, &mut chunks).await.unwrap();
+
+        // The upload was checked as it was stored, it isn't read again when
+        // it is marked finished.
+        let path = object_store::path::Path::from(instructions.upload_id.as_str());
+        store
+            .bucket
+            .put(&path, b"replaced".to_vec().into())
+            .await
+            .unwrap();
+        store
+            .mark_upload_finished(Request::new(MarkUploadFinishedRequest {
+                build_id: BUILD_ID.into(),
+                upload_id: instructions.upload_id,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        let dbginfo = store
+            .metadata
+            .fetch(BUILD_ID, &DebuginfoType::DebuginfoUnspecified)
+            .unwrap();
+        assert_eq!(dbginfo.upload.unwrap().state(), State::Uploaded);
+        assert!(dbginfo.quality.unwrap().has_dwarf);
+    }
+
+    #[tokio::test]
+    async fn test_expire_uploads() {
+        let store = store(1 << 30);
+        let req_type = DebuginfoType::DebuginfoUnspecified;
+        let long_ago = Utc::now() - Duration::hours(1);
+        let put = |upload_id: String| {
+            let bucket = Arc::clone(&store.bucket);
+            async move {
+                bucket
+                    .put(
+                        &object_store::path::Path::from(upload_id),
+                        b"ELF".to_vec().into(),
+                    )
+                    .await
+                    .unwrap();
+            }
+        };
+
+        // An abandoned upload, and one that is still in progress.
+        let abandoned = ulid::Ulid::new().to_string();
+        store
+            .metadata
+            .mark_as_uploading(
+                BUILD_ID,
+                &abandoned,
+                "hash",
+                &req_type,
+                BuildIdType::Gnu,
+                UploadStrategy::Grpc,
+                long_ago,
+            )
+            .unwrap();
+        put(abandoned.clone()).await;
+        let other = "0000000000000000000000000000000000000000";
+        let in_progress = ulid::Ulid::new().to_string();
+        store
+            .metadata
+            .mark_as_uploading(
+                other,
+                &in_progress,
+                "hash",
+                &req_type,
+                BuildIdType::Gnu,
+                UploadStrategy::Grpc,
+                Utc::now(),
+            )
+            .unwrap();
+        put(in_progress.clone()).await;
+
+        let finished = MarkUploadFinishedRequest {
+            build_id: BUILD_ID.into(),
+            upload_id: abandoned.clone(),
+            ..Default::default()
+        };
+        let status = store
+            .mark_upload_finished(Request::new(finished))
+            .await
+            .unwrap_err();
+        assert!(status.message().contains("expired"), "{}", status);
+
+        put(abandoned.clone()).await;
+        assert_eq!(store.expire_uploads().await.unwrap(), 1);
+        assert!(store.metadata.fetch(BUILD_ID, &req_type).is_none());
+        assert!(store.metadata.fetch(other, &req_type).is_some());
+        let exists = |upload_id: String| {
+            let bucket = Arc::clone(&store.bucket);
+            async move {
+                bucket
+                    .head(&object_store::path::Path::from(upload_id))
+                    .await
+                    .is_ok()
+            }
+        };
+        assert!(!exists(abandoned).await);
+        assert!(exists(in_progress.clone()).await);
+
+        // An old object that isn't tracked is left alone, even without
+        // metadata, while a tracked upload whose metadata was evicted is
+        // deleted once it is stale.
+        let untracked = ulid::Ulid::from_datetime(long_ago.into()).to_string();
+        put(untracked.clone()).await;
+        let evicted = ulid::Ulid::new().to_string();
+        store
+            .uploads
+            .track(&*store.bucket, &evicted, other, req_type, long_ago)
+            .await;
+        put(evicted.clone()).await;
+        assert_eq!(store.expire_uploads().await.unwrap(), 1);
+        assert!(exists(untracked.clone()).await);
+        assert!(!exists(evicted).await);
+        assert!(exists(in_progress.clone()).await);
+
+        // Uploads abandoned before a restart are deleted after it.
+        let restarted = ulid::Ulid::new().to_string();
+        store
+            .uploads
+            .track(&*store.bucket, &restarted, other, req_type, long_ago)
+            .await;
+        put(restarted.clone()).await;
+        let store = DebuginfoStore {
+            bucket: Arc::clone(&store.bucket),
+            ..self::store(1 << 30)
+        };
+        assert_eq!(store.uploads.load(&*store.bucket).await.unwrap(), 1);
+        assert_eq!(store.expire_uploads().await.unwrap(), 1);
+        assert!(!exists(restarted).await);
+        assert!(exists(untracked).await);
+        assert!(exists(in_progress).await);
+    }
+}
@@ -0,0 +1,372 @@
+use super::{fetcher::DWP, DebugInfod, MetadataStore, SourceStore};
+use crate::debuginfopb::{debuginfo::Source, debuginfo_upload::State, DebuginfoType};
+use axum::{
+    body::Body,
+    extract::{Path, State as AxumState},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use object_store::ObjectStore;
+use std::sync::Arc;
+use tonic::Status;
+
+/// DebuginfodServer serves the debuginfo in the store over the debuginfod
+/// protocol, so that gdb, perf and other debuginfod clients can use
+/// evprofiler as their symbol server. What wasn't uploaded is proxied to the
+/// upstream debuginfod servers.
+#[derive(Debug)]
+pub struct DebuginfodServer {
+    metadata: MetadataStore,
+    debuginfod: DebugInfod,
+    bucket: Arc<dyn ObjectStore>,
+    sources: SourceStore,
+}
+
+impl DebuginfodServer {
+    pub fn new(
+        metadata: MetadataStore,
+        debuginfod: DebugInfod,
+        bucket: Arc<dyn ObjectStore>,
+    ) -> Self {
+        let sources = SourceStore::new(
+            MetadataStore::with_store(metadata.store.clone()),
+            Arc::clone(&bucket),
+        );
+        Self {
+            metadata,
+            debuginfod,
+            bucket,
+            sources,
+        }
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/buildid/:build_id/debuginfo", get(debuginfo))
+            .route("/buildid/:build_id/executable", get(executable))
+            .route("/buildid/:build_id/dwp", get(dwp))
+            .route("/buildid/:build_id/source/*path", get(source))
+            .with_state(Arc::new(self))
+    }
+
+    /// Serves the uploaded file of the given type, or proxies the artifact
+    /// from the debuginfod servers if there is none.
+    async fn artifact(
+        &self,
+        build_id: &str,
+        req_type: DebuginfoType,
+        artifact: &str,
+    ) -> anyhow::Result<object_store::GetResult> {
+        let recorded = match self.metadata.fetch(build_id, &req_type) {
+            Some(dbginfo) => {
+                let valid = dbginfo.quality.is_none_or(|q| !q.not_valid_elf);
+                match (dbginfo.source(), dbginfo.upload) {
+                    (Source::Upload, Some(upload))
+                        if upload.state() == State::Uploaded && valid =>
+                    {
+                        let path = object_store::path::Path::from(upload.id.as_str());
+                        return Ok(self.bucket.get(&path).await?);
+                    }
+                    _ => dbginfo.debuginfod_servers,
+                }
+            }
+            None => vec![],
+        };
+
+        let servers = self.debuginfod.servers_for(&recorded);
+        self.debuginfod
+            .get_stream(
+                &servers,
+                build_id,
+                self.metadata.build_id_type(build_id),
+                artifact,
+            )
+            .await
+    }
+}
+
+async fn debuginfo(
+    AxumState(server): AxumState<Arc<DebuginfodServer>>,
+    Path(build_id): Path<String>,
+) -> Response {
+    if !is_valid_build_id(&build_id) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    respond(
+        &build_id,
+        server
+            .artifact(&build_id, DebuginfoType::DebuginfoUnspecified, "debuginfo")
+            .await,
+    )
+}
+
+async fn executable(
+    AxumState(server): AxumState<Arc<DebuginfodServer>>,
+    Path(build_id): Path<String>,
+) -> Response {
+    if !is_valid_build_id(&build_id) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    respond(
+        &build_id,
+        server
+            .artifact(&build_id, DebuginfoType::Executable, "executable")
+            .await,
+    )
+}
+
+/// DWARF packages aren't part of the debuginfod protocol, they are served so
+/// that other evprofiler instances can symbolize split DWARF with them.
+async fn dwp(
+    AxumState(server): AxumState<Arc<DebuginfodServer>>,
+    Path(build_id): Path<String>,
+) -> Response {
+    if !is_valid_build_id(&build_id) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    respond(
+        &build_id,
+        server
+            .artifact(&build_id, DebuginfoType::DwarfPackage, DWP)
+            .await,
+    )
+}
+
+/// Sources are served from the source archive uploaded for the build ID, and
+/// proxied if there is none or it doesn't contain the file.
+async fn source(
+    AxumState(server): AxumState<Arc<DebuginfodServer>>,
+    Path((build_id, path)): Path<(String, String)>,
+) -> Response {
+    if !is_valid_build_id(&build_id) || !is_valid_source_path(&path) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    match server.sources.file(&build_id, &path).await {
+        Ok(Some(file)) => {
+            return ([(header::CONTENT_TYPE, "application/octet-stream")], file).into_response()
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to read the sources of {}: {:#}", build_id, e),
+    }
+
+    let servers = server.debuginfod.servers_for(&[]);
+    let res = server
+        .debuginfod
+        .get_stream(
+            &servers,
+            &build_id,
+            server.metadata.build_id_type(&build_id),
+            &format!("source/{}", path),
+        )
+        .await;
+    respond(&build_id, res)
+}
+
+fn respond(build_id: &str, res: anyhow::Result<object_store::GetResult>) -> Response {
+    match res {
+        Ok(res) => (
+            [
+                (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                (header::CONTENT_LENGTH, res.meta.size.to_string()),
+            ],
+            Body::from_stream(res.into_stream()),
+        )
+            .into_response(),
+        Err(e) if is_not_found(&e) => {
+            log::debug!("Debuginfod request for {} not found: {:#}", build_id, e);
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            log::warn!("Debuginfod request for {} failed: {:#}", build_id, e);
+            StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+/// Build IDs end up in object paths, so only hex digits are accepted, like
+/// debuginfod itself does.
+fn is_valid_build_id(build_id: &str) -> bool {
+    !build_id.is_empty() && build_id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Source paths end up in object paths and upstream URLs, where dot segments
+/// would escape the source tree of the build ID, so they are rejected.
+fn is_valid_source_path(path: &str) -> bool {
+    !path.is_empty()
+        && path
+            .split('/')
+            .all(|segment| segment != "." && segment != "..")
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    if let Some(status) = e.downcast_ref::<Status>() {
+        return status.code() == tonic::Code::NotFound;
+    }
+    if let Some(object_store::Error::NotFound { .. }) = e.downcast_ref::<object_store::Error>() {
+        return true;
+    }
+    super::debuginfod::is_not_found(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        debuginfo_store::{debuginfod::tests::serve, sources::tests::archive},
+        debuginfopb::{upload_instructions::UploadStrategy, BuildIdType},
+        storage,
+    };
+    use chrono::Utc;
+
+    const BUILD_ID: &str = "126cf12e76810726c76d87ab8a6a57e9a1d4c815";
+
+    async fn start(server: DebuginfodServer) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, server.router()).await });
+        format!("http://{}", addr)
+    }
+
+    async fn get(url: String) -> (u16, Vec<u8>) {
+        tokio::task::spawn_blocking(move || match ureq::get(&url).call() {
+            Ok(response) => {
+                let mut body = vec![];
+                response.into_reader().read_to_end(&mut body).unwrap();
+                (200, body)
+            }
+            Err(ureq::Error::Status(status, _)) => (status, vec![]),
+            Err(e) => panic!("{}", e),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serve_debuginfod() {
+        let (upstream, requests) = serve(|_, path, _| match path {
+            "/buildid/126cf12e76810726c76d87ab8a6a57e9a1d4c815/debuginfo" => {
+                (200, b"upstream debuginfo".to_vec())
+            }
+            "/buildid/126cf12e76810726c76d87ab8a6a57e9a1d4c815/source/usr/src/main.c" => {
+                (200, b"int main() {}".to_vec())
+            }
+            _ => (404, vec![]),
+        });
+
+        let metadata = MetadataStore::new();
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let upload_id = ulid::Ulid::new().to_string();
+        metadata
+            .mark_as_uploading(
+                BUILD_ID,
+                &upload_id,
+                "hash",
+                &DebuginfoType::Executable,
+                BuildIdType::Gnu,
+                UploadStrategy::Grpc,
+                Utc::now(),
+            )
+            .unwrap();
+        bucket
+            .put(
+                &object_store::path::Path::from(upload_id.as_str()),
+                b"uploaded executable".to_vec().into(),
+            )
+            .await
+            .unwrap();
+        metadata
+            .mark_as_uploaded(BUILD_ID, &upload_id, &DebuginfoType::Executable, Utc::now())
+            .unwrap();
+
+        let sources_id = ulid::Ulid::new().to_string();
+        metadata
+            .mark_as_uploading(
+                BUILD_ID,
+                &sources_id,
+                "hash",
+                &DebuginfoType::Sources,
+                BuildIdType::Gnu,
+                UploadStrategy::Grpc,
+                Utc::now(),
+            )
+            .unwrap();
+        bucket
+            .put(
+                &object_store::path::Path::from(sources_id.as_str()),
+                archive(&[("proj/src/util.c", "void util() {}")]).into(),
+            )
+            .await
+            .unwrap();
+        metadata
+            .mark_as_uploaded(BUILD_ID, &sources_id, &DebuginfoType::Sources, Utc::now())
+            .unwrap();
+
+        let url = start(DebuginfodServer::new(
+            metadata,
+            DebugInfod::default().with_upstream_servers(vec![upstream]),
+            bucket,
+        ))
+        .await;
+
+        // Uploaded files are served from the bucket.
+        assert_eq!(
+            get(format!("{}/buildid/{}/executable", url, BUILD_ID)).await,
+            (200, b"uploaded executable".to_vec())
+        );
+        // Everything else is proxied.
+        assert_eq!(
+            get(format!("{}/buildid/{}/debuginfo", url, BUILD_ID)).await,
+            (200, b"upstream debuginfo".to_vec())
+        );
+        assert_eq!(
+            get(format!(
+                "{}/buildid/{}/source/usr/src/main.c",
+                url, BUILD_ID
+            ))
+            .await,
+            (200, b"int main() {}".to_vec())
+        );
+        // Sources that were uploaded are served from their archive.
+        assert_eq!(
+            get(format!(
+                "{}/buildid/{}/source/build/proj/src/util.c",
+                url, BUILD_ID
+            ))
+            .await,
+            (200, b"void util() {}".to_vec())
+        );
+        // Upstream isn't asked for build IDs that aren't known to be GNU build
+        // IDs.
+        assert_eq!(
+            get(format!("{}/buildid/{}/debuginfo", url, "abcdef"))
+                .await
+                .0,
+            404
+        );
+        assert_eq!(
+            get(format!("{}/buildid/{}/debuginfo", url, "not-hex"))
+                .await
+                .0,
+            400
+        );
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_source_path_dot_segments() {
+        assert!(is_valid_source_path("usr/src/main.c"));
+        assert!(is_valid_source_path("usr/src/.config"));
+        assert!(is_valid_source_path("usr/src/..c"));
+        assert!(!is_valid_source_path(""));
+        assert!(!is_valid_source_path("../debuginfo"));
+        assert!(!is_valid_source_path("usr/../../executable"));
+        assert!(!is_valid_source_path("usr/./src/main.c"));
+        assert!(!is_valid_source_path("usr/src/.."));
+    }
+}
@@ -0,0 +1,29 @@
+use object_store::path::Path;
+use std::{fmt::Debug, time::Duration};
+use tonic::async_trait;
+use url::Url;
+
+/// UploadSigner hands out presigned URLs that agents upload debuginfo to
+/// directly, so that it doesn't have to pass through the server.
+#[async_trait]
+pub trait UploadSigner: Send + Sync + Debug {
+    /// Returns a URL that the object at path can be PUT to until it expires.
+    async fn signed_put_url(&self, path: &Path, expires_in: Duration) -> anyhow::Result<Url>;
+}
+
+/// ObjectStoreSigner signs URLs with the credentials of an object store that
+/// supports it, like S3 or GCS.
+#[cfg(feature = "cloud")]
+#[derive(Debug)]
+pub struct ObjectStoreSigner(pub std::sync::Arc<dyn object_store::signer::Signer>);
+
+#[cfg(feature = "cloud")]
+#[async_trait]
+impl UploadSigner for ObjectStoreSigner {
+    async fn signed_put_url(&self, path: &Path, expires_in: Duration) -> anyhow::Result<Url> {
+        Ok(self
+            .0
+            .signed_url(axum::http::Method::PUT, path, expires_in)
+            .await?)
+    }
+}
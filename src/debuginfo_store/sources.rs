@@ -0,0 +1,223 @@
+use super::MetadataStore;
+use crate::debuginfopb::{debuginfo::Source, debuginfo_upload::State, DebuginfoType};
+use anyhow::{bail, Context};
+use moka::sync::Cache;
+use object_store::ObjectStore;
+use std::{
+    collections::BTreeMap,
+    io::{BufReader, Read},
+    ops::Range,
+    sync::Arc,
+};
+
+/// Archives are kept unpacked in memory, up to this many bytes in total.
+const ARCHIVE_CACHE_SIZE: u64 = 256 << 20;
+
+/// Archives that unpack to more than this are rejected, so that a small
+/// compressed upload can't take up an unbounded amount of memory.
+const MAX_UNPACKED_SIZE: u64 = 128 << 20;
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// SourceArchive is an unpacked source archive uploaded for a build ID, with
+/// its files indexed by path.
+#[derive(Debug)]
+pub struct SourceArchive {
+    data: Vec<u8>,
+    files: BTreeMap<String, Range<usize>>,
+}
+
+impl SourceArchive {
+    /// Unpacks a tar archive, which may be compressed with zstd or gzip.
+    pub fn parse(archive: &[u8]) -> anyhow::Result<Self> {
+        let reader: Box<dyn Read + '_> = if archive.starts_with(ZSTD_MAGIC) {
+            Box::new(zstd::Decoder::new(archive)?)
+        } else if archive.starts_with(GZIP_MAGIC) {
+            Box::new(flate2::read::GzDecoder::new(archive))
+        } else {
+            Box::new(archive)
+        };
+        let mut data = Vec::new();
+        reader.take(MAX_UNPACKED_SIZE + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > MAX_UNPACKED_SIZE {
+            bail!(
+                "Source archive unpacks to more than {} bytes",
+                MAX_UNPACKED_SIZE
+            );
+        }
+
+        let mut files = BTreeMap::new();
+        let mut tar = tar::Archive::new(BufReader::new(data.as_slice()));
+        for entry in tar.entries().context("Not a tar archive")? {
+            let entry = entry.context("Not a tar archive")?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?.to_string_lossy().into_owned();
+            let start = entry.raw_file_position() as usize;
+            let end = start + entry.size() as usize;
+            if end > data.len() {
+                bail!("Tar archive is truncated at {}", path);
+            }
+            files.insert(normalize(&path).to_string(), start..end);
+        }
+        if files.is_empty() {
+            bail!("Source archive contains no files");
+        }
+
+        Ok(Self { data, files })
+    }
+
+    /// Returns the file the path refers to. The paths in DWARF are usually
+    /// absolute, the compilation directory of the build joined with the path
+    /// given to the compiler, while archives are made relative to some
+    /// directory of the checkout. So the file that shares the most trailing
+    /// path components with the path is returned, as long as all components
+    /// of the shorter of the two match.
+    pub fn file(&self, path: &str) -> Option<&[u8]> {
+        let path = normalize(path);
+        if let Some(range) = self.files.get(path) {
+            return Some(&self.data[range.clone()]);
+        }
+
+        let components: Vec<&str> = path.rsplit('/').collect();
+        let mut best: Option<(usize, &Range<usize>)> = None;
+        for (entry, range) in self.files.iter() {
+            let entry_components = entry.rsplit('/').count();
+            let matching = entry
+                .rsplit('/')
+                .zip(components.iter())
+                .take_while(|(a, b)| a == *b)
+                .count();
+            if matching == 0 || matching < entry_components.min(components.len()) {
+                continue;
+            }
+            if best.is_none_or(|(m, _)| matching > m) {
+                best = Some((matching, range));
+            }
+        }
+        best.map(|(_, range)| &self.data[range.clone()])
+    }
+}
+
+fn normalize(path: &str) -> &str {
+    let path = path.trim_start_matches("./");
+    path.trim_start_matches('/')
+}
+
+/// SourceStore serves the files of the source archives uploaded as
+/// DebuginfoType::Sources.
+#[derive(Debug, Clone)]
+pub struct SourceStore {
+    metadata: Arc<MetadataStore>,
+    bucket: Arc<dyn ObjectStore>,
+    /// Unpacked archives by upload ID, so that a new upload for the build ID
+    /// is never served from a stale archive.
+    archives: Cache<String, Arc<SourceArchive>>,
+}
+
+impl SourceStore {
+    pub fn new(metadata: MetadataStore, bucket: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            metadata: Arc::new(metadata),
+            bucket,
+            archives: Cache::builder()
+                .max_capacity(ARCHIVE_CACHE_SIZE)
+                .weigher(|_, archive: &Arc<SourceArchive>| {
+                    archive.data.len().try_into().unwrap_or(u32::MAX)
+                })
+                .build(),
+        }
+    }
+
+    /// Returns the source archive of the build ID, or None if no archive was
+    /// uploaded for it.
+    pub async fn archive(&self, build_id: &str) -> anyhow::Result<Option<Arc<SourceArchive>>> {
+        let upload = match self.metadata.fetch(build_id, &DebuginfoType::Sources) {
+            Some(dbginfo) if dbginfo.source() == Source::Upload => match dbginfo.upload {
+                Some(upload) if upload.state() == State::Uploaded => upload,
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+
+        if let Some(archive) = self.archives.get(&upload.id) {
+            return Ok(Some(archive));
+        }
+
+        let path = object_store::path::Path::from(upload.id.as_str());
+        let data = self.bucket.get(&path).await?.bytes().await?;
+        let archive = tokio::task::spawn_blocking(move || SourceArchive::parse(&data))
+            .await?
+            .with_context(|| format!("Failed to read the source archive of {}", build_id))?;
+        let archive = Arc::new(archive);
+        self.archives.insert(upload.id, Arc::clone(&archive));
+        Ok(Some(archive))
+    }
+
+    /// Returns the source file of the build ID at the path, as recorded in
+    /// its DWARF.
+    pub async fn file(&self, build_id: &str, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self
+            .archive(build_id)
+            .await?
+            .and_then(|archive| archive.file(path).map(<[u8]>::to_vec)))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds a tar archive of the files, compressed with zstd.
+    pub(crate) fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        zstd::encode_all(builder.into_inner().unwrap().as_slice(), 0).unwrap()
+    }
+
+    #[test]
+    fn test_source_archive() {
+        let archive = SourceArchive::parse(&archive(&[
+            ("proj/src/main.c", "int main() {}"),
+            ("proj/src/util.c", "void util() {}"),
+            ("proj/include/util.h", "void util();"),
+            ("vendor/lib/util.c", "void lib_util() {}"),
+        ]))
+        .unwrap();
+
+        assert_eq!(archive.files.len(), 4);
+        assert_eq!(archive.file("proj/src/main.c"), Some(&b"int main() {}"[..]));
+        // Absolute paths are mapped from the compilation directory.
+        assert_eq!(
+            archive.file("/home/ci/build/proj/src/main.c"),
+            Some(&b"int main() {}"[..])
+        );
+        // Relative paths are mapped to the deeper archive entry.
+        assert_eq!(archive.file("src/util.c"), Some(&b"void util() {}"[..]));
+        assert_eq!(
+            archive.file("/build/vendor/lib/util.c"),
+            Some(&b"void lib_util() {}"[..])
+        );
+        assert_eq!(archive.file("/build/other/main.c"), None);
+        assert_eq!(archive.file("/usr/include/stdio.h"), None);
+
+        assert!(SourceArchive::parse(b"not an archive").is_err());
+    }
+
+    #[test]
+    fn test_source_archive_too_large() {
+        let content = "0".repeat(MAX_UNPACKED_SIZE as usize);
+        let err = SourceArchive::parse(&archive(&[("big.c", &content)])).unwrap_err();
+        assert!(err.to_string().contains("unpacks to more than"), "{}", err);
+    }
+}
@@ -1,33 +0,0 @@
-use arrow2::{
-    error::{Error, Result},
-    io::parquet::write::{CompressedPage, FallibleStreamingIterator},
-};
-use std::collections::VecDeque;
-
-pub struct Bla {
-    columns: VecDeque<CompressedPage>,
-    current: Option<CompressedPage>,
-}
-
-impl Bla {
-    pub fn new(columns: VecDeque<CompressedPage>) -> Self {
-        Self {
-            columns,
-            current: None,
-        }
-    }
-}
-
-impl FallibleStreamingIterator for Bla {
-    type Item = CompressedPage;
-    type Error = Error;
-
-    fn advance(&mut self) -> Result<()> {
-        self.current = self.columns.pop_front();
-        Ok(())
-    }
-
-    fn get(&self) -> Option<&Self::Item> {
-        self.current.as_ref()
-    }
-}
@@ -1,166 +1,200 @@
-mod bla;
-
-use anyhow::bail;
-use arrow2::{
-    array::Array,
-    chunk::Chunk as Achunk,
-    datatypes::{DataType, PhysicalType},
-    error::Result,
-    io::parquet::{read::ParquetError, write::*},
-};
-use bla::Bla;
+use anyhow::Context;
 use chrono::Utc;
+use datafusion::{
+    arrow::array::RecordBatch,
+    parquet::{
+        arrow::{async_writer::ParquetObjectWriter, AsyncArrowWriter},
+        basic::Compression,
+        file::properties::{WriterProperties, WriterVersion},
+    },
+};
 use object_store::{path::Path, ObjectStore};
-use rayon::prelude::*;
-use std::{
-    collections::VecDeque,
-    sync::{Arc, Mutex},
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
 };
 
 use crate::profile::schema;
+use crate::stacktrace_store::StacktraceStore;
 
-type Chunk = Achunk<Arc<dyn Array>>;
+/// BufferFull is returned when ingesting a record would exceed the memory
+/// budget of the ingester. Callers are expected to back off and retry.
+#[derive(Debug)]
+pub struct BufferFull {
+    pub buffered: usize,
+    pub budget: usize,
+}
+
+impl std::fmt::Display for BufferFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Ingestion buffer is full: {} of {} bytes in use",
+            self.buffered, self.budget
+        )
+    }
+}
+
+impl std::error::Error for BufferFull {}
 
 #[derive(Debug)]
 pub struct Ingester {
-    chunks: Mutex<Vec<Chunk>>,
+    records: Mutex<Vec<RecordBatch>>,
     max_size: usize,
+    /// Bytes held by records that are buffered or being persisted.
+    buffered_bytes: Arc<AtomicUsize>,
+    max_buffered_bytes: usize,
     storage: Arc<dyn ObjectStore>,
+    stacktraces: Arc<StacktraceStore>,
 }
 
 impl Ingester {
-    pub fn new(max_size: usize, storage: Arc<dyn ObjectStore>) -> Self {
+    pub fn new(
+        max_size: usize,
+        max_buffered_bytes: usize,
+        storage: Arc<dyn ObjectStore>,
+        stacktraces: Arc<StacktraceStore>,
+    ) -> Self {
         Self {
-            chunks: vec![].into(),
+            records: vec![].into(),
             max_size,
+            buffered_bytes: Arc::new(AtomicUsize::new(0)),
+            max_buffered_bytes,
             storage,
+            stacktraces,
         }
     }
 
-    pub async fn ingest(&self, chunk: Achunk<Arc<dyn Array>>) -> anyhow::Result<()> {
-        let mut chunks = self.chunks.lock().unwrap();
-        chunks.push(chunk);
+    /// Returns an error if the memory budget is used up. Callers can use this
+    /// to reject writes before doing the work of building a record.
+    pub fn check_capacity(&self) -> Result<(), BufferFull> {
+        let buffered = self.buffered_bytes.load(Ordering::Relaxed);
+        if buffered >= self.max_buffered_bytes {
+            return Err(BufferFull {
+                buffered,
+                budget: self.max_buffered_bytes,
+            });
+        }
+        Ok(())
+    }
 
-        let is_full = chunks.len() >= self.max_size;
+    pub async fn ingest(&self, record: RecordBatch) -> anyhow::Result<()> {
+        let size = record.get_array_memory_size();
+        self.buffered_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |buffered| {
+                (buffered + size <= self.max_buffered_bytes).then_some(buffered + size)
+            })
+            .map_err(|buffered| BufferFull {
+                buffered,
+                budget: self.max_buffered_bytes,
+            })?;
+
+        let full = {
+            let mut records = self.records.lock().unwrap();
+            records.push(record);
+
+            if records.len() >= self.max_size {
+                Some(std::mem::take(&mut *records))
+            } else {
+                None
+            }
+        };
 
-        log::info!("Ingested a chunk");
+        log::info!("Ingested a record");
 
-        if is_full {
-            let c = chunks.clone();
-            chunks.clear();
+        if let Some(records) = full {
             let s = Arc::clone(&self.storage);
-            tokio::spawn(Self::persist(c, s));
+            let st = Arc::clone(&self.stacktraces);
+            let buffered_bytes = Arc::clone(&self.buffered_bytes);
+            tokio::spawn(async move {
+                let size: usize = records.iter().map(|r| r.get_array_memory_size()).sum();
+                if let Err(e) = Self::persist(records, s, st).await {
+                    log::error!("Failed to persist records: {:#}", e);
+                }
+                buffered_bytes.fetch_sub(size, Ordering::SeqCst);
+            });
         }
 
         Ok(())
     }
 
-    async fn persist(chunks: Vec<Chunk>, storage: Arc<dyn ObjectStore>) -> anyhow::Result<()> {
-        log::info!("Chunks max_size met. Trying to persist.");
-        let schema = schema::create_schema();
-        let options = WriteOptions {
-            write_statistics: true,
-            compression: CompressionOptions::Snappy,
-            version: Version::V2,
-            data_pagesize_limit: None,
-        };
+    async fn persist(
+        records: Vec<RecordBatch>,
+        storage: Arc<dyn ObjectStore>,
+        stacktraces: Arc<StacktraceStore>,
+    ) -> anyhow::Result<()> {
+        log::info!("Records max_size met. Trying to persist.");
 
-        let encoding_map = |data_type: &DataType| match data_type.to_physical_type() {
-            PhysicalType::Dictionary(_) => Encoding::RleDictionary,
-            _ => Encoding::Plain,
-        };
+        // The samples reference stacktraces by ID only, so the dictionary
+        // entries have to be durable before the samples are.
+        stacktraces
+            .flush()
+            .await
+            .context("Failed to persist stacktraces")?;
 
-        let encodings = (&schema.fields)
-            .iter()
-            .map(|f| transverse(&f.data_type, encoding_map))
-            .collect::<Vec<_>>();
-
-        let parquet_schema = to_parquet_schema(&schema)
-            .map_err(|e| anyhow::anyhow!("Failed to create Parquet schema: {}", e))?;
-
-        log::info!("Did I come here --just after creating parquet schema--??");
-
-        let row_groups = chunks.iter().map(|chunk| {
-            let columns = chunk
-                .columns()
-                .par_iter()
-                .zip(parquet_schema.fields().to_vec())
-                .zip(encodings.par_iter())
-                .flat_map(move |((array, type_), encoding)| {
-                    let encoded_columns =
-                        array_to_columns(array, type_, options, encoding).unwrap();
-                    encoded_columns
-                        .into_iter()
-                        .map(|encoded_pages| {
-                            let encoded_pages = DynIter::new(encoded_pages.into_iter().map(|x| {
-                                x.map_err(|e| ParquetError::InvalidParameter(e.to_string()))
-                            }));
-                            encoded_pages
-                                .map(|page| {
-                                    compress(page?, vec![], options.compression)
-                                        .map_err(|x| x.into())
-                                })
-                                .collect::<Result<VecDeque<_>>>()
-                        })
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Result<Vec<VecDeque<CompressedPage>>>>()?;
-
-            let row_group = DynIter::new(
-                columns
-                    .into_iter()
-                    .map(|column| Result::Ok(DynStreamingIterator::new(Bla::new(column)))),
-            );
-            Result::Ok(row_group)
-        });
-
-        log::info!("row_groups: {:?}", row_groups.len());
-        let mut buf: Vec<u8> = vec![];
-        let mut writer = match FileWriter::try_new(&mut buf, schema, options) {
-            Ok(fw) => fw,
-            Err(e) => {
-                log::error!("{}", e);
-                bail!("{}", e)
-            }
-        };
-
-        for group in row_groups {
-            let group = match group {
-                Ok(g) => g,
-                Err(e) => {
-                    log::error!("{}", e);
-                    bail!("{}", e)
-                }
-            };
-            match writer.write(group) {
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!("{}", e);
-                }
-            };
-        }
-        let _size = match writer.end(None) {
-            Ok(_) => {}
-            Err(e) => {
-                log::error!("{}", e);
-            }
-        };
-
-        log::info!("buf::: {:#?}", buf.len());
         let current_date = chrono::Local::now().date_naive();
         let timestamp = Utc::now().timestamp();
 
-        let p = Path::parse(&format!(
+        let p = Path::parse(format!(
             "date={}/{}.parquet",
-            current_date.format("%Y-%m-%d").to_string(),
+            current_date.format("%Y-%m-%d"),
             timestamp
         ))?;
 
-        match storage.put(&p, buf.into()).await {
-            Ok(_) => {}
-            Err(e) => log::error!("{}", e),
-        };
-        log::info!("Persisted the parquet chunks to {}", p);
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_writer_version(WriterVersion::PARQUET_2_0)
+            .build();
+
+        // ParquetObjectWriter uploads in parts as row groups are flushed, so
+        // only the row group being encoded is held in memory.
+        let writer = ParquetObjectWriter::new(storage, p.clone());
+        let mut writer =
+            AsyncArrowWriter::try_new(writer, Arc::new(schema::create_schema()), Some(props))?;
+
+        for record in records.iter() {
+            writer.write(record).await?;
+            // One row group per record, as every record is a separate write.
+            writer.flush().await?;
+        }
+        writer.close().await?;
+
+        log::info!("Persisted {} records to {}", records.len(), p);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage;
+    use datafusion::arrow::{
+        array::Int64Array,
+        datatypes::{DataType, Field, Schema},
+    };
+
+    fn record(len: usize) -> RecordBatch {
+        let schema = Schema::new(vec![Field::new("value", DataType::Int64, false)]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![Arc::new(Int64Array::from(vec![0; len]))],
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ingest_rejects_over_budget() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let stacktraces = Arc::new(StacktraceStore::new(Arc::clone(&bucket)));
+        let budget = record(1024).get_array_memory_size() * 2;
+        let ingester = Ingester::new(100, budget, bucket, stacktraces);
+
+        ingester.ingest(record(1024)).await.unwrap();
+        ingester.ingest(record(1024)).await.unwrap();
+        assert!(ingester.check_capacity().is_err());
+
+        let err = ingester.ingest(record(1024)).await.unwrap_err();
+        assert!(err.downcast_ref::<BufferFull>().is_some());
+        assert_eq!(ingester.records.lock().unwrap().len(), 2);
+    }
+}
@@ -7,10 +7,12 @@ use profilestorepb::{
     agents_service_server::AgentsServiceServer,
     profile_store_service_server::ProfileStoreServiceServer,
 };
-use std::sync::Arc;
+use stacktrace_store::StacktraceStore;
+use std::{sync::Arc, time::Duration};
 use tonic::{codec::CompressionEncoding, transport::Server};
 
 mod agent_store;
+mod cli;
 mod columnquery;
 mod dal;
 mod debuginfo_store;
@@ -19,6 +21,7 @@ mod normalizer;
 mod profile;
 mod profile_store;
 mod schema_builder;
+mod stacktrace_store;
 mod storage;
 mod symbolizer;
 mod symbols;
@@ -39,13 +42,70 @@ pub(crate) mod debuginfopb {
     tonic::include_proto!("parca.debuginfo.v1alpha1");
 }
 
+/// Maximum size of a single (decompressed) ProfileStoreService message.
+const MAX_PROFILE_STORE_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Memory budget for records buffered in or being persisted by the ingester.
+const MAX_INGESTER_BUFFERED_BYTES: usize = 512 << 20;
+
+/// Directory with kallsyms snapshots of kernels that agents can't upload
+/// them for, laid out as `<kernel_release>/kallsyms`.
+const KALLSYMS_DIR: &str = "evprofiler-data/kallsyms";
+
+/// Directory of the on-disk symbolization cache, so that restarts don't have
+/// to symbolize everything from scratch.
+const SYMBOLIZER_CACHE_DIR: &str = "evprofiler-data/symbolizer-cache";
+
+/// How often newly ingested locations are symbolized in the background. Set
+/// to None to only symbolize at query time.
+const EAGER_SYMBOLIZATION_INTERVAL: Option<Duration> = Some(Duration::from_secs(30));
+
+/// How long a debuginfod server that didn't have a build ID isn't asked for
+/// it again.
+const DEBUGINFOD_NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Directory artifacts downloaded from debuginfod servers are cached in, and
+/// how large it may grow and how long they are kept for.
+const DEBUGINFOD_CACHE_DIR: &str = "evprofiler-data/debuginfod-cache";
+const DEBUGINFOD_CACHE_SIZE: u64 = 16 << 30;
+const DEBUGINFOD_CACHE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Where the debuginfod protocol is served, for gdb, perf and other
+/// debuginfod clients, along with write metrics.
+const HTTP_ADDR: &str = "[::1]:3334";
+
+/// Where the debuginfo admin API is served, unless overridden by
+/// ADMIN_ADDR_ENV. It isn't authenticated, so it listens on its own address,
+/// which should only be reachable by operators.
+const ADMIN_ADDR: &str = "[::1]:3335";
+const ADMIN_ADDR_ENV: &str = "EVPROFILER_ADMIN_ADDR";
+
+/// How often uploads that were never finished are cleaned up.
+const UPLOAD_JANITOR_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Names the bucket debuginfo is kept in, eg. s3://bucket. Agents upload to
+/// it directly through signed URLs. Without it, debuginfo is kept in memory
+/// and uploaded through the server.
+const DEBUGINFO_BUCKET_ENV: &str = "EVPROFILER_DEBUGINFO_BUCKET";
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     colog::init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args).await;
+    }
+
     let metadata_store = debuginfo_store::MetadataStore::new();
-    let debuginfod = debuginfo_store::DebugInfod::default();
-    let debuginfod_bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+    std::fs::create_dir_all(DEBUGINFOD_CACHE_DIR)?;
+    let debuginfod = debuginfo_store::DebugInfod::from_env()
+        .with_negative_cache_ttl(DEBUGINFOD_NEGATIVE_CACHE_TTL)
+        .with_bucket(Arc::new(local::LocalFileSystem::new_with_prefix(
+            DEBUGINFOD_CACHE_DIR,
+        )?))
+        .with_cache_limits(DEBUGINFOD_CACHE_SIZE, DEBUGINFOD_CACHE_TTL);
+    let (debuginfod_bucket, signer) = debuginfo_bucket()?;
     let stackrace_bucket: Arc<dyn ObjectStore> = Arc::new(
         match local::LocalFileSystem::new_with_prefix("evprofiler-data") {
             Ok(s) => s,
@@ -55,42 +115,107 @@ async fn main() -> anyhow::Result<()> {
             }
         },
     );
-    let ingester = Arc::new(Ingester::new(10, Arc::clone(&stackrace_bucket)));
-    let symbolizer = Arc::new(symbolizer::Symbolizer::new(
-        debuginfo_store::MetadataStore::with_store(metadata_store.store.clone()),
-        DebuginfoFetcher::new(Arc::clone(&debuginfod_bucket), debuginfod.clone()),
+    let stacktrace_store = Arc::new(StacktraceStore::load(Arc::clone(&stackrace_bucket)).await?);
+    let ingester = Arc::new(Ingester::new(
+        10,
+        MAX_INGESTER_BUFFERED_BYTES,
+        Arc::clone(&stackrace_bucket),
+        Arc::clone(&stacktrace_store),
     ));
+    let symbolizer = Arc::new(
+        symbolizer::Symbolizer::new(
+            debuginfo_store::MetadataStore::with_store(metadata_store.store.clone()),
+            DebuginfoFetcher::new(Arc::clone(&debuginfod_bucket), debuginfod.clone()),
+        )
+        .with_cache(symbolizer::SymbolizerCache::with_config(
+            symbolizer::SymbolizerCacheConfig {
+                capacity: 100_000,
+                dir: Some(SYMBOLIZER_CACHE_DIR.into()),
+                ..Default::default()
+            },
+        )?)
+        .with_kallsyms_dir(KALLSYMS_DIR),
+    );
+    let cache_metrics = symbolizer.cache_metrics();
+    // Whatever was symbolized with debuginfo that an admin deletes or
+    // invalidates is purged, including the lines symbolized after ingestion.
+    let purger: Arc<dyn debuginfo_store::admin::Purger> = match EAGER_SYMBOLIZATION_INTERVAL {
+        Some(interval) => {
+            let eager = Arc::new(symbolizer::EagerSymbolizer::new(
+                Arc::clone(&symbolizer),
+                Arc::clone(&stacktrace_store),
+            ));
+            Arc::clone(&eager).spawn(interval);
+            eager
+        }
+        None => Arc::clone(&symbolizer) as _,
+    };
 
     log::info!("Starting Server");
 
     let addr = "[::1]:3333".parse().unwrap();
 
     log::info!("Attaching ProfileStoreService to the server");
-    let profile_store_impl = profile_store::ProfileStore::new(symbolizer, ingester);
+    let profile_store_impl = profile_store::ProfileStore::new(
+        symbolizer,
+        ingester,
+        stacktrace_store,
+        normalizer::Limits::default(),
+    );
+    let write_metrics = profile_store_impl.metrics();
 
     log::info!("Attaching AgentsService to the server");
     let agent_store_impl = agent_store::AgentStore::default();
 
     log::info!("Attaching DebugInfo to the server");
-    let debug_store_impl = debuginfo_store::DebuginfoStore {
-        metadata: metadata_store,
-        debuginfod,
+    let debug_store_impl = Arc::new(debuginfo_store::DebuginfoStore {
+        metadata: debuginfo_store::MetadataStore::with_store(metadata_store.store.clone()),
+        debuginfod: debuginfod.clone(),
         max_upload_duration: TimeDelta::new(60 * 15, 0).unwrap(),
         max_upload_size: 1000000000,
         bucket: Arc::clone(&debuginfod_bucket),
-    };
+        signer,
+        uploads: Default::default(),
+    });
+    Arc::clone(&debug_store_impl).spawn_janitor(UPLOAD_JANITOR_INTERVAL);
+
+    log::info!("Serving debuginfod and metrics at {}", HTTP_ADDR);
+    let http = debuginfo_store::DebuginfodServer::new(
+        metadata_store,
+        debuginfod,
+        Arc::clone(&debuginfod_bucket),
+    )
+    .router()
+    .route(
+        "/metrics",
+        axum::routing::get(move || async move { write_metrics.encode() + &cache_metrics.encode() }),
+    );
+    tokio::spawn(async move {
+        if let Err(e) = serve_http(http, HTTP_ADDR).await {
+            log::error!("HTTP server failed: {:#}", e);
+        }
+    });
+
+    let admin_addr = std::env::var(ADMIN_ADDR_ENV).unwrap_or_else(|_| ADMIN_ADDR.into());
+    log::info!("Serving the debuginfo admin API at {}", admin_addr);
+    let admin = debuginfo_store::admin::router(Arc::clone(&debug_store_impl), Some(purger));
+    tokio::spawn(async move {
+        if let Err(e) = serve_http(admin, &admin_addr).await {
+            log::error!("Admin HTTP server failed: {:#}", e);
+        }
+    });
 
     log::info!("Starting server at {}", addr);
     Server::builder()
         .add_service(
             ProfileStoreServiceServer::new(profile_store_impl)
                 .accept_compressed(CompressionEncoding::Gzip)
-                .max_decoding_message_size(1000000000)
-                .max_encoding_message_size(1000000000),
+                .max_decoding_message_size(MAX_PROFILE_STORE_MESSAGE_SIZE)
+                .max_encoding_message_size(MAX_PROFILE_STORE_MESSAGE_SIZE),
         )
         .add_service(AgentsServiceServer::new(agent_store_impl))
         .add_service(
-            DebuginfoServiceServer::new(debug_store_impl)
+            DebuginfoServiceServer::from_arc(debug_store_impl)
                 .accept_compressed(CompressionEncoding::Gzip)
                 .max_decoding_message_size(1000000000)
                 .max_encoding_message_size(1000000000),
@@ -100,3 +225,36 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+type UploadSigner = Arc<dyn debuginfo_store::UploadSigner>;
+
+/// Returns the bucket debuginfo is kept in, and the signer of upload URLs
+/// for it if it has one.
+fn debuginfo_bucket() -> anyhow::Result<(Arc<dyn ObjectStore>, Option<UploadSigner>)> {
+    let url = match std::env::var(DEBUGINFO_BUCKET_ENV) {
+        Ok(url) if !url.trim().is_empty() => url,
+        _ => return Ok((Arc::new(storage::new_memory_bucket()), None)),
+    };
+
+    #[cfg(feature = "cloud")]
+    {
+        let (bucket, signer) = storage::new_signing_bucket(url.trim())?;
+        log::info!("Keeping debuginfo in {}", url);
+        Ok((
+            bucket,
+            Some(Arc::new(debuginfo_store::ObjectStoreSigner(signer))),
+        ))
+    }
+    #[cfg(not(feature = "cloud"))]
+    anyhow::bail!(
+        "{} is set to {}, but buckets need the cloud feature",
+        DEBUGINFO_BUCKET_ENV,
+        url
+    )
+}
+
+async fn serve_http(router: axum::Router, addr: &str) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, router).await?;
+    Ok(())
+}
@@ -0,0 +1,324 @@
+use super::{NormalizedProfile, NormalizedSample};
+use moka::{ops::compute::Op, sync::Cache};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use xxhash_rust::xxh3::Xxh3;
+
+const NANOS_PER_MILLI: i64 = 1_000_000;
+
+/// Sample types that Go's runtime/pprof reports as counters accumulated since
+/// process start, rather than as a value over the profiling duration.
+const CUMULATIVE_SAMPLE_TYPES: [&str; 4] = ["alloc_objects", "alloc_space", "contentions", "delay"];
+
+/// Temporality describes how the values of a profile relate to time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Temporality {
+    /// Values were collected over the profile's duration, eg. CPU profiles
+    /// sampled by the agent.
+    Delta,
+    /// Values are counters accumulated since the process started, eg.
+    /// allocations in a Go heap profile.
+    Cumulative,
+    /// Values are a point in time snapshot, eg. memory in use.
+    Instant,
+}
+
+impl Temporality {
+    /// Detects the temporality of a sample type. Profiles that carry a
+    /// duration were collected over a window and are always deltas.
+    pub fn detect(duration_nanos: i64, sample_type: &str) -> Self {
+        if duration_nanos > 0 {
+            Temporality::Delta
+        } else if CUMULATIVE_SAMPLE_TYPES.contains(&sample_type) {
+            Temporality::Cumulative
+        } else {
+            Temporality::Instant
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Snapshot {
+    timestamp: i64,
+    values: HashMap<u64, i64>,
+}
+
+/// CumulativeTracker converts cumulative profiles into delta profiles by
+/// remembering the previous profile of each series.
+#[derive(Debug, Clone)]
+pub struct CumulativeTracker {
+    series: Cache<u64, Arc<Snapshot>>,
+}
+
+impl Default for CumulativeTracker {
+    fn default() -> Self {
+        // A series that hasn't been seen for a while most likely belongs to a
+        // process that is gone. Forgetting it only costs one baseline profile
+        // should it come back.
+        Self::new(100_000, Duration::from_secs(60 * 60))
+    }
+}
+
+impl CumulativeTracker {
+    pub fn new(max_series: u64, time_to_idle: Duration) -> Self {
+        Self {
+            series: Cache::builder()
+                .max_capacity(max_series)
+                .time_to_idle(time_to_idle)
+                .build(),
+        }
+    }
+
+    /// Starts a batch of profiles, typically the ones of a single request.
+    /// The baselines of the batch only replace the tracked ones once the batch
+    /// is committed.
+    pub fn begin(&self) -> CumulativeBatch<'_> {
+        CumulativeBatch {
+            tracker: self,
+            snapshots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn sample_key(sample: &NormalizedSample) -> u64 {
+        let mut hasher = Xxh3::new();
+        for location in sample.locations.iter() {
+            hasher.update(&(location.len() as u64).to_le_bytes());
+            hasher.update(location);
+        }
+
+        let mut labels: Vec<_> = sample.label.iter().collect();
+        labels.sort();
+        for (name, value) in labels {
+            hasher.update(name.as_bytes());
+            hasher.update(&[0]);
+            hasher.update(value.as_bytes());
+            hasher.update(&[0]);
+        }
+
+        let mut num_labels: Vec<_> = sample.num_label.iter().collect();
+        num_labels.sort();
+        for (name, value) in num_labels {
+            hasher.update(name.as_bytes());
+            hasher.update(&[0]);
+            hasher.update(&value.to_le_bytes());
+        }
+
+        hasher.digest()
+    }
+}
+
+/// CumulativeBatch computes deltas against the tracked baselines, but keeps
+/// the new baselines to itself until `commit` is called. Dropping a batch, eg.
+/// because its request couldn't be ingested, leaves the tracker untouched, so
+/// a retry of the request yields the same deltas instead of being dropped as
+/// out of order.
+#[derive(Debug)]
+pub struct CumulativeBatch<'a> {
+    tracker: &'a CumulativeTracker,
+    snapshots: Mutex<HashMap<u64, Arc<Snapshot>>>,
+}
+
+impl CumulativeBatch<'_> {
+    /// Returns the difference between the profile and the previous profile of
+    /// the same series. The first profile of a series only serves as baseline,
+    /// so None is returned for it, as well as for profiles that are older than
+    /// the baseline.
+    pub fn to_delta(
+        &self,
+        series: u64,
+        mut profile: NormalizedProfile,
+    ) -> Option<NormalizedProfile> {
+        // Samples with an identical stack and labels are merged, so every
+        // sample lines up with exactly one value of the previous profile.
+        let mut keys: Vec<u64> = Vec::with_capacity(profile.samples.len());
+        let mut values: HashMap<u64, i64> = HashMap::with_capacity(profile.samples.len());
+        let mut samples = Vec::with_capacity(profile.samples.len());
+        for sample in profile.samples.drain(..) {
+            let key = CumulativeTracker::sample_key(&sample);
+            match values.get_mut(&key) {
+                Some(value) => *value += sample.value,
+                None => {
+                    values.insert(key, sample.value);
+                    keys.push(key);
+                    samples.push(sample);
+                }
+            }
+        }
+        for (sample, key) in samples.iter_mut().zip(keys.iter()) {
+            sample.value = values[key];
+        }
+
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let previous = snapshots
+            .get(&series)
+            .cloned()
+            .or_else(|| self.tracker.series.get(&series));
+        if let Some(previous) = &previous {
+            if profile.meta.timestamp <= previous.timestamp {
+                return None;
+            }
+        }
+
+        snapshots.insert(
+            series,
+            Arc::new(Snapshot {
+                timestamp: profile.meta.timestamp,
+                values,
+            }),
+        );
+        drop(snapshots);
+
+        let previous = previous?;
+
+        // Counters only ever grow, so a decrease means the process restarted
+        // and everything in this profile accumulated since then.
+        let reset = samples
+            .iter()
+            .zip(keys.iter())
+            .any(|(sample, key)| previous.values.get(key).is_some_and(|v| sample.value < *v));
+
+        if !reset {
+            for (sample, key) in samples.iter_mut().zip(keys.iter()) {
+                sample.value -= previous.values.get(key).copied().unwrap_or_default();
+            }
+            samples.retain(|sample| sample.value != 0);
+        }
+
+        profile.samples = samples;
+        profile.meta.duration = (profile.meta.timestamp - previous.timestamp) * NANOS_PER_MILLI;
+        profile.meta.delta = true;
+
+        Some(profile)
+    }
+
+    /// Makes the baselines of the batch the tracked ones. Baselines that were
+    /// replaced by a newer profile in the meantime are left alone.
+    pub fn commit(self) {
+        for (series, snapshot) in self.snapshots.into_inner().unwrap() {
+            self.tracker
+                .series
+                .entry(series)
+                .and_compute_with(|previous| match previous {
+                    Some(previous) if previous.value().timestamp >= snapshot.timestamp => Op::Nop,
+                    _ => Op::Put(snapshot),
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::{Meta, ValueType};
+
+    fn profile(timestamp: i64, values: &[(u8, i64)]) -> NormalizedProfile {
+        let value_type = ValueType {
+            type_: "alloc_space".into(),
+            unit: "bytes".into(),
+        };
+        let meta = Meta {
+            name: "memory".into(),
+            period_type: value_type.clone(),
+            sample_type: value_type,
+            timestamp,
+            duration: 0,
+            period: 0,
+            delta: false,
+        };
+        let samples = values
+            .iter()
+            .map(|(location, value)| NormalizedSample {
+                locations: vec![vec![*location]],
+                value: *value,
+                diff_value: 0,
+                label: HashMap::new(),
+                num_label: HashMap::new(),
+            })
+            .collect();
+
+        NormalizedProfile::new(samples, meta)
+    }
+
+    fn values(profile: &NormalizedProfile) -> Vec<(u8, i64)> {
+        profile
+            .samples
+            .iter()
+            .map(|s| (s.locations[0][0], s.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Temporality::detect(10, "samples"), Temporality::Delta);
+        assert_eq!(Temporality::detect(10, "alloc_space"), Temporality::Delta);
+        assert_eq!(
+            Temporality::detect(0, "alloc_space"),
+            Temporality::Cumulative
+        );
+        assert_eq!(Temporality::detect(0, "inuse_space"), Temporality::Instant);
+    }
+
+    #[test]
+    fn test_to_delta() {
+        let tracker = CumulativeTracker::default();
+
+        let batch = tracker.begin();
+        assert!(batch
+            .to_delta(1, profile(1000, &[(1, 10), (2, 5)]))
+            .is_none());
+        batch.commit();
+
+        let batch = tracker.begin();
+        let delta = batch
+            .to_delta(1, profile(2000, &[(1, 15), (2, 5), (3, 1)]))
+            .unwrap();
+        assert_eq!(values(&delta), vec![(1, 5), (3, 1)]);
+        assert_eq!(delta.meta.duration, 1000 * NANOS_PER_MILLI);
+        assert!(delta.meta.delta);
+
+        // Out of order profiles are dropped.
+        assert!(batch.to_delta(1, profile(1500, &[(1, 12)])).is_none());
+        batch.commit();
+    }
+
+    #[test]
+    fn test_to_delta_reset() {
+        let tracker = CumulativeTracker::default();
+
+        let batch = tracker.begin();
+        assert!(batch.to_delta(1, profile(1000, &[(1, 10)])).is_none());
+
+        let delta = batch.to_delta(1, profile(2000, &[(1, 3)])).unwrap();
+        assert_eq!(values(&delta), vec![(1, 3)]);
+    }
+
+    #[test]
+    fn test_uncommitted_batch() {
+        let tracker = CumulativeTracker::default();
+
+        let batch = tracker.begin();
+        assert!(batch.to_delta(1, profile(1000, &[(1, 10)])).is_none());
+        batch.commit();
+
+        // The first attempt fails to be ingested, so its batch is dropped and
+        // the retry is computed against the same baseline.
+        let batch = tracker.begin();
+        let delta = batch.to_delta(1, profile(2000, &[(1, 15)])).unwrap();
+        assert_eq!(values(&delta), vec![(1, 5)]);
+        drop(batch);
+
+        let batch = tracker.begin();
+        let delta = batch.to_delta(1, profile(2000, &[(1, 15)])).unwrap();
+        assert_eq!(values(&delta), vec![(1, 5)]);
+
+        // A concurrently committed newer baseline isn't replaced.
+        let newer = tracker.begin();
+        assert!(newer.to_delta(1, profile(3000, &[(1, 20)])).is_some());
+        newer.commit();
+        batch.commit();
+
+        let batch = tracker.begin();
+        assert!(batch.to_delta(1, profile(2500, &[(1, 18)])).is_none());
+    }
+}
@@ -1,13 +1,16 @@
+mod cumulative;
 mod profile;
 mod sample;
 mod series;
 mod utils;
 mod write_raw;
 
+pub use cumulative::{CumulativeBatch, CumulativeTracker};
 use profile::NormalizedProfile;
 pub use sample::NormalizedSample;
 pub use series::Series;
-pub use utils::write_raw_request_to_arrow_chunk;
+pub use utils::write_raw_request_to_record_batch;
+pub use write_raw::{InvalidRequest, LimitExceeded, Limits};
 
 pub const POSSIBLE_METADATA_LABELS: [&str; 20] = [
     "pid",
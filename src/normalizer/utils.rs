@@ -1,17 +1,19 @@
+use super::cumulative::{CumulativeBatch, Temporality};
 use super::profile::NormalizedProfile;
-use super::write_raw::NormalizedWriteRawRequest;
+use super::write_raw::{InvalidRequest, Limits, NormalizedWriteRawRequest};
 use super::{NormalizedSample, POSSIBLE_METADATA_LABELS};
 use crate::pprofpb::{Function, Location, Mapping, Profile, Sample};
-use crate::profile::{Meta, PprofLocations, ValueType};
+use crate::profile::{schema, Meta, PprofLocations, ValueType};
 use crate::profilestorepb::{ExecutableInfo, WriteRawRequest};
-use anyhow::bail;
-use arrow2::array::{
-    Array, DictionaryArray, Int64Array, ListArray, MutableArray, MutableBinaryArray,
-    MutableDictionaryArray, MutableListArray, MutablePrimitiveArray, MutableUtf8Array, TryPush,
+use crate::stacktrace_store::StacktraceStore;
+use anyhow::{bail, Context};
+use datafusion::arrow::{
+    array::{ArrayRef, BooleanBuilder, Int64Builder, RecordBatch, StringDictionaryBuilder},
+    datatypes::Int32Type,
 };
-use arrow2::chunk::Chunk;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use xxhash_rust::xxh3::Xxh3;
 
 const NANOS_PER_MILLI: i64 = 1_000_000;
 
@@ -182,6 +184,7 @@ pub fn normalize_pprof(
     name: &str,
     taken_label_names: &HashMap<String, String>,
     p: &Profile,
+    cumulative: &CumulativeBatch<'_>,
 ) -> anyhow::Result<Vec<NormalizedProfile>> {
     let mut profiles: Vec<NormalizedProfile> = Vec::with_capacity(p.sample_type.len());
 
@@ -221,9 +224,50 @@ pub fn normalize_pprof(
         }
     }
 
+    // Cumulative profiles are stored as the delta to the previous profile of
+    // the series, so that all stored profiles of a type can be merged alike.
+    let profiles = profiles
+        .into_iter()
+        .filter_map(
+            |np| match Temporality::detect(p.duration_nanos, &np.meta.sample_type.type_) {
+                Temporality::Cumulative => {
+                    let series = series_key(taken_label_names, &np.meta);
+                    cumulative.to_delta(series, np)
+                }
+                _ => Some(np),
+            },
+        )
+        .collect();
+
     Ok(profiles)
 }
 
+/// Returns a key identifying the series a profile of the given type belongs to.
+fn series_key(labels: &HashMap<String, String>, meta: &Meta) -> u64 {
+    let mut hasher = Xxh3::new();
+    for s in [
+        &meta.name,
+        &meta.sample_type.type_,
+        &meta.sample_type.unit,
+        &meta.period_type.type_,
+        &meta.period_type.unit,
+    ] {
+        hasher.update(s.as_bytes());
+        hasher.update(&[0]);
+    }
+
+    let mut labels: Vec<_> = labels.iter().collect();
+    labels.sort();
+    for (name, value) in labels {
+        hasher.update(name.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(value.as_bytes());
+        hasher.update(&[0]);
+    }
+
+    hasher.digest()
+}
+
 fn meta_from_pprof(p: &Profile, name: &str, sample_index: usize) -> Meta {
     let period_type = match p.period_type {
         Some(pt) => ValueType {
@@ -247,6 +291,8 @@ fn meta_from_pprof(p: &Profile, name: &str, sample_index: usize) -> Meta {
         },
     };
 
+    let delta = Temporality::detect(p.duration_nanos, &sample_type.type_) == Temporality::Delta;
+
     Meta {
         name: name.to_string(),
         timestamp: p.time_nanos / NANOS_PER_MILLI,
@@ -254,6 +300,7 @@ fn meta_from_pprof(p: &Profile, name: &str, sample_index: usize) -> Meta {
         period: p.period,
         period_type,
         sample_type,
+        delta,
     }
 }
 
@@ -325,101 +372,94 @@ fn serialize_pprof_stacktrace(
     Ok(stacktrace)
 }
 
-type AChunk = Chunk<Arc<dyn Array>>;
-pub async fn write_raw_request_to_arrow_chunk(request: &WriteRawRequest) -> anyhow::Result<AChunk> {
-    let normalized_request = NormalizedWriteRawRequest::try_from(request)?;
-
-    let mut duration_column = MutablePrimitiveArray::new();
-    let mut name_column: MutableDictionaryArray<i32, MutableUtf8Array<i32>> =
-        MutableDictionaryArray::new();
-    let mut period_column = MutablePrimitiveArray::new();
-    let mut period_type_column: MutableDictionaryArray<i32, MutableUtf8Array<i32>> =
-        MutableDictionaryArray::new();
-    let mut period_unit_column: MutableDictionaryArray<i32, MutableUtf8Array<i32>> =
-        MutableDictionaryArray::new();
-    let mut sample_type_column: MutableDictionaryArray<i32, MutableUtf8Array<i32>> =
-        MutableDictionaryArray::new();
-    let mut sample_unit_column: MutableDictionaryArray<i32, MutableUtf8Array<i32>> =
-        MutableDictionaryArray::new();
-    let mut stacktrace_column: MutableListArray<i32, MutableBinaryArray<i32>> =
-        MutableListArray::new();
-    let mut timestamp_column = MutablePrimitiveArray::new();
-    let mut value_column = MutablePrimitiveArray::new();
+/// Label carrying the release of the kernel a profile was taken on.
+const KERNEL_RELEASE_LABEL: &str = "kernel_release";
+
+pub async fn write_raw_request_to_record_batch(
+    request: &WriteRawRequest,
+    stacktraces: &StacktraceStore,
+    limits: &Limits,
+    cumulative: &CumulativeBatch<'_>,
+) -> anyhow::Result<RecordBatch> {
+    let normalized_request =
+        NormalizedWriteRawRequest::try_new(request, limits, cumulative).context(InvalidRequest)?;
+
+    let mut delta_column = BooleanBuilder::new();
+    let mut duration_column = Int64Builder::new();
+    let mut name_column = StringDictionaryBuilder::<Int32Type>::new();
+    let mut period_column = Int64Builder::new();
+    let mut period_type_column = StringDictionaryBuilder::<Int32Type>::new();
+    let mut period_unit_column = StringDictionaryBuilder::<Int32Type>::new();
+    let mut sample_type_column = StringDictionaryBuilder::<Int32Type>::new();
+    let mut sample_unit_column = StringDictionaryBuilder::<Int32Type>::new();
+    let mut stacktrace_id_column = StringDictionaryBuilder::<Int32Type>::new();
+    let mut timestamp_column = Int64Builder::new();
+    let mut value_column = Int64Builder::new();
 
     for series in normalized_request.series.iter() {
+        if let Some(release) = series.labels.get(KERNEL_RELEASE_LABEL) {
+            stacktraces.track_kernel_release(release);
+        }
         for profiles in series.samples.iter() {
             for p in profiles {
                 for ns in p.samples.iter() {
-                    duration_column.push(Some(p.meta.duration));
-                    name_column.try_push(Some(p.meta.name.clone()))?;
-                    period_column.push(Some(p.meta.period));
-                    period_type_column.try_push(Some(p.meta.period_type.type_.clone()))?;
-                    period_unit_column.try_push(Some(p.meta.period_type.unit.clone()))?;
-                    sample_type_column.try_push(Some(p.meta.sample_type.type_.clone()))?;
-                    sample_unit_column.try_push(Some(p.meta.sample_type.unit.clone()))?;
+                    delta_column.append_value(p.meta.delta);
+                    duration_column.append_value(p.meta.duration);
+                    name_column.append(&p.meta.name)?;
+                    period_column.append_value(p.meta.period);
+                    period_type_column.append(&p.meta.period_type.type_)?;
+                    period_unit_column.append(&p.meta.period_type.unit)?;
+                    sample_type_column.append(&p.meta.sample_type.type_)?;
+                    sample_unit_column.append(&p.meta.sample_type.unit)?;
                     if ns.locations.is_empty() {
-                        stacktrace_column.push_null();
+                        stacktrace_id_column.append_null();
                     } else {
-                        let converted_locations: Vec<Option<&[u8]>> = ns
-                            .locations
-                            .iter()
-                            .map(|loc| {
-                                if loc.is_empty() {
-                                    None
-                                } else {
-                                    Some(loc.as_slice())
-                                }
-                            })
-                            .collect();
-                        stacktrace_column.try_push(Some(converted_locations))?;
+                        stacktrace_id_column.append(stacktraces.insert(&ns.locations))?;
                     }
-                    timestamp_column.push(Some(p.meta.timestamp));
-                    value_column.push(Some(ns.value));
+                    timestamp_column.append_value(p.meta.timestamp);
+                    value_column.append_value(ns.value);
                 }
             }
         }
     }
 
-    let mut fields = vec![
-        Int64Array::from(duration_column).arced(),
-        DictionaryArray::from(name_column).arced(),
-        Int64Array::from(period_column).arced(),
-        DictionaryArray::from(period_type_column).arced(),
-        DictionaryArray::from(period_unit_column).arced(),
-        DictionaryArray::from(sample_type_column).arced(),
-        DictionaryArray::from(sample_unit_column).arced(),
-        ListArray::from(stacktrace_column).arced(),
-        Int64Array::from(timestamp_column).arced(),
-        Int64Array::from(value_column).arced(),
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(delta_column.finish()),
+        Arc::new(duration_column.finish()),
+        Arc::new(name_column.finish()),
+        Arc::new(period_column.finish()),
+        Arc::new(period_type_column.finish()),
+        Arc::new(period_unit_column.finish()),
+        Arc::new(sample_type_column.finish()),
+        Arc::new(sample_unit_column.finish()),
+        Arc::new(stacktrace_id_column.finish()),
+        Arc::new(timestamp_column.finish()),
+        Arc::new(value_column.finish()),
     ];
 
     for name in POSSIBLE_METADATA_LABELS {
-        let mut arr: MutableDictionaryArray<i32, MutableUtf8Array<i32>> =
-            MutableDictionaryArray::new();
+        let mut arr = StringDictionaryBuilder::<Int32Type>::new();
 
         for series in normalized_request.series.iter() {
-            if series.labels.contains_key(name) {
-                for profiles in series.samples.iter() {
-                    for p in profiles {
-                        for _ in p.samples.iter() {
-                            arr.try_push(Some(series.labels[name].clone()))?;
-                        }
-                    }
-                }
-            } else {
-                for profiles in series.samples.iter() {
-                    for p in profiles {
-                        for _ in p.samples.iter() {
-                            arr.push_null();
+            let value = series.labels.get(name);
+            for profiles in series.samples.iter() {
+                for p in profiles {
+                    for _ in p.samples.iter() {
+                        match value {
+                            Some(value) => {
+                                arr.append(value)?;
+                            }
+                            None => arr.append_null(),
                         }
                     }
                 }
             }
         }
-        let arr = DictionaryArray::from(arr);
-        //log::info!("labels.{}: {:#?}", name, arr);
-        fields.push(arr.arced());
+        columns.push(Arc::new(arr.finish()));
     }
 
-    Ok(Chunk::new(fields))
+    Ok(RecordBatch::try_new(
+        Arc::new(schema::create_schema()),
+        columns,
+    )?)
 }
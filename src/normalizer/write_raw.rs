@@ -1,4 +1,4 @@
-use super::{NormalizedProfile, Series};
+use super::{CumulativeBatch, NormalizedProfile, Series};
 use crate::pprofpb::Profile;
 use crate::profilestorepb::WriteRawRequest;
 use anyhow::bail;
@@ -8,16 +8,73 @@ use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::io::Read;
 
+/// Limits bounds the amount of work a single `WriteRawRequest` can cause.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum number of series in a request.
+    pub max_series: usize,
+    /// Maximum number of pprof samples across all profiles of a request.
+    pub max_samples: usize,
+    /// Maximum size of a single profile after decompression. This guards
+    /// against gzip bombs, which are tiny on the wire but expand to gigabytes.
+    pub max_decompressed_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_series: 10_000,
+            max_samples: 1_000_000,
+            max_decompressed_size: 64 << 20,
+        }
+    }
+}
+
+/// LimitExceeded is returned when a request exceeds one of the `Limits`.
+#[derive(Debug)]
+pub struct LimitExceeded(pub String);
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request limit exceeded: {}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// InvalidRequest marks requests that can't be normalized, eg. because the
+/// pprof is malformed or the labels are invalid. Retrying them won't help.
+#[derive(Debug)]
+pub struct InvalidRequest;
+
+impl std::fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid WriteRawRequest")
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NormalizedWriteRawRequest {
     pub(crate) series: Vec<Series>,
     pub(crate) all_label_names: Vec<String>,
 }
 
-impl TryFrom<&WriteRawRequest> for NormalizedWriteRawRequest {
-    type Error = anyhow::Error;
+impl NormalizedWriteRawRequest {
+    pub fn try_new(
+        request: &WriteRawRequest,
+        limits: &Limits,
+        cumulative: &CumulativeBatch<'_>,
+    ) -> anyhow::Result<Self> {
+        if request.series.len() > limits.max_series {
+            return Err(LimitExceeded(format!(
+                "{} series exceed the maximum of {}",
+                request.series.len(),
+                limits.max_series
+            ))
+            .into());
+        }
 
-    fn try_from(request: &WriteRawRequest) -> anyhow::Result<Self> {
+        let mut num_samples = 0;
         let mut all_label_names: HashSet<String> = HashSet::new();
         let mut series: Vec<Series> = Vec::with_capacity(request.series.len());
 
@@ -49,14 +106,8 @@ impl TryFrom<&WriteRawRequest> for NormalizedWriteRawRequest {
                 Vec::with_capacity(raw_series.samples.len());
 
             for sample in raw_series.samples.iter() {
-                let mut decompressed = Vec::new();
-
-                let mut decoder = GzDecoder::new(sample.raw_profile.as_slice());
-                if decoder.header().is_some() {
-                    if let Err(e) = decoder.read_to_end(&mut decompressed) {
-                        bail!("Failed to decompress gzip: {}", e);
-                    }
-                }
+                let decompressed =
+                    decompress(sample.raw_profile.as_slice(), limits.max_decompressed_size)?;
 
                 //let path: PathBuf = "/tmp".into();
                 //let mut file = std::fs::File::create(&path.join("pp"))?;
@@ -64,6 +115,15 @@ impl TryFrom<&WriteRawRequest> for NormalizedWriteRawRequest {
 
                 let p = Profile::decode(decompressed.as_slice())?;
 
+                num_samples += p.sample.len();
+                if num_samples > limits.max_samples {
+                    return Err(LimitExceeded(format!(
+                        "more than the maximum of {} samples",
+                        limits.max_samples
+                    ))
+                    .into());
+                }
+
                 // let _ =
                 super::utils::validate_pprof_profile(&p, sample.executable_info.as_slice())?;
 
@@ -75,7 +135,7 @@ impl TryFrom<&WriteRawRequest> for NormalizedWriteRawRequest {
                 );
 
                 let np: Vec<NormalizedProfile> =
-                    super::utils::normalize_pprof(name.as_str(), &ls, &p)?;
+                    super::utils::normalize_pprof(name.as_str(), &ls, &p, cumulative)?;
 
                 samples.push(np);
             }
@@ -94,3 +154,141 @@ impl TryFrom<&WriteRawRequest> for NormalizedWriteRawRequest {
         })
     }
 }
+
+/// Decompresses a gzipped profile, reading at most `max_size` bytes of output.
+fn decompress(raw_profile: &[u8], max_size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+
+    let mut decoder = GzDecoder::new(raw_profile);
+    if decoder.header().is_some() {
+        // Read one byte past the limit to tell a profile of exactly max_size
+        // apart from one that is larger.
+        let mut limited = decoder.by_ref().take(max_size as u64 + 1);
+        if let Err(e) = limited.read_to_end(&mut decompressed) {
+            bail!("Failed to decompress gzip: {}", e);
+        }
+        if decompressed.len() > max_size {
+            return Err(LimitExceeded(format!(
+                "decompressed profile exceeds the maximum of {} bytes",
+                max_size
+            ))
+            .into());
+        }
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalizer::{write_raw_request_to_record_batch, CumulativeTracker};
+    use crate::profilestorepb::{Label, LabelSet, RawProfileSeries, RawSample};
+    use crate::stacktrace_store::StacktraceStore;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    fn request(raw_profile: Vec<u8>, num_series: usize) -> WriteRawRequest {
+        let series = RawProfileSeries {
+            labels: Some(LabelSet {
+                labels: vec![Label {
+                    name: "__name__".into(),
+                    value: "parca_agent".into(),
+                }],
+            }),
+            samples: vec![RawSample {
+                raw_profile,
+                executable_info: vec![],
+            }],
+        };
+
+        WriteRawRequest {
+            series: vec![series; num_series],
+            ..Default::default()
+        }
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_rejects_gzip_bomb() {
+        let limits = Limits {
+            max_decompressed_size: 1024,
+            ..Default::default()
+        };
+        let req = request(gzip(&vec![0; 1 << 20]), 1);
+
+        let err = NormalizedWriteRawRequest::try_new(
+            &req,
+            &limits,
+            &CumulativeTracker::default().begin(),
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<LimitExceeded>().is_some());
+    }
+
+    #[test]
+    fn test_rejects_too_many_series() {
+        let limits = Limits {
+            max_series: 2,
+            ..Default::default()
+        };
+        let req = request(vec![], 3);
+
+        let err = NormalizedWriteRawRequest::try_new(
+            &req,
+            &limits,
+            &CumulativeTracker::default().begin(),
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<LimitExceeded>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request() {
+        let stacktraces = StacktraceStore::new(Arc::new(object_store::memory::InMemory::new()));
+        let limits = Limits::default();
+        let tracker = CumulativeTracker::default();
+        let cumulative = tracker.begin();
+
+        // A truncated gzip stream can't be normalized, however often it's sent.
+        let mut truncated = gzip(&[1; 64]);
+        truncated.truncate(12);
+        let err = write_raw_request_to_record_batch(
+            &request(truncated, 1),
+            &stacktraces,
+            &limits,
+            &cumulative,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.downcast_ref::<InvalidRequest>().is_some());
+        assert!(err.downcast_ref::<LimitExceeded>().is_none());
+
+        // Limits are still distinguishable from other invalid requests.
+        let limits = Limits {
+            max_series: 1,
+            ..Default::default()
+        };
+        let err = write_raw_request_to_record_batch(
+            &request(vec![], 2),
+            &stacktraces,
+            &limits,
+            &cumulative,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.downcast_ref::<LimitExceeded>().is_some());
+    }
+
+    #[test]
+    fn test_decompress_within_limit() {
+        let data = vec![1; 1024];
+        assert_eq!(decompress(&gzip(&data), 1024).unwrap(), data);
+    }
+}
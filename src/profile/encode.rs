@@ -2,6 +2,7 @@ use crate::{
     metapb::Function,
     pprofpb::{Location, Mapping},
 };
+use anyhow::{anyhow, bail};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct PprofLocations {
@@ -81,139 +82,357 @@ impl PprofLocations {
         }
     }
 
+    /// Converts the location into a `profile::Location`, keeping the lines the
+    /// agent may have already resolved.
+    pub fn to_location(&self) -> super::Location {
+        let mapping = if self.build_id.is_empty() && self.file_name.is_empty() {
+            None
+        } else {
+            Some(crate::metapb::Mapping {
+                build_id: self.build_id.clone(),
+                file: self.file_name.clone(),
+                start: self.mapping_memory_start,
+                limit: self.mapping_memory_end,
+                offset: self.mapping_file_offset,
+                ..Default::default()
+            })
+        };
+
+        super::Location {
+            address: self.address,
+            mapping,
+            lines: self
+                .functions
+                .iter()
+                .map(|f| super::LocationLine {
+                    line: f.start_line,
+                    function: Some(f.clone()),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Encodes the location in the current version of the location format.
+    /// See [`ENCODING_VERSION`] for the layout.
     pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
-        Ok(bincode::serialize(&self)?)
+        let mut buf = Vec::with_capacity(self.encoded_size());
+        buf.extend_from_slice(&ENCODING_MAGIC);
+        buf.push(ENCODING_VERSION);
+
+        write_uvarint(&mut buf, self.address);
+        write_uvarint(&mut buf, self.number_of_lines as u64);
+
+        write_string(&mut buf, &self.build_id);
+        write_string(&mut buf, &self.file_name);
+        write_uvarint(&mut buf, self.mapping_memory_start);
+        write_uvarint(
+            &mut buf,
+            self.mapping_memory_end
+                .wrapping_sub(self.mapping_memory_start),
+        );
+        write_uvarint(&mut buf, self.mapping_file_offset);
+
+        write_uvarint(&mut buf, self.functions.len() as u64);
+        for f in self.functions.iter() {
+            write_varint(&mut buf, f.start_line);
+            write_string(&mut buf, &f.name);
+            write_string(&mut buf, &f.system_name);
+            write_string(&mut buf, &f.filename);
+        }
+
+        Ok(buf)
     }
 
+    /// Decodes a location written by any version of the location format,
+    /// including the unversioned bincode encoding used before the format was
+    /// versioned.
     pub fn decode(data: &[u8]) -> anyhow::Result<PprofLocations> {
+        if data.len() > ENCODING_MAGIC.len() && data[..ENCODING_MAGIC.len()] == ENCODING_MAGIC {
+            let mut r = Reader::new(&data[ENCODING_MAGIC.len()..]);
+            let decoded = match r.byte()? {
+                1 => Self::decode_v1(&mut r),
+                v => Err(anyhow!("unsupported location encoding version {}", v)),
+            };
+
+            match decoded {
+                Ok(decoded) => return Ok(decoded),
+                // Locations written before the format was versioned carry no
+                // magic, but an address could happen to start with it.
+                Err(e) => return bincode::deserialize(data).map_err(|_| e),
+            }
+        }
+
         Ok(bincode::deserialize(data)?)
     }
+
+    fn decode_v1(r: &mut Reader) -> anyhow::Result<PprofLocations> {
+        let address = r.uvarint()?;
+        let number_of_lines = r.uvarint()? as usize;
+
+        let build_id = r.string()?;
+        let file_name = r.string()?;
+        let mapping_memory_start = r.uvarint()?;
+        let mapping_memory_end = mapping_memory_start.wrapping_add(r.uvarint()?);
+        let mapping_file_offset = r.uvarint()?;
+
+        let num_functions = r.uvarint()? as usize;
+        // Every function takes at least 4 bytes, which bounds the allocation
+        // for corrupted input.
+        let mut functions = Vec::with_capacity(num_functions.min(r.remaining() / 4));
+        for _ in 0..num_functions {
+            functions.push(Function {
+                start_line: r.varint()?,
+                name: r.string()?,
+                system_name: r.string()?,
+                filename: r.string()?,
+                ..Default::default()
+            });
+        }
+
+        if r.remaining() != 0 {
+            bail!("{} trailing bytes after encoded location", r.remaining());
+        }
+
+        Ok(PprofLocations {
+            address,
+            number_of_lines,
+            build_id,
+            file_name,
+            mapping_memory_start,
+            mapping_memory_end,
+            mapping_file_offset,
+            functions,
+        })
+    }
+
+    fn encoded_size(&self) -> usize {
+        let mut size = ENCODING_MAGIC.len() + 1;
+        size += uvarint_size(self.address);
+        size += uvarint_size(self.number_of_lines as u64);
+        size += string_size(&self.build_id);
+        size += string_size(&self.file_name);
+        size += uvarint_size(self.mapping_memory_start);
+        size += uvarint_size(
+            self.mapping_memory_end
+                .wrapping_sub(self.mapping_memory_start),
+        );
+        size += uvarint_size(self.mapping_file_offset);
+
+        size += uvarint_size(self.functions.len() as u64);
+        for f in self.functions.iter() {
+            size += uvarint_size(zigzag(f.start_line));
+            size += string_size(&f.name);
+            size += string_size(&f.system_name);
+            size += string_size(&f.filename);
+        }
+
+        size
+    }
+}
+
+/// Prefix of every versioned encoded location. It distinguishes them from
+/// locations encoded with bincode, which carry no header at all.
+const ENCODING_MAGIC: [u8; 3] = *b"EVL";
+
+/// Version of the location format written by [`PprofLocations::encode`].
+///
+/// Version 1 is laid out as follows, where `uvarint` is an unsigned LEB128
+/// integer, `varint` a zigzag-encoded `uvarint` and `string` a `uvarint`
+/// length followed by that many bytes of UTF-8:
+///
+/// ```text
+/// magic                "EVL"
+/// u8                   version
+/// uvarint              address
+/// uvarint              number of lines in the original pprof location
+/// string               mapping build ID
+/// string               mapping file name
+/// uvarint              mapping memory start
+/// uvarint              mapping size (memory end - memory start)
+/// uvarint              mapping file offset
+/// uvarint              number of functions, followed by each function as
+///   varint             start line
+///   string             name
+///   string             system name
+///   string             file name
+/// ```
+///
+/// New versions must get a new number and a decoder of their own, older
+/// decoders must be kept so existing data stays readable.
+const ENCODING_VERSION: u8 = 1;
+
+fn write_uvarint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn write_varint(buf: &mut Vec<u8>, v: i64) {
+    write_uvarint(buf, zigzag(v));
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    write_uvarint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn uvarint_size(mut v: u64) -> usize {
+    let mut size = 1;
+    while v >= 0x80 {
+        v >>= 7;
+        size += 1;
+    }
+    size
 }
 
-// pub fn encode_pprof_location(
-//     location: &Location,
-//     mapping: &Mapping,
-//     functions: &[Function],
-//     string_table: &[String],
-// ) -> Vec<u8> {
-// let mut buf = Vec::with_capacity(serialized_pprof_location_size(
-//     location,
-//     mapping,
-//     functions,
-//     string_table,
-// ));
-// write_uvarint(&mut buf, location.address);
-// write_uvarint(&mut buf, location.line.len() as u64);
-//
-// if mapping.id == 0 {
-//     buf.push(0x0);
-// } else {
-//     buf.push(0x1);
-//     let build_id = match mapping.build_id {
-//         0 => "",
-//         _ => &string_table[mapping.build_id as usize],
-//     };
-//     write_string(&mut buf, build_id);
-//
-//     let filename = match mapping.filename {
-//         0 => "",
-//         _ => &string_table[mapping.filename as usize],
-//     };
-//     write_string(&mut buf, filename);
-//     write_uvarint(&mut buf, mapping.memory_start);
-//     write_uvarint(&mut buf, mapping.memory_limit - mapping.memory_start);
-//     write_uvarint(&mut buf, mapping.file_offset);
-// }
-//
-// for line in location.line.iter() {
-//     write_uvarint(&mut buf, line.line as u64);
-//
-//     if line.function_id != 0 {
-//         buf.push(0x1);
-//
-//         let f = &functions[line.function_id as usize - 1];
-//         write_uvarint(&mut buf, f.start_line as u64);
-//
-//         let name = match f.name {
-//             0 => "",
-//             _ => &string_table[f.name as usize],
-//         };
-//         write_string(&mut buf, name);
-//
-//         let system_name = match f.system_name {
-//             0 => "",
-//             _ => &string_table[f.system_name as usize],
-//         };
-//         write_string(&mut buf, system_name);
-//
-//         let filename = match f.filename {
-//             0 => "",
-//             _ => &string_table[f.filename as usize],
-//         };
-//         write_string(&mut buf, filename);
-//     } else {
-//         buf.push(0x0);
-//     }
-// }
-//
-// buf
-// }
-
-// fn serialized_pprof_location_size(
-//     location: &Location,
-//     mapping: &Mapping,
-//     functions: &[Function],
-//     string_table: &[String],
-// ) -> usize {
-//     let mut size = uvarint_size(location.address);
-//     size += 1;
-//
-//     size += uvarint_size(location.line.len() as u64);
-//
-//     if mapping.id != 0 {
-//         let build_id = match mapping.build_id {
-//             0 => "",
-//             _ => &string_table[mapping.build_id as usize],
-//         };
-//         size += uvarint_size(build_id.len() as u64) + build_id.len();
-//
-//         let filename = match mapping.filename {
-//             0 => "",
-//             _ => &string_table[mapping.filename as usize],
-//         };
-//         size += uvarint_size(filename.len() as u64) + filename.len();
-//         size += uvarint_size(mapping.memory_start);
-//         size += uvarint_size(mapping.memory_limit - mapping.memory_start);
-//         size += uvarint_size(mapping.file_offset);
-//     }
-//
-//     for line in location.line.iter() {
-//         size += uvarint_size(line.line as u64);
-//         size += 1;
-//
-//         if line.function_id != 0 {
-//             let f = &functions[line.function_id as usize - 1];
-//             size += uvarint_size(f.start_line as u64);
-//
-//             let name = match f.name {
-//                 0 => "",
-//                 _ => &string_table[f.name as usize],
-//             };
-//             size += uvarint_size(name.len() as u64) + name.len();
-//
-//             let system_name = match f.system_name {
-//                 0 => "",
-//                 _ => &string_table[f.system_name as usize],
-//             };
-//             size += uvarint_size(system_name.len() as u64) + system_name.len();
-//
-//             let filename = match f.filename {
-//                 0 => "",
-//                 _ => &string_table[f.filename as usize],
-//             };
-//             size += uvarint_size(filename.len() as u64) + filename.len();
-//         }
-//     }
-//
-//     size
-// }
+fn string_size(s: &str) -> usize {
+    uvarint_size(s.len() as u64) + s.len()
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        match self.data.get(self.pos) {
+            Some(b) => {
+                self.pos += 1;
+                Ok(*b)
+            }
+            None => bail!("unexpected end of encoded location"),
+        }
+    }
+
+    fn uvarint(&mut self) -> anyhow::Result<u64> {
+        let mut v: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return Ok(v);
+            }
+        }
+        bail!("varint overflows a 64-bit integer")
+    }
+
+    fn varint(&mut self) -> anyhow::Result<i64> {
+        let v = self.uvarint()?;
+        Ok(((v >> 1) as i64) ^ -((v & 1) as i64))
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let len = self.uvarint()? as usize;
+        if len > self.remaining() {
+            bail!("string length {} exceeds encoded location", len);
+        }
+        let s = std::str::from_utf8(&self.data[self.pos..self.pos + len])?.to_string();
+        self.pos += len;
+        Ok(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location() -> PprofLocations {
+        PprofLocations {
+            address: 0x401156,
+            number_of_lines: 2,
+            build_id: "2d6912fd3dd64542f6f6294f4bf9cb6c265b3085".into(),
+            file_name: "/usr/bin/basic-cpp".into(),
+            mapping_memory_start: 0x400000,
+            mapping_memory_end: 0x4a0000,
+            mapping_file_offset: 0,
+            functions: vec![
+                Function {
+                    start_line: 12,
+                    name: "main".into(),
+                    system_name: "main".into(),
+                    filename: "main.cpp".into(),
+                    ..Default::default()
+                },
+                Function {
+                    start_line: -1,
+                    name: "".into(),
+                    system_name: "_ZNSaIcEC1ERKS_".into(),
+                    filename: "".into(),
+                    ..Default::default()
+                },
+            ],
+        }
+    }
+
+    fn assert_location_eq(a: &PprofLocations, b: &PprofLocations) {
+        assert_eq!(a.address, b.address);
+        assert_eq!(a.number_of_lines, b.number_of_lines);
+        assert_eq!(a.build_id, b.build_id);
+        assert_eq!(a.file_name, b.file_name);
+        assert_eq!(a.mapping_memory_start, b.mapping_memory_start);
+        assert_eq!(a.mapping_memory_end, b.mapping_memory_end);
+        assert_eq!(a.mapping_file_offset, b.mapping_file_offset);
+        assert_eq!(a.functions, b.functions);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let loc = location();
+        let encoded = loc.encode().unwrap();
+        assert_eq!(encoded.len(), loc.encoded_size());
+        assert_eq!(encoded[3], ENCODING_VERSION);
+
+        let decoded = PprofLocations::decode(&encoded).unwrap();
+        assert_location_eq(&loc, &decoded);
+    }
+
+    #[test]
+    fn test_decode_bincode() {
+        let loc = location();
+        let encoded = bincode::serialize(&loc).unwrap();
+
+        let decoded = PprofLocations::decode(&encoded).unwrap();
+        assert_location_eq(&loc, &decoded);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let mut encoded = location().encode().unwrap();
+        assert!(PprofLocations::decode(&encoded[..encoded.len() - 1]).is_err());
+
+        encoded[3] = ENCODING_VERSION + 1;
+        assert!(PprofLocations::decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_varint() {
+        for v in [0_u64, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = vec![];
+            write_uvarint(&mut buf, v);
+            assert_eq!(buf.len(), uvarint_size(v));
+            assert_eq!(Reader::new(&buf).uvarint().unwrap(), v);
+        }
+
+        for v in [0_i64, -1, 1, i64::MIN, i64::MAX] {
+            let mut buf = vec![];
+            write_varint(&mut buf, v);
+            assert_eq!(Reader::new(&buf).varint().unwrap(), v);
+        }
+    }
+}
@@ -52,4 +52,36 @@ pub struct Meta {
     pub timestamp: i64,
     pub duration: i64,
     pub period: i64,
+    /// Whether the values were collected over `duration`, as opposed to
+    /// being a point in time snapshot.
+    #[serde(default)]
+    pub delta: bool,
+}
+
+/// ProfileType identifies a kind of profile, eg. CPU samples or allocated
+/// bytes, independent of the series it was collected from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProfileType {
+    pub name: String,
+    pub sample_type: String,
+    pub sample_unit: String,
+    pub period_type: String,
+    pub period_unit: String,
+    pub delta: bool,
+}
+
+impl std::fmt::Display for ProfileType {
+    /// Formats the profile type as it is used in queries:
+    /// <name>:<sample-type>:<sample-unit>:<period-type>:<period-unit>[:delta]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}:{}",
+            self.name, self.sample_type, self.sample_unit, self.period_type, self.period_unit
+        )?;
+        if self.delta {
+            write!(f, ":delta")?;
+        }
+        Ok(())
+    }
 }
@@ -1,4 +1,4 @@
-use crate::{metapb, profile::executableinfo, symbolizer};
+use crate::{metapb, symbolizer};
 use datafusion::arrow::{
     array::{Array, FixedSizeBinaryArray, GenericByteArray},
     datatypes::GenericBinaryType,
@@ -102,18 +102,23 @@ pub struct MappingLocations<'a> {
     locations: HashMap<u64, &'a super::Location>,
 }
 
+/// Kernel frames are grouped by kernel release and build ID.
+type KernelGroupKey = (Option<String>, String);
+
+/// Symbolizes the encoded locations. kernel_releases holds the kernel
+/// release of the profile each location came from, which kernel frames are
+/// symbolized against.
 pub async fn symbolize_locations(
     locations: &GenericByteArray<GenericBinaryType<i32>>,
+    kernel_releases: &[Option<String>],
     symbolizer: Arc<symbolizer::Symbolizer>,
 ) -> anyhow::Result<Vec<Option<super::Location>>> {
     // Pre-allocate result vector
     let mut result_locations = Vec::with_capacity(locations.len());
 
-    // Create a single map to group locations by build_id and mapping
-    let mut symbolization_groups: HashMap<
-        (String, executableinfo::Mapping),
-        (metapb::Mapping, Vec<(usize, super::Location)>),
-    > = HashMap::new();
+    // Group locations by build_id, each group is symbolized as one task.
+    let mut symbolization_groups: HashMap<String, Vec<(usize, super::Location)>> = HashMap::new();
+    let mut kernel_groups: HashMap<KernelGroupKey, Vec<(usize, super::Location)>> = HashMap::new();
 
     // First pass: group locations and fill result vector
     for (idx, loc) in locations.iter().enumerate() {
@@ -126,67 +131,106 @@ pub async fn symbolize_locations(
         let decoded_location = match crate::profile::PprofLocations::decode(loc.unwrap()) {
             Ok(loc) => loc,
             Err(e) => {
+                log::warn!("Failed to decode location: {}", e);
                 continue;
             }
         };
 
-        // Skip invalid locations
-        if decoded_location.address == 0
-            || decoded_location.build_id.is_empty()
-            || decoded_location.number_of_lines > 0
-        {
+        // Locations that can't or don't need to be symbolized are returned as is.
+        if decoded_location.address == 0 || decoded_location.number_of_lines > 0 {
+            result_locations[idx] = Some(decoded_location.to_location());
             continue;
         }
 
-        let mapping = executableinfo::Mapping {
-            start: decoded_location.mapping_memory_start,
-            end: decoded_location.mapping_memory_end,
-            offset: decoded_location.mapping_file_offset,
-            file: decoded_location.file_name.clone(),
-        };
-
-        let key = (decoded_location.build_id.clone(), mapping.clone());
-        let group = symbolization_groups.entry(key).or_insert_with(|| {
-            (
-                metapb::Mapping {
+        // Kernel frames often come without a build ID, they are resolved
+        // through kallsyms instead.
+        if symbolizer::kernel::is_kernel_mapping(&decoded_location.file_name) {
+            let release = kernel_releases.get(idx).cloned().flatten();
+            let location = super::Location {
+                address: decoded_location.address,
+                mapping: Some(metapb::Mapping {
                     build_id: decoded_location.build_id.clone(),
                     file: decoded_location.file_name.clone(),
                     start: decoded_location.mapping_memory_start,
                     limit: decoded_location.mapping_memory_end,
                     offset: decoded_location.mapping_file_offset,
                     ..Default::default()
-                },
-                Vec::new(),
-            )
-        });
+                }),
+                ..Default::default()
+            };
+            kernel_groups
+                .entry((release, decoded_location.build_id.clone()))
+                .or_default()
+                .push((idx, location));
+            continue;
+        }
+
+        if decoded_location.build_id.is_empty() {
+            result_locations[idx] = Some(decoded_location.to_location());
+            continue;
+        }
 
         let location = super::Location {
             address: decoded_location.address,
-            mapping: Some(group.0.clone()),
+            mapping: Some(metapb::Mapping {
+                build_id: decoded_location.build_id.clone(),
+                file: decoded_location.file_name.clone(),
+                start: decoded_location.mapping_memory_start,
+                limit: decoded_location.mapping_memory_end,
+                offset: decoded_location.mapping_file_offset,
+                ..Default::default()
+            }),
             ..Default::default()
         };
 
-        group.1.push((idx, location));
+        // Locations that fail to symbolize are returned without lines.
+        result_locations[idx] = Some(location.clone());
+        symbolization_groups
+            .entry(decoded_location.build_id)
+            .or_default()
+            .push((idx, location));
     }
 
-    // Symbolization phase
-    for ((build_id, _), (_, locations_with_indices)) in symbolization_groups.iter_mut() {
+    // Symbolization phase: build IDs are symbolized concurrently, a failing
+    // one doesn't fail the others. The symbolizer bounds how many run at once.
+    let mut tasks = tokio::task::JoinSet::new();
+    for (build_id, locations_with_indices) in symbolization_groups {
+        let (indices, locations): (Vec<usize>, Vec<super::Location>) =
+            locations_with_indices.into_iter().unzip();
+        let symbolizer = Arc::clone(&symbolizer);
+        tasks.spawn(async move {
+            let locations = symbolizer
+                .symbolize_owned(build_id.clone(), locations)
+                .await
+                .map_err(|e| e.context(format!("build_id {}", build_id)))?;
+            anyhow::Ok((indices, locations))
+        });
+    }
+
+    while let Some(res) = tasks.join_next().await {
+        match res? {
+            Ok((indices, locations)) => {
+                for (idx, loc) in indices.into_iter().zip(locations) {
+                    result_locations[idx] = Some(loc);
+                }
+            }
+            Err(e) => log::warn!("Failed to symbolize locations: {:#}", e),
+        }
+    }
+
+    for ((release, build_id), locations_with_indices) in kernel_groups.iter_mut() {
         let mut locations: Vec<&mut super::Location> = locations_with_indices
             .iter_mut()
             .map(|(_, loc)| loc)
             .collect();
 
-        // Then create SymbolizationRequestMappingAddrs with a slice from the Vec
-        let mut sym_req = symbolizer::SymbolizationRequest {
-            build_id: build_id.clone(),
-            mappings: vec![symbolizer::SymbolizationRequestMappingAddrs {
-                locations: locations.as_mut_slice(),
-            }],
-        };
-
-        symbolizer.symbolize(&mut sym_req).await?;
+        if let Err(e) = symbolizer
+            .symbolize_kernel(release.as_deref(), build_id, locations.as_mut_slice())
+            .await
+        {
+            log::warn!("Failed to symbolize kernel locations: {:#}", e);
+        }
 
-        // Update result_locations directly from the symbolized locations
         for (idx, loc) in locations_with_indices.iter() {
             if let Some(result) = result_locations.get_mut(*idx) {
                 *result = Some(loc.clone());
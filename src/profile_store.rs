@@ -1,16 +1,61 @@
 use crate::profilestorepb::profile_store_service_server::ProfileStoreService;
 use crate::profilestorepb::{WriteRawRequest, WriteRawResponse, WriteRequest, WriteResponse};
-use crate::{ingester, normalizer, symbolizer};
-use anyhow::bail;
-use std::sync::Arc;
+use crate::{ingester, normalizer, stacktrace_store, symbolizer};
+use anyhow::Context;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use std::{pin::Pin, result::Result};
 use tokio_stream::Stream;
 use tonic::{Request, Response, Status, Streaming};
 
+/// WriteMetrics counts the outcome of writes to the ProfileStore.
+#[derive(Debug, Default)]
+pub struct WriteMetrics {
+    pub accepted: AtomicU64,
+    /// Writes rejected because the request exceeded the per-request limits.
+    pub rejected_limits: AtomicU64,
+    /// Writes rejected because the ingester was out of memory budget.
+    pub rejected_backpressure: AtomicU64,
+    /// Writes rejected because the request could not be normalized.
+    pub rejected_invalid: AtomicU64,
+    /// Writes that failed for reasons unrelated to the request.
+    pub failed: AtomicU64,
+}
+
+impl WriteMetrics {
+    /// Renders the counters in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut out = String::from(
+            "# HELP evprofiler_writes_total Writes to the ProfileStore by outcome.\n\
+             # TYPE evprofiler_writes_total counter\n",
+        );
+        for (outcome, counter) in [
+            ("accepted", &self.accepted),
+            ("rejected_limits", &self.rejected_limits),
+            ("rejected_backpressure", &self.rejected_backpressure),
+            ("rejected_invalid", &self.rejected_invalid),
+            ("failed", &self.failed),
+        ] {
+            out.push_str(&format!(
+                "evprofiler_writes_total{{outcome=\"{}\"}} {}\n",
+                outcome,
+                counter.load(Ordering::Relaxed)
+            ));
+        }
+        out
+    }
+}
+
 #[derive(Debug)]
 pub struct ProfileStore {
     symbolizer: Arc<symbolizer::Symbolizer>,
     ingester: Arc<ingester::Ingester>,
+    stacktraces: Arc<stacktrace_store::StacktraceStore>,
+    limits: normalizer::Limits,
+    cumulative: normalizer::CumulativeTracker,
+    metrics: Arc<WriteMetrics>,
 }
 
 #[tonic::async_trait]
@@ -20,10 +65,31 @@ impl ProfileStoreService for ProfileStore {
         &self,
         request: Request<WriteRawRequest>,
     ) -> anyhow::Result<Response<WriteRawResponse>, Status> {
-        let _ = match self.write_series(&request.into_inner()).await {
-            Ok(_) => (),
-            Err(e) => return Err(Status::internal(e.to_string())),
-        };
+        if let Err(e) = self.write_series(&request.into_inner()).await {
+            let (counter, status) = if e.downcast_ref::<normalizer::LimitExceeded>().is_some() {
+                (
+                    &self.metrics.rejected_limits,
+                    Status::resource_exhausted(format!("{:#}", e)),
+                )
+            } else if e.downcast_ref::<ingester::BufferFull>().is_some() {
+                (
+                    &self.metrics.rejected_backpressure,
+                    Status::resource_exhausted(format!("{:#}", e)),
+                )
+            } else if e.downcast_ref::<normalizer::InvalidRequest>().is_some() {
+                (
+                    &self.metrics.rejected_invalid,
+                    Status::invalid_argument(format!("{:#}", e)),
+                )
+            } else {
+                (&self.metrics.failed, Status::internal(format!("{:#}", e)))
+            };
+            let rejected = counter.fetch_add(1, Ordering::Relaxed) + 1;
+            log::warn!("Rejected write ({} so far of this kind): {:#}", rejected, e);
+            return Err(status);
+        }
+
+        self.metrics.accepted.fetch_add(1, Ordering::Relaxed);
         return Ok(Response::new(WriteRawResponse {}));
     }
     /// Server streaming response type for the Write method.
@@ -58,29 +124,46 @@ impl ProfileStoreService for ProfileStore {
 }
 
 impl ProfileStore {
-    pub fn new(symbolizer: Arc<symbolizer::Symbolizer>, ingester: Arc<ingester::Ingester>) -> Self {
+    pub fn new(
+        symbolizer: Arc<symbolizer::Symbolizer>,
+        ingester: Arc<ingester::Ingester>,
+        stacktraces: Arc<stacktrace_store::StacktraceStore>,
+        limits: normalizer::Limits,
+    ) -> Self {
         Self {
             symbolizer: Arc::clone(&symbolizer),
             ingester: Arc::clone(&ingester),
+            stacktraces: Arc::clone(&stacktraces),
+            limits,
+            cumulative: normalizer::CumulativeTracker::default(),
+            metrics: Arc::new(WriteMetrics::default()),
         }
     }
 
+    pub fn metrics(&self) -> Arc<WriteMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
     pub async fn write_series(&self, request: &WriteRawRequest) -> anyhow::Result<()> {
-        let chunk = match normalizer::write_raw_request_to_arrow_chunk(request).await {
-            Ok(record) => record,
-            Err(e) => {
-                bail!(
-                    "Failed to normalize WriteRawRequest to Arrow Record, details: {}",
-                    e
-                );
-            }
-        };
-        if chunk.is_empty() {
-            return Ok(());
+        // Reject early, before spending any work on a request we can't buffer.
+        self.ingester.check_capacity()?;
+
+        // Cumulative baselines only move once the request is ingested, so
+        // that a rejected request can be retried.
+        let cumulative = self.cumulative.begin();
+        let record = normalizer::write_raw_request_to_record_batch(
+            request,
+            &self.stacktraces,
+            &self.limits,
+            &cumulative,
+        )
+        .await
+        .context("Failed to normalize WriteRawRequest to Arrow Record")?;
+        if record.num_rows() > 0 {
+            self.ingester.ingest(record).await?;
         }
 
-        let ingester = Arc::clone(&self.ingester);
-        tokio::spawn(async move { ingester.ingest(chunk).await });
+        cumulative.commit();
         Ok(())
     }
 }
@@ -0,0 +1,974 @@
+use crate::profile::LocationLine;
+use anyhow::Context;
+use moka::sync::Cache;
+use object_store::{path::Path, ObjectStore};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio_stream::StreamExt;
+use xxhash_rust::xxh3::xxh3_128;
+
+/// Prefix under which dictionary segments are persisted in the bucket.
+const SEGMENTS_PREFIX: &str = "stacktraces";
+
+/// Prefix under which the lines of locations symbolized after ingestion are
+/// persisted in the bucket.
+const SYMBOLIZED_PREFIX: &str = "symbolized";
+
+/// Total size of the segments kept in memory.
+const SEGMENT_CACHE_SIZE: u64 = 256 << 20;
+
+/// Segments smaller than this are merged together once there are
+/// MAX_SMALL_SEGMENTS of them, as every flush writes one, however few
+/// entries it has.
+const COMPACTED_SEGMENT_SIZE: usize = 4 << 20;
+const MAX_SMALL_SEGMENTS: usize = 32;
+
+/// Segment is the unit in which new dictionary entries are persisted. Every
+/// flush writes the entries added since the previous flush as one segment.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Segment {
+    locations: Vec<(String, Vec<u8>)>,
+    stacktraces: Vec<(String, Vec<String>)>,
+}
+
+impl Segment {
+    fn is_empty(&self) -> bool {
+        self.locations.is_empty() && self.stacktraces.is_empty()
+    }
+}
+
+/// Entries of a segment, or of those not persisted yet, by ID.
+#[derive(Debug, Default)]
+struct Entries {
+    locations: HashMap<u128, Arc<Vec<u8>>>,
+    stacktraces: HashMap<u128, Arc<Vec<String>>>,
+    size: usize,
+}
+
+impl Entries {
+    fn from_segment(segment: Segment) -> Self {
+        let mut entries = Self::default();
+        for (id, location) in segment.locations {
+            if let Some(key) = parse_id(&id) {
+                entries.size += location.len();
+                entries.locations.insert(key, Arc::new(location));
+            }
+        }
+        for (id, location_ids) in segment.stacktraces {
+            if let Some(key) = parse_id(&id) {
+                entries.size += location_ids.len() * 32;
+                entries.stacktraces.insert(key, Arc::new(location_ids));
+            }
+        }
+        entries
+    }
+
+    fn to_segment(&self) -> Segment {
+        Segment {
+            locations: self
+                .locations
+                .iter()
+                .map(|(key, location)| (format_id(*key), location.to_vec()))
+                .collect(),
+            stacktraces: self
+                .stacktraces
+                .iter()
+                .map(|(key, location_ids)| (format_id(*key), location_ids.to_vec()))
+                .collect(),
+        }
+    }
+}
+
+/// Index records the segment every persisted entry is in.
+#[derive(Debug, Default)]
+struct Index {
+    locations: HashMap<u128, u32>,
+    stacktraces: HashMap<u128, u32>,
+    segments: HashMap<u32, SegmentInfo>,
+    next_segment: u32,
+}
+
+#[derive(Debug, Clone)]
+struct SegmentInfo {
+    path: Path,
+    size: usize,
+}
+
+/// SymbolizedSegment is the unit in which symbolized locations are persisted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SymbolizedSegment {
+    locations: Vec<(String, Vec<LocationLine>)>,
+}
+
+/// StacktraceStore is a content-addressed dictionary of stacktraces.
+///
+/// A stacktrace ID maps to the list of location IDs it is made of, and a
+/// location ID maps to the encoded `PprofLocations`. IDs are derived from the
+/// content itself, so the same stack (or location) seen in different samples,
+/// scrapes or agents always resolves to the same ID and is stored only once.
+///
+/// Only the IDs are kept in memory for every entry. The entries themselves
+/// are read from their segment in the bucket, and the most recently used
+/// segments are cached up to a total size.
+#[derive(Debug)]
+pub struct StacktraceStore {
+    bucket: Arc<dyn ObjectStore>,
+    index: RwLock<Index>,
+    segments: Cache<u32, Arc<Entries>>,
+    /// Entries added since the last flush.
+    pending: RwLock<Entries>,
+    symbolized: RwLock<HashMap<String, Arc<Vec<LocationLine>>>>,
+    pending_symbolized: Mutex<SymbolizedSegment>,
+    /// Held for the whole of a flush, so that a flush only returns once the
+    /// segments of any flush in flight are persisted too.
+    flush_lock: tokio::sync::Mutex<()>,
+    /// IDs of locations added since they were last taken, if tracked.
+    unsymbolized: Mutex<Option<Vec<String>>>,
+    /// Kernel releases seen while tracking, and those not yet taken.
+    kernel_releases: Mutex<Option<(HashSet<String>, Vec<String>)>>,
+}
+
+impl StacktraceStore {
+    pub fn new(bucket: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            bucket,
+            index: RwLock::new(Index::default()),
+            segments: segment_cache(SEGMENT_CACHE_SIZE),
+            pending: RwLock::new(Entries::default()),
+            symbolized: RwLock::new(HashMap::new()),
+            pending_symbolized: Mutex::new(SymbolizedSegment::default()),
+            flush_lock: tokio::sync::Mutex::new(()),
+            unsymbolized: Mutex::new(None),
+            kernel_releases: Mutex::new(None),
+        }
+    }
+
+    /// Creates a store and indexes every segment previously persisted to the
+    /// bucket.
+    pub async fn load(bucket: Arc<dyn ObjectStore>) -> anyhow::Result<Self> {
+        let store = Self::new(bucket);
+        let prefix = Path::from(SEGMENTS_PREFIX);
+        let mut num_segments = 0;
+
+        {
+            // Segments are listed in the order they were written, so an entry
+            // is indexed to the latest segment it is in.
+            let mut paths = vec![];
+            let mut segments = store.bucket.list(Some(&prefix));
+            while let Some(meta) = segments.next().await {
+                paths.push(meta?.location);
+            }
+            paths.sort();
+
+            for path in paths {
+                let (segment, size) = store.read_segment(&path).await?;
+                store.register(path, size, Arc::new(Entries::from_segment(segment)));
+                num_segments += 1;
+            }
+        }
+
+        let prefix = Path::from(SYMBOLIZED_PREFIX);
+        {
+            let mut segments = store.bucket.list(Some(&prefix));
+            while let Some(meta) = segments.next().await {
+                let meta = meta?;
+                let data = store.bucket.get(&meta.location).await?.bytes().await?;
+                let segment: SymbolizedSegment =
+                    bincode::deserialize(&data).with_context(|| {
+                        format!("Failed to decode symbolized segment {}", meta.location)
+                    })?;
+                let mut symbolized = store.symbolized.write().unwrap();
+                for (id, lines) in segment.locations {
+                    symbolized.insert(id, Arc::new(lines));
+                }
+            }
+        }
+
+        {
+            let index = store.index.read().unwrap();
+            log::info!(
+                "Loaded {} stacktrace segments: {} stacktraces, {} locations, {} symbolized",
+                num_segments,
+                index.stacktraces.len(),
+                index.locations.len(),
+                store.symbolized.read().unwrap().len(),
+            );
+        }
+
+        store.compact().await?;
+        Ok(store)
+    }
+
+    /// Inserts a stacktrace made of the given encoded locations and returns its
+    /// ID. Locations and stacktraces that are already known are not stored
+    /// again.
+    pub fn insert(&self, locations: &[Vec<u8>]) -> String {
+        let location_ids: Vec<String> = locations
+            .iter()
+            .map(|location| self.insert_location(location))
+            .collect();
+
+        let id = Self::stacktrace_id(&location_ids);
+        let key = parse_id(&id).unwrap();
+        if self.pending.read().unwrap().stacktraces.contains_key(&key) {
+            return id;
+        }
+
+        let mut pending = self.pending.write().unwrap();
+        if !pending.stacktraces.contains_key(&key)
+            && !self.index.read().unwrap().stacktraces.contains_key(&key)
+        {
+            pending.size += location_ids.len() * 32;
+            pending.stacktraces.insert(key, Arc::new(location_ids));
+        }
+
+        id
+    }
+
+    fn insert_location(&self, location: &[u8]) -> String {
+        let id = Self::location_id(location);
+        let key = parse_id(&id).unwrap();
+        if self.pending.read().unwrap().locations.contains_key(&key) {
+            return id;
+        }
+
+        let mut pending = self.pending.write().unwrap();
+        if !pending.locations.contains_key(&key)
+            && !self.index.read().unwrap().locations.contains_key(&key)
+        {
+            pending.size += location.len();
+            pending.locations.insert(key, Arc::new(location.to_vec()));
+            if let Some(unsymbolized) = self.unsymbolized.lock().unwrap().as_mut() {
+                unsymbolized.push(id.clone());
+            }
+        }
+
+        id
+    }
+
+    /// Returns the location IDs the stacktrace is made of, innermost frame first.
+    pub async fn stacktrace(&self, id: &str) -> anyhow::Result<Option<Arc<Vec<String>>>> {
+        self.lookup(
+            id,
+            |entries, key| entries.stacktraces.get(&key).cloned(),
+            |index, key| index.stacktraces.get(&key).copied(),
+        )
+        .await
+    }
+
+    /// Returns the encoded `PprofLocations` for the given location ID.
+    pub async fn location(&self, id: &str) -> anyhow::Result<Option<Arc<Vec<u8>>>> {
+        self.lookup(
+            id,
+            |entries, key| entries.locations.get(&key).cloned(),
+            |index, key| index.locations.get(&key).copied(),
+        )
+        .await
+    }
+
+    async fn lookup<T>(
+        &self,
+        id: &str,
+        get: impl Fn(&Entries, u128) -> Option<T>,
+        segment_of: impl Fn(&Index, u128) -> Option<u32>,
+    ) -> anyhow::Result<Option<T>> {
+        let key = match parse_id(id) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        if let Some(entry) = get(&self.pending.read().unwrap(), key) {
+            return Ok(Some(entry));
+        }
+
+        // A compaction can move the entry to another segment between looking
+        // up the segment and reading it, so it is looked up once more.
+        for _ in 0..2 {
+            let (number, info) = {
+                let index = self.index.read().unwrap();
+                match segment_of(&index, key) {
+                    Some(number) => (number, index.segments.get(&number).cloned()),
+                    None => return Ok(None),
+                }
+            };
+            let entries = match self.segments.get(&number) {
+                Some(entries) => entries,
+                None => {
+                    let info = match info {
+                        Some(info) => info,
+                        None => continue,
+                    };
+                    let segment = match self.read_segment(&info.path).await {
+                        Ok((segment, _)) => segment,
+                        Err(e) if is_not_found(&e) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let entries = Arc::new(Entries::from_segment(segment));
+                    self.segments.insert(number, Arc::clone(&entries));
+                    entries
+                }
+            };
+            return Ok(get(&entries, key));
+        }
+
+        Ok(None)
+    }
+
+    /// Starts keeping track of new locations, so they can be symbolized
+    /// after ingestion. Every location known but not yet symbolized is
+    /// considered new.
+    pub fn track_unsymbolized(&self) {
+        let symbolized = self.symbolized.read().unwrap();
+        let pending = self.pending.read().unwrap();
+        let index = self.index.read().unwrap();
+        let keys: HashSet<u128> = index
+            .locations
+            .keys()
+            .chain(pending.locations.keys())
+            .copied()
+            .collect();
+        let ids = keys
+            .into_iter()
+            .map(format_id)
+            .filter(|id| !symbolized.contains_key(id))
+            .collect();
+        *self.unsymbolized.lock().unwrap() = Some(ids);
+        *self.kernel_releases.lock().unwrap() = Some(Default::default());
+    }
+
+    /// Returns the IDs of locations added since the last call.
+    pub fn take_unsymbolized(&self) -> Vec<String> {
+        match self.unsymbolized.lock().unwrap().as_mut() {
+            Some(ids) => std::mem::take(ids),
+            None => vec![],
+        }
+    }
+
+    /// Records the release of a kernel that profiles were ingested from, so
+    /// kernel frames can be symbolized for it after ingestion.
+    pub fn track_kernel_release(&self, release: &str) {
+        if let Some((seen, new)) = self.kernel_releases.lock().unwrap().as_mut() {
+            if seen.insert(release.to_string()) {
+                new.push(release.to_string());
+            }
+        }
+    }
+
+    /// Returns the kernel releases seen for the first time since the last
+    /// call.
+    pub fn take_kernel_releases(&self) -> Vec<String> {
+        match self.kernel_releases.lock().unwrap().as_mut() {
+            Some((_, new)) => std::mem::take(new),
+            None => vec![],
+        }
+    }
+
+    /// Returns the lines a location was symbolized to after ingestion. Kernel
+    /// frames resolve differently for every kernel, so their lines are kept
+    /// per release.
+    pub fn symbolized(
+        &self,
+        id: &str,
+        kernel_release: Option<&str>,
+    ) -> Option<Arc<Vec<LocationLine>>> {
+        let symbolized = self.symbolized.read().unwrap();
+        symbolized.get(id).cloned().or_else(|| {
+            kernel_release.and_then(|release| {
+                symbolized
+                    .get(&Self::symbolized_key(id, Some(release)))
+                    .cloned()
+            })
+        })
+    }
+
+    /// Stores the lines a location was symbolized to, for the given kernel
+    /// release if it is a kernel frame. They are persisted with the next
+    /// flush. A location that resolved to nothing isn't stored, so it is
+    /// still symbolized at query time.
+    pub fn insert_symbolized(
+        &self,
+        id: &str,
+        kernel_release: Option<&str>,
+        lines: Vec<LocationLine>,
+    ) {
+        if lines.is_empty() {
+            return;
+        }
+
+        let key = Self::symbolized_key(id, kernel_release);
+        self.symbolized
+            .write()
+            .unwrap()
+            .insert(key.clone(), Arc::new(lines.clone()));
+        self.pending_symbolized
+            .lock()
+            .unwrap()
+            .locations
+            .push((key, lines));
+    }
+
+    /// Drops the lines of every location that matches, in memory and in the
+    /// persisted segments, and tracks those locations as unsymbolized again.
+    /// Returns the IDs of the locations whose lines were dropped.
+    pub async fn purge_symbolized(
+        &self,
+        matches: impl Fn(&[u8]) -> bool,
+    ) -> anyhow::Result<HashSet<String>> {
+        // No segment is written while the persisted ones are rewritten.
+        let _guard = self.flush_lock.lock().await;
+
+        let matching = self.matching_locations(&matches).await?;
+        let mut purged = HashSet::new();
+        self.symbolized.write().unwrap().retain(|key, _| {
+            let id = Self::symbolized_id(key);
+            if matching.contains(id) {
+                purged.insert(id.to_string());
+                return false;
+            }
+            true
+        });
+        self.pending_symbolized
+            .lock()
+            .unwrap()
+            .locations
+            .retain(|(key, _)| !purged.contains(Self::symbolized_id(key)));
+        if purged.is_empty() {
+            return Ok(purged);
+        }
+
+        let prefix = Path::from(SYMBOLIZED_PREFIX);
+        let mut paths = vec![];
+        {
+            let mut segments = self.bucket.list(Some(&prefix));
+            while let Some(meta) = segments.next().await {
+                paths.push(meta?.location);
+            }
+        }
+        for path in paths {
+            let data = self.bucket.get(&path).await?.bytes().await?;
+            let mut segment: SymbolizedSegment = bincode::deserialize(&data)
+                .with_context(|| format!("Failed to decode symbolized segment {}", path))?;
+            let len = segment.locations.len();
+            segment
+                .locations
+                .retain(|(key, _)| !purged.contains(Self::symbolized_id(key)));
+            if segment.locations.len() == len {
+                continue;
+            }
+
+            // Segments are rewritten in place, so they are loaded in the
+            // same order as before.
+            if segment.locations.is_empty() {
+                self.bucket.delete(&path).await?;
+            } else {
+                let buf = bincode::serialize(&segment)?;
+                self.bucket.put(&path, buf.into()).await?;
+            }
+        }
+
+        if let Some(unsymbolized) = self.unsymbolized.lock().unwrap().as_mut() {
+            unsymbolized.extend(purged.iter().cloned());
+        }
+        Ok(purged)
+    }
+
+    fn symbolized_key(id: &str, kernel_release: Option<&str>) -> String {
+        match kernel_release {
+            Some(release) => format!("{}/{}", id, release),
+            None => id.to_string(),
+        }
+    }
+
+    /// Returns the location ID of a key of symbolized lines.
+    fn symbolized_id(key: &str) -> &str {
+        key.split_once('/').map_or(key, |(id, _)| id)
+    }
+
+    /// Persists every entry added since the last flush as a new segment. This
+    /// must complete before any data referencing those entries is persisted.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let _guard = self.flush_lock.lock().await;
+        self.flush_symbolized().await?;
+
+        // Entries stay pending until they are indexed to their segment, so
+        // that they can be looked up all along, and are retried with the
+        // next flush if the segment can't be written.
+        let segment = self.pending.read().unwrap().to_segment();
+        if segment.is_empty() {
+            return Ok(());
+        }
+
+        let (stacktraces, locations) = (segment.stacktraces.len(), segment.locations.len());
+        let path = self.write_segment(segment).await?;
+        log::info!(
+            "Persisted stacktrace segment to {}: {} stacktraces, {} locations",
+            path,
+            stacktraces,
+            locations,
+        );
+
+        self.compact().await
+    }
+
+    async fn flush_symbolized(&self) -> anyhow::Result<()> {
+        let segment = std::mem::take(&mut *self.pending_symbolized.lock().unwrap());
+        if segment.locations.is_empty() {
+            return Ok(());
+        }
+
+        let path = Path::from(format!("{}/{}.bin", SYMBOLIZED_PREFIX, ulid::Ulid::new()));
+        let buf = bincode::serialize(&segment)?;
+
+        if let Err(e) = self.bucket.put(&path, buf.into()).await {
+            let mut pending = self.pending_symbolized.lock().unwrap();
+            pending.locations.extend(segment.locations);
+            return Err(e.into());
+        }
+
+        log::info!(
+            "Persisted symbolized segment to {}: {} locations",
+            path,
+            segment.locations.len(),
+        );
+        Ok(())
+    }
+
+    /// Merges the small segments into one, once there are enough of them.
+    /// The flush lock must be held, or the store not shared yet.
+    async fn compact(&self) -> anyhow::Result<()> {
+        let small: Vec<(u32, Path)> = {
+            let index = self.index.read().unwrap();
+            let mut small: Vec<(u32, Path)> = index
+                .segments
+                .iter()
+                .filter(|(_, info)| info.size < COMPACTED_SEGMENT_SIZE)
+                .map(|(number, info)| (*number, info.path.clone()))
+                .collect();
+            small.sort();
+            small
+        };
+        if small.len() < MAX_SMALL_SEGMENTS {
+            return Ok(());
+        }
+
+        // Later segments win, as when loading.
+        let mut entries = Entries::default();
+        for (_, path) in small.iter() {
+            let (segment, _) = self.read_segment(path).await?;
+            let segment = Entries::from_segment(segment);
+            entries.locations.extend(segment.locations);
+            entries.stacktraces.extend(segment.stacktraces);
+        }
+        let path = self.write_segment(entries.to_segment()).await?;
+
+        {
+            let mut index = self.index.write().unwrap();
+            for (number, _) in small.iter() {
+                index.segments.remove(number);
+            }
+        }
+        for (number, path) in small.iter() {
+            self.segments.invalidate(number);
+            self.bucket.delete(path).await?;
+        }
+
+        log::info!(
+            "Compacted {} stacktrace segments into {}",
+            small.len(),
+            path
+        );
+        Ok(())
+    }
+
+    /// Persists the segment and indexes its entries to it. Entries that were
+    /// pending are not anymore.
+    async fn write_segment(&self, segment: Segment) -> anyhow::Result<Path> {
+        let path = Path::from(format!("{}/{}.bin", SEGMENTS_PREFIX, ulid::Ulid::new()));
+        let buf = bincode::serialize(&segment)?;
+        let size = buf.len();
+        self.bucket.put(&path, buf.into()).await?;
+
+        let entries = Arc::new(Entries::from_segment(segment));
+        self.register(path.clone(), size, Arc::clone(&entries));
+
+        let mut pending = self.pending.write().unwrap();
+        for key in entries.locations.keys() {
+            if let Some(location) = pending.locations.remove(key) {
+                pending.size -= location.len();
+            }
+        }
+        for key in entries.stacktraces.keys() {
+            if let Some(location_ids) = pending.stacktraces.remove(key) {
+                pending.size -= location_ids.len() * 32;
+            }
+        }
+        Ok(path)
+    }
+
+    /// Indexes the entries to the segment, and caches them.
+    fn register(&self, path: Path, size: usize, entries: Arc<Entries>) {
+        let mut index = self.index.write().unwrap();
+        let number = index.next_segment;
+        index.next_segment += 1;
+        index.segments.insert(number, SegmentInfo { path, size });
+        for key in entries.locations.keys() {
+            index.locations.insert(*key, number);
+        }
+        for key in entries.stacktraces.keys() {
+            index.stacktraces.insert(*key, number);
+        }
+        self.segments.insert(number, entries);
+    }
+
+    /// Reads a segment from the bucket, and returns it with its size.
+    async fn read_segment(&self, path: &Path) -> anyhow::Result<(Segment, usize)> {
+        let data = self.bucket.get(path).await?.bytes().await?;
+        let segment = bincode::deserialize(&data)
+            .with_context(|| format!("Failed to decode stacktrace segment {}", path))?;
+        Ok((segment, data.len()))
+    }
+
+    /// Returns the IDs of the locations that match, reading every persisted
+    /// segment that isn't cached.
+    async fn matching_locations(
+        &self,
+        matches: &impl Fn(&[u8]) -> bool,
+    ) -> anyhow::Result<HashSet<String>> {
+        let mut matching: HashSet<String> = self
+            .pending
+            .read()
+            .unwrap()
+            .locations
+            .iter()
+            .filter(|(_, location)| matches(location))
+            .map(|(key, _)| format_id(*key))
+            .collect();
+
+        let segments: Vec<(u32, Path)> = {
+            let index = self.index.read().unwrap();
+            index
+                .segments
+                .iter()
+                .map(|(number, info)| (*number, info.path.clone()))
+                .collect()
+        };
+        for (number, path) in segments {
+            // Segments that aren't cached are read without being cached, so
+            // that the ones in use aren't evicted.
+            let entries = match self.segments.get(&number) {
+                Some(entries) => entries,
+                None => Arc::new(Entries::from_segment(self.read_segment(&path).await?.0)),
+            };
+            matching.extend(
+                entries
+                    .locations
+                    .iter()
+                    .filter(|(_, location)| matches(location))
+                    .map(|(key, _)| format_id(*key)),
+            );
+        }
+        Ok(matching)
+    }
+
+    pub(crate) fn location_id(location: &[u8]) -> String {
+        format!("{:032x}", xxh3_128(location))
+    }
+
+    fn stacktrace_id(location_ids: &[String]) -> String {
+        let mut buf = Vec::with_capacity(location_ids.len() * 32);
+        for id in location_ids {
+            buf.extend_from_slice(id.as_bytes());
+        }
+        format!("{:032x}", xxh3_128(&buf))
+    }
+}
+
+fn segment_cache(size: u64) -> Cache<u32, Arc<Entries>> {
+    Cache::builder()
+        .max_capacity(size)
+        .weigher(|_, entries: &Arc<Entries>| entries.size.try_into().unwrap_or(u32::MAX))
+        .build()
+}
+
+fn parse_id(id: &str) -> Option<u128> {
+    match id.len() {
+        32 => u128::from_str_radix(id, 16).ok(),
+        _ => None,
+    }
+}
+
+fn format_id(key: u128) -> String {
+    format!("{:032x}", key)
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<object_store::Error>(),
+        Some(object_store::Error::NotFound { .. })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage;
+
+    #[tokio::test]
+    async fn test_insert_deduplicates() {
+        let store = StacktraceStore::new(Arc::new(storage::new_memory_bucket()));
+
+        let a = store.insert(&[vec![1, 2, 3], vec![4, 5, 6]]);
+        let b = store.insert(&[vec![1, 2, 3], vec![4, 5, 6]]);
+        let c = store.insert(&[vec![4, 5, 6], vec![1, 2, 3]]);
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(store.pending.read().unwrap().stacktraces.len(), 2);
+        assert_eq!(store.pending.read().unwrap().locations.len(), 2);
+
+        let location_ids = store.stacktrace(&a).await.unwrap().unwrap();
+        assert_eq!(location_ids.len(), 2);
+        assert_eq!(
+            store
+                .location(&location_ids[0])
+                .await
+                .unwrap()
+                .unwrap()
+                .as_slice(),
+            &[1, 2, 3]
+        );
+
+        // Entries are deduplicated against the persisted ones too.
+        store.flush().await.unwrap();
+        store.insert(&[vec![1, 2, 3], vec![4, 5, 6]]);
+        assert!(store.pending.read().unwrap().stacktraces.is_empty());
+        assert!(store.pending.read().unwrap().locations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_flush_and_load() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let store = StacktraceStore::new(Arc::clone(&bucket));
+
+        let id = store.insert(&[vec![1, 2, 3], vec![4, 5, 6]]);
+        store.flush().await.unwrap();
+        assert!(store.pending.read().unwrap().to_segment().is_empty());
+
+        let loaded = StacktraceStore::load(bucket).await.unwrap();
+        let location_ids = loaded.stacktrace(&id).await.unwrap().unwrap();
+        assert_eq!(
+            loaded
+                .location(&location_ids[1])
+                .await
+                .unwrap()
+                .unwrap()
+                .as_slice(),
+            &[4, 5, 6]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_evicted_segments() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let mut store = StacktraceStore::new(Arc::clone(&bucket));
+        store.segments = segment_cache(1);
+
+        let id = store.insert(&[vec![1, 2, 3], vec![4, 5, 6]]);
+        store.flush().await.unwrap();
+        store.segments.run_pending_tasks();
+        assert_eq!(store.segments.entry_count(), 0);
+
+        // Evicted entries are read back from their segment.
+        let location_ids = store.stacktrace(&id).await.unwrap().unwrap();
+        assert_eq!(
+            store
+                .location(&location_ids[0])
+                .await
+                .unwrap()
+                .unwrap()
+                .as_slice(),
+            &[1, 2, 3]
+        );
+        assert!(store
+            .location(&StacktraceStore::location_id(&[7]))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let store = StacktraceStore::new(Arc::clone(&bucket));
+
+        let mut ids = vec![];
+        for i in 0..MAX_SMALL_SEGMENTS as u8 {
+            ids.push(store.insert(&[vec![i], vec![i, i]]));
+            store.flush().await.unwrap();
+        }
+
+        let prefix = Path::from(SEGMENTS_PREFIX);
+        let segments: Vec<_> = bucket.list(Some(&prefix)).collect().await;
+        assert_eq!(segments.len(), 1);
+        assert_eq!(store.index.read().unwrap().segments.len(), 1);
+
+        let loaded = StacktraceStore::load(bucket).await.unwrap();
+        for (i, id) in ids.iter().enumerate() {
+            let location_ids = loaded.stacktrace(id).await.unwrap().unwrap();
+            assert_eq!(
+                loaded
+                    .location(&location_ids[1])
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .as_slice(),
+                &[i as u8, i as u8]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_symbolized() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let store = StacktraceStore::new(Arc::clone(&bucket));
+
+        let id = store.insert(&[vec![1, 2, 3]]);
+        store.track_unsymbolized();
+        store.insert(&[vec![1, 2, 3], vec![4, 5, 6]]);
+        let location_ids = store.stacktrace(&id).await.unwrap().unwrap();
+
+        assert_eq!(store.take_unsymbolized().len(), 2);
+        assert!(store.take_unsymbolized().is_empty());
+
+        store.insert_symbolized(
+            &location_ids[0],
+            None,
+            vec![LocationLine {
+                line: 7,
+                function: None,
+            }],
+        );
+        store.flush().await.unwrap();
+
+        let loaded = StacktraceStore::load(bucket).await.unwrap();
+        assert_eq!(
+            loaded.symbolized(&location_ids[0], None).unwrap()[0].line,
+            7
+        );
+
+        // Only the location that wasn't symbolized is left to do.
+        loaded.track_unsymbolized();
+        assert_eq!(loaded.take_unsymbolized().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_purge_symbolized() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let store = StacktraceStore::new(Arc::clone(&bucket));
+        let id = store.insert(&[vec![1, 2, 3], vec![4, 5, 6]]);
+        let location_ids = store.stacktrace(&id).await.unwrap().unwrap();
+        let lines = vec![LocationLine {
+            line: 7,
+            function: None,
+        }];
+
+        store.insert_symbolized(&location_ids[0], None, lines.clone());
+        store.insert_symbolized(&location_ids[1], None, lines.clone());
+        store.flush().await.unwrap();
+        store.insert_symbolized(&location_ids[0], Some("6.8.0-45-generic"), lines);
+        store.track_unsymbolized();
+
+        let purged = store
+            .purge_symbolized(|location| location == [1, 2, 3])
+            .await
+            .unwrap();
+        assert_eq!(purged, HashSet::from([location_ids[0].clone()]));
+        assert!(store.symbolized(&location_ids[0], None).is_none());
+        assert!(store
+            .symbolized(&location_ids[0], Some("6.8.0-45-generic"))
+            .is_none());
+        assert_eq!(store.take_unsymbolized(), vec![location_ids[0].clone()]);
+
+        // The lines stay dropped across restarts.
+        store.flush().await.unwrap();
+        let loaded = StacktraceStore::load(bucket).await.unwrap();
+        assert!(loaded.symbolized(&location_ids[0], None).is_none());
+        assert!(loaded.symbolized(&location_ids[1], None).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_symbolized_kernel() {
+        let store = StacktraceStore::new(Arc::new(storage::new_memory_bucket()));
+        let id = store.insert(&[vec![1, 2, 3]]);
+        let location_id = store.stacktrace(&id).await.unwrap().unwrap()[0].clone();
+
+        store.track_kernel_release("6.8.0-45-generic");
+        assert!(store.take_kernel_releases().is_empty());
+
+        store.track_unsymbolized();
+        store.track_kernel_release("6.8.0-45-generic");
+        store.track_kernel_release("6.8.0-45-generic");
+        assert_eq!(store.take_kernel_releases(), vec!["6.8.0-45-generic"]);
+        store.track_kernel_release("6.8.0-45-generic");
+        assert!(store.take_kernel_releases().is_empty());
+
+        store.insert_symbolized(
+            &location_id,
+            Some("6.8.0-45-generic"),
+            vec![LocationLine {
+                line: 7,
+                function: None,
+            }],
+        );
+        assert!(store.symbolized(&location_id, None).is_none());
+        assert!(store
+            .symbolized(&location_id, Some("6.8.0-40-generic"))
+            .is_none());
+        assert_eq!(
+            store
+                .symbolized(&location_id, Some("6.8.0-45-generic"))
+                .unwrap()[0]
+                .line,
+            7
+        );
+    }
+
+    #[tokio::test]
+    async fn test_insert_symbolized_skips_empty() {
+        let store = StacktraceStore::new(Arc::new(storage::new_memory_bucket()));
+        let id = store.insert(&[vec![1, 2, 3]]);
+        let location_id = store.stacktrace(&id).await.unwrap().unwrap()[0].clone();
+
+        store.insert_symbolized(&location_id, None, vec![]);
+        assert!(store.symbolized(&location_id, None).is_none());
+        assert!(store
+            .pending_symbolized
+            .lock()
+            .unwrap()
+            .locations
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_flushes() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let store = Arc::new(StacktraceStore::new(Arc::clone(&bucket)));
+
+        // Every flush returns only once the entries inserted before it are
+        // persisted, whichever flush took them.
+        let mut tasks = vec![];
+        for i in 0..16u8 {
+            let store = Arc::clone(&store);
+            let bucket = Arc::clone(&bucket);
+            tasks.push(tokio::spawn(async move {
+                let id = store.insert(&[vec![i]]);
+                store.flush().await.unwrap();
+                let loaded = StacktraceStore::load(bucket).await.unwrap();
+                assert!(loaded.stacktrace(&id).await.unwrap().is_some());
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+}
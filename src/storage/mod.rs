@@ -3,3 +3,32 @@ use object_store::{memory::InMemory, ObjectStore};
 pub fn new_memory_bucket() -> impl ObjectStore {
     InMemory::new()
 }
+
+/// Opens a bucket that can sign upload URLs, eg. s3://bucket or gs://bucket,
+/// with credentials from the environment.
+#[cfg(feature = "cloud")]
+pub fn new_signing_bucket(
+    url: &str,
+) -> anyhow::Result<(
+    std::sync::Arc<dyn ObjectStore>,
+    std::sync::Arc<dyn object_store::signer::Signer>,
+)> {
+    use object_store::{aws::AmazonS3Builder, gcp::GoogleCloudStorageBuilder};
+    use std::sync::Arc;
+
+    match url.split_once("://") {
+        Some(("s3", _)) => {
+            let bucket = Arc::new(AmazonS3Builder::from_env().with_url(url).build()?);
+            Ok((bucket.clone(), bucket))
+        }
+        Some(("gs", _)) => {
+            let bucket = Arc::new(
+                GoogleCloudStorageBuilder::from_env()
+                    .with_url(url)
+                    .build()?,
+            );
+            Ok((bucket.clone(), bucket))
+        }
+        _ => anyhow::bail!("Bucket {} can't sign upload URLs", url),
+    }
+}
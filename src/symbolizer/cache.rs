@@ -1,11 +1,96 @@
+use crate::debuginfo_store::buildid;
 use crate::profile::LocationLine;
 
 use super::normalize::NormalizedAddress;
+use anyhow::Context;
 use moka::sync::Cache;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+/// Configuration of the SymbolizerCache.
+#[derive(Debug, Clone)]
+pub struct SymbolizerCacheConfig {
+    /// Number of addresses kept in memory.
+    pub capacity: u64,
+    /// How long an address is kept in memory, and how long a build ID's file
+    /// is kept on disk after it was created.
+    pub ttl: Duration,
+    /// Directory of the on-disk tier, which is disabled when None.
+    pub dir: Option<PathBuf>,
+    /// Number of build IDs whose on-disk entries are kept loaded.
+    pub loaded_build_ids: u64,
+    /// Total size of the files of the on-disk tier. The least recently
+    /// written ones are removed beyond it.
+    pub max_disk_size: u64,
+}
+
+impl Default for SymbolizerCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            dir: None,
+            loaded_build_ids: 64,
+            max_disk_size: 1 << 30,
+        }
+    }
+}
+
+/// CacheMetrics counts the lookups of the SymbolizerCache.
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    pub memory_hits: AtomicU64,
+    pub disk_hits: AtomicU64,
+    pub misses: AtomicU64,
+    /// Failures to read or write the on-disk tier, which are otherwise
+    /// treated as misses.
+    pub disk_errors: AtomicU64,
+}
+
+impl CacheMetrics {
+    /// Renders the counters in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut out = String::from(
+            "# HELP evprofiler_symbolizer_cache_lookups_total Lookups in the symbolizer cache by outcome.\n\
+             # TYPE evprofiler_symbolizer_cache_lookups_total counter\n",
+        );
+        for (outcome, counter) in [
+            ("memory_hit", &self.memory_hits),
+            ("disk_hit", &self.disk_hits),
+            ("miss", &self.misses),
+        ] {
+            out.push_str(&format!(
+                "evprofiler_symbolizer_cache_lookups_total{{outcome=\"{}\"}} {}\n",
+                outcome,
+                counter.load(Ordering::Relaxed)
+            ));
+        }
+        out.push_str(&format!(
+            "# HELP evprofiler_symbolizer_cache_disk_errors_total Failures to read or write the on-disk tier of the symbolizer cache.\n\
+             # TYPE evprofiler_symbolizer_cache_disk_errors_total counter\n\
+             evprofiler_symbolizer_cache_disk_errors_total {}\n",
+            self.disk_errors.load(Ordering::Relaxed)
+        ));
+        out
+    }
+}
+
+/// SymbolizerCache keeps the lines of symbolized addresses. It has a memory
+/// tier in front of an optional on-disk tier that survives restarts.
+///
+/// Lines are cached per version of a build ID's files, which changes when
+/// better debuginfo is uploaded for it, so that they aren't stuck with what
+/// the previous files resolved them to.
 #[derive(Debug, Clone)]
 pub struct SymbolizerCache {
     pub(crate) c: Cache<Vec<u8>, Vec<Vec<u8>>>,
+    disk: Option<Arc<DiskTier>>,
+    metrics: Arc<CacheMetrics>,
 }
 
 impl Default for SymbolizerCache {
@@ -16,42 +101,131 @@ impl Default for SymbolizerCache {
 
 impl SymbolizerCache {
     pub fn new(cap: u64) -> Self {
-        let c = Cache::new(cap);
-        Self { c }
+        let c = Cache::builder()
+            .max_capacity(cap)
+            .support_invalidation_closures()
+            .build();
+        Self {
+            c,
+            disk: None,
+            metrics: Arc::new(CacheMetrics::default()),
+        }
+    }
+
+    pub fn with_config(config: SymbolizerCacheConfig) -> anyhow::Result<Self> {
+        let c = Cache::builder()
+            .max_capacity(config.capacity)
+            .time_to_live(config.ttl)
+            .support_invalidation_closures()
+            .build();
+        let disk = match config.dir {
+            Some(dir) => Some(Arc::new(DiskTier::new(
+                dir,
+                config.ttl,
+                config.loaded_build_ids,
+                config.max_disk_size,
+            )?)),
+            None => None,
+        };
+
+        Ok(Self {
+            c,
+            disk,
+            metrics: Arc::new(CacheMetrics::default()),
+        })
+    }
+
+    pub fn metrics(&self) -> Arc<CacheMetrics> {
+        Arc::clone(&self.metrics)
     }
 
     pub fn get(
         &self,
         build_id: &str,
+        version: &str,
         addr: &NormalizedAddress,
     ) -> anyhow::Result<Option<Vec<LocationLine>>> {
-        let key = Self::build_cache_key(build_id, addr);
-        let ll = match self.c.get(&key) {
+        let key = Self::build_cache_key(build_id, version, addr);
+        if let Some(ll) = self.c.get(&key) {
+            self.metrics.memory_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(Self::decode(&ll)?));
+        }
+
+        let ll = match &self.disk {
+            Some(disk) => disk.get(build_id, version, addr.0).unwrap_or_else(|e| {
+                log::warn!("Failed to read symbolizer cache of {}: {}", build_id, e);
+                self.metrics.disk_errors.fetch_add(1, Ordering::Relaxed);
+                None
+            }),
+            None => None,
+        };
+        let ll = match ll {
             Some(ll) => ll,
-            None => return Ok(None),
+            None => {
+                self.metrics.misses.fetch_add(1, Ordering::Relaxed);
+                return Ok(None);
+            }
         };
-        Ok(Some(Self::decode(&ll)?))
+
+        self.metrics.disk_hits.fetch_add(1, Ordering::Relaxed);
+        let res = Self::decode(&ll)?;
+        self.c.insert(key, ll);
+        Ok(Some(res))
     }
 
-    pub fn set(
+    /// Caches the lines of addresses of the build ID, which the on-disk tier
+    /// writes at once. Addresses that resolved to nothing aren't cached, as
+    /// they may well resolve once the debuginfo is there.
+    pub fn set_all(
         &self,
         build_id: &str,
-        addr: &NormalizedAddress,
-        ll: Vec<LocationLine>,
+        version: &str,
+        lines: Vec<(NormalizedAddress, Vec<LocationLine>)>,
     ) -> anyhow::Result<()> {
-        let key = Self::build_cache_key(build_id, addr);
-        let mut encoded = vec![];
+        let mut records = vec![];
+        for (addr, ll) in lines {
+            if ll.is_empty() {
+                continue;
+            }
+            let mut encoded = vec![];
+            for line in ll.iter() {
+                encoded.push(line.encode()?);
+            }
+            records.push((addr.0, encoded));
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
 
-        for line in ll.iter() {
-            encoded.push(line.encode()?);
+        if let Some(disk) = &self.disk {
+            if let Err(e) = disk.set(build_id, version, &records) {
+                log::warn!("Failed to write symbolizer cache of {}: {}", build_id, e);
+                self.metrics.disk_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        for (addr, encoded) in records {
+            let key = Self::build_cache_key(build_id, version, &NormalizedAddress(addr));
+            self.c.insert(key, encoded);
         }
+        Ok(())
+    }
 
-        self.c.insert(key, encoded);
+    /// Drops the lines of every address of the build ID, from both tiers.
+    pub fn invalidate(&self, build_id: &str) -> anyhow::Result<()> {
+        let prefix = format!("{}/", build_id).into_bytes();
+        self.c
+            .invalidate_entries_if(move |key, _| key.starts_with(&prefix))?;
+        if let Some(disk) = &self.disk {
+            disk.remove(build_id)?;
+        }
         Ok(())
     }
 
-    fn build_cache_key(build_id: &str, addr: &NormalizedAddress) -> Vec<u8> {
-        format!("{}/0x{}", build_id, addr.0).as_bytes().to_vec()
+    fn build_cache_key(build_id: &str, version: &str, addr: &NormalizedAddress) -> Vec<u8> {
+        format!("{}/{}/0x{}", build_id, version, addr.0)
+            .as_bytes()
+            .to_vec()
     }
 
     fn decode(ll: &[Vec<u8>]) -> anyhow::Result<Vec<LocationLine>> {
@@ -64,3 +238,708 @@ impl SymbolizerCache {
         Ok(res)
     }
 }
+
+type Entries = BTreeMap<u64, Vec<Vec<u8>>>;
+
+/// Header starts every file of the on-disk tier.
+#[derive(Debug, Clone, PartialEq)]
+struct Header {
+    /// Seconds since the epoch the file was created at. Appending to the file
+    /// doesn't change it, so a file expires even if it is still written to.
+    created: u64,
+    /// Version of the build ID's files the lines were resolved with.
+    version: String,
+}
+
+impl Header {
+    fn new(version: &str) -> Self {
+        Self {
+            created: unix_now(),
+            version: version.to_string(),
+        }
+    }
+
+    fn expired(&self, ttl: Duration) -> bool {
+        unix_now().saturating_sub(self.created) > ttl.as_secs()
+    }
+}
+
+/// DiskFile is the loaded content of a build ID's file, without a header if
+/// there is no file.
+#[derive(Debug, Default)]
+struct DiskFile {
+    header: Option<Header>,
+    entries: RwLock<Entries>,
+}
+
+impl DiskFile {
+    /// Returns whether the file holds lines of the version that are still
+    /// fresh.
+    fn is_current(&self, version: &str, ttl: Duration) -> bool {
+        self.header
+            .as_ref()
+            .is_some_and(|header| header.version == version && !header.expired(ttl))
+    }
+}
+
+/// DiskTier keeps one file per build ID, holding its addresses sorted. New
+/// addresses are appended, and the file is sorted again the next time it is
+/// loaded. A file is started over when the build ID's files change, or when
+/// it expires.
+#[derive(Debug)]
+struct DiskTier {
+    dir: PathBuf,
+    ttl: Duration,
+    max_size: u64,
+    loaded: Cache<String, Arc<DiskFile>>,
+    // Serializes writes, so that appends don't interleave with each other or
+    // with a file being rewritten, and holds the total size of the files.
+    size: Mutex<u64>,
+}
+
+impl DiskTier {
+    fn new(
+        dir: PathBuf,
+        ttl: Duration,
+        loaded_build_ids: u64,
+        max_size: u64,
+    ) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let tier = Self {
+            dir,
+            ttl,
+            max_size,
+            loaded: Cache::new(loaded_build_ids),
+            size: Mutex::new(0),
+        };
+        let mut size = tier.size.lock().unwrap();
+        *size = tier.remove_expired();
+        tier.evict(&mut size);
+        drop(size);
+        Ok(tier)
+    }
+
+    fn get(
+        &self,
+        build_id: &str,
+        version: &str,
+        addr: u64,
+    ) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+        let file = match self.file(build_id)? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        if !file.is_current(version, self.ttl) {
+            return Ok(None);
+        }
+        let entries = file.entries.read().unwrap();
+        Ok(entries.get(&addr).cloned())
+    }
+
+    /// Writes the lines of the addresses, appending them to the file of the
+    /// build ID at once.
+    fn set(
+        &self,
+        build_id: &str,
+        version: &str,
+        lines: &[(u64, Vec<Vec<u8>>)],
+    ) -> anyhow::Result<()> {
+        let path = match self.path(build_id) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        // Versions that wouldn't be read back aren't written.
+        if version.len() > MAX_VERSION_LEN {
+            return Ok(());
+        }
+        // Loads the file first, as loading takes the write lock too.
+        self.file(build_id)?;
+
+        let mut records = vec![];
+        for (addr, ll) in lines.iter() {
+            encode_record(&mut records, *addr, ll);
+        }
+
+        let mut size = self.size.lock().unwrap();
+        let file = self.loaded.get(build_id);
+        match file {
+            Some(file) if file.is_current(version, self.ttl) => {
+                let mut entries = file.entries.write().unwrap();
+                let mut f = fs::OpenOptions::new().append(true).open(path)?;
+                f.write_all(&records)?;
+                *size += records.len() as u64;
+                entries.extend(lines.iter().cloned());
+            }
+            _ => {
+                let header = Header::new(version);
+                let mut data = vec![];
+                encode_header(&mut data, &header);
+                data.extend_from_slice(&records);
+                self.write(&mut size, &path, &data)?;
+
+                let file = DiskFile {
+                    header: Some(header),
+                    entries: RwLock::new(lines.iter().cloned().collect()),
+                };
+                self.loaded.insert(build_id.to_string(), Arc::new(file));
+            }
+        }
+        self.evict(&mut size);
+        Ok(())
+    }
+
+    fn file(&self, build_id: &str) -> anyhow::Result<Option<Arc<DiskFile>>> {
+        let path = match self.path(build_id) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        if let Some(file) = self.loaded.get(build_id) {
+            return Ok(Some(file));
+        }
+
+        let file = Arc::new(self.load(&path)?);
+        self.loaded.insert(build_id.to_string(), Arc::clone(&file));
+        Ok(Some(file))
+    }
+
+    fn load(&self, path: &Path) -> anyhow::Result<DiskFile> {
+        let mut size = self.size.lock().unwrap();
+
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(DiskFile::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut records = data.as_slice();
+        let (header, entries, sorted) = match decode_header(&mut records)
+            .and_then(|header| Some((header, decode_records(records)?)))
+        {
+            Some((header, _)) if header.expired(self.ttl) => {
+                self.remove_file(&mut size, path)?;
+                return Ok(DiskFile::default());
+            }
+            Some((header, (entries, sorted))) => (header, entries, sorted),
+            None => {
+                // A torn write, most likely from a crash. Whatever is in the
+                // file is thrown away, it's only a cache.
+                self.remove_file(&mut size, path)?;
+                return Ok(DiskFile::default());
+            }
+        };
+
+        if !sorted {
+            let mut data = Vec::with_capacity(data.len());
+            encode_header(&mut data, &header);
+            for (addr, ll) in entries.iter() {
+                encode_record(&mut data, *addr, ll);
+            }
+            self.write(&mut size, path, &data)?;
+        }
+
+        Ok(DiskFile {
+            header: Some(header),
+            entries: RwLock::new(entries),
+        })
+    }
+
+    /// Removes the file of the build ID.
+    fn remove(&self, build_id: &str) -> anyhow::Result<()> {
+        let path = match self.path(build_id) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut size = self.size.lock().unwrap();
+        self.loaded.invalidate(build_id);
+        self.remove_file(&mut size, &path)
+    }
+
+    /// Replaces the file at path with the data.
+    fn write(&self, size: &mut u64, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        let replaced = fs::metadata(path).map(|m| m.len()).unwrap_or_default();
+        let mut f = tempfile::NamedTempFile::new_in(&self.dir)?;
+        f.write_all(data)?;
+        f.persist(path)?;
+        *size = size.saturating_sub(replaced) + data.len() as u64;
+        Ok(())
+    }
+
+    fn remove_file(&self, size: &mut u64, path: &Path) -> anyhow::Result<()> {
+        let len = match fs::metadata(path) {
+            Ok(m) => m.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        *size = size.saturating_sub(len);
+        Ok(())
+    }
+
+    /// Removes the least recently written files once the files are over the
+    /// size bound, until they are under three quarters of it.
+    fn evict(&self, size: &mut u64) {
+        if *size <= self.max_size {
+            return;
+        }
+
+        let mut files = vec![];
+        match fs::read_dir(&self.dir) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    match entry.metadata() {
+                        Ok(m) if m.is_file() => {
+                            files.push((m.modified().unwrap_or(UNIX_EPOCH), m.len(), entry.path()))
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Err(e) => {
+                log::warn!("Failed to list the symbolizer cache: {}", e);
+                return;
+            }
+        }
+        files.sort();
+
+        let mut removed = HashSet::new();
+        for (_, len, path) in files {
+            if *size <= self.max_size / 4 * 3 {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    *size = size.saturating_sub(len);
+                    removed.insert(path);
+                }
+                Err(e) => log::warn!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+
+        // Loaded files that were removed are started over on the next write.
+        for (build_id, _) in self.loaded.iter() {
+            if self
+                .path(&build_id)
+                .is_some_and(|path| removed.contains(&path))
+            {
+                self.loaded.invalidate(build_id.as_str());
+            }
+        }
+        log::info!("Evicted {} files from the symbolizer cache", removed.len());
+    }
+
+    /// Removes the files that expired, or whose header can't be read, and
+    /// returns the size of the others. Files are otherwise only checked when
+    /// their build ID is looked up.
+    fn remove_expired(&self) -> u64 {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("Failed to list the symbolizer cache: {}", e);
+                return 0;
+            }
+        };
+
+        let mut size = 0;
+        for entry in entries {
+            match entry
+                .map_err(anyhow::Error::from)
+                .and_then(|entry| self.check_expired(&entry.path()))
+            {
+                Ok(len) => size += len,
+                Err(e) => log::warn!("Skipping file of the symbolizer cache: {:#}", e),
+            }
+        }
+        size
+    }
+
+    /// Removes the file if it expired, or if its header can't be read, and
+    /// returns its size otherwise.
+    fn check_expired(&self, path: &Path) -> anyhow::Result<u64> {
+        let m = fs::metadata(path)?;
+        if !m.is_file() {
+            return Ok(0);
+        }
+
+        let mut f = fs::File::open(path)?;
+        let mut buf = vec![0; HEADER_PREFIX_LEN];
+        let header = match f.read_exact(&mut buf) {
+            Ok(()) => {
+                let version_len = u32::from_le_bytes(buf[8..].try_into()?) as usize;
+                if version_len > MAX_VERSION_LEN {
+                    None
+                } else {
+                    buf.resize(HEADER_PREFIX_LEN + version_len, 0);
+                    f.read_exact(&mut buf[HEADER_PREFIX_LEN..])
+                        .ok()
+                        .and_then(|()| decode_header(&mut buf.as_slice()))
+                }
+            }
+            Err(_) => None,
+        };
+        if header.is_none_or(|header| header.expired(self.ttl)) {
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+            return Ok(0);
+        }
+        Ok(m.len())
+    }
+
+    /// Returns the file of the build ID, named after its object name.
+    fn path(&self, build_id: &str) -> Option<PathBuf> {
+        if build_id.is_empty() {
+            return None;
+        }
+        Some(self.dir.join(buildid::object_name(build_id).as_ref()))
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Length of the creation time and the length of the version that a header
+/// starts with.
+const HEADER_PREFIX_LEN: usize = 12;
+
+/// Versions name the uploads of every file a build ID is symbolized with,
+/// which stays far below this. The bound only guards against corrupt headers.
+const MAX_VERSION_LEN: usize = 1 << 20;
+
+/// Headers are laid out as the creation time, and then the version prefixed
+/// by its length, all little endian.
+fn encode_header(buf: &mut Vec<u8>, header: &Header) {
+    buf.extend_from_slice(&header.created.to_le_bytes());
+    buf.extend_from_slice(&(header.version.len() as u32).to_le_bytes());
+    buf.extend_from_slice(header.version.as_bytes());
+}
+
+fn decode_header(data: &mut &[u8]) -> Option<Header> {
+    if data.len() < HEADER_PREFIX_LEN {
+        return None;
+    }
+    let created = u64::from_le_bytes(data[..8].try_into().ok()?);
+    let version_len = u32::from_le_bytes(data[8..12].try_into().ok()?) as usize;
+    if version_len > MAX_VERSION_LEN {
+        return None;
+    }
+    let version = data.get(HEADER_PREFIX_LEN..HEADER_PREFIX_LEN + version_len)?;
+    let version = String::from_utf8(version.to_vec()).ok()?;
+    *data = &data[HEADER_PREFIX_LEN + version_len..];
+    Some(Header { created, version })
+}
+
+/// Records are laid out as the address, the number of lines and then each
+/// line prefixed by its length, all little endian.
+fn encode_record(buf: &mut Vec<u8>, addr: u64, ll: &[Vec<u8>]) {
+    buf.extend_from_slice(&addr.to_le_bytes());
+    buf.extend_from_slice(&(ll.len() as u32).to_le_bytes());
+    for line in ll.iter() {
+        buf.extend_from_slice(&(line.len() as u32).to_le_bytes());
+        buf.extend_from_slice(line);
+    }
+}
+
+/// Decodes the records, returning whether they were already sorted and
+/// unique, or None if the data is truncated.
+fn decode_records(mut data: &[u8]) -> Option<(Entries, bool)> {
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        if data.len() < n {
+            return None;
+        }
+        let (head, tail) = data.split_at(n);
+        *data = tail;
+        Some(head)
+    }
+    fn take_u32(data: &mut &[u8]) -> Option<u32> {
+        Some(u32::from_le_bytes(take(data, 4)?.try_into().ok()?))
+    }
+
+    let mut entries = Entries::new();
+    let mut sorted = true;
+    let mut last = None;
+
+    while !data.is_empty() {
+        let addr = u64::from_le_bytes(take(&mut data, 8)?.try_into().ok()?);
+        let mut ll = vec![];
+        for _ in 0..take_u32(&mut data)? {
+            let len = take_u32(&mut data)? as usize;
+            ll.push(take(&mut data, len)?.to_vec());
+        }
+
+        if last.is_some_and(|last| addr <= last) {
+            sorted = false;
+        }
+        last = Some(addr);
+        entries.insert(addr, ll);
+    }
+
+    Some((entries, sorted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metapb::Function;
+
+    fn lines(name: &str) -> Vec<LocationLine> {
+        vec![LocationLine {
+            line: 42,
+            function: Some(Function {
+                name: name.into(),
+                system_name: name.into(),
+                filename: "main.c".into(),
+                ..Default::default()
+            }),
+        }]
+    }
+
+    const VERSION: &str = "01J9Z3Q1V5XK8M2N4P6R8T0W2Y";
+
+    fn persistent(dir: &Path) -> SymbolizerCache {
+        SymbolizerCache::with_config(SymbolizerCacheConfig {
+            dir: Some(dir.to_path_buf()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_persistent_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_id = "126cf12e76810726c76d87ab8a6a57e9a1d4c815";
+
+        let cache = persistent(dir.path());
+        assert!(cache
+            .get(build_id, VERSION, &NormalizedAddress(2))
+            .unwrap()
+            .is_none());
+        cache
+            .set_all(build_id, VERSION, vec![(NormalizedAddress(2), lines("b"))])
+            .unwrap();
+        cache
+            .set_all(build_id, VERSION, vec![(NormalizedAddress(1), lines("a"))])
+            .unwrap();
+        assert_eq!(cache.metrics().misses.load(Ordering::Relaxed), 1);
+
+        // A new cache starts with an empty memory tier, as after a restart.
+        let cache = persistent(dir.path());
+        let ll = cache
+            .get(build_id, VERSION, &NormalizedAddress(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ll[0].function.as_ref().unwrap().name, "a");
+        let ll = cache
+            .get(build_id, VERSION, &NormalizedAddress(2))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ll[0].function.as_ref().unwrap().name, "b");
+        assert!(cache
+            .get(build_id, VERSION, &NormalizedAddress(3))
+            .unwrap()
+            .is_none());
+
+        assert!(cache
+            .get(build_id, VERSION, &NormalizedAddress(1))
+            .unwrap()
+            .is_some());
+        assert_eq!(cache.metrics().disk_hits.load(Ordering::Relaxed), 2);
+        assert_eq!(cache.metrics().memory_hits.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.metrics().misses.load(Ordering::Relaxed), 1);
+
+        // Loading sorted the file.
+        let data = fs::read(dir.path().join(build_id)).unwrap();
+        let mut records = data.as_slice();
+        assert_eq!(decode_header(&mut records).unwrap().version, VERSION);
+        let (entries, sorted) = decode_records(records).unwrap();
+        assert!(sorted);
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[test]
+    fn test_persistent_cache_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_id = "deadbeef";
+
+        let cache = persistent(dir.path());
+        cache
+            .set_all(build_id, VERSION, vec![(NormalizedAddress(1), lines("a"))])
+            .unwrap();
+        let path = dir.path().join(build_id);
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+
+        let cache = persistent(dir.path());
+        assert!(cache
+            .get(build_id, VERSION, &NormalizedAddress(1))
+            .unwrap()
+            .is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_persistent_cache_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_id = "deadbeef";
+
+        let cache = persistent(dir.path());
+        cache
+            .set_all(build_id, VERSION, vec![(NormalizedAddress(1), lines("a"))])
+            .unwrap();
+        cache
+            .set_all(build_id, VERSION, vec![(NormalizedAddress(2), vec![])])
+            .unwrap();
+
+        // Lines resolved with other files don't count, and are replaced.
+        let cache = persistent(dir.path());
+        let other = "01J9Z3Q1V5XK8M2N4P6R8T0W2Z";
+        assert!(cache
+            .get(build_id, other, &NormalizedAddress(1))
+            .unwrap()
+            .is_none());
+        cache
+            .set_all(build_id, other, vec![(NormalizedAddress(3), lines("c"))])
+            .unwrap();
+
+        let cache = persistent(dir.path());
+        assert!(cache
+            .get(build_id, VERSION, &NormalizedAddress(1))
+            .unwrap()
+            .is_none());
+        assert!(cache
+            .get(build_id, other, &NormalizedAddress(3))
+            .unwrap()
+            .is_some());
+        let data = fs::read(dir.path().join(build_id)).unwrap();
+        let mut records = data.as_slice();
+        decode_header(&mut records).unwrap();
+        let (entries, _) = decode_records(records).unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), vec![&3]);
+    }
+
+    #[test]
+    fn test_persistent_cache_expires_by_creation() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_id = "deadbeef";
+        let path = dir.path().join(build_id);
+
+        // A file created two weeks ago, and appended to just now.
+        let mut data = vec![];
+        encode_header(
+            &mut data,
+            &Header {
+                created: unix_now() - 14 * 24 * 60 * 60,
+                version: VERSION.into(),
+            },
+        );
+        encode_record(&mut data, 1, &[lines("a")[0].encode().unwrap()]);
+        fs::write(&path, &data).unwrap();
+
+        let cache = persistent(dir.path());
+        assert!(!path.exists());
+        assert!(cache
+            .get(build_id, VERSION, &NormalizedAddress(1))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_id = "126cf12e76810726c76d87ab8a6a57e9a1d4c815";
+        let other = "deadbeef";
+
+        let cache = persistent(dir.path());
+        for id in [build_id, other] {
+            cache
+                .set_all(id, VERSION, vec![(NormalizedAddress(1), lines("a"))])
+                .unwrap();
+        }
+        cache.invalidate(build_id).unwrap();
+        assert!(!dir.path().join(build_id).exists());
+        assert!(cache
+            .get(build_id, VERSION, &NormalizedAddress(1))
+            .unwrap()
+            .is_none());
+        assert!(cache
+            .get(other, VERSION, &NormalizedAddress(1))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_persistent_cache_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SymbolizerCacheConfig {
+            dir: Some(dir.path().to_path_buf()),
+            max_disk_size: 4096,
+            ..Default::default()
+        };
+        let cache = SymbolizerCache::with_config(config.clone()).unwrap();
+
+        // Every build ID writes its addresses at once, in a file of its own.
+        let build_ids: Vec<String> = (0..16).map(|i| format!("{:08x}", i)).collect();
+        for build_id in build_ids.iter() {
+            let lines = (0..4)
+                .map(|addr| (NormalizedAddress(addr), lines("a")))
+                .collect();
+            cache.set_all(build_id, VERSION, lines).unwrap();
+        }
+
+        let size = |dir: &Path| -> u64 {
+            fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().metadata().unwrap().len())
+                .sum()
+        };
+        assert!(size(dir.path()) <= 4096);
+        let last = build_ids.last().unwrap();
+        assert!(dir.path().join(last).exists());
+        assert!(!dir.path().join(&build_ids[0]).exists());
+        assert!(cache
+            .get(last, VERSION, &NormalizedAddress(3))
+            .unwrap()
+            .is_some());
+
+        // The bound holds for what is found on disk at startup too.
+        let config = SymbolizerCacheConfig {
+            max_disk_size: 1024,
+            ..config
+        };
+        SymbolizerCache::with_config(config).unwrap();
+        assert!(size(dir.path()) <= 1024);
+    }
+
+    #[test]
+    fn test_persistent_cache_corrupt_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deadbeef");
+
+        // A version length that would have the header read gigabytes.
+        let mut data = unix_now().to_le_bytes().to_vec();
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &data).unwrap();
+        // Entries that aren't files are skipped.
+        fs::create_dir(dir.path().join("dir")).unwrap();
+
+        persistent(dir.path());
+        assert!(!path.exists());
+        assert!(dir.path().join("dir").exists());
+    }
+
+    #[test]
+    fn test_metrics_encode() {
+        let cache = SymbolizerCache::default();
+        cache
+            .get("deadbeef", VERSION, &NormalizedAddress(1))
+            .unwrap();
+        let encoded = cache.metrics().encode();
+        assert!(encoded.contains("evprofiler_symbolizer_cache_lookups_total{outcome=\"miss\"} 1\n"));
+        assert!(encoded.contains("evprofiler_symbolizer_cache_disk_errors_total 0\n"));
+    }
+}
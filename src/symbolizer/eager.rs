@@ -0,0 +1,656 @@
+use super::{kernel, Symbolizer};
+use crate::{
+    debuginfo_store::admin,
+    profile::{Location, LocationLine, PprofLocations},
+    stacktrace_store::StacktraceStore,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long to wait before retrying a group that couldn't be symbolized.
+/// It doubles with every failed attempt, up to MAX_BACKOFF.
+const MIN_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Number of attempts after which a group is given up on, about a day's
+/// worth of retries. Locations that are given up on, or that don't fit in
+/// MAX_WAITING_LOCATIONS, are still symbolized at query time.
+const MAX_ATTEMPTS: u32 = 30;
+const MAX_WAITING_LOCATIONS: usize = 1_000_000;
+
+/// Number of kernel frames and of kernel releases that new ones are paired
+/// with. Beyond them, the oldest frames aren't symbolized for new releases,
+/// and new frames aren't symbolized for the oldest releases, which leaves
+/// them to query time.
+const MAX_KERNEL_LOCATIONS: usize = 100_000;
+const MAX_KERNEL_RELEASES: usize = 16;
+
+/// Group is a set of locations that are symbolized together.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Group {
+    User(String),
+    /// Kernel frames, which resolve differently for every kernel release.
+    Kernel {
+        release: String,
+        build_id: String,
+    },
+}
+
+impl Group {
+    fn kernel_release(&self) -> Option<&str> {
+        match self {
+            Group::User(_) => None,
+            Group::Kernel { release, .. } => Some(release),
+        }
+    }
+}
+
+/// Resolved holds the lines of the locations of a group that resolved, and
+/// the IDs of those that didn't. Frames in kernel modules are neither.
+#[derive(Debug, Default)]
+struct Resolved {
+    lines: Vec<(String, Vec<LocationLine>)>,
+    unresolved: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Waiting {
+    location_ids: Vec<String>,
+    next_attempt: Instant,
+    backoff: Duration,
+    attempts: u32,
+}
+
+/// Kernel frames and the kernel releases seen so far, the most recent ones
+/// up to MAX_KERNEL_LOCATIONS and MAX_KERNEL_RELEASES. Every kernel frame is
+/// symbolized for every release, as the locations don't say which kernels
+/// they came from.
+#[derive(Debug, Default)]
+struct KernelFrames {
+    /// IDs of kernel locations, with their build IDs.
+    locations: Vec<(String, String)>,
+    releases: Vec<String>,
+}
+
+/// EagerSymbolizer symbolizes locations in the background after they are
+/// ingested, and stores the lines with the stacktraces, so that queries
+/// don't have to symbolize them. Locations that don't resolve, most likely
+/// because their debuginfo isn't there yet, are retried for a while.
+///
+/// Kernel frames are symbolized for each kernel release profiles were
+/// ingested from. Frames in kernel modules are left to query time, as they
+/// are resolved to the module's mapping.
+#[derive(Debug)]
+pub struct EagerSymbolizer {
+    symbolizer: Arc<Symbolizer>,
+    stacktraces: Arc<StacktraceStore>,
+    waiting: Mutex<HashMap<Group, Waiting>>,
+    kernel: Mutex<KernelFrames>,
+    min_backoff: Duration,
+}
+
+impl EagerSymbolizer {
+    pub fn new(symbolizer: Arc<Symbolizer>, stacktraces: Arc<StacktraceStore>) -> Self {
+        stacktraces.track_unsymbolized();
+        Self {
+            symbolizer,
+            stacktraces,
+            waiting: Mutex::new(HashMap::new()),
+            kernel: Mutex::new(KernelFrames::default()),
+            min_backoff: MIN_BACKOFF,
+        }
+    }
+
+    /// Symbolizes new locations every interval, until the runtime shuts down.
+    pub fn spawn(self: Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match self.run_once().await {
+                    Ok(0) => (),
+                    Ok(n) => log::info!("Symbolized {} locations after ingestion", n),
+                    Err(e) => log::error!("Failed to symbolize after ingestion: {:#}", e),
+                }
+            }
+        })
+    }
+
+    /// Symbolizes the locations added since the last run, along with those
+    /// that are due for a retry, and returns how many were symbolized.
+    pub async fn run_once(&self) -> anyhow::Result<usize> {
+        let mut groups: HashMap<Group, Vec<String>> = HashMap::new();
+        let mut new_kernel_locations = vec![];
+        for id in self.stacktraces.take_unsymbolized() {
+            let location = match self.unsymbolized_location(&id).await? {
+                Some(location) => location,
+                None => continue,
+            };
+            if kernel::is_kernel_mapping(&location.file_name) {
+                new_kernel_locations.push((id, location.build_id));
+            } else if !location.build_id.is_empty() {
+                groups
+                    .entry(Group::User(location.build_id))
+                    .or_default()
+                    .push(id);
+            }
+        }
+        self.group_kernel_frames(
+            new_kernel_locations,
+            self.stacktraces.take_kernel_releases(),
+            &mut groups,
+        );
+
+        let mut retries = HashMap::new();
+        {
+            let mut waiting = self.waiting.lock().unwrap();
+            let now = Instant::now();
+            let due: Vec<Group> = waiting
+                .iter()
+                .filter(|(_, w)| w.next_attempt <= now)
+                .map(|(group, _)| group.clone())
+                .collect();
+            for group in due {
+                let w = waiting.remove(&group).unwrap();
+                groups
+                    .entry(group.clone())
+                    .or_default()
+                    .extend(w.location_ids);
+                retries.insert(group, (w.backoff, w.attempts));
+            }
+
+            // Groups that are still waiting keep their schedule.
+            let mut num_waiting = Self::num_waiting(&waiting);
+            groups.retain(|group, ids| match waiting.get_mut(group) {
+                Some(w) => {
+                    if num_waiting + ids.len() <= MAX_WAITING_LOCATIONS {
+                        num_waiting += ids.len();
+                        w.location_ids.append(ids);
+                    }
+                    false
+                }
+                None => true,
+            });
+        }
+
+        // Groups are symbolized concurrently, like at query time, and share
+        // the symbolizer's bound on concurrent build IDs.
+        let mut tasks = tokio::task::JoinSet::new();
+        for (group, ids) in groups {
+            let symbolizer = Arc::clone(&self.symbolizer);
+            let stacktraces = Arc::clone(&self.stacktraces);
+            tasks.spawn(async move {
+                let res = Self::symbolize_group(symbolizer, &stacktraces, &group, &ids).await;
+                (group, ids, res)
+            });
+        }
+
+        let mut symbolized = 0;
+        while let Some(res) = tasks.join_next().await {
+            let (group, ids, res) = res?;
+            let unresolved = match res {
+                Ok(resolved) => {
+                    for (id, lines) in resolved.lines {
+                        self.stacktraces
+                            .insert_symbolized(&id, group.kernel_release(), lines);
+                        symbolized += 1;
+                    }
+                    resolved.unresolved
+                }
+                Err(e) => {
+                    log::debug!("Failed to symbolize {:?}: {:#}", group, e);
+                    ids
+                }
+            };
+            if !unresolved.is_empty() {
+                let retry = retries.get(&group).copied();
+                self.wait(group, unresolved, retry);
+            }
+        }
+
+        if symbolized > 0 {
+            self.stacktraces.flush().await?;
+        }
+        Ok(symbolized)
+    }
+
+    /// Drops what the symbolizer keeps for the build ID, along with the lines
+    /// its locations were symbolized to, so that they are symbolized again
+    /// with whatever debuginfo it has now.
+    pub async fn purge(&self, build_id: &str) -> anyhow::Result<()> {
+        self.symbolizer.purge(build_id)?;
+        let purged = self
+            .stacktraces
+            .purge_symbolized(|encoded| {
+                PprofLocations::decode(encoded).is_ok_and(|location| location.build_id == build_id)
+            })
+            .await?;
+
+        // Kernel frames come back as new ones, with every release.
+        self.kernel
+            .lock()
+            .unwrap()
+            .locations
+            .retain(|(id, _)| !purged.contains(id));
+        log::info!(
+            "Purged the lines of {} locations of build_id {}",
+            purged.len(),
+            build_id
+        );
+        Ok(())
+    }
+
+    /// Schedules the locations of a group for another attempt, unless it
+    /// was attempted too many times already, or too many are waiting.
+    fn wait(&self, group: Group, location_ids: Vec<String>, retry: Option<(Duration, u32)>) {
+        let (backoff, attempts) = match retry {
+            Some((backoff, attempts)) => ((backoff * 2).min(MAX_BACKOFF), attempts + 1),
+            None => (self.min_backoff, 1),
+        };
+        if attempts >= MAX_ATTEMPTS {
+            log::debug!(
+                "Giving up on {} locations of {:?} after {} attempts",
+                location_ids.len(),
+                group,
+                attempts
+            );
+            return;
+        }
+
+        let mut waiting = self.waiting.lock().unwrap();
+        if Self::num_waiting(&waiting) + location_ids.len() > MAX_WAITING_LOCATIONS {
+            log::warn!(
+                "Too many locations waiting to be symbolized, leaving {} of {:?} to query time",
+                location_ids.len(),
+                group
+            );
+            return;
+        }
+        log::debug!(
+            "Retrying {} locations of {:?} in {:?}",
+            location_ids.len(),
+            group,
+            backoff
+        );
+        waiting.insert(
+            group,
+            Waiting {
+                location_ids,
+                next_attempt: Instant::now() + backoff,
+                backoff,
+                attempts,
+            },
+        );
+    }
+
+    fn num_waiting(waiting: &HashMap<Group, Waiting>) -> usize {
+        waiting.values().map(|w| w.location_ids.len()).sum()
+    }
+
+    /// Adds a group for every kernel release a kernel frame hasn't been
+    /// symbolized for yet: new frames for every release, and the frames seen
+    /// before for the new releases.
+    fn group_kernel_frames(
+        &self,
+        new_locations: Vec<(String, String)>,
+        new_releases: Vec<String>,
+        groups: &mut HashMap<Group, Vec<String>>,
+    ) {
+        let mut kernel = self.kernel.lock().unwrap();
+        let KernelFrames {
+            locations,
+            releases,
+        } = &mut *kernel;
+
+        let pairs = locations
+            .iter()
+            .flat_map(|location| new_releases.iter().map(move |r| (location, r)))
+            .chain(new_locations.iter().flat_map(|location| {
+                releases
+                    .iter()
+                    .chain(new_releases.iter())
+                    .map(move |r| (location, r))
+            }));
+        for ((id, build_id), release) in pairs {
+            if self.stacktraces.symbolized(id, Some(release)).is_some() {
+                continue;
+            }
+            groups
+                .entry(Group::Kernel {
+                    release: release.clone(),
+                    build_id: build_id.clone(),
+                })
+                .or_default()
+                .push(id.clone());
+        }
+
+        locations.extend(new_locations);
+        releases.extend(new_releases);
+        if locations.len() > MAX_KERNEL_LOCATIONS {
+            let n = locations.len() - MAX_KERNEL_LOCATIONS;
+            log::debug!("Leaving {} kernel frames to query time for new releases", n);
+            locations.drain(..n);
+        }
+        if releases.len() > MAX_KERNEL_RELEASES {
+            let n = releases.len() - MAX_KERNEL_RELEASES;
+            releases.drain(..n);
+        }
+    }
+
+    /// Symbolizes the locations of a group.
+    async fn symbolize_group(
+        symbolizer: Arc<Symbolizer>,
+        stacktraces: &StacktraceStore,
+        group: &Group,
+        ids: &[String],
+    ) -> anyhow::Result<Resolved> {
+        let mut symbolize_ids = Vec::with_capacity(ids.len());
+        let mut locations: Vec<Location> = Vec::with_capacity(ids.len());
+        for id in ids.iter() {
+            let location = stacktraces
+                .location(id)
+                .await?
+                .and_then(|encoded| PprofLocations::decode(&encoded).ok());
+            if let Some(location) = location {
+                symbolize_ids.push(id.as_str());
+                locations.push(location.to_location());
+            }
+        }
+
+        match group {
+            Group::User(build_id) => {
+                let locations = symbolizer
+                    .symbolize_owned(build_id.clone(), locations)
+                    .await?;
+                Ok(Self::partition(symbolize_ids.into_iter().zip(locations)))
+            }
+            Group::Kernel { release, build_id } => {
+                let mut refs: Vec<&mut Location> = locations.iter_mut().collect();
+                symbolizer
+                    .symbolize_kernel(Some(release), build_id, &mut refs)
+                    .await?;
+                Ok(Self::partition(
+                    symbolize_ids
+                        .into_iter()
+                        .zip(locations)
+                        .filter(|(_, location)| {
+                            location
+                                .mapping
+                                .as_ref()
+                                .is_some_and(|m| kernel::is_kernel_mapping(&m.file))
+                        }),
+                ))
+            }
+        }
+    }
+
+    fn partition<'a>(locations: impl Iterator<Item = (&'a str, Location)>) -> Resolved {
+        let mut resolved = Resolved::default();
+        for (id, location) in locations {
+            if location.lines.is_empty() {
+                resolved.unresolved.push(id.to_string());
+            } else {
+                resolved.lines.push((id.to_string(), location.lines));
+            }
+        }
+        resolved
+    }
+
+    /// Returns the location if it needs symbolizing.
+    async fn unsymbolized_location(&self, id: &str) -> anyhow::Result<Option<PprofLocations>> {
+        let encoded = match self.stacktraces.location(id).await? {
+            Some(encoded) => encoded,
+            None => return Ok(None),
+        };
+        let location = match PprofLocations::decode(&encoded) {
+            Ok(location) => location,
+            Err(e) => {
+                log::warn!("Failed to decode location {}: {}", id, e);
+                return Ok(None);
+            }
+        };
+
+        if location.address == 0 || location.number_of_lines > 0 {
+            return Ok(None);
+        }
+        Ok(Some(location))
+    }
+}
+
+#[tonic::async_trait]
+impl admin::Purger for EagerSymbolizer {
+    async fn purge(&self, build_id: &str) -> anyhow::Result<()> {
+        EagerSymbolizer::purge(self, build_id).await
+    }
+
+    async fn purge_kallsyms(&self, release: &str) -> anyhow::Result<()> {
+        admin::Purger::purge_kallsyms(&*self.symbolizer, release).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        debuginfo_store::{DebugInfod, DebuginfoFetcher, MetadataStore},
+        debuginfopb::{upload_instructions::UploadStrategy, BuildIdType, DebuginfoType},
+        storage,
+    };
+    use chrono::Utc;
+    use object_store::{path::Path, ObjectStore};
+
+    const BUILD_ID: &str = "126cf12e76810726c76d87ab8a6a57e9a1d4c815";
+    const TESTDATA: &str = "src/symbols/addr_to_line/testdata";
+
+    async fn upload(
+        metadata: &MetadataStore,
+        bucket: &dyn ObjectStore,
+        req_type: DebuginfoType,
+        file: &str,
+    ) {
+        let upload_id = ulid::Ulid::new().to_string();
+        metadata
+            .mark_as_uploading(
+                BUILD_ID,
+                &upload_id,
+                "hash",
+                &req_type,
+                BuildIdType::Gnu,
+                UploadStrategy::Grpc,
+                Utc::now(),
+            )
+            .unwrap();
+        let data = std::fs::read(format!("{}/{}", TESTDATA, file)).unwrap();
+        bucket
+            .put(&Path::from(upload_id.as_str()), data.into())
+            .await
+            .unwrap();
+        metadata
+            .mark_as_uploaded(BUILD_ID, &upload_id, &req_type, Utc::now())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_once_waits_for_debuginfo() {
+        let metadata = MetadataStore::new();
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let symbolizer = Arc::new(Symbolizer::new(
+            MetadataStore::with_store(metadata.store.clone()),
+            DebuginfoFetcher::new(Arc::clone(&bucket), DebugInfod::default()),
+        ));
+        let stacktraces = Arc::new(StacktraceStore::new(Arc::new(storage::new_memory_bucket())));
+        let mut eager = EagerSymbolizer::new(symbolizer, Arc::clone(&stacktraces));
+        eager.min_backoff = Duration::ZERO;
+
+        // c2() in the text segment, which starts at file offset 0x1000.
+        let location = PprofLocations {
+            address: 0x401156,
+            number_of_lines: 0,
+            build_id: BUILD_ID.into(),
+            file_name: "basic-cpp".into(),
+            mapping_memory_start: 0x401000,
+            mapping_memory_end: 0x402000,
+            mapping_file_offset: 0x1000,
+            functions: vec![],
+        };
+        let id = stacktraces.insert(&[location.encode().unwrap()]);
+        let location_id = stacktraces.stacktrace(&id).await.unwrap().unwrap()[0].clone();
+
+        assert_eq!(eager.run_once().await.unwrap(), 0);
+        assert!(stacktraces.symbolized(&location_id, None).is_none());
+
+        upload(
+            &metadata,
+            bucket.as_ref(),
+            DebuginfoType::Executable,
+            "basic-cpp-no-fp-stripped-debug",
+        )
+        .await;
+        upload(
+            &metadata,
+            bucket.as_ref(),
+            DebuginfoType::DebuginfoUnspecified,
+            "basic-cpp-no-fp.debug",
+        )
+        .await;
+
+        assert_eq!(eager.run_once().await.unwrap(), 1);
+        let lines = stacktraces.symbolized(&location_id, None).unwrap();
+        assert_eq!(lines[0].function.as_ref().unwrap().system_name, "_Z2c2v");
+
+        // Purged locations are symbolized again.
+        eager.purge(BUILD_ID).await.unwrap();
+        assert!(stacktraces.symbolized(&location_id, None).is_none());
+        assert_eq!(eager.run_once().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_once_symbolizes_kernel_frames_per_release() {
+        const KERNEL_RELEASE: &str = "6.8.0-45-generic";
+
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let symbolizer = Arc::new(Symbolizer::new(
+            MetadataStore::new(),
+            DebuginfoFetcher::new(Arc::clone(&bucket), DebugInfod::default()),
+        ));
+        let stacktraces = Arc::new(StacktraceStore::new(Arc::new(storage::new_memory_bucket())));
+        let mut eager = EagerSymbolizer::new(symbolizer, Arc::clone(&stacktraces));
+        eager.min_backoff = Duration::ZERO;
+
+        let location = PprofLocations {
+            address: 0xffffffff81001004,
+            number_of_lines: 0,
+            build_id: "".into(),
+            file_name: kernel::KERNEL_MAPPING.into(),
+            mapping_memory_start: 0,
+            mapping_memory_end: 0,
+            mapping_file_offset: 0,
+            functions: vec![],
+        };
+        let id = stacktraces.insert(&[location.encode().unwrap()]);
+        let location_id = stacktraces.stacktrace(&id).await.unwrap().unwrap()[0].clone();
+
+        // Nothing to do until a profile from some kernel is ingested, and
+        // then until there are symbols for that kernel.
+        assert_eq!(eager.run_once().await.unwrap(), 0);
+        stacktraces.track_kernel_release(KERNEL_RELEASE);
+        assert_eq!(eager.run_once().await.unwrap(), 0);
+
+        bucket
+            .put(
+                &Path::from(kernel::kallsyms_path(KERNEL_RELEASE)),
+                b"ffffffff81001000 T _Z2c2v\n".to_vec().into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(eager.run_once().await.unwrap(), 1);
+
+        assert!(stacktraces.symbolized(&location_id, None).is_none());
+        assert!(stacktraces
+            .symbolized(&location_id, Some("6.8.0-40-generic"))
+            .is_none());
+        let lines = stacktraces
+            .symbolized(&location_id, Some(KERNEL_RELEASE))
+            .unwrap();
+        assert_eq!(lines[0].function.as_ref().unwrap().system_name, "_Z2c2v");
+    }
+
+    #[test]
+    fn test_kernel_frames_bounded() {
+        let symbolizer = Arc::new(Symbolizer::new(
+            MetadataStore::new(),
+            DebuginfoFetcher::new(
+                Arc::new(storage::new_memory_bucket()),
+                DebugInfod::default(),
+            ),
+        ));
+        let stacktraces = Arc::new(StacktraceStore::new(Arc::new(storage::new_memory_bucket())));
+        let eager = EagerSymbolizer::new(symbolizer, stacktraces);
+
+        let location = |i: usize| (format!("{:032x}", i), String::new());
+        let releases: Vec<String> = (0..2 * MAX_KERNEL_RELEASES)
+            .map(|i| format!("6.8.0-{}-generic", i))
+            .collect();
+        let mut groups = HashMap::new();
+        eager.group_kernel_frames(
+            (0..MAX_KERNEL_LOCATIONS + 10).map(location).collect(),
+            releases.clone(),
+            &mut groups,
+        );
+        assert_eq!(groups.len(), releases.len());
+
+        // Only the most recent frames are paired with a new release, and new
+        // frames only with the most recent releases.
+        let mut groups = HashMap::new();
+        eager.group_kernel_frames(
+            vec![location(MAX_KERNEL_LOCATIONS + 10)],
+            vec!["6.9.0-1-generic".into()],
+            &mut groups,
+        );
+        let kernel = eager.kernel.lock().unwrap();
+        assert_eq!(kernel.locations.len(), MAX_KERNEL_LOCATIONS);
+        assert_eq!(kernel.releases.len(), MAX_KERNEL_RELEASES);
+        assert_eq!(kernel.locations[0], location(11));
+        assert_eq!(groups.len(), MAX_KERNEL_RELEASES + 1);
+        let group = Group::Kernel {
+            release: "6.9.0-1-generic".into(),
+            build_id: String::new(),
+        };
+        assert_eq!(groups[&group].len(), MAX_KERNEL_LOCATIONS + 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_once_gives_up() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        let symbolizer = Arc::new(Symbolizer::new(
+            MetadataStore::new(),
+            DebuginfoFetcher::new(Arc::clone(&bucket), DebugInfod::default()),
+        ));
+        let stacktraces = Arc::new(StacktraceStore::new(Arc::new(storage::new_memory_bucket())));
+        let mut eager = EagerSymbolizer::new(symbolizer, Arc::clone(&stacktraces));
+        eager.min_backoff = Duration::ZERO;
+
+        let location = PprofLocations {
+            address: 0x401156,
+            number_of_lines: 0,
+            build_id: BUILD_ID.into(),
+            file_name: "basic-cpp".into(),
+            mapping_memory_start: 0x401000,
+            mapping_memory_end: 0x402000,
+            mapping_file_offset: 0x1000,
+            functions: vec![],
+        };
+        stacktraces.insert(&[location.encode().unwrap()]);
+
+        for _ in 1..MAX_ATTEMPTS {
+            assert_eq!(eager.run_once().await.unwrap(), 0);
+            assert_eq!(
+                EagerSymbolizer::num_waiting(&eager.waiting.lock().unwrap()),
+                1
+            );
+        }
+        assert_eq!(eager.run_once().await.unwrap(), 0);
+        assert!(eager.waiting.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::bail;
+use moka::sync::Cache;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Name of the mapping that the agent puts kernel frames in.
+pub const KERNEL_MAPPING: &str = "[kernel.kallsyms]";
+
+pub fn is_kernel_mapping(file: &str) -> bool {
+    file == KERNEL_MAPPING
+}
+
+/// Returns the path of the kallsyms snapshot of the release in the debuginfo
+/// bucket.
+pub fn kallsyms_path(release: &str) -> String {
+    format!("kernel/{}/kallsyms", release)
+}
+
+/// Kernel releases end up in object paths, so they are restricted to what
+/// `uname -r` actually produces.
+pub fn validate_kernel_release(release: &str) -> anyhow::Result<()> {
+    if release.is_empty()
+        || release.starts_with('.')
+        || !release
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-+~".contains(c))
+    {
+        bail!("Invalid kernel release: {:?}", release);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelSymbol {
+    pub address: u64,
+    pub name: String,
+    /// The module the symbol belongs to, None for the kernel image itself.
+    pub module: Option<String>,
+}
+
+/// Kallsyms is a snapshot of /proc/kallsyms, with the text symbols sorted by
+/// address.
+#[derive(Debug, Default)]
+pub struct Kallsyms {
+    symbols: Vec<KernelSymbol>,
+    /// The end of the text of the kernel image, if kallsyms has it.
+    etext: Option<u64>,
+}
+
+impl Kallsyms {
+    /// Parses lines of the form `<address> <type> <name>[\t[<module>]]`.
+    /// Only text symbols are kept, as those are the ones frames point into.
+    pub fn parse(data: &str) -> anyhow::Result<Self> {
+        let mut symbols = vec![];
+        let mut zero_addresses = 0;
+
+        for line in data.lines() {
+            let mut fields = line.split_whitespace();
+            let (address, kind, name) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(address), Some(kind), Some(name)) => (address, kind, name),
+                _ => continue,
+            };
+            if !matches!(kind, "t" | "T" | "w" | "W") {
+                continue;
+            }
+
+            let address = match u64::from_str_radix(address, 16) {
+                Ok(address) => address,
+                Err(_) => bail!("Invalid kallsyms line: {:?}", line),
+            };
+            if address == 0 {
+                zero_addresses += 1;
+                continue;
+            }
+
+            let module = fields
+                .next()
+                .and_then(|m| m.strip_prefix('[').and_then(|m| m.strip_suffix(']')))
+                .map(|m| m.to_string());
+
+            symbols.push(KernelSymbol {
+                address,
+                name: name.to_string(),
+                module,
+            });
+        }
+
+        if symbols.is_empty() {
+            if zero_addresses > 0 {
+                bail!("kallsyms has no addresses, it has to be read with CAP_SYSLOG");
+            }
+            bail!("kallsyms has no text symbols");
+        }
+
+        symbols.sort_by_key(|s| s.address);
+        let etext = symbols
+            .iter()
+            .find(|s| s.name == "_etext" && s.module.is_none())
+            .map(|s| s.address);
+        Ok(Self { symbols, etext })
+    }
+
+    /// Returns the symbol with the closest address at or below addr. A
+    /// symbol of the kernel image ends at the next symbol or at _etext,
+    /// whichever comes first, as the image is followed by its data.
+    pub fn lookup(&self, addr: u64) -> Option<&KernelSymbol> {
+        let symbol = match self.symbols.partition_point(|s| s.address <= addr) {
+            0 => return None,
+            i => &self.symbols[i - 1],
+        };
+        if symbol.module.is_none() && self.etext.is_some_and(|etext| addr >= etext) {
+            return None;
+        }
+        Some(symbol)
+    }
+}
+
+/// KallsymsCache keeps parsed kallsyms snapshots by kernel release.
+/// Snapshots are read from the debuginfo bucket, where agents upload them,
+/// or from a local directory with the same `<release>/kallsyms` layout.
+#[derive(Debug, Clone)]
+pub struct KallsymsCache {
+    c: Cache<String, Arc<Kallsyms>>,
+    dir: Option<PathBuf>,
+}
+
+impl Default for KallsymsCache {
+    fn default() -> Self {
+        Self::new(64, None)
+    }
+}
+
+impl KallsymsCache {
+    pub fn new(cap: u64, dir: Option<PathBuf>) -> Self {
+        Self {
+            c: Cache::new(cap),
+            dir,
+        }
+    }
+
+    pub fn get(&self, release: &str) -> Option<Arc<Kallsyms>> {
+        self.c.get(release)
+    }
+
+    pub fn insert(&self, release: &str, kallsyms: Arc<Kallsyms>) {
+        self.c.insert(release.to_string(), kallsyms);
+    }
+
+    /// Drops the snapshot of the release, so it is read again.
+    pub fn invalidate(&self, release: &str) {
+        self.c.invalidate(release);
+    }
+
+    /// Reads the snapshot for the release from the local directory, if one
+    /// is configured.
+    pub fn read_local(&self, release: &str) -> anyhow::Result<Option<String>> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+        validate_kernel_release(release)?;
+
+        match std::fs::read_to_string(dir.join(release).join("kallsyms")) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KALLSYMS: &str = "\
+ffffffff81000000 T _stext
+ffffffff81000000 T _text
+ffffffff81001000 t do_one_initcall
+ffffffff82000000 D jiffies
+ffffffff81002000 T schedule
+ffffffff81003000 T _etext
+ffffffffc0a01000 t nft_do_chain\t[nf_tables]
+";
+
+    #[test]
+    fn test_parse_and_lookup() {
+        let kallsyms = Kallsyms::parse(KALLSYMS).unwrap();
+
+        assert!(kallsyms.lookup(0xffffffff80000000).is_none());
+        assert_eq!(
+            kallsyms.lookup(0xffffffff81001010).unwrap().name,
+            "do_one_initcall"
+        );
+        assert_eq!(
+            kallsyms.lookup(0xffffffff81002010).unwrap().name,
+            "schedule"
+        );
+        // Data symbols are skipped, and the text ends at _etext.
+        assert!(kallsyms.lookup(0xffffffff82000010).is_none());
+        assert!(kallsyms.lookup(0xffffffff81003000).is_none());
+
+        let symbol = kallsyms.lookup(0xffffffffc0a01004).unwrap();
+        assert_eq!(symbol.name, "nft_do_chain");
+        assert_eq!(symbol.module.as_deref(), Some("nf_tables"));
+    }
+
+    #[test]
+    fn test_parse_restricted() {
+        let restricted = "0000000000000000 T _stext\n0000000000000000 t schedule\n";
+        assert!(Kallsyms::parse(restricted).is_err());
+    }
+
+    #[test]
+    fn test_validate_kernel_release() {
+        assert!(validate_kernel_release("6.8.0-45-generic").is_ok());
+        assert!(validate_kernel_release("5.15.153.1-microsoft-standard-WSL2+").is_ok());
+        assert!(validate_kernel_release("").is_err());
+        assert!(validate_kernel_release("../etc").is_err());
+        assert!(validate_kernel_release("6.8/x").is_err());
+    }
+}
@@ -2,31 +2,36 @@ use super::{normalize::NormalizedAddress, ElfDebugInfo, SymbolizerCache};
 use crate::{
     profile::LocationLine,
     symbols::{
-        addr_to_line::{self, DwarfLiner, SymbolLiner},
+        addr_to_line::{self, DwarfContext, DwarfLiner, GoLiner, SymbolLiner},
         Demangler,
     },
 };
 use anyhow::bail;
+use std::sync::Arc;
 
 pub enum LinerKind<'data> {
     Dwarf(DwarfLiner<'data>),
-    // Go,
+    Go(GoLiner<'data>),
     Symbol(SymbolLiner<'data>),
 }
 
 pub struct Liner<'data> {
     pub l: Option<LinerKind<'data>>,
     build_id: &'data str,
+    version: &'data str,
     elfdbginfo: &'data ElfDebugInfo<'data>,
     cache: &'data SymbolizerCache,
+    dwarf: Option<Arc<DwarfContext>>,
     demangler: &'data Demangler,
+    /// Lines resolved since the last flush, which are cached at once.
+    resolved: Vec<(NormalizedAddress, Vec<LocationLine>)>,
 }
 
 impl LinerKind<'_> {
     pub fn pc_to_lines(&self, pc: NormalizedAddress) -> anyhow::Result<Vec<LocationLine>> {
         match self {
             LinerKind::Dwarf(l) => l.pc_to_lines(pc),
-            // LinerKind::Go => todo!(),
+            LinerKind::Go(l) => l.pc_to_lines(pc),
             LinerKind::Symbol(l) => l.pc_to_lines(pc),
         }
     }
@@ -35,22 +40,27 @@ impl LinerKind<'_> {
 impl<'data> Liner<'data> {
     pub fn new(
         build_id: &'data str,
+        version: &'data str,
         dbginfo: &'data ElfDebugInfo,
         cache: &'data SymbolizerCache,
+        dwarf: Option<Arc<DwarfContext>>,
         demangler: &'data Demangler,
     ) -> Self {
         Self {
             build_id,
+            version,
             l: None,
             elfdbginfo: dbginfo,
             cache,
+            dwarf,
             demangler,
+            resolved: vec![],
         }
     }
 
     pub fn pc_to_lines(&mut self, pc: NormalizedAddress) -> anyhow::Result<Vec<LocationLine>> {
         // Cache lookup
-        match self.cache.get(self.build_id, &pc) {
+        match self.cache.get(self.build_id, self.version, &pc) {
             Ok(ll) => {
                 if let Some(ll) = ll {
                     return Ok(ll);
@@ -68,11 +78,16 @@ impl<'data> Liner<'data> {
         let liner = self.l.as_ref().unwrap();
         let ll = liner.pc_to_lines(pc)?;
 
-        // Cache the result
-        let () = self.cache.set(self.build_id, &pc, ll.clone())?;
+        self.resolved.push((pc, ll.clone()));
         Ok(ll)
     }
 
+    /// Caches the lines resolved so far.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let resolved = std::mem::take(&mut self.resolved);
+        self.cache.set_all(self.build_id, self.version, resolved)
+    }
+
     fn construct_liner(&self) -> anyhow::Result<LinerKind<'data>> {
         let quality = match self.elfdbginfo.quality {
             Some(q) => q,
@@ -80,7 +95,13 @@ impl<'data> Liner<'data> {
         };
 
         if quality.has_dwarf {
-            Ok(LinerKind::Dwarf(addr_to_line::dwarf(
+            let ctx = match &self.dwarf {
+                Some(ctx) => Arc::clone(ctx),
+                None => bail!("No DWARF context for build_id {}", self.build_id),
+            };
+            Ok(LinerKind::Dwarf(addr_to_line::dwarf(ctx, self.demangler)))
+        } else if quality.has_go_pclntab {
+            Ok(LinerKind::Go(addr_to_line::go(
                 self.elfdbginfo,
                 self.demangler,
             )?))
@@ -94,10 +115,17 @@ impl<'data> Liner<'data> {
         } else {
             bail!("LinerError: Check debuginfo quality.");
         }
+    }
+}
 
-        // else if quality.has_go_pclntab {
-        // Ok(addr_to_line::go(self.elfdbginfo, self.demangler)?)
-        // Ok(LinerKind::Go)
-        // }
+impl Drop for Liner<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::warn!(
+                "Failed to cache the lines of build_id {}: {}",
+                self.build_id,
+                e
+            );
+        }
     }
 }
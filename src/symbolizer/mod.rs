@@ -1,30 +1,115 @@
 mod cache;
+mod eager;
+pub mod kernel;
 pub mod liner;
 pub mod normalize;
 
-use self::debuginfopb::Debuginfo;
-use crate::debuginfo_store::DebuginfoFetcher;
-use crate::symbols::{elfutils, Demangler};
-use crate::{debuginfo_store::MetadataStore, profile::Location};
+use self::debuginfopb::{debuginfo_upload, Debuginfo};
+use crate::debuginfo_store::{admin, buildid, DebuginfoFetcher};
+use crate::symbols::{
+    addr_to_line::{DwarfContext, DwarfContextCache, DwarfData, DwarfSupplements},
+    elfutils, Demangler,
+};
+use crate::{
+    debuginfo_store::MetadataStore,
+    metapb::Function,
+    profile::{Location, LocationLine},
+};
 use crate::{
     debuginfopb::{self, DebuginfoQuality, DebuginfoType},
     profile::executableinfo::{ExecutableInfo, Mapping},
 };
 use anyhow::{bail, Context};
-pub use cache::SymbolizerCache;
+pub use cache::{CacheMetrics, SymbolizerCache, SymbolizerCacheConfig};
+pub use eager::EagerSymbolizer;
+use kernel::{Kallsyms, KallsymsCache};
 use liner::Liner;
+use moka::sync::Cache;
 use normalize::NormalizedAddress;
-use std::io::Write;
+use object::{Object, ObjectSymbol, SymbolKind};
+use object_store::{GetResult, GetResultPayload};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use tokio_stream::StreamExt;
 use tonic::Status;
 
+/// How many build IDs are symbolized at once, across all callers. Each
+/// holds its files open while its locations are looked up.
+const MAX_CONCURRENT_BUILD_IDS: usize = 16;
+
 #[derive(Debug)]
 pub struct Symbolizer {
     pub(crate) demangler: Demangler,
     cache: SymbolizerCache,
+    dwarf_contexts: DwarfContextCache,
+    kallsyms: KallsymsCache,
+    elf_files: Cache<(String, DebuginfoType), Arc<ElfFile>>,
     metadata: MetadataStore,
     fetcher: DebuginfoFetcher,
     temp_dir: PathBuf,
+    /// Permits to symbolize a build ID, shared by queries and the eager
+    /// symbolizer.
+    build_ids: Arc<Semaphore>,
+    preparing: BuildIdLocks,
+}
+
+/// BuildIdLocks are held while the files of a build ID are fetched and
+/// parsed, so that concurrent callers wait for the first one and then find
+/// them cached, rather than fetching and parsing them again.
+#[derive(Debug, Default)]
+struct BuildIdLocks(std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>);
+
+impl BuildIdLocks {
+    async fn lock(&self, build_id: &str) -> BuildIdGuard<'_> {
+        let lock = Arc::clone(
+            self.0
+                .lock()
+                .unwrap()
+                .entry(build_id.to_string())
+                .or_default(),
+        );
+        let guard = Arc::clone(&lock).lock_owned().await;
+        BuildIdGuard {
+            locks: self,
+            build_id: build_id.to_string(),
+            lock,
+            guard: Some(guard),
+        }
+    }
+}
+
+struct BuildIdGuard<'a> {
+    locks: &'a BuildIdLocks,
+    build_id: String,
+    lock: Arc<tokio::sync::Mutex<()>>,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for BuildIdGuard<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        // The lock is dropped with its last user, the map holds the other
+        // reference.
+        let mut locks = self.locks.0.lock().unwrap();
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.build_id);
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl admin::Purger for Symbolizer {
+    async fn purge(&self, build_id: &str) -> anyhow::Result<()> {
+        Symbolizer::purge(self, build_id)
+    }
+
+    async fn purge_kallsyms(&self, release: &str) -> anyhow::Result<()> {
+        self.kallsyms.invalidate(release);
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -39,6 +124,65 @@ pub struct SymbolizationRequest<'a> {
     pub mappings: Vec<SymbolizationRequestMappingAddrs<'a>>,
 }
 
+/// ElfFile is a fetched ELF file, mapped from its local copy. Files are kept
+/// mapped across requests, so each is fetched and validated only once.
+#[derive(Debug)]
+pub struct ElfFile {
+    dbginfo: Debuginfo,
+    target_path: PathBuf,
+    mmap: memmap2::Mmap,
+    dwarf_refs: DwarfRefs,
+}
+
+/// DwarfRefs are the other files the DWARF of an ELF file refers to.
+#[derive(Debug, Default)]
+struct DwarfRefs {
+    /// The build ID of the supplementary file in .gnu_debugaltlink.
+    sup: Option<String>,
+    /// The DWO IDs of the units that were split out.
+    dwo_ids: Vec<u64>,
+}
+
+impl AsRef<[u8]> for ElfFile {
+    fn as_ref(&self) -> &[u8] {
+        &self.mmap
+    }
+}
+
+impl ElfFile {
+    pub(crate) fn debug_info(&self) -> anyhow::Result<ElfDebugInfo<'_>> {
+        Ok(ElfDebugInfo {
+            target_path: self.target_path.clone(),
+            e: object::File::parse(&*self.mmap)?,
+            quality: self.dbginfo.quality,
+        })
+    }
+
+    /// Returns the upload the file came from, or where it came from if it
+    /// wasn't uploaded.
+    fn source(&self) -> String {
+        match &self.dbginfo.upload {
+            Some(upload) => upload.id.clone(),
+            None => self.dbginfo.source().as_str_name().to_string(),
+        }
+    }
+}
+
+/// BuildIdFiles are the files a build ID is symbolized with. Line tables
+/// come from the debuginfo file, while addresses are normalized with the
+/// program headers of the executable. A separate debug file has program
+/// headers too, but its segments have no file contents, so their offsets
+/// can't be relied on. Either file is enough to symbolize with.
+#[derive(Debug, Clone)]
+pub struct BuildIdFiles {
+    debuginfo: Option<Arc<ElfFile>>,
+    executable: Option<Arc<ElfFile>>,
+    dwarf: Option<Arc<DwarfContext>>,
+    /// Changes whenever one of the files does, so that lines cached for
+    /// the previous files aren't used.
+    version: String,
+}
+
 #[derive(Debug)]
 pub struct ElfDebugInfo<'data> {
     pub(crate) target_path: PathBuf,
@@ -51,66 +195,602 @@ impl Symbolizer {
         Self {
             demangler: Demangler::new(false),
             cache: SymbolizerCache::default(),
+            dwarf_contexts: DwarfContextCache::default(),
+            kallsyms: KallsymsCache::default(),
+            elf_files: Cache::new(128),
             metadata,
             fetcher,
             temp_dir: PathBuf::from("/tmp"),
+            build_ids: Arc::new(Semaphore::new(MAX_CONCURRENT_BUILD_IDS)),
+            preparing: BuildIdLocks::default(),
         }
     }
 
+    /// Replaces the default in-memory cache of symbolized addresses.
+    pub fn with_cache(mut self, cache: SymbolizerCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn cache_metrics(&self) -> Arc<CacheMetrics> {
+        self.cache.metrics()
+    }
+
+    /// Drops everything kept for the build ID: its mapped files, its parsed
+    /// DWARF and the lines of its addresses, so that it is symbolized from
+    /// scratch with whatever debuginfo it has now.
+    pub fn purge(&self, build_id: &str) -> anyhow::Result<()> {
+        for req_type in [
+            DebuginfoType::DebuginfoUnspecified,
+            DebuginfoType::Executable,
+        ] {
+            self.elf_files.invalidate(&(build_id.to_string(), req_type));
+        }
+        self.dwarf_contexts.invalidate(build_id);
+        self.cache.invalidate(build_id)
+    }
+
+    /// Sets a local directory to read kallsyms snapshots from, laid out as
+    /// `<dir>/<kernel_release>/kallsyms`.
+    pub fn with_kallsyms_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.kallsyms = KallsymsCache::new(64, Some(dir.into()));
+        self
+    }
+
     pub async fn symbolize(&self, request: &mut SymbolizationRequest<'_>) -> anyhow::Result<()> {
         log::info!("Symbolizing request for build_id: {}", request.build_id);
 
-        let build_id = &request.build_id;
+        let _permit = self.build_ids.acquire().await?;
+        let files = self.prepare(&request.build_id).await?;
+        for mapping in request.mappings.iter_mut() {
+            self.symbolize_prepared(&request.build_id, &files, mapping.locations)?;
+        }
 
-        let mut dbginfo_md = {
-            self.metadata
-                .fetch(build_id, &DebuginfoType::DebuginfoUnspecified)
-                .ok_or_else(|| {
+        Ok(())
+    }
+
+    /// Symbolizes the locations of a build ID. The files are fetched on the
+    /// async runtime, while the lookups run on the rayon pool, which bounds
+    /// the CPU spent on symbolization to the number of cores.
+    pub async fn symbolize_owned(
+        self: Arc<Self>,
+        build_id: String,
+        mut locations: Vec<Location>,
+    ) -> anyhow::Result<Vec<Location>> {
+        log::info!("Symbolizing request for build_id: {}", build_id);
+
+        let permit = Arc::clone(&self.build_ids).acquire_owned().await?;
+        let files = self.prepare(&build_id).await?;
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        rayon::spawn(move || {
+            let mut refs: Vec<&mut Location> = locations.iter_mut().collect();
+            let res = self.symbolize_prepared(&build_id, &files, &mut refs);
+            drop(permit);
+            let _ = tx.send(res.map(|()| locations));
+        });
+        rx.await?
+    }
+
+    /// Fetches the files to symbolize the build ID with, and parses their
+    /// DWARF if that hasn't been done yet. A problem with one of the files is
+    /// only an error if the other one is missing too.
+    pub async fn prepare(&self, build_id: &str) -> anyhow::Result<BuildIdFiles> {
+        let _guard = self.preparing.lock(build_id).await;
+        let mut errors = vec![];
+        let debuginfo = self
+            .load_elf(build_id, DebuginfoType::DebuginfoUnspecified)
+            .await
+            .unwrap_or_else(|e| {
+                errors.push(e);
+                None
+            });
+        let executable = self
+            .load_elf(build_id, DebuginfoType::Executable)
+            .await
+            .unwrap_or_else(|e| {
+                errors.push(e);
+                None
+            });
+
+        let elf = match (&debuginfo, &executable) {
+            (Some(elf), _) | (None, Some(elf)) => Arc::clone(elf),
+            (None, None) => {
+                return Err(errors.pop().unwrap_or_else(|| {
                     Status::not_found(format!("Debuginfo for build_id {} not found", build_id))
-                })?
-                .clone()
+                        .into()
+                }))
+            }
         };
+        for e in errors.iter() {
+            log::warn!(
+                "Symbolizing build_id {} with partial debuginfo: {:#}",
+                build_id,
+                e
+            );
+        }
 
-        if let Some(q) = &dbginfo_md.quality {
-            Self::check_quality(q)?;
+        let dwarf = self.dwarf_context(build_id, &elf).await?;
+        let version = [
+            debuginfo.as_ref().map(|f| f.source()).unwrap_or_default(),
+            executable.as_ref().map(|f| f.source()).unwrap_or_default(),
+            self.dwarf_supplements_version(build_id, &elf.dwarf_refs),
+        ]
+        .join(";");
+
+        Ok(BuildIdFiles {
+            debuginfo,
+            executable,
+            dwarf,
+            version,
+        })
+    }
+
+    /// Returns the parsed DWARF of the build ID, parsing it along with its
+    /// supplements if it isn't cached yet, or the supplements changed since.
+    /// Files without DWARF have none.
+    async fn dwarf_context(
+        &self,
+        build_id: &str,
+        elf: &Arc<ElfFile>,
+    ) -> anyhow::Result<Option<Arc<DwarfContext>>> {
+        if !elf.dbginfo.quality.is_some_and(|q| q.has_dwarf) {
+            return Ok(None);
+        }
+        let version = self.dwarf_supplements_version(build_id, &elf.dwarf_refs);
+        if let Some(ctx) = self.dwarf_contexts.get(build_id, &version) {
+            return Ok(Some(ctx));
         }
-        let _ = Self::validate_source(&dbginfo_md);
 
-        let raw_data = self.fetcher.fetch_raw_elf(&dbginfo_md).await?;
-        let elf_debug_info = self.get_debug_info(&request.build_id, &mut dbginfo_md, &raw_data)?;
+        let supplements = self
+            .fetch_dwarf_supplements(build_id, &elf.dwarf_refs)
+            .await;
+        let complete = (elf.dwarf_refs.sup.is_none() || supplements.sup.is_some())
+            && (supplements.dwp.is_some()
+                || supplements.dwos.len() == elf.dwarf_refs.dwo_ids.len());
+        let data = Arc::clone(elf) as DwarfData;
+        let ctx = Arc::new(
+            tokio::task::spawn_blocking(move || DwarfContext::with_supplements(data, supplements))
+                .await??,
+        );
+        self.dwarf_contexts
+            .insert(build_id, version, complete, Arc::clone(&ctx));
+        Ok(Some(ctx))
+    }
+
+    /// Returns the uploads of the supplements of the build ID, so a parsed
+    /// context can tell whether it is still up to date.
+    fn dwarf_supplements_version(&self, build_id: &str, refs: &DwarfRefs) -> String {
+        let upload_id = |build_id: &str, req_type: DebuginfoType| {
+            self.metadata
+                .fetch(build_id, &req_type)
+                .and_then(|dbginfo| dbginfo.upload)
+                .filter(|upload| upload.state() == debuginfo_upload::State::Uploaded)
+                .map(|upload| upload.id)
+                .unwrap_or_default()
+        };
+
+        let mut version = vec![];
+        if let Some(sup) = &refs.sup {
+            version.push(upload_id(sup, DebuginfoType::DebuginfoUnspecified));
+        }
+        if !refs.dwo_ids.is_empty() {
+            version.push(upload_id(build_id, DebuginfoType::DwarfPackage));
+            for dwo_id in refs.dwo_ids.iter() {
+                version.push(upload_id(
+                    &format!("{:016x}", dwo_id),
+                    DebuginfoType::SplitDwarf,
+                ));
+            }
+        }
+        version.join(",")
+    }
+
+    /// Symbolizes locations with the files of their build ID. This is CPU
+    /// bound. A location that can't be symbolized is left without lines, so
+    /// it doesn't fail the others.
+    pub fn symbolize_prepared(
+        &self,
+        build_id: &str,
+        files: &BuildIdFiles,
+        locations: &mut [&mut Location],
+    ) -> anyhow::Result<()> {
+        let debuginfo = files
+            .debuginfo
+            .as_ref()
+            .map(|f| f.debug_info())
+            .transpose()?;
+        let executable = files
+            .executable
+            .as_ref()
+            .map(|f| f.debug_info())
+            .transpose()?;
+
+        let (elf_debug_info, elf_executable) = match (&debuginfo, &executable) {
+            (Some(debuginfo), Some(executable)) => (debuginfo, executable),
+            (Some(debuginfo), None) => (debuginfo, debuginfo),
+            (None, Some(executable)) => (executable, executable),
+            (None, None) => bail!("No files to symbolize build_id {} with", build_id),
+        };
 
         let mut l = Liner::new(
-            &request.build_id,
-            &elf_debug_info,
+            build_id,
+            &files.version,
+            elf_debug_info,
             &self.cache,
+            files.dwarf.clone(),
             &self.demangler,
         );
 
-        let ei = ExecutableInfo::try_from(&elf_debug_info.e)?;
+        let ei = ExecutableInfo::try_from(&elf_executable.e)?;
 
-        for mapping in request.mappings.iter_mut() {
-            for location in mapping.locations.into_iter() {
-                let mapping = match &location.mapping {
-                    Some(mapping) => mapping,
-                    None => bail!("Mapping not found"),
-                };
-                let addr = NormalizedAddress::try_new(
+        for location in locations.iter_mut() {
+            let mapping = match &location.mapping {
+                Some(mapping) => mapping,
+                None => {
+                    log::debug!("Location {:#x} has no mapping", location.address);
+                    continue;
+                }
+            };
+            let addr = NormalizedAddress::try_new(
+                location.address,
+                &ei,
+                &Mapping {
+                    start: mapping.start,
+                    end: mapping.limit,
+                    offset: mapping.offset,
+                    file: String::new(),
+                },
+            );
+            match addr
+                .map_err(anyhow::Error::from)
+                .and_then(|addr| l.pc_to_lines(addr))
+            {
+                Ok(lines) => location.lines = lines,
+                Err(e) => log::debug!(
+                    "Failed to symbolize {:#x} in build_id {}: {:#}",
                     location.address,
-                    &ei,
-                    &Mapping {
-                        start: mapping.start,
-                        end: mapping.limit,
-                        offset: mapping.offset,
-                        file: String::new(),
-                    },
-                )?;
-                location.lines = l.pc_to_lines(addr)?;
+                    build_id,
+                    e
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Symbolizes kernel frames, which the agent sends as absolute addresses.
+    /// Function names come from the kallsyms snapshot of the kernel release.
+    /// If there is debuginfo for the build ID of vmlinux or a module, it is
+    /// used instead, with kallsyms telling where the kernel or the module
+    /// was actually loaded.
+    pub async fn symbolize_kernel(
+        &self,
+        kernel_release: Option<&str>,
+        build_id: &str,
+        locations: &mut [&mut Location],
+    ) -> anyhow::Result<()> {
+        log::info!(
+            "Symbolizing kernel frames for release: {:?}",
+            kernel_release
+        );
+
+        let kallsyms = match kernel_release {
+            Some(release) => self.kallsyms(release).await,
+            None => None,
+        };
+
+        let _permit = self.build_ids.acquire().await?;
+        let guard = self.preparing.lock(build_id).await;
+        let debuginfo = if build_id.is_empty() {
+            None
+        } else {
+            self.load_elf(build_id, DebuginfoType::DebuginfoUnspecified)
+                .await
+                .unwrap_or_else(|e| {
+                    log::warn!("Failed to fetch kernel debuginfo {}: {:#}", build_id, e);
+                    None
+                })
+        };
+        let dwarf = match &debuginfo {
+            Some(elf) => self.dwarf_context(build_id, elf).await.unwrap_or_else(|e| {
+                log::warn!("Failed to load kernel DWARF {}: {:#}", build_id, e);
+                None
+            }),
+            None => None,
+        };
+        drop(guard);
+        let elf_debug_info = debuginfo.as_ref().and_then(|elf| {
+            elf.debug_info()
+                .map_err(|e| log::warn!("Failed to load kernel debuginfo {}: {:#}", build_id, e))
+                .ok()
+        });
+        let version = match &debuginfo {
+            Some(elf) => [
+                elf.source(),
+                self.dwarf_supplements_version(build_id, &elf.dwarf_refs),
+            ]
+            .join(";"),
+            None => String::new(),
+        };
+
+        let mut liner = elf_debug_info.as_ref().map(|elf_debug_info| {
+            Liner::new(
+                build_id,
+                &version,
+                elf_debug_info,
+                &self.cache,
+                dwarf.clone(),
+                &self.demangler,
+            )
+        });
+        let elf_symbols: HashMap<&str, u64> = match &elf_debug_info {
+            Some(elf_debug_info) => elf_debug_info
+                .e
+                .symbols()
+                .filter(|symbol| symbol.kind() == SymbolKind::Text)
+                .filter_map(|symbol| Some((symbol.name().ok()?, symbol.address())))
+                .collect(),
+            None => HashMap::new(),
+        };
+
+        for location in locations.iter_mut() {
+            let symbol = kallsyms.as_ref().and_then(|k| k.lookup(location.address));
+
+            if let Some(liner) = liner.as_mut() {
+                // The offset into the symbol is the same at runtime as in the
+                // ELF file. Without kallsyms there's no telling where the
+                // kernel was loaded, so it is assumed to be at its link
+                // address, ie. KASLR is off.
+                let addr = match symbol {
+                    Some(symbol) => elf_symbols
+                        .get(symbol.name.as_str())
+                        .map(|value| value + (location.address - symbol.address)),
+                    None => Some(location.address),
+                };
+                if let Some(addr) = addr {
+                    match liner.pc_to_lines(NormalizedAddress(addr)) {
+                        Ok(lines) if !lines.is_empty() => {
+                            location.lines = lines;
+                            continue;
+                        }
+                        Ok(_) => (),
+                        Err(e) => {
+                            log::debug!("Failed to symbolize kernel address {:#x}: {}", addr, e)
+                        }
+                    }
+                }
+            }
+
+            if let Some(symbol) = symbol {
+                let function = self.demangler.demangle(&Function {
+                    system_name: symbol.name.clone(),
+                    filename: "?".into(),
+                    ..Default::default()
+                });
+                location.lines = vec![LocationLine {
+                    line: 0,
+                    function: Some(function),
+                }];
+                if let (Some(module), Some(mapping)) = (&symbol.module, location.mapping.as_mut()) {
+                    mapping.file = format!("[{}]", module);
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Returns the kallsyms snapshot of the kernel release, looking in the
+    /// bucket first and then in the local directory.
+    async fn kallsyms(&self, release: &str) -> Option<Arc<Kallsyms>> {
+        if let Some(kallsyms) = self.kallsyms.get(release) {
+            return Some(kallsyms);
+        }
+
+        let data = match kernel::validate_kernel_release(release) {
+            Ok(()) => match self.fetcher.fetch_kallsyms(release).await {
+                Ok(Some(data)) => Ok(Some(String::from_utf8_lossy(&data).into_owned())),
+                Ok(None) => self.kallsyms.read_local(release),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+
+        let kallsyms = match data.and_then(|data| data.map(|d| Kallsyms::parse(&d)).transpose()) {
+            Ok(Some(kallsyms)) => Arc::new(kallsyms),
+            Ok(None) => {
+                log::warn!("No kallsyms found for kernel release {}", release);
+                return None;
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to load kallsyms for kernel release {}: {:#}",
+                    release,
+                    e
+                );
+                return None;
+            }
+        };
+
+        self.kallsyms.insert(release, Arc::clone(&kallsyms));
+        Some(kallsyms)
+    }
+
+    /// Returns the ELF file of the given type, fetching and mapping it if that
+    /// hasn't been done yet. Returns None if there is no metadata for it.
+    async fn load_elf(
+        &self,
+        build_id: &str,
+        req_type: DebuginfoType,
+    ) -> anyhow::Result<Option<Arc<ElfFile>>> {
+        let key = (build_id.to_string(), req_type);
+        if let Some(elf) = self.elf_files.get(&key) {
+            return Ok(Some(elf));
+        }
+
+        let (mut dbginfo, res) = match self.fetch_elf(build_id, &req_type).await? {
+            Some(elf) => elf,
+            None => return Ok(None),
+        };
+        // Go build IDs contain '/', so the files are named after the object
+        // name of the build ID.
+        let name = buildid::object_name(build_id);
+        let name = match req_type {
+            DebuginfoType::Executable => format!("{}.executable", name),
+            _ => name.to_string(),
+        };
+
+        let (target_path, mmap) = self.map_fetched(res, name).await?;
+
+        // Assessing the file is CPU bound.
+        let known = dbginfo.quality;
+        let (mmap, checked) = tokio::task::spawn_blocking(move || {
+            let checked = check_elf(&mmap, known);
+            (mmap, checked)
+        })
+        .await?;
+        let dwarf_refs = self.record_check(build_id, &mut dbginfo, checked)?;
+        let elf = Arc::new(ElfFile {
+            dbginfo,
+            target_path,
+            mmap,
+            dwarf_refs,
+        });
+        self.elf_files.insert(key, Arc::clone(&elf));
+        Ok(Some(elf))
+    }
+
+    /// Fetches the ELF file of the given type. Returns None if there is no
+    /// metadata for it, ie. it was neither uploaded nor found on debuginfod.
+    async fn fetch_elf(
+        &self,
+        build_id: &str,
+        req_type: &DebuginfoType,
+    ) -> anyhow::Result<Option<(Debuginfo, GetResult)>> {
+        let dbginfo_md = match self.metadata.fetch(build_id, req_type) {
+            Some(dbginfo_md) => dbginfo_md,
+            None => return Ok(None),
+        };
+
+        if let Some(q) = &dbginfo_md.quality {
+            Self::check_quality(q)?;
+        }
+        let _ = Self::validate_source(&dbginfo_md);
+
+        let res = self.fetcher.fetch_raw_elf(&dbginfo_md).await?;
+        Ok(Some((dbginfo_md, res)))
+    }
+
+    /// Fetches the files that the DWARF of an executable refers to. None of
+    /// them are required, a missing file only makes the affected lookups less
+    /// detailed, so errors are logged rather than returned.
+    async fn fetch_dwarf_supplements(&self, build_id: &str, refs: &DwarfRefs) -> DwarfSupplements {
+        let mut supplements = DwarfSupplements::default();
+
+        if let Some(sup_build_id) = &refs.sup {
+            let dbginfo = self
+                .metadata
+                .fetch(sup_build_id, &DebuginfoType::DebuginfoUnspecified);
+            match self
+                .fetcher
+                .fetch_supplementary(sup_build_id, dbginfo.as_ref())
+                .await
+            {
+                Ok(Some(res)) => {
+                    let name = format!("{}.sup", buildid::object_name(sup_build_id));
+                    match self.map_dwarf_data(res, name).await {
+                        Ok(data) => supplements.sup = Some(data),
+                        Err(e) => log::warn!(
+                            "Failed to map supplementary file {} for build_id {}: {}",
+                            sup_build_id,
+                            build_id,
+                            e
+                        ),
+                    }
+                }
+                Ok(None) => log::warn!(
+                    "Supplementary file {} for build_id {} not found",
+                    sup_build_id,
+                    build_id
+                ),
+                Err(e) => log::warn!(
+                    "Failed to fetch supplementary file {} for build_id {}: {}",
+                    sup_build_id,
+                    build_id,
+                    e
+                ),
+            }
+        }
+
+        if refs.dwo_ids.is_empty() {
+            return supplements;
+        }
+
+        let dbginfo = self.metadata.fetch(build_id, &DebuginfoType::DwarfPackage);
+        match self
+            .fetcher
+            .fetch_dwp(
+                build_id,
+                self.metadata.build_id_type(build_id),
+                dbginfo.as_ref(),
+            )
+            .await
+        {
+            // The package holds every split unit of the executable.
+            Ok(Some(res)) => {
+                let name = format!("{}.dwp", buildid::object_name(build_id));
+                match self.map_dwarf_data(res, name).await {
+                    Ok(data) => {
+                        supplements.dwp = Some(data);
+                        return supplements;
+                    }
+                    Err(e) => log::warn!(
+                        "Failed to map DWARF package for build_id {}: {}",
+                        build_id,
+                        e
+                    ),
+                }
+            }
+            Ok(None) => (),
+            Err(e) => log::warn!(
+                "Failed to fetch DWARF package for build_id {}: {}",
+                build_id,
+                e
+            ),
+        }
+
+        for dwo_id in refs.dwo_ids.iter().copied() {
+            let dbginfo = self
+                .metadata
+                .fetch(&format!("{:016x}", dwo_id), &DebuginfoType::SplitDwarf);
+            match self.fetcher.fetch_dwo(dbginfo.as_ref()).await {
+                Ok(Some(res)) => {
+                    match self
+                        .map_dwarf_data(res, format!("{:016x}.dwo", dwo_id))
+                        .await
+                    {
+                        Ok(data) => {
+                            supplements.dwos.insert(dwo_id, data);
+                        }
+                        Err(e) => log::warn!(
+                            "Failed to map split DWARF for dwo_id {:016x}: {}",
+                            dwo_id,
+                            e
+                        ),
+                    }
+                }
+                Ok(None) => log::debug!("Split DWARF for dwo_id {:016x} not found", dwo_id),
+                Err(e) => log::warn!(
+                    "Failed to fetch split DWARF for dwo_id {:016x}: {}",
+                    dwo_id,
+                    e
+                ),
+            }
+        }
+
+        supplements
+    }
+
     fn check_quality(q: &DebuginfoQuality) -> anyhow::Result<()> {
         if q.not_valid_elf {
             bail!("Not a valid ELF file");
@@ -140,58 +820,91 @@ impl Symbolizer {
         Ok(())
     }
 
-    fn create_and_write_temp_file(&self, data: &[u8], build_id: &str) -> anyhow::Result<PathBuf> {
-        let mut tmp_file = tempfile::NamedTempFile::new_in(&self.temp_dir)
-            .map_err(|e| Status::internal(format!("Failed to create temporary file: {}", e)))?;
+    /// Maps a fetched file. Files the bucket keeps on disk are mapped where
+    /// they are, anything else is streamed to a file in the temporary
+    /// directory first, so that the file is never held in memory as a whole.
+    async fn map_fetched(
+        &self,
+        res: GetResult,
+        name: String,
+    ) -> anyhow::Result<(PathBuf, memmap2::Mmap)> {
+        let (file, target_path) = match res.payload {
+            GetResultPayload::File(file, path) => (file, path),
+            GetResultPayload::Stream(_) => self.write_temp_file(res, name).await?,
+        };
 
-        tmp_file
-            .write_all(data)
-            .map_err(|e| Status::internal(format!("Failed to write to temporary file: {}", e)))?;
+        tokio::task::spawn_blocking(move || {
+            // SAFETY: Both the bucket and the temporary directory only ever
+            // replace or remove files by renaming or unlinking them, which
+            // leaves the mapped file untouched.
+            let mmap = unsafe { memmap2::Mmap::map(&file)? };
+            Ok((target_path, mmap))
+        })
+        .await?
+    }
+
+    async fn map_dwarf_data(&self, res: GetResult, name: String) -> anyhow::Result<DwarfData> {
+        let (_, mmap) = self.map_fetched(res, name).await?;
+        Ok(Arc::new(mmap))
+    }
+
+    /// Streams the fetched file to the temporary directory, and returns it
+    /// opened.
+    async fn write_temp_file(
+        &self,
+        res: GetResult,
+        name: String,
+    ) -> anyhow::Result<(std::fs::File, PathBuf)> {
+        let temp_dir = self.temp_dir.clone();
+        let tmp_file =
+            tokio::task::spawn_blocking(move || tempfile::NamedTempFile::new_in(temp_dir))
+                .await?
+                .map_err(|e| Status::internal(format!("Failed to create temporary file: {}", e)))?;
 
-        tmp_file
+        let mut writer = tokio::fs::File::from_std(tmp_file.as_file().try_clone()?);
+        let mut stream = res.into_stream();
+        while let Some(chunk) = stream.next().await {
+            writer.write_all(&chunk?).await.map_err(|e| {
+                Status::internal(format!("Failed to write to temporary file: {}", e))
+            })?;
+        }
+        writer
             .flush()
+            .await
             .map_err(|e| Status::internal(format!("Failed to flush temporary file: {e}")))?;
 
-        let target_path = self.temp_dir.join(build_id);
-        tmp_file
-            .persist(&target_path)
-            .map_err(|e| Status::internal(format!("Failed to persist temporary file: {}", e)))?;
-
-        Ok(target_path)
+        let target_path = self.temp_dir.join(name);
+        tokio::task::spawn_blocking(move || {
+            let file = tmp_file.persist(&target_path).map_err(|e| {
+                Status::internal(format!("Failed to persist temporary file: {}", e))
+            })?;
+            Ok((file, target_path))
+        })
+        .await?
     }
 
-    fn update_quality(&self, build_id: &str, quality: DebuginfoQuality) -> anyhow::Result<()> {
-        self.metadata
-            .set_quality(build_id, &quality, &DebuginfoType::DebuginfoUnspecified)?;
+    fn update_quality(
+        &self,
+        build_id: &str,
+        quality: DebuginfoQuality,
+        req_type: &DebuginfoType,
+    ) -> anyhow::Result<()> {
+        self.metadata.set_quality(build_id, &quality, req_type)?;
         Ok(())
     }
 
-    fn get_debug_info<'a>(
+    /// Records the outcome of check_elf: the file is marked invalid if it
+    /// isn't an ELF file, and its quality is recorded if it wasn't known yet.
+    fn record_check(
         &self,
         build_id: &str,
         dbginfo: &mut Debuginfo,
-        in_data: &'a [u8],
-    ) -> anyhow::Result<ElfDebugInfo<'a>> {
-        let target_path = self.create_and_write_temp_file(in_data, build_id)?;
-
-        let file = object::File::parse(in_data).map_err(|e| {
-            log::warn!("Received a bad object type. Details: {:#?}", e);
-            let quality = DebuginfoQuality {
-                not_valid_elf: true,
-                has_dwarf: false,
-                has_go_pclntab: false,
-                has_symtab: false,
-                has_dynsym: false,
-            };
-            let _ = self.update_quality(build_id, quality);
-            Status::internal(format!("Failed to parse object file: {}", e))
-        })?;
-
-        // check if the file is a valid ELF file, object crate does take other types of files
-        match file {
-            object::File::Elf32(_) | object::File::Elf64(_) => (),
-            _ => {
-                log::warn!("Received a different object type.");
+        checked: anyhow::Result<CheckedElf>,
+    ) -> anyhow::Result<DwarfRefs> {
+        let req_type = dbginfo.r#type();
+        let checked = match checked {
+            Ok(checked) => checked,
+            Err(e) => {
                 let quality = DebuginfoQuality {
                     not_valid_elf: true,
                     has_dwarf: false,
@@ -199,69 +912,463 @@ impl Symbolizer {
                     has_symtab: false,
                     has_dynsym: false,
                 };
-                let _ = self.update_quality(build_id, quality);
-                bail!("Not a valid ELF file");
+                let _ = self.update_quality(build_id, quality, &req_type);
+                return Err(e);
             }
-        }
-
-        if dbginfo.quality.is_none() {
-            let quality = DebuginfoQuality {
-                not_valid_elf: false,
-                has_dwarf: elfutils::has_dwarf(&file),
-                has_go_pclntab: elfutils::has_go_pcln_tab(&file),
-                has_symtab: elfutils::has_symtab(&file),
-                has_dynsym: elfutils::has_dynsym(&file),
-            };
+        };
 
-            // log::warn!(
-            //     "I got hereee inside get_debug_info(). i got this quality:  {:#?}",
-            //     quality
-            // );
+        if let Some(quality) = checked.quality {
             dbginfo.quality = Some(quality);
-            self.update_quality(&dbginfo.build_id, quality)?;
+            self.update_quality(&dbginfo.build_id, quality, &req_type)?;
 
             // Validate the new quality
             Self::check_quality(&quality)?;
         }
 
-        Ok(ElfDebugInfo {
-            target_path,
-            e: file,
-            quality: dbginfo.quality,
-        })
+        Ok(checked.dwarf_refs)
+    }
+}
+
+/// CheckedElf is what check_elf found out about a file.
+struct CheckedElf {
+    /// The quality of the file, if it wasn't known yet.
+    quality: Option<DebuginfoQuality>,
+    dwarf_refs: DwarfRefs,
+}
+
+/// Checks that the data is an ELF file, assessing its quality if it isn't
+/// known yet, and finds the other files its DWARF refers to. This is CPU
+/// bound.
+fn check_elf(data: &[u8], known: Option<DebuginfoQuality>) -> anyhow::Result<CheckedElf> {
+    let file = object::File::parse(data).map_err(|e| {
+        log::warn!("Received a bad object type. Details: {:#?}", e);
+        Status::internal(format!("Failed to parse object file: {}", e))
+    })?;
+
+    // check if the file is a valid ELF file, object crate does take other types of files
+    match file {
+        object::File::Elf32(_) | object::File::Elf64(_) => (),
+        _ => {
+            log::warn!("Received a different object type.");
+            bail!("Not a valid ELF file");
+        }
     }
+
+    let quality = match known {
+        Some(_) => None,
+        None => Some(elfutils::quality(&file)),
+    };
+    let dwarf_refs = if known.or(quality).is_some_and(|q| q.has_dwarf) {
+        DwarfRefs {
+            sup: elfutils::debug_alt_link(&file),
+            dwo_ids: elfutils::dwo_ids(&file),
+        }
+    } else {
+        DwarfRefs::default()
+    };
+    Ok(CheckedElf {
+        quality,
+        dwarf_refs,
+    })
 }
 
-//#[cfg(test)]
-//mod tests {
-//
-//    use object_store::ObjectStore;
-//
-//    use crate::{debuginfo_store, metapb, profile, storage};
-//
-//    use super::*;
-//
-//    #[test]
-//    fn symbolization_test() {
-//        let metadata_store = debuginfo_store::MetadataStore::new();
-//        let debuginfod = Arc::new(Mutex::new(debuginfo_store::DebugInfod::default()));
-//        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
-//        let symbolizer = Arc::new(Symbolizer::new(
-//            debuginfo_store::MetadataStore::with_store(metadata_store.store.clone()),
-//            DebuginfoFetcher::new(Arc::clone(&bucket), Arc::clone(&debuginfod)),
-//        ));
-//
-//        let mapping = metapb::Mapping {
-//            start: 4194304,
-//            limit: 4603904,
-//            build_id: "2d6912fd3dd64542f6f6294f4bf9cb6c265b3085".into(),
-//            ..Default::default()
-//        };
-//
-//        let location = profile::Location {
-//            mapping: Some(mapping.clone()),
-//            address: 0x463781,
-//            ..Default::default()
-//        };
-//    }
-//}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        debuginfo_store::DebugInfod,
+        debuginfopb::{upload_instructions::UploadStrategy, BuildIdType},
+        metapb,
+        profile::LocationLine,
+        storage,
+    };
+    use chrono::Utc;
+    use object_store::{path::Path, ObjectStore};
+    use std::sync::Arc;
+
+    const BUILD_ID: &str = "126cf12e76810726c76d87ab8a6a57e9a1d4c815";
+    const TESTDATA: &str = "src/symbols/addr_to_line/testdata";
+
+    async fn upload(
+        metadata: &MetadataStore,
+        bucket: &dyn ObjectStore,
+        req_type: DebuginfoType,
+        file: &str,
+    ) {
+        upload_as(metadata, bucket, BUILD_ID, req_type, file).await
+    }
+
+    async fn upload_as(
+        metadata: &MetadataStore,
+        bucket: &dyn ObjectStore,
+        build_id: &str,
+        req_type: DebuginfoType,
+        file: &str,
+    ) {
+        let upload_id = ulid::Ulid::new().to_string();
+        metadata
+            .mark_as_uploading(
+                build_id,
+                &upload_id,
+                "hash",
+                &req_type,
+                BuildIdType::Gnu,
+                UploadStrategy::Grpc,
+                Utc::now(),
+            )
+            .unwrap();
+        let data = std::fs::read(format!("{}/{}", TESTDATA, file)).unwrap();
+        bucket
+            .put(&Path::from(upload_id.as_str()), data.into())
+            .await
+            .unwrap();
+        metadata
+            .mark_as_uploaded(build_id, &upload_id, &req_type, Utc::now())
+            .unwrap();
+    }
+
+    async fn symbolize(files: &[(DebuginfoType, &str)]) -> anyhow::Result<Vec<LocationLine>> {
+        let metadata = MetadataStore::new();
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        for (req_type, file) in files {
+            upload(&metadata, bucket.as_ref(), *req_type, file).await;
+        }
+        let symbolizer = Symbolizer::new(
+            MetadataStore::with_store(metadata.store.clone()),
+            DebuginfoFetcher::new(Arc::clone(&bucket), DebugInfod::default()),
+        );
+
+        // c2() in the text segment, which starts at file offset 0x1000.
+        let mut location = Location {
+            address: 0x401156,
+            mapping: Some(metapb::Mapping {
+                start: 0x401000,
+                limit: 0x402000,
+                offset: 0x1000,
+                build_id: BUILD_ID.into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut locations = vec![&mut location];
+        let mut request = SymbolizationRequest {
+            build_id: BUILD_ID.into(),
+            mappings: vec![SymbolizationRequestMappingAddrs {
+                locations: locations.as_mut_slice(),
+            }],
+        };
+        symbolizer.symbolize(&mut request).await?;
+
+        Ok(location.lines)
+    }
+
+    #[tokio::test]
+    async fn test_symbolize_separate_debuginfo() {
+        let lines = symbolize(&[
+            (DebuginfoType::Executable, "basic-cpp-no-fp-stripped-debug"),
+            (DebuginfoType::DebuginfoUnspecified, "basic-cpp-no-fp.debug"),
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].line > 0);
+        let function = lines[0].function.as_ref().unwrap();
+        assert_eq!(function.system_name, "_Z2c2v");
+        assert_ne!(function.filename, "?");
+    }
+
+    #[tokio::test]
+    async fn test_symbolize_executable_only() {
+        let lines = symbolize(&[(DebuginfoType::Executable, "basic-cpp-no-fp-stripped-debug")])
+            .await
+            .unwrap();
+
+        // Without debuginfo the executable's symbol table is used.
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].line, 0);
+        assert_eq!(lines[0].function.as_ref().unwrap().system_name, "_Z2c2v");
+    }
+
+    #[tokio::test]
+    async fn test_build_id_locks() {
+        let locks = Arc::new(BuildIdLocks::default());
+        let first = locks.lock("a").await;
+        let _other = locks.lock("b").await;
+
+        let waiting = tokio::spawn({
+            let locks = Arc::clone(&locks);
+            async move {
+                let _guard = locks.lock("a").await;
+            }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        waiting.await.unwrap();
+        // The entry is removed once nobody holds or waits for it.
+        assert_eq!(locks.0.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_symbolize_not_found() {
+        assert!(symbolize(&[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_symbolize_locations_isolates_failures() {
+        let metadata = MetadataStore::new();
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        upload(
+            &metadata,
+            bucket.as_ref(),
+            DebuginfoType::Executable,
+            "basic-cpp-no-fp-stripped-debug",
+        )
+        .await;
+        let symbolizer = Arc::new(Symbolizer::new(
+            MetadataStore::with_store(metadata.store.clone()),
+            DebuginfoFetcher::new(Arc::clone(&bucket), DebugInfod::default()),
+        ));
+
+        let location = |build_id: &str| crate::profile::PprofLocations {
+            address: 0x401156,
+            number_of_lines: 0,
+            build_id: build_id.into(),
+            file_name: "basic-cpp".into(),
+            mapping_memory_start: 0x401000,
+            mapping_memory_end: 0x402000,
+            mapping_file_offset: 0x1000,
+            functions: vec![],
+        };
+        let encoded = [
+            location("0000000000000000000000000000000000000000")
+                .encode()
+                .unwrap(),
+            location(BUILD_ID).encode().unwrap(),
+        ];
+        let locations = datafusion::arrow::array::BinaryArray::from_iter_values(encoded.iter());
+
+        let symbolized = crate::profile::utils::symbolize_locations(
+            &locations,
+            &[None, None],
+            Arc::clone(&symbolizer),
+        )
+        .await
+        .unwrap();
+
+        // The build ID without debuginfo is returned unsymbolized.
+        let missing = symbolized[0].as_ref().unwrap();
+        assert_eq!(missing.address, 0x401156);
+        assert!(missing.lines.is_empty());
+        let found = symbolized[1].as_ref().unwrap();
+        assert_eq!(
+            found.lines[0].function.as_ref().unwrap().system_name,
+            "_Z2c2v"
+        );
+
+        // The executable stays mapped for the next request.
+        assert!(symbolizer
+            .elf_files
+            .contains_key(&(BUILD_ID.to_string(), DebuginfoType::Executable)));
+    }
+
+    #[tokio::test]
+    async fn test_symbolize_go_build_id() {
+        let file = "basic-go-with-debuginfo";
+        let data = std::fs::read(format!("{}/{}", TESTDATA, file)).unwrap();
+        let elf = object::File::parse(&*data).unwrap();
+        // The binary has no GNU build ID, only a Go build ID, which contains
+        // '/' and so can't be used as a file name as is.
+        assert!(elfutils::build_id(&elf).is_none());
+        let build_id = elfutils::go_build_id(&elf).unwrap();
+        assert_eq!(
+            build_id,
+            "GBQARYFuCXw4zGISXIvG/KjNR3sbcTb46XVfHcKCJ/G5iOmSaPKQvCL6SeuXIh/8m1xd8RXcywCH0gKwCcy"
+        );
+        let main = elfutils::gopclntab::symbol_address(&elf, "main.main").unwrap();
+
+        let metadata = MetadataStore::new();
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        upload_as(
+            &metadata,
+            bucket.as_ref(),
+            &build_id,
+            DebuginfoType::Executable,
+            file,
+        )
+        .await;
+        let symbolizer = Symbolizer::new(
+            MetadataStore::with_store(metadata.store.clone()),
+            DebuginfoFetcher::new(Arc::clone(&bucket), DebugInfod::default()),
+        );
+
+        let mut location = Location {
+            address: main,
+            mapping: Some(metapb::Mapping {
+                start: 0x10000,
+                limit: 0x80000,
+                offset: 0,
+                build_id: build_id.clone(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut locations = vec![&mut location];
+        let mut request = SymbolizationRequest {
+            build_id,
+            mappings: vec![SymbolizationRequestMappingAddrs {
+                locations: locations.as_mut_slice(),
+            }],
+        };
+        symbolizer.symbolize(&mut request).await.unwrap();
+
+        let function = location.lines.last().unwrap().function.as_ref().unwrap();
+        assert_eq!(function.name, "main.main");
+    }
+
+    #[tokio::test]
+    async fn test_symbolize_split_dwarf_uploaded_later() {
+        const SPLIT_BUILD_ID: &str = "58e86b9789342302bc7849e31d412b7e531240ac";
+        let metadata = MetadataStore::new();
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        upload_as(
+            &metadata,
+            bucket.as_ref(),
+            SPLIT_BUILD_ID,
+            DebuginfoType::Executable,
+            "basic-cpp-split-dwarf",
+        )
+        .await;
+        let symbolizer = Symbolizer::new(
+            MetadataStore::with_store(metadata.store.clone()),
+            DebuginfoFetcher::new(
+                Arc::clone(&bucket),
+                DebugInfod::default().with_upstream_servers(vec![]),
+            ),
+        );
+        // Addresses in add(int, int), in the text segment of the PIE.
+        let symbolize = |address: u64| {
+            let symbolizer = &symbolizer;
+            async move {
+                let mut location = Location {
+                    address: 0x555555555000 + address - 0x1000,
+                    mapping: Some(metapb::Mapping {
+                        start: 0x555555555000,
+                        limit: 0x555555556000,
+                        offset: 0x1000,
+                        build_id: SPLIT_BUILD_ID.into(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                let mut locations = vec![&mut location];
+                let mut request = SymbolizationRequest {
+                    build_id: SPLIT_BUILD_ID.into(),
+                    mappings: vec![SymbolizationRequestMappingAddrs {
+                        locations: locations.as_mut_slice(),
+                    }],
+                };
+                symbolizer.symbolize(&mut request).await.unwrap();
+                location.lines
+            }
+        };
+
+        // Without the split unit there are no functions to attribute lines to.
+        assert!(symbolize(0x1147).await.is_empty());
+
+        // The package is picked up once it is uploaded.
+        upload_as(
+            &metadata,
+            bucket.as_ref(),
+            SPLIT_BUILD_ID,
+            DebuginfoType::DwarfPackage,
+            "basic-cpp-split-dwarf.dwp",
+        )
+        .await;
+        let lines = symbolize(0x1143).await;
+        assert_eq!(lines[0].function.as_ref().unwrap().system_name, "_Z3addii");
+    }
+
+    const KERNEL_RELEASE: &str = "6.8.0-45-generic";
+
+    async fn symbolize_kernel(
+        files: &[(DebuginfoType, &str)],
+        build_id: &str,
+        address: u64,
+    ) -> Location {
+        let metadata = MetadataStore::new();
+        let bucket: Arc<dyn ObjectStore> = Arc::new(storage::new_memory_bucket());
+        for (req_type, file) in files {
+            upload(&metadata, bucket.as_ref(), *req_type, file).await;
+        }
+        // c2() stands in for a kernel function, loaded at a KASLR offset.
+        let kallsyms = "\
+ffffffff81000000 T _stext
+ffffffff81001000 T _Z2c2v
+ffffffffc0a01000 t nft_do_chain\t[nf_tables]
+";
+        bucket
+            .put(
+                &Path::from(format!("kernel/{}/kallsyms", KERNEL_RELEASE)),
+                kallsyms.as_bytes().to_vec().into(),
+            )
+            .await
+            .unwrap();
+        let symbolizer = Symbolizer::new(
+            MetadataStore::with_store(metadata.store.clone()),
+            DebuginfoFetcher::new(Arc::clone(&bucket), DebugInfod::default()),
+        );
+
+        let mut location = Location {
+            address,
+            mapping: Some(metapb::Mapping {
+                file: kernel::KERNEL_MAPPING.into(),
+                build_id: build_id.into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        symbolizer
+            .symbolize_kernel(Some(KERNEL_RELEASE), build_id, &mut [&mut location])
+            .await
+            .unwrap();
+
+        location
+    }
+
+    #[tokio::test]
+    async fn test_symbolize_kernel_debuginfo() {
+        let location = symbolize_kernel(
+            &[(DebuginfoType::DebuginfoUnspecified, "basic-cpp-no-fp.debug")],
+            BUILD_ID,
+            0xffffffff81001004,
+        )
+        .await;
+
+        assert_eq!(location.lines.len(), 1);
+        assert!(location.lines[0].line > 0);
+        let function = location.lines[0].function.as_ref().unwrap();
+        assert_eq!(function.system_name, "_Z2c2v");
+        assert_ne!(function.filename, "?");
+    }
+
+    #[tokio::test]
+    async fn test_symbolize_kernel_kallsyms() {
+        let location = symbolize_kernel(&[], "", 0xffffffff81001004).await;
+        assert_eq!(location.lines.len(), 1);
+        assert_eq!(location.lines[0].line, 0);
+        assert_eq!(
+            location.lines[0].function.as_ref().unwrap().system_name,
+            "_Z2c2v"
+        );
+
+        let location = symbolize_kernel(&[], "", 0xffffffffc0a01010).await;
+        assert_eq!(
+            location.lines[0].function.as_ref().unwrap().system_name,
+            "nft_do_chain"
+        );
+        assert_eq!(location.mapping.unwrap().file, "[nf_tables]");
+    }
+}
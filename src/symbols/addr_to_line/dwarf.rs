@@ -1,99 +1,471 @@
 use crate::symbolizer::normalize::NormalizedAddress;
-use crate::{metapb, profile, symbolizer::ElfDebugInfo, symbols::Demangler};
-use addr2line::LookupResult;
+use crate::{metapb, profile, symbols::Demangler};
+use addr2line::{LookupContinuation, LookupResult, SplitDwarfLoad};
+use anyhow::Context;
+use moka::sync::Cache;
 use object::{Object, ObjectSection};
-use std::borrow;
+use stable_deref_trait::{CloneStableDeref, StableDeref};
+use std::collections::HashMap;
+use std::ops::{Deref, Range};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-pub struct DwarfLiner<'data> {
-    elfdbginfo: &'data ElfDebugInfo<'data>,
-    demangler: &'data Demangler,
+/// DwarfData is a file DWARF is read from, usually a mapped one. Sections are
+/// borrowed from it, so it is kept as long as the context parsed from it.
+pub type DwarfData = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// Reader over DWARF sections that share their file, so the context can
+/// outlive the ELF file handle it was parsed from.
+type Reader = gimli::EndianReader<gimli::RunTimeEndian, SectionData>;
+
+/// SectionData is the data of a section, borrowed from the file it is in, or
+/// owned if it had to be decompressed.
+#[derive(Clone)]
+pub struct SectionData {
+    data: DwarfData,
+    range: Range<usize>,
+}
+
+impl SectionData {
+    fn empty() -> Self {
+        Self::owned(vec![])
+    }
+
+    fn owned(data: Vec<u8>) -> Self {
+        let range = 0..data.len();
+        Self {
+            data: Arc::new(data),
+            range,
+        }
+    }
+
+    /// Borrows the slice from the file it is in. Slices that aren't in the
+    /// file are copied.
+    fn borrowed(data: &DwarfData, slice: &[u8]) -> Self {
+        let file = (**data).as_ref();
+        let start = (slice.as_ptr() as usize).wrapping_sub(file.as_ptr() as usize);
+        match start.checked_add(slice.len()) {
+            Some(end) if end <= file.len() => Self {
+                data: Arc::clone(data),
+                range: start..end,
+            },
+            _ => Self::owned(slice.to_vec()),
+        }
+    }
+}
+
+impl Deref for SectionData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &(*self.data).as_ref()[self.range.clone()]
+    }
+}
+
+impl std::fmt::Debug for SectionData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SectionData")
+            .field("range", &self.range)
+            .finish_non_exhaustive()
+    }
+}
+
+// SAFETY: The file is shared by every clone and is neither moved nor
+// modified while it is, so they all deref to the same bytes.
+unsafe impl StableDeref for SectionData {}
+unsafe impl CloneStableDeref for SectionData {}
+
+/// DwarfSupplements are the debug files that complement the DWARF of an
+/// executable. Lookups can't wait on I/O, so they are fetched up front.
+#[derive(Default)]
+pub struct DwarfSupplements {
+    /// The supplementary file named by .gnu_debugaltlink, as produced by dwz.
+    pub sup: Option<DwarfData>,
+    /// The DWARF package (.dwp) with the split units of the executable.
+    pub dwp: Option<DwarfData>,
+    /// Split DWARF objects (.dwo) by DWO ID, for units missing a package.
+    pub dwos: HashMap<u64, DwarfData>,
+}
+
+impl std::fmt::Debug for DwarfSupplements {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DwarfSupplements")
+            .field("sup", &self.sup.is_some())
+            .field("dwp", &self.dwp.is_some())
+            .field("dwos", &self.dwos.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Lookups in the same executable that run at the same time each use a
+/// context of their own, up to this many.
+const CONTEXTS_PER_EXECUTABLE: usize = 4;
+
+/// DwarfContext is the parsed DWARF of an executable. Units, line programs
+/// and functions are parsed lazily on first lookup and then kept, so a
+/// context should be reused for all lookups in the same executable.
+pub struct DwarfContext {
+    sections: gimli::DwarfSections<Reader>,
+    sup: Option<gimli::DwarfSections<Reader>>,
+    // addr2line populates a context through interior mutability that isn't
+    // thread safe, so each is used by one lookup at a time. The sections are
+    // shared, only what was parsed from them is not.
+    contexts: Vec<Mutex<Option<addr2line::Context<Reader>>>>,
+    next_context: AtomicUsize,
+    dwp: Option<gimli::DwarfPackage<Reader>>,
+    dwos: HashMap<u64, DwarfData>,
+    size: usize,
+}
+
+impl std::fmt::Debug for DwarfContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DwarfContext")
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+fn endian(e: &object::File<'_>) -> gimli::RunTimeEndian {
+    if e.is_little_endian() {
+        gimli::RunTimeEndian::Little
+    } else {
+        gimli::RunTimeEndian::Big
+    }
+}
+
+/// Loads a section, borrowing it from the file unless it is compressed.
+/// Missing sections are loaded as empty ones.
+fn load_section(
+    data: &DwarfData,
+    e: &object::File<'_>,
+    name: Option<&str>,
     endian: gimli::RunTimeEndian,
+) -> anyhow::Result<Reader> {
+    let (name, section) = match name.and_then(|name| Some((name, e.section_by_name(name)?))) {
+        Some(section) => section,
+        None => return Ok(gimli::EndianReader::new(SectionData::empty(), endian)),
+    };
+    let compressed = section
+        .compressed_data()
+        .with_context(|| format!("Failed to read section {}", name))?;
+    let section = match compressed.format {
+        object::CompressionFormat::None => SectionData::borrowed(data, compressed.data),
+        _ => SectionData::owned(
+            compressed
+                .decompress()
+                .with_context(|| format!("Failed to decompress section {}", name))?
+                .into_owned(),
+        ),
+    };
+    Ok(gimli::EndianReader::new(section, endian))
 }
 
-impl<'data> DwarfLiner<'data> {
-    pub fn try_new(
-        elfdbginfo: &'data ElfDebugInfo,
-        demangler: &'data Demangler,
+impl DwarfContext {
+    pub fn with_supplements(
+        data: DwarfData,
+        supplements: DwarfSupplements,
     ) -> anyhow::Result<Self> {
-        let endian = if elfdbginfo.e.is_little_endian() {
-            gimli::RunTimeEndian::Little
-        } else {
-            gimli::RunTimeEndian::Big
+        let mut size = 0;
+        let e = object::File::parse((*data).as_ref())?;
+        let endian = endian(&e);
+        let sections = gimli::DwarfSections::load(|id| {
+            let section = load_section(&data, &e, Some(id.name()), endian)?;
+            size += section.len();
+            anyhow::Ok(section)
+        })?;
+
+        let sup = match &supplements.sup {
+            Some(data) => {
+                let e = object::File::parse((**data).as_ref())?;
+                Some(gimli::DwarfSections::load(|id| {
+                    let section = load_section(data, &e, Some(id.name()), endian)?;
+                    size += section.len();
+                    anyhow::Ok(section)
+                })?)
+            }
+            None => None,
+        };
+
+        let dwp = match &supplements.dwp {
+            Some(data) => {
+                let dwp = object::File::parse((**data).as_ref())?;
+                let endian = self::endian(&dwp);
+                size += (**data).as_ref().len();
+                Some(gimli::DwarfPackage::load(
+                    |id| load_section(data, &dwp, id.dwo_name(), endian),
+                    gimli::EndianReader::new(SectionData::empty(), endian),
+                )?)
+            }
+            None => None,
+        };
+        size += supplements
+            .dwos
+            .values()
+            .map(|data| (**data).as_ref().len())
+            .sum::<usize>();
+
+        let ctx = Self {
+            sections,
+            sup,
+            contexts: (0..CONTEXTS_PER_EXECUTABLE)
+                .map(|_| Mutex::new(None))
+                .collect(),
+            next_context: AtomicUsize::new(0),
+            dwp,
+            dwos: supplements.dwos,
+            size,
         };
+        // The first context is parsed right away, so that broken DWARF is
+        // an error here rather than on every lookup.
+        *ctx.contexts[0].lock().unwrap() = Some(ctx.new_context()?);
+        Ok(ctx)
+    }
 
-        Ok(Self {
-            elfdbginfo,
-            demangler,
-            endian,
-        })
+    /// Returns the size of the sections the context was parsed from.
+    pub fn size(&self) -> usize {
+        self.size
     }
 
-    pub fn pc_to_lines(
+    fn new_context(&self) -> anyhow::Result<addr2line::Context<Reader>> {
+        let mut dwarf = self.sections.borrow(|section| section.clone());
+        if let Some(sup) = &self.sup {
+            dwarf.set_sup(sup.borrow(|section| section.clone()));
+        }
+        Ok(addr2line::Context::from_dwarf(dwarf)?)
+    }
+
+    /// Runs the lookup with a context no other lookup is using, parsing a new
+    /// one if there are none left but there can be more. Otherwise it waits
+    /// for one.
+    fn with_context<T>(
         &self,
-        addr: NormalizedAddress,
-    ) -> anyhow::Result<Vec<profile::LocationLine>> {
-        self.source_lines(addr.0)
+        lookup: impl FnOnce(&addr2line::Context<Reader>) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut slot = match self.contexts.iter().find_map(|slot| slot.try_lock().ok()) {
+            Some(slot) => slot,
+            None => {
+                let next = self.next_context.fetch_add(1, Ordering::Relaxed);
+                self.contexts[next % self.contexts.len()].lock().unwrap()
+            }
+        };
+        if slot.is_none() {
+            *slot = Some(self.new_context()?);
+        }
+        lookup(slot.as_ref().unwrap())
     }
 
-    fn source_lines(&self, addr: u64) -> anyhow::Result<Vec<profile::LocationLine>> {
-        // Load a section and return as `Cow<[u8]>`.
-        let load_section = |id: gimli::SectionId| -> anyhow::Result<borrow::Cow<[u8]>> {
-            Ok(match self.elfdbginfo.e.section_by_name(id.name()) {
-                Some(section) => section.uncompressed_data()?,
-                None => borrow::Cow::Borrowed(&[]),
+    /// Loads the split unit with the given DWO ID, looking in the package
+    /// first and then in the separate .dwo files.
+    fn load_dwo(
+        &self,
+        dwo_id: gimli::DwoId,
+        parent: &gimli::Dwarf<Reader>,
+    ) -> anyhow::Result<Option<gimli::Dwarf<Reader>>> {
+        let dwo = match &self.dwp {
+            Some(dwp) => dwp.find_cu(dwo_id, parent)?,
+            None => None,
+        };
+        let mut dwo = match (dwo, self.dwos.get(&dwo_id.0)) {
+            (Some(dwo), _) => dwo,
+            (None, Some(data)) => {
+                let e = object::File::parse((**data).as_ref())?;
+                let endian = endian(&e);
+                let mut dwo =
+                    gimli::Dwarf::load(|id| load_section(data, &e, id.dwo_name(), endian))?;
+                dwo.make_dwo(parent);
+                dwo
+            }
+            (None, None) => return Ok(None),
+        };
+
+        // Lines always come from the skeleton unit, but addr2line resolves
+        // their file names against the sections of the split unit. Split
+        // units never use .debug_line_str themselves, so the parent's can be
+        // shared.
+        dwo.debug_line_str = parent.debug_line_str.clone();
+        Ok(Some(dwo))
+    }
+}
+
+/// How long a context that was parsed without some of its supplements is
+/// used, before they are looked for again.
+const INCOMPLETE_CONTEXT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Total size of the DWARF of the contexts kept. What addr2line parses from
+/// the sections grows with their size, and decompressed sections are held in
+/// memory.
+const DWARF_CONTEXT_CACHE_SIZE: u64 = 2 << 30;
+
+/// DwarfContextCache keeps the DWARF contexts of recently symbolized
+/// executables by build ID, so they are shared across requests. A context is
+/// kept along with the version of the supplements it was parsed with, eg. the
+/// uploads they came from, so that it is parsed again once they change.
+#[derive(Debug, Clone)]
+pub struct DwarfContextCache {
+    c: Cache<String, CachedContext>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedContext {
+    ctx: Arc<DwarfContext>,
+    supplements: String,
+    expires: Option<Instant>,
+}
+
+impl Default for DwarfContextCache {
+    fn default() -> Self {
+        Self::new(DWARF_CONTEXT_CACHE_SIZE)
+    }
+}
+
+impl DwarfContextCache {
+    /// Creates a cache that keeps contexts up to the total size of their
+    /// DWARF.
+    pub fn new(size: u64) -> Self {
+        Self {
+            c: Cache::builder()
+                .max_capacity(size)
+                .weigher(|_, cached: &CachedContext| {
+                    cached.ctx.size().try_into().unwrap_or(u32::MAX)
+                })
+                .build(),
+        }
+    }
+
+    /// Returns the context of the build ID if it was parsed with the given
+    /// version of its supplements.
+    pub fn get(&self, build_id: &str, supplements: &str) -> Option<Arc<DwarfContext>> {
+        self.c
+            .get(build_id)
+            .filter(|cached| cached.supplements == supplements)
+            .filter(|cached| {
+                cached
+                    .expires
+                    .is_none_or(|expires| Instant::now() < expires)
             })
+            .map(|cached| cached.ctx)
+    }
+
+    /// Keeps the context, parsed with the given version of its supplements.
+    /// Contexts that are missing some of them are only kept for a while.
+    pub fn insert(
+        &self,
+        build_id: &str,
+        supplements: String,
+        complete: bool,
+        ctx: Arc<DwarfContext>,
+    ) {
+        let expires = (!complete).then(|| Instant::now() + INCOMPLETE_CONTEXT_TTL);
+        self.c.insert(
+            build_id.to_string(),
+            CachedContext {
+                ctx,
+                supplements,
+                expires,
+            },
+        );
+    }
+
+    /// Drops the context of the build ID.
+    pub fn invalidate(&self, build_id: &str) {
+        self.c.invalidate(build_id);
+    }
+}
+
+/// Returns the line a function is declared at. Inlined and out-of-line
+/// instances refer to the declaration through their abstract origin or
+/// specification, which are followed within the unit.
+fn decl_line(unit: gimli::UnitRef<Reader>, mut offset: gimli::UnitOffset) -> Option<i64> {
+    // Chains are short, the bound only guards against malformed DWARF.
+    for _ in 0..8 {
+        let entry = unit.entry(offset).ok()?;
+        if let Some(line) = entry
+            .attr_value(gimli::DW_AT_decl_line)
+            .ok()?
+            .and_then(|v| v.udata_value())
+        {
+            return Some(line as i64);
+        }
+
+        let origin = match entry.attr_value(gimli::DW_AT_abstract_origin).ok()? {
+            Some(origin) => Some(origin),
+            None => entry.attr_value(gimli::DW_AT_specification).ok()?,
+        };
+        offset = match origin? {
+            gimli::AttributeValue::UnitRef(offset) => offset,
+            _ => return None,
         };
+    }
+    None
+}
 
-        // Borrow a `Cow<[u8]>` to create an `EndianSlice`.
-        let borrow_section =
-            |section| gimli::EndianSlice::new(borrow::Cow::as_ref(section), self.endian);
+pub struct DwarfLiner<'data> {
+    ctx: Arc<DwarfContext>,
+    demangler: &'data Demangler,
+}
 
-        // Load all of the sections.
-        let dwarf_sections = gimli::DwarfSections::load(&load_section)?;
+impl<'data> DwarfLiner<'data> {
+    pub fn with_context(ctx: Arc<DwarfContext>, demangler: &'data Demangler) -> Self {
+        Self { ctx, demangler }
+    }
 
-        // Create `EndianSlice`s for all of the sections.
-        let dwarf = dwarf_sections.borrow(borrow_section);
+    pub fn pc_to_lines(
+        &self,
+        addr: NormalizedAddress,
+    ) -> anyhow::Result<Vec<profile::LocationLine>> {
+        self.source_lines(addr.0)
+    }
 
-        // Constructing a Context is somewhat costly, so users should aim to reuse Contexts when performing lookups for many addresses in the same executable.
-        let c = addr2line::Context::from_dwarf(dwarf)?;
+    /// Returns a line per frame at the address, innermost first. The line of
+    /// the innermost frame is the one of the address, the line of each of
+    /// its callers the one the call was inlined at.
+    fn source_lines(&self, addr: u64) -> anyhow::Result<Vec<profile::LocationLine>> {
+        self.ctx.with_context(|c| self.frames(c, addr))
+    }
+
+    fn frames(
+        &self,
+        c: &addr2line::Context<Reader>,
+        addr: u64,
+    ) -> anyhow::Result<Vec<profile::LocationLine>> {
+        let mut lookup = c.find_dwarf_and_unit(addr);
+        let unit = loop {
+            match lookup {
+                LookupResult::Output(unit) => break unit,
+                LookupResult::Load { load, continuation } => {
+                    lookup = continuation.resume(self.load_split_dwarf(load));
+                }
+            }
+        };
 
         let mut lines = vec![];
-        let frames = c.find_frames(addr);
+        let mut lookup = c.find_frames(addr);
 
         let mut result = loop {
-            match frames {
+            match lookup {
                 LookupResult::Output(result) => break result,
-                LookupResult::Load {
-                    load: _,
-                    continuation: _,
-                } => {}
+                LookupResult::Load { load, continuation } => {
+                    lookup = continuation.resume(self.load_split_dwarf(load));
+                }
             }
         }?;
 
         while let Some(frame) = result.next()? {
-            let function = match frame.function {
-                Some(function) => function,
-                None => continue,
+            // Without a name there is nothing to attribute the frame to.
+            let name = match frame.function.as_ref().map(|f| f.raw_name()) {
+                Some(Ok(name)) => name,
+                _ => continue,
             };
 
-            let location = match frame.location {
-                Some(location) => location,
-                None => continue,
+            let (file, line) = match &frame.location {
+                Some(location) => (location.file, location.line),
+                None => (None, None),
             };
 
-            let start_line = match location.line {
-                Some(line) => line as i64,
-                None => continue,
-            };
-
-            let file = match location.file {
-                Some(file) => file,
-                None => continue,
-            };
-
-            let name = match function.raw_name() {
-                Ok(name) => name,
-                Err(_) => continue,
+            let start_line = match (unit, frame.dw_die_offset) {
+                (Some(unit), Some(offset)) => decl_line(unit, offset).unwrap_or(0),
+                _ => 0,
             };
 
             let func = self.demangler.demangle(&metapb::Function {
@@ -101,20 +473,44 @@ impl<'data> DwarfLiner<'data> {
                 start_line,
                 name: String::default(),
                 system_name: name.into(),
-                filename: file.to_owned(),
+                filename: file.unwrap_or("?").to_owned(),
                 name_string_index: 0,
                 system_name_string_index: 0,
                 filename_string_index: 0,
             });
 
             lines.push(profile::LocationLine {
-                line: start_line,
+                line: line.map(|l| l as i64).unwrap_or(0),
                 function: Some(func),
             });
         }
 
         Ok(lines)
     }
+
+    /// Loads the split DWARF a skeleton unit refers to. If it isn't
+    /// available, the lookup continues with what's in the skeleton unit,
+    /// which has the line table but none of the functions.
+    fn load_split_dwarf(&self, load: SplitDwarfLoad<Reader>) -> Option<Arc<gimli::Dwarf<Reader>>> {
+        match self.ctx.load_dwo(load.dwo_id, &load.parent) {
+            Ok(Some(dwo)) => Some(Arc::new(dwo)),
+            Ok(None) => {
+                log::debug!(
+                    "Split DWARF for dwo_id {:016x} is not available, continuing without it",
+                    load.dwo_id.0
+                );
+                None
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to load split DWARF for dwo_id {:016x}: {}",
+                    load.dwo_id.0,
+                    e
+                );
+                None
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -123,6 +519,11 @@ mod tests {
 
     use super::*;
 
+    fn context(data: &[u8]) -> DwarfContext {
+        DwarfContext::with_supplements(Arc::new(data.to_vec()), DwarfSupplements::default())
+            .unwrap()
+    }
+
     #[test]
     fn test_cpp_symbolizer() {
         let path =
@@ -131,18 +532,144 @@ mod tests {
             Ok(data) => data,
             Err(e) => panic!("Failed to read file: {:?}", e),
         };
-        let elfdbginfo = ElfDebugInfo {
-            target_path: path,
-            e: object::File::parse(&*data).unwrap(),
-            quality: None,
-        };
         let demangler = Demangler::new(false);
-        let d = DwarfLiner::try_new(&elfdbginfo, &demangler).unwrap();
+        let d = DwarfLiner::with_context(Arc::new(context(&data)), &demangler);
         let _ = d
             .pc_to_lines(NormalizedAddress(0x0000000000401156))
             .unwrap();
     }
 
+    #[test]
+    fn test_context_cache() {
+        let path =
+            PathBuf::from("src/symbols/addr_to_line/testdata/basic-cpp-no-fp-with-debuginfo");
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => panic!("Failed to read file: {:?}", e),
+        };
+        let contexts = DwarfContextCache::default();
+        assert!(contexts.get("build-id", "").is_none());
+        contexts.insert("build-id", "".into(), true, Arc::new(context(&data)));
+        let a = contexts.get("build-id", "").unwrap();
+        let b = contexts.get("build-id", "").unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        // A context parsed with other supplements is parsed again.
+        assert!(contexts.get("build-id", "dwp").is_none());
+
+        let demangler = Demangler::new(false);
+        let d = DwarfLiner::with_context(a, &demangler);
+        let lines = d
+            .pc_to_lines(NormalizedAddress(0x0000000000401156))
+            .unwrap();
+        assert!(!lines.is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_lookups() {
+        let data = std::fs::read("src/symbols/addr_to_line/testdata/basic-cpp-inline").unwrap();
+        let ctx = Arc::new(context(&data));
+        assert!(ctx.size() > 0);
+
+        let demangler = Demangler::new(false);
+        let expected = format!(
+            "{:?}",
+            DwarfLiner::with_context(Arc::clone(&ctx), &demangler)
+                .pc_to_lines(NormalizedAddress(0x401172))
+                .unwrap()
+        );
+        std::thread::scope(|s| {
+            for _ in 0..2 * CONTEXTS_PER_EXECUTABLE {
+                s.spawn(|| {
+                    let d = DwarfLiner::with_context(Arc::clone(&ctx), &demangler);
+                    for _ in 0..16 {
+                        let lines = d.pc_to_lines(NormalizedAddress(0x401172)).unwrap();
+                        assert_eq!(format!("{:?}", lines), expected);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_split_dwarf() {
+        let path = PathBuf::from("src/symbols/addr_to_line/testdata/basic-cpp-split-dwarf");
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => panic!("Failed to read file: {:?}", e),
+        };
+        let dwo_ids = crate::symbols::elfutils::dwo_ids(&object::File::parse(&*data).unwrap());
+        assert_eq!(dwo_ids.len(), 1);
+
+        let demangler = Demangler::new(false);
+        // add(int, int)
+        let addr = NormalizedAddress(0x1147);
+
+        // Without the split unit there are no functions to attribute lines to.
+        let d = DwarfLiner::with_context(Arc::new(context(&data)), &demangler);
+        assert!(d.pc_to_lines(addr).unwrap().is_empty());
+
+        let dwp =
+            std::fs::read("src/symbols/addr_to_line/testdata/basic-cpp-split-dwarf.dwp").unwrap();
+        let dwo = std::fs::read("src/symbols/addr_to_line/testdata/basic-cpp-split-dwarf-main.dwo")
+            .unwrap();
+        for supplements in [
+            DwarfSupplements {
+                dwp: Some(Arc::new(dwp)),
+                ..Default::default()
+            },
+            DwarfSupplements {
+                dwos: HashMap::from([(dwo_ids[0], Arc::new(dwo) as DwarfData)]),
+                ..Default::default()
+            },
+        ] {
+            let ctx = DwarfContext::with_supplements(Arc::new(data.clone()), supplements).unwrap();
+            let d = DwarfLiner::with_context(Arc::new(ctx), &demangler);
+            let lines = d.pc_to_lines(addr).unwrap();
+            assert_eq!(lines.len(), 1);
+            let function = lines[0].function.as_ref().unwrap();
+            assert_eq!(function.system_name, "_Z3addii");
+            assert!(function.filename.ends_with("main.cpp"));
+            assert_eq!(lines[0].line, 4);
+            assert_eq!(function.start_line, 3);
+        }
+    }
+
+    #[test]
+    fn test_inlined_frames() {
+        let path = PathBuf::from("src/symbols/addr_to_line/testdata/basic-cpp-inline");
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => panic!("Failed to read file: {:?}", e),
+        };
+        let demangler = Demangler::new(false);
+        let d = DwarfLiner::with_context(Arc::new(context(&data)), &demangler);
+
+        // The multiplication in square(), inlined into sum_squares<int>(),
+        // inlined into compute(int).
+        let lines = d.pc_to_lines(NormalizedAddress(0x401172)).unwrap();
+        let frames: Vec<(&str, i64, i64)> = lines
+            .iter()
+            .map(|l| {
+                let f = l.function.as_ref().unwrap();
+                (f.name.as_str(), f.start_line, l.line)
+            })
+            .collect();
+        assert_eq!(
+            frames,
+            vec![
+                ("square", 3, 4),
+                ("sum_squares<int>", 8, 11),
+                ("compute(int)", 15, 16),
+            ]
+        );
+        assert!(lines.iter().all(|l| l
+            .function
+            .as_ref()
+            .unwrap()
+            .filename
+            .ends_with("inline.cpp")));
+    }
+
     #[test]
     fn test_go_symbolizer() {
         let path = PathBuf::from("src/symbols/addr_to_line/testdata/basic-go-with-debuginfo");
@@ -150,13 +677,8 @@ mod tests {
             Ok(data) => data,
             Err(e) => panic!("Failed to read file: {:?}", e),
         };
-        let elfdbginfo = ElfDebugInfo {
-            target_path: path,
-            e: object::File::parse(&*data).unwrap(),
-            quality: None,
-        };
         let demangler = Demangler::new(false);
-        let d = DwarfLiner::try_new(&elfdbginfo, &demangler).unwrap();
+        let d = DwarfLiner::with_context(Arc::new(context(&data)), &demangler);
         let _ = d
             .pc_to_lines(NormalizedAddress(0x0000000000041290))
             .unwrap();
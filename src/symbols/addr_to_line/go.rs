@@ -1,107 +1,531 @@
 use crate::symbolizer::{normalize::NormalizedAddress, ElfDebugInfo};
-use object::{Object, ObjectSection, ObjectSegment, ObjectSymbol};
-use tonic::Status;
+use crate::symbols::elfutils::gopclntab::{
+    self, read_u32, read_uintptr, GO_1_16_MAGIC, GO_1_18_MAGIC, GO_1_20_MAGIC, GO_1_2_MAGIC,
+};
+use crate::{metapb, profile, symbols::Demangler};
+use anyhow::{bail, Context};
+use object::{Object, ObjectSection};
+use std::borrow::Cow;
 
+/// Index of the PCDATA table that maps a PC to its inline tree index.
+const PCDATA_INL_TREE_INDEX: usize = 2;
+/// Index of the FUNCDATA entry that points to the inline tree.
+const FUNCDATA_INL_TREE: usize = 3;
+
+/// Upper bound of inlined frames resolved for a single PC, which protects
+/// against cycles in corrupted inline trees.
+const MAX_INLINE_DEPTH: usize = 1024;
+
+/// Version of the pclntab format. It only changes with some Go releases, so
+/// every variant is named after the first release that used it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Version {
+    Go12,
+    Go116,
+    Go118,
+    Go120,
+}
+
+/// Func is a function entry of the pclntab.
+struct Func {
+    entry: u64,
+    /// Offset of the function's `_func` struct in the table.
+    offset: usize,
+}
+
+/// InlinedCall is an entry of a function's inline tree.
+struct InlinedCall {
+    name_off: u32,
+    parent_pc: u32,
+    start_line: i64,
+}
+
+/// GoLiner resolves PCs using the pclntab, which the Go runtime needs for
+/// stack unwinding and therefore survives stripping.
 pub struct GoLiner<'data> {
     elfdbginfo: &'data ElfDebugInfo<'data>,
+    demangler: &'data Demangler,
+    data: Cow<'data, [u8]>,
+    version: Version,
+    little_endian: bool,
+    quantum: u32,
+    ptr_size: usize,
+    text_start: u64,
+    nfunctab: usize,
+    functab: usize,
+    funcdata: usize,
+    funcnametab: usize,
+    cutab: usize,
+    filetab: usize,
+    pctab: usize,
+    /// Base address of funcdata offsets, only used from Go 1.18 on.
+    gofunc: Option<u64>,
 }
 
 impl<'data> GoLiner<'data> {
-    pub fn try_new(elfdbginfo: &'data ElfDebugInfo) -> Result<Self, Status> {
-        Ok(Self { elfdbginfo })
+    pub fn try_new(
+        elfdbginfo: &'data ElfDebugInfo,
+        demangler: &'data Demangler,
+    ) -> anyhow::Result<Self> {
+        let pclntab = gopclntab::find_pcln_tab(&elfdbginfo.e).context("No pclntab found")?;
+        Self::with_pclntab(elfdbginfo, demangler, pclntab)
     }
 
-    pub fn pc_to_lines(&self, pc: NormalizedAddress) -> Result<(), Status> {
-        let mut text_start = 0_u64;
-        if let Some(section) = self.elfdbginfo.e.section_by_name(".text") {
-            text_start = section.address();
-        }
+    fn with_pclntab(
+        elfdbginfo: &'data ElfDebugInfo,
+        demangler: &'data Demangler,
+        pclntab: gopclntab::PclnTab<'data>,
+    ) -> anyhow::Result<Self> {
+        let e = &elfdbginfo.e;
+        let data = pclntab.data;
+        let little_endian = e.is_little_endian();
 
-        let symtab = match self.elfdbginfo.e.section_by_name(".gosymtab") {
-            Some(section) => section.uncompressed_data(),
-            None => {
-                let section = match self.elfdbginfo.e.section_by_name(".data.rel.ro.gosymtab") {
-                    Some(section) => section.uncompressed_data(),
-                    None => Self::symbol_data(self.elfdbginfo, "runtime.symtab", "runtime.esymtab"),
-                };
-                section
+        let version = match read_u32(little_endian, &data) {
+            GO_1_2_MAGIC => Version::Go12,
+            GO_1_16_MAGIC => Version::Go116,
+            GO_1_18_MAGIC => Version::Go118,
+            GO_1_20_MAGIC => Version::Go120,
+            magic => bail!("Unknown pclntab magic {:#x}", magic),
+        };
+        let quantum = data[6] as u32;
+        let ptr_size = data[7] as usize;
+        let word = |n: usize| -> anyhow::Result<u64> {
+            let offset = 8 + n * ptr_size;
+            if offset + ptr_size > data.len() {
+                bail!("pclntab header is truncated");
             }
+            Ok(read_uintptr(little_endian, ptr_size, &data[offset..]))
+        };
+        // End of a function table of nfunctab entries and the end PC, which
+        // a corrupted count can make overflow.
+        let functab_end = |functab: usize, nfunctab: usize, field_size: usize| {
+            nfunctab
+                .checked_mul(2)
+                .and_then(|n| n.checked_add(1))
+                .and_then(|n| n.checked_mul(field_size))
+                .and_then(|size| functab.checked_add(size))
         };
 
-        let pclntab = match self.elfdbginfo.e.section_by_name(".gopclntab") {
-            Some(section) => section.uncompressed_data(),
-            None => {
-                let section = match self.elfdbginfo.e.section_by_name(".data.rel.ro.gopclntab") {
-                    Some(section) => section.uncompressed_data(),
-                    None => {
-                        Self::symbol_data(self.elfdbginfo, "runtime.pclntab", "runtime.epclntab")
-                    }
+        let nfunctab = word(0)? as usize;
+        let (functab, funcdata, funcnametab, cutab, filetab, pctab) = match version {
+            Version::Go12 => {
+                let functab = 8 + ptr_size;
+                let filetab = match functab_end(functab, nfunctab, ptr_size)
+                    .and_then(|offset| data.get(offset..)?.get(..4))
+                {
+                    Some(data) => read_u32(little_endian, data) as usize,
+                    None => bail!("pclntab is truncated"),
                 };
-                section
+                (functab, 0, 0, 0, filetab, 0)
             }
+            Version::Go116 => (
+                word(6)? as usize,
+                word(6)? as usize,
+                word(2)? as usize,
+                word(3)? as usize,
+                word(4)? as usize,
+                word(5)? as usize,
+            ),
+            Version::Go118 | Version::Go120 => (
+                word(7)? as usize,
+                word(7)? as usize,
+                word(3)? as usize,
+                word(4)? as usize,
+                word(5)? as usize,
+                word(6)? as usize,
+            ),
+        };
+
+        let header_text_start = match version {
+            Version::Go118 | Version::Go120 => word(2)?,
+            _ => 0,
         };
 
-        if let Some(a) = Self::runtime_text_addr(self.elfdbginfo) {
-            text_start = a;
+        let mut liner = Self {
+            elfdbginfo,
+            demangler,
+            version,
+            little_endian,
+            quantum,
+            ptr_size,
+            text_start: 0,
+            nfunctab,
+            functab,
+            funcdata,
+            funcnametab,
+            cutab,
+            filetab,
+            pctab,
+            gofunc: None,
+            data,
         };
 
-        Ok(())
+        match functab_end(liner.functab, nfunctab, liner.functab_field_size()) {
+            Some(end) if end <= liner.data.len() => (),
+            _ => bail!("pclntab function table is out of bounds"),
+        }
+
+        if version >= Version::Go118 {
+            // The start of the text section recorded in the header may be
+            // unrelocated, so prefer the runtime's own view of it.
+            let moduledata = liner.moduledata(pclntab.address);
+            liner.text_start = gopclntab::symbol_address(e, "runtime.text")
+                .or(moduledata.map(|(text, _)| text))
+                .or(Some(header_text_start).filter(|text| *text != 0))
+                .or_else(|| e.section_by_name(".text").map(|s| s.address()))
+                .unwrap_or_default();
+            liner.gofunc = gopclntab::symbol_address(e, "go:func.*")
+                .or_else(|| gopclntab::symbol_address(e, "go.func.*"))
+                .or(moduledata.map(|(_, gofunc)| gofunc));
+        }
+
+        Ok(liner)
     }
 
-    fn symbol_data(
-        elfdbginfo: &ElfDebugInfo<'data>,
-        start: &str,
-        end: &str,
-    ) -> Result<std::borrow::Cow<'data, [u8]>, object::Error> {
-        let symbols = elfdbginfo.e.symbols();
-        let (addr, eaddr) = {
-            let mut addr = 0_u64;
-            let mut eaddr = 0_u64;
+    pub fn pc_to_lines(&self, pc: NormalizedAddress) -> anyhow::Result<Vec<profile::LocationLine>> {
+        self.source_lines(pc.0)
+    }
 
-            for symbol in symbols {
-                let name = match symbol.name() {
-                    Ok(name) => name,
-                    Err(_) => continue,
-                };
-                if name.eq(start) {
-                    addr = symbol.address();
-                } else if name.eq(end) {
-                    eaddr = symbol.address();
-                }
+    fn source_lines(&self, pc: u64) -> anyhow::Result<Vec<profile::LocationLine>> {
+        let f = match self.find_func(pc) {
+            Some(f) => f,
+            None => bail!("No function found for the given address"),
+        };
 
-                if addr != 0 && eaddr != 0 {
-                    break;
-                }
+        let pcfile = self.field(&f, 5)?;
+        let pcln = self.field(&f, 6)?;
+        let inline_tree = self.inline_tree(&f);
+
+        let mut lines = vec![];
+        let mut pc = pc;
+        for _ in 0..MAX_INLINE_DEPTH {
+            let filename = match self.pcvalue(pcfile, f.entry, pc) {
+                Some(fno) => self.file_name(&f, fno)?,
+                None => "?".into(),
+            };
+            let line = self.pcvalue(pcln, f.entry, pc).unwrap_or_default() as i64;
+
+            let call = match inline_tree {
+                Some((index_table, tree)) => match self.pcvalue(index_table, f.entry, pc) {
+                    Some(ix) if ix >= 0 => Some(self.inlined_call(tree, ix as u64)?),
+                    _ => None,
+                },
+                None => None,
+            };
+
+            // PCs of inlined code resolve to the position in the inlined
+            // function. Its caller is found by walking up the inline tree,
+            // until the function the code was inlined into is reached.
+            let (name_off, start_line) = match &call {
+                Some(call) => (call.name_off, call.start_line),
+                None => (self.field(&f, 1)?, self.start_line(&f)?),
+            };
+
+            lines.push(profile::LocationLine {
+                line,
+                function: Some(self.demangler.demangle(&metapb::Function {
+                    start_line,
+                    system_name: self.func_name(name_off)?,
+                    filename,
+                    ..Default::default()
+                })),
+            });
+
+            match call {
+                Some(call) => pc = f.entry + call.parent_pc as u64,
+                None => return Ok(lines),
+            }
+        }
+
+        bail!(
+            "Inline tree exceeds the maximum depth of {}",
+            MAX_INLINE_DEPTH
+        )
+    }
+
+    /// Finds the function containing pc with a binary search of the function
+    /// table, which is sorted by entry PC and terminated by the end PC of the
+    /// last function.
+    fn find_func(&self, pc: u64) -> Option<Func> {
+        if self.nfunctab == 0 || pc < self.func_entry(0) || pc >= self.func_entry(self.nfunctab) {
+            return None;
+        }
+
+        let (mut lo, mut hi) = (0, self.nfunctab);
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if self.func_entry(mid) <= pc {
+                lo = mid;
+            } else {
+                hi = mid;
             }
+        }
+
+        let field_size = self.functab_field_size();
+        let offset = self.read_field(self.functab + (lo * 2 + 1) * field_size);
+        Some(Func {
+            entry: self.func_entry(lo),
+            offset: self.funcdata + offset as usize,
+        })
+    }
+
+    fn functab_field_size(&self) -> usize {
+        if self.version >= Version::Go118 {
+            4
+        } else {
+            self.ptr_size
+        }
+    }
 
-            (addr, eaddr)
+    fn func_entry(&self, i: usize) -> u64 {
+        let entry = self.read_field(self.functab + i * 2 * self.functab_field_size());
+        if self.version >= Version::Go118 {
+            self.text_start + entry
+        } else {
+            entry
+        }
+    }
+
+    fn read_field(&self, offset: usize) -> u64 {
+        if self.version >= Version::Go118 {
+            read_u32(self.little_endian, &self.data[offset..]) as u64
+        } else {
+            read_uintptr(self.little_endian, self.ptr_size, &self.data[offset..])
+        }
+    }
+
+    /// Returns the nth 32-bit field of a `_func`, following its entry PC.
+    fn field(&self, f: &Func, n: usize) -> anyhow::Result<u32> {
+        let offset = f.offset + self.entry_size() + (n - 1) * 4;
+        match self.data.get(offset..offset + 4) {
+            Some(data) => Ok(read_u32(self.little_endian, data)),
+            None => bail!("pclntab function data is out of bounds"),
+        }
+    }
+
+    /// Size of the entry PC at the start of a `_func`.
+    fn entry_size(&self) -> usize {
+        if self.version >= Version::Go118 {
+            4
+        } else {
+            self.ptr_size
+        }
+    }
+
+    fn start_line(&self, f: &Func) -> anyhow::Result<i64> {
+        // The start line was added to `_func` in Go 1.20.
+        if self.version >= Version::Go120 {
+            Ok(self.field(f, 9)? as i32 as i64)
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Returns the size of the fixed part of a `_func`, which is followed by
+    /// the pcdata offsets and the funcdata, and the offset of nfuncdata in it.
+    fn func_header_size(&self) -> (usize, usize) {
+        let size = match self.version {
+            Version::Go12 => self.entry_size() + 32,
+            Version::Go116 => self.entry_size() + 36,
+            Version::Go118 => 40,
+            Version::Go120 => 44,
         };
+        (size, size - 1)
+    }
 
-        let size = eaddr - addr;
-        let mut data = vec![0; size as usize];
+    /// Returns the offset of the pcdata table with index i in the pctab.
+    fn pcdata(&self, f: &Func, i: usize) -> Option<u32> {
+        let npcdata = self.field(f, 7).ok()? as usize;
+        if i >= npcdata {
+            return None;
+        }
+        let offset = f.offset + self.func_header_size().0 + i * 4;
+        self.data
+            .get(offset..offset + 4)
+            .map(|data| read_u32(self.little_endian, data))
+    }
 
-        for prog in elfdbginfo.e.segments() {
-            if prog.address() <= addr && addr + size - 1 <= prog.address() + prog.size() - 1 {
-                let segment_data = prog.data()?;
-                let start_offset = (addr - prog.address()) as usize;
-                let end_offset = start_offset + size as usize;
+    /// Returns the address of the funcdata with index i.
+    fn funcdata(&self, f: &Func, i: usize) -> Option<u64> {
+        let (header_size, nfuncdata_offset) = self.func_header_size();
+        let npcdata = self.field(f, 7).ok()? as usize;
+        let nfuncdata = *self.data.get(f.offset + nfuncdata_offset)? as usize;
+        if i >= nfuncdata {
+            return None;
+        }
 
-                if end_offset <= segment_data.len() {
-                    data.copy_from_slice(&segment_data[start_offset..end_offset]);
-                }
+        let offset = f.offset + header_size + npcdata * 4;
+        if self.version >= Version::Go118 {
+            // Offsets relative to go:func.*, with all bits set meaning none.
+            let offset = offset + i * 4;
+            let raw = read_u32(self.little_endian, self.data.get(offset..offset + 4)?);
+            if raw == u32::MAX {
+                return None;
             }
+            return self.gofunc.map(|gofunc| gofunc + raw as u64);
         }
 
-        Ok(data.into())
+        // Pointers, aligned to the pointer size.
+        let offset = if self.ptr_size == 8 && offset & 4 != 0 {
+            offset + 4
+        } else {
+            offset
+        } + i * self.ptr_size;
+        let data = self.data.get(offset..offset + self.ptr_size)?;
+        match read_uintptr(self.little_endian, self.ptr_size, data) {
+            0 => None,
+            address => Some(address),
+        }
+    }
+
+    /// Returns the pctab offset of the inline tree index table and the
+    /// address of the inline tree of a function, if anything was inlined
+    /// into it.
+    fn inline_tree(&self, f: &Func) -> Option<(u32, u64)> {
+        // Before Go 1.12 inlined calls had no parent PC, and the layout of
+        // `_func` in Go 1.2 tables changed over time, so only rely on it from
+        // Go 1.16 on.
+        if self.version < Version::Go116 {
+            return None;
+        }
+        Some((
+            self.pcdata(f, PCDATA_INL_TREE_INDEX)?,
+            self.funcdata(f, FUNCDATA_INL_TREE)?,
+        ))
     }
 
-    fn runtime_text_addr(elfdbginfo: &ElfDebugInfo<'data>) -> Option<u64> {
-        let symbols = elfdbginfo.e.symbols();
+    fn inlined_call(&self, tree: u64, index: u64) -> anyhow::Result<InlinedCall> {
+        let size = if self.version >= Version::Go120 {
+            16
+        } else {
+            20
+        };
+        let data = match gopclntab::read_at(&self.elfdbginfo.e, tree + index * size, size) {
+            Some(data) => data,
+            None => bail!("Inline tree entry {} is out of bounds", index),
+        };
+
+        let read = |offset: usize| read_u32(self.little_endian, &data[offset..]);
+        Ok(if self.version >= Version::Go120 {
+            InlinedCall {
+                name_off: read(4),
+                parent_pc: read(8),
+                start_line: read(12) as i32 as i64,
+            }
+        } else {
+            InlinedCall {
+                name_off: read(12),
+                parent_pc: read(16),
+                start_line: 0,
+            }
+        })
+    }
+
+    /// Evaluates the pc-value table at off for targetpc.
+    fn pcvalue(&self, off: u32, entry: u64, targetpc: u64) -> Option<i32> {
+        let mut p = self.data.get(self.pctab + off as usize..)?;
+        let mut val: i32 = -1;
+        let mut pc = entry;
+        let mut first = true;
+
+        loop {
+            let uvdelta = read_varint(&mut p)?;
+            if uvdelta == 0 && !first {
+                return None;
+            }
+            first = false;
+
+            let vdelta = if uvdelta & 1 != 0 {
+                !(uvdelta >> 1) as i32
+            } else {
+                (uvdelta >> 1) as i32
+            };
+            let pcdelta = read_varint(&mut p)? * self.quantum;
+            pc += pcdelta as u64;
+            val = val.wrapping_add(vdelta);
+
+            if targetpc < pc {
+                return Some(val);
+            }
+        }
+    }
+
+    fn func_name(&self, name_off: u32) -> anyhow::Result<String> {
+        self.string(self.funcnametab + name_off as usize)
+    }
 
-        for symbol in symbols {
-            if let Ok(name) = symbol.name() {
-                if name.eq("runtime.text") {
-                    return Some(symbol.address());
+    fn file_name(&self, f: &Func, fno: i32) -> anyhow::Result<String> {
+        if fno < 0 {
+            return Ok("?".into());
+        }
+
+        if self.version == Version::Go12 {
+            let offset = self.filetab + 4 * fno as usize;
+            let data = match self.data.get(offset..offset + 4) {
+                Some(data) => data,
+                None => bail!("File index {} is out of bounds", fno),
+            };
+            return self.string(read_u32(self.little_endian, data) as usize);
+        }
+
+        // From Go 1.16 on, file numbers are relative to the compilation unit.
+        let cu_offset = self.field(f, 8)?;
+        let offset = self.cutab + (cu_offset as usize + fno as usize) * 4;
+        let data = match self.data.get(offset..offset + 4) {
+            Some(data) => data,
+            None => bail!("File index {} is out of bounds", fno),
+        };
+        match read_u32(self.little_endian, data) {
+            u32::MAX => Ok("?".into()),
+            file_off => self.string(self.filetab + file_off as usize),
+        }
+    }
+
+    /// Reads a NUL terminated string at the given offset of the table.
+    fn string(&self, offset: usize) -> anyhow::Result<String> {
+        let data = match self.data.get(offset..) {
+            Some(data) => data,
+            None => bail!("String offset {} is out of bounds", offset),
+        };
+        let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+        Ok(String::from_utf8_lossy(&data[..end]).into_owned())
+    }
+
+    /// Finds `runtime.firstmoduledata` by looking for the pointer to the
+    /// pclntab it starts with, and returns the text start and go:func.*
+    /// address recorded in it. Stripped binaries have no symbols for either.
+    fn moduledata(&self, pclntab_address: u64) -> Option<(u64, u64)> {
+        let (text_word, gofunc_word) = match self.version {
+            Version::Go118 => (22, 38),
+            Version::Go120 => (22, 40),
+            _ => return None,
+        };
+
+        for name in [".noptrdata", ".data.rel.ro", ".data"] {
+            let section = match self.elfdbginfo.e.section_by_name(name) {
+                Some(section) => section,
+                None => continue,
+            };
+            let data = match section.data() {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+
+            let size = (gofunc_word + 1) * self.ptr_size;
+            for offset in (0..data.len().saturating_sub(size)).step_by(self.ptr_size) {
+                let word = |n: usize| {
+                    read_uintptr(
+                        self.little_endian,
+                        self.ptr_size,
+                        &data[offset + n * self.ptr_size..],
+                    )
+                };
+                if word(0) != pclntab_address {
+                    continue;
+                }
+                let (text, gofunc) = (word(text_word), word(gofunc_word));
+                if text != 0 && gofunc != 0 {
+                    return Some((text, gofunc));
                 }
             }
         }
@@ -110,27 +534,405 @@ impl<'data> GoLiner<'data> {
     }
 }
 
+fn read_varint(p: &mut &[u8]) -> Option<u32> {
+    let mut v: u32 = 0;
+    let mut shift = 0;
+    loop {
+        let (b, rest) = p.split_first()?;
+        *p = rest;
+        v |= ((b & 0x7f) as u32) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+        shift += 7;
+        if shift >= 32 {
+            return None;
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::symbolizer::normalize::NormalizedAddress;
+    use crate::symbols::addr_to_line::{DwarfContext, DwarfLiner};
+    use object::ObjectSymbol;
     use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn load(path: &str) -> Vec<u8> {
+        match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) => panic!("Failed to read file: {:?}", e),
+        }
+    }
 
     #[test]
     fn test_pc_to_lines() {
         let path = PathBuf::from("src/symbols/addr_to_line/testdata/basic-go-with-debuginfo");
-        let data = match std::fs::read(&path) {
-            Ok(data) => data,
-            Err(e) => panic!("Failed to read file: {:?}", e),
+        let data = load(path.to_str().unwrap());
+        let elfdbginfo = ElfDebugInfo {
+            target_path: path,
+            e: object::File::parse(&*data).unwrap(),
+            quality: None,
+        };
+        let demangler = Demangler::new(false);
+        let l = GoLiner::try_new(&elfdbginfo, &demangler).unwrap();
+
+        let main = gopclntab::symbol_address(&elfdbginfo.e, "main.main").unwrap();
+        let lines = l.pc_to_lines(NormalizedAddress(main)).unwrap();
+        let function = lines.last().unwrap().function.as_ref().unwrap();
+        assert_eq!(function.name, "main.main");
+        assert!(function.filename.ends_with(".go"));
+        assert!(function.start_line > 0);
+        assert!(lines.last().unwrap().line >= function.start_line);
+    }
+
+    #[test]
+    fn test_moduledata() {
+        let path = PathBuf::from("src/symbols/addr_to_line/testdata/basic-go-with-debuginfo");
+        let data = load(path.to_str().unwrap());
+        let elfdbginfo = ElfDebugInfo {
+            target_path: path,
+            e: object::File::parse(&*data).unwrap(),
+            quality: None,
+        };
+        let demangler = Demangler::new(false);
+        let l = GoLiner::try_new(&elfdbginfo, &demangler).unwrap();
+
+        // The values found without symbols, as for a stripped binary, have
+        // to match the symbols.
+        let pclntab = gopclntab::find_pcln_tab(&elfdbginfo.e).unwrap();
+        let (text, gofunc) = l.moduledata(pclntab.address).unwrap();
+        assert_eq!(
+            Some(text),
+            gopclntab::symbol_address(&elfdbginfo.e, "runtime.text")
+        );
+        assert_eq!(
+            Some(gofunc),
+            gopclntab::symbol_address(&elfdbginfo.e, "go:func.*")
+        );
+    }
+
+    /// Every function and line resolved through the pclntab, including the
+    /// inlined frames, has to match what the DWARF says.
+    #[test]
+    fn test_matches_dwarf() {
+        let path = PathBuf::from("src/symbols/addr_to_line/testdata/basic-go-with-debuginfo");
+        let data = load(path.to_str().unwrap());
+        let elfdbginfo = ElfDebugInfo {
+            target_path: path,
+            e: object::File::parse(&*data).unwrap(),
+            quality: None,
+        };
+        let demangler = Demangler::new(false);
+        let go = GoLiner::try_new(&elfdbginfo, &demangler).unwrap();
+        let ctx =
+            DwarfContext::with_supplements(Arc::new(data.clone()), Default::default()).unwrap();
+        let dwarf = DwarfLiner::with_context(Arc::new(ctx), &demangler);
+
+        let pcs: Vec<u64> = elfdbginfo
+            .e
+            .symbols()
+            .filter(|s| s.kind() == object::SymbolKind::Text && s.size() > 0)
+            .flat_map(|s| (s.address()..s.address() + s.size()).step_by(4 * 5))
+            .collect();
+
+        let summarize = |lines: &[profile::LocationLine]| -> Vec<(String, i64)> {
+            lines
+                .iter()
+                .map(|l| (l.function.as_ref().unwrap().name.clone(), l.line))
+                .collect()
+        };
+
+        let (mut checked, mut inlined) = (0, 0);
+        for pc in pcs {
+            let expected = dwarf.pc_to_lines(NormalizedAddress(pc)).unwrap();
+            // The DWARF of generated wrappers has no lines, while the
+            // pclntab puts them at <autogenerated>:1.
+            if expected.is_empty() || expected.iter().any(|l| l.line == 0) {
+                continue;
+            }
+            let actual = go.pc_to_lines(NormalizedAddress(pc)).unwrap();
+            assert_eq!(summarize(&actual), summarize(&expected), "pc {:#x}", pc);
+            checked += 1;
+            inlined += (actual.len() > 1) as usize;
+        }
+        assert!(checked > 1000);
+        assert!(inlined > 0);
+    }
+
+    /// Symbols are gone from stripped binaries, so the text start and the
+    /// inline trees are found through the moduledata instead.
+    #[test]
+    fn test_stripped() {
+        let path = PathBuf::from("src/symbols/addr_to_line/testdata/basic-go-with-debuginfo");
+        let data = load(path.to_str().unwrap());
+        let elfdbginfo = ElfDebugInfo {
+            target_path: path,
+            e: object::File::parse(&*data).unwrap(),
+            quality: None,
+        };
+        let path = PathBuf::from("src/symbols/addr_to_line/testdata/basic-go-stripped");
+        let stripped_data = load(path.to_str().unwrap());
+        let stripped = ElfDebugInfo {
+            target_path: path,
+            e: object::File::parse(&*stripped_data).unwrap(),
+            quality: None,
+        };
+        assert!(gopclntab::symbol_address(&stripped.e, "runtime.text").is_none());
+
+        let demangler = Demangler::new(false);
+        let expected = GoLiner::try_new(&elfdbginfo, &demangler).unwrap();
+        let actual = GoLiner::try_new(&stripped, &demangler).unwrap();
+
+        let mut checked = 0;
+        for symbol in elfdbginfo.e.symbols() {
+            if symbol.kind() != object::SymbolKind::Text || symbol.size() == 0 {
+                continue;
+            }
+            for pc in (symbol.address()..symbol.address() + symbol.size()).step_by(4 * 25) {
+                let pc = NormalizedAddress(pc);
+                assert_eq!(
+                    format!("{:?}", actual.pc_to_lines(pc).unwrap()),
+                    format!("{:?}", expected.pc_to_lines(pc).unwrap()),
+                    "pc {:#x}",
+                    pc.0
+                );
+                checked += 1;
+            }
+        }
+        assert!(checked > 100);
+    }
+
+    const TEXT_START: u64 = 0x401000;
+
+    /// Functions of the synthetic tables: name, offset from the start of the
+    /// text, size, file number, and the lines of consecutive ranges of PCs.
+    const FUNCS: [(&str, u64, u64, i32, &[(u64, i32)]); 2] = [
+        ("main.a", 0, 0x10, 0, &[(0x10, 5)]),
+        ("main.b", 0x10, 0x20, 1, &[(0x8, 10), (0x18, 11)]),
+    ];
+    const FILES: [&str; 2] = ["a.go", "b.go"];
+
+    fn write_varint(buf: &mut Vec<u8>, mut v: u32) {
+        while v >= 0x80 {
+            buf.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        buf.push(v as u8);
+    }
+
+    fn pcvalue_table(runs: &[(u64, i32)]) -> Vec<u8> {
+        let mut buf = vec![];
+        let mut prev = -1;
+        for (len, value) in runs {
+            let delta = value - prev;
+            let uv = if delta < 0 {
+                ((!delta as u32) << 1) | 1
+            } else {
+                (delta as u32) << 1
+            };
+            write_varint(&mut buf, uv);
+            write_varint(&mut buf, *len as u32);
+            prev = *value;
+        }
+        buf.push(0);
+        buf
+    }
+
+    /// Builds a little endian table of FUNCS in the layout of the given
+    /// version, with 8 byte pointers and a PC quantum of 1.
+    fn synthetic_pclntab(version: Version) -> Vec<u8> {
+        let (magic, nwords) = match version {
+            Version::Go12 => (GO_1_2_MAGIC, 1),
+            Version::Go116 => (GO_1_16_MAGIC, 7),
+            Version::Go118 => (GO_1_18_MAGIC, 8),
+            Version::Go120 => unimplemented!("covered by the test binaries"),
         };
+        let go12 = version == Version::Go12;
+        let mut data = magic.to_le_bytes().to_vec();
+        data.extend([0, 0, 1, 8]);
+        data.resize(8 + nwords * 8, 0);
+        let set_word = |data: &mut Vec<u8>, n: usize, v: usize| {
+            data[8 + n * 8..16 + n * 8].copy_from_slice(&(v as u64).to_le_bytes());
+        };
+        set_word(&mut data, 0, FUNCS.len());
+
+        // Go 1.2 tables start with the function table, and every offset is
+        // from the start of the table.
+        let go12_functab = data.len();
+        if go12 {
+            data.resize(data.len() + (FUNCS.len() * 2 + 1) * 8 + 8, 0);
+        }
+        let base = |offset: usize| if go12 { 0 } else { offset };
+
+        let funcnametab = data.len();
+        let mut name_offsets = vec![];
+        for (name, ..) in FUNCS {
+            name_offsets.push((data.len() - base(funcnametab)) as u32);
+            data.extend(name.as_bytes());
+            data.push(0);
+        }
+
+        let filetab = data.len();
+        let mut file_offsets = vec![];
+        for file in FILES {
+            file_offsets.push((data.len() - base(filetab)) as u32);
+            data.extend(file.as_bytes());
+            data.push(0);
+        }
+        // The file numbers index the filetab in Go 1.2 tables, and the
+        // compilation unit's entries of the cutab later on.
+        let cutab = data.len();
+        for offset in file_offsets.iter() {
+            data.extend(offset.to_le_bytes());
+        }
+
+        let pctab = data.len();
+        let mut pc_offsets = vec![];
+        for (_, _, size, file, lines) in FUNCS {
+            let pcfile = (data.len() - base(pctab)) as u32;
+            data.extend(pcvalue_table(&[(size, file)]));
+            let pcln = (data.len() - base(pctab)) as u32;
+            data.extend(pcvalue_table(lines));
+            pc_offsets.push((pcfile, pcln));
+        }
+        data.resize(data.len().next_multiple_of(8), 0);
+
+        // The functions follow the function table, which for Go 1.2 tables
+        // is at the start.
+        let pcln = data.len();
+        let (functab, field_size) = match version {
+            Version::Go12 => (go12_functab, 8),
+            Version::Go116 => (pcln, 8),
+            _ => (pcln, 4),
+        };
+        if !go12 {
+            data.resize(data.len() + (FUNCS.len() * 2 + 1) * field_size, 0);
+            data.resize(data.len().next_multiple_of(8), 0);
+        }
+        let set_field = |data: &mut Vec<u8>, offset: usize, v: u64| match field_size {
+            8 => data[offset..offset + 8].copy_from_slice(&v.to_le_bytes()),
+            _ => data[offset..offset + 4].copy_from_slice(&(v as u32).to_le_bytes()),
+        };
+        let entry = |offset: u64| match version {
+            Version::Go118 => offset,
+            _ => TEXT_START + offset,
+        };
+
+        for (i, (_, offset, size, ..)) in FUNCS.iter().enumerate() {
+            let func = data.len();
+            set_field(&mut data, functab + i * 2 * field_size, entry(*offset));
+            let funcoff = (func - if go12 { 0 } else { pcln }) as u64;
+            set_field(&mut data, functab + (i * 2 + 1) * field_size, funcoff);
+            if i == FUNCS.len() - 1 {
+                let end = entry(offset + size);
+                set_field(&mut data, functab + (i * 2 + 2) * field_size, end);
+            }
+
+            match version {
+                Version::Go118 => data.extend((*offset as u32).to_le_bytes()),
+                _ => data.extend(entry(*offset).to_le_bytes()),
+            }
+            let (pcfile, pcln) = pc_offsets[i];
+            // nameoff, args, deferreturn, pcsp, pcfile, pcln, npcdata.
+            for field in [name_offsets[i], 0, 0, 0, pcfile, pcln, 0] {
+                data.extend(field.to_le_bytes());
+            }
+            match version {
+                // nfuncdata.
+                Version::Go12 => data.extend(0u32.to_le_bytes()),
+                // cuOffset, funcID, two bytes of padding and nfuncdata.
+                _ => data.extend([0, 0, 0, 0, 0, 0, 0, 0]),
+            }
+            data.resize(data.len().next_multiple_of(8), 0);
+        }
+
+        if go12 {
+            let offset = go12_functab + (FUNCS.len() * 2 + 1) * 8;
+            data[offset..offset + 4].copy_from_slice(&(cutab as u32).to_le_bytes());
+        } else {
+            let words = [funcnametab, cutab, filetab, pctab, pcln];
+            let first = match version {
+                Version::Go116 => 2,
+                _ => {
+                    set_word(&mut data, 2, TEXT_START as usize);
+                    3
+                }
+            };
+            for (n, offset) in words.into_iter().enumerate() {
+                set_word(&mut data, first + n, offset);
+            }
+        }
+        data
+    }
+
+    /// Liner for a synthetic table. The binary only stands in for the ELF,
+    /// and has no symbols or moduledata a Go binary would.
+    fn synthetic_liner<'data>(
+        elfdbginfo: &'data ElfDebugInfo<'data>,
+        demangler: &'data Demangler,
+        data: Vec<u8>,
+    ) -> anyhow::Result<GoLiner<'data>> {
+        let pclntab = gopclntab::PclnTab {
+            address: 0x7fff_dead_0000,
+            data: Cow::Owned(data),
+        };
+        GoLiner::with_pclntab(elfdbginfo, demangler, pclntab)
+    }
+
+    #[test]
+    fn test_pclntab_layouts() {
+        let path =
+            PathBuf::from("src/symbols/addr_to_line/testdata/basic-cpp-no-fp-stripped-debug");
+        let data = load(path.to_str().unwrap());
+        let elfdbginfo = ElfDebugInfo {
+            target_path: path,
+            e: object::File::parse(&*data).unwrap(),
+            quality: None,
+        };
+        let demangler = Demangler::new(false);
+
+        for version in [Version::Go12, Version::Go116, Version::Go118] {
+            let l = synthetic_liner(&elfdbginfo, &demangler, synthetic_pclntab(version)).unwrap();
+            let resolve = |offset: u64| -> Vec<(String, String, i64)> {
+                l.pc_to_lines(NormalizedAddress(TEXT_START + offset))
+                    .unwrap()
+                    .into_iter()
+                    .map(|line| {
+                        let function = line.function.unwrap();
+                        (function.name, function.filename, line.line)
+                    })
+                    .collect()
+            };
+            let line = |name: &str, file: &str, line| vec![(name.into(), file.into(), line)];
+
+            assert_eq!(resolve(0x4), line("main.a", "a.go", 5), "{:?}", version);
+            assert_eq!(resolve(0x17), line("main.b", "b.go", 10), "{:?}", version);
+            assert_eq!(resolve(0x18), line("main.b", "b.go", 11), "{:?}", version);
+            assert!(l.pc_to_lines(NormalizedAddress(TEXT_START + 0x30)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_corrupted_pclntab() {
+        let path =
+            PathBuf::from("src/symbols/addr_to_line/testdata/basic-cpp-no-fp-stripped-debug");
+        let data = load(path.to_str().unwrap());
         let elfdbginfo = ElfDebugInfo {
             target_path: path,
             e: object::File::parse(&*data).unwrap(),
             quality: None,
         };
-        let d = GoLiner::try_new(&elfdbginfo).unwrap();
-        let _ = d
-            .pc_to_lines(NormalizedAddress(0x0000000000041290))
-            .unwrap();
+        let demangler = Demangler::new(false);
+
+        // A function count that makes the end of the function table overflow.
+        for version in [Version::Go12, Version::Go116, Version::Go118] {
+            let mut data = synthetic_pclntab(version);
+            data[8..16].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+            assert!(synthetic_liner(&elfdbginfo, &demangler, data).is_err());
+        }
+
+        assert!(gopclntab::read_at(&elfdbginfo.e, u64::MAX - 8, 16).is_none());
     }
 }
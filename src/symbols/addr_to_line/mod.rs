@@ -1,17 +1,23 @@
 pub mod dwarf;
+mod go;
 mod symbol;
-// pub mod go;
 
 use super::Demangler;
 use crate::symbolizer::ElfDebugInfo;
-pub(crate) use dwarf::DwarfLiner;
+pub(crate) use dwarf::{DwarfContext, DwarfContextCache, DwarfData, DwarfLiner, DwarfSupplements};
+pub(crate) use go::GoLiner;
+use std::sync::Arc;
 pub(crate) use symbol::SymbolLiner;
 
-pub fn dwarf<'data>(
+pub fn dwarf(ctx: Arc<DwarfContext>, demangler: &Demangler) -> DwarfLiner<'_> {
+    DwarfLiner::with_context(ctx, demangler)
+}
+
+pub fn go<'data>(
     dbg: &'data ElfDebugInfo,
     demangler: &'data Demangler,
-) -> anyhow::Result<DwarfLiner<'data>> {
-    DwarfLiner::try_new(dbg, demangler)
+) -> anyhow::Result<GoLiner<'data>> {
+    GoLiner::try_new(dbg, demangler)
 }
 
 pub fn symbol<'data>(
@@ -4,13 +4,19 @@ use crate::{
     symbolizer::{normalize::NormalizedAddress, ElfDebugInfo},
     symbols::Demangler,
 };
-use anyhow::bail;
-use object::{Object, ObjectSection, ObjectSymbol, RelocationTarget};
+use object::{
+    elf, Architecture, Object, ObjectSection, ObjectSymbol, ObjectSymbolTable, RelocationFlags,
+    RelocationTarget, SymbolKind,
+};
 
 #[derive(Clone, Debug)]
 struct SymbolInfo {
     address: u64,
+    /// End of the symbol, exclusive.
+    end: u64,
     name: String,
+    /// Rank used to pick a name among aliases, lower is preferred.
+    rank: u8,
 }
 
 pub struct SymbolLiner<'data> {
@@ -36,58 +42,118 @@ impl<'data> SymbolLiner<'data> {
         self.source_lines(pc.0)
     }
 
-    /// symtab returns symbols from the symbol table extracted from the ELF file.
-    /// The symbols are sorted by their memory addresses in ascending order
-    /// to facilitate searching.
+    /// symtab returns the functions from the symbol tables of the ELF file,
+    /// plus a symbol for every PLT stub. The symbols are sorted by address,
+    /// with one name per address.
     fn symtab(elfdbginfo: &'data ElfDebugInfo) -> Vec<SymbolInfo> {
+        let e = &elfdbginfo.e;
         let mut symbols: Vec<SymbolInfo> = Vec::new();
 
-        for symbol in elfdbginfo.e.symbols() {
-            if let Ok(name) = symbol.name() {
-                symbols.push(SymbolInfo {
-                    address: symbol.address(),
-                    name: name.to_string(),
-                });
+        for symbol in e.symbols().chain(e.dynamic_symbols()) {
+            if symbol.kind() != SymbolKind::Text || symbol.is_undefined() {
+                continue;
             }
+            let name = match symbol.name() {
+                Ok(name) if !name.is_empty() => name,
+                _ => continue,
+            };
+
+            let rank = if symbol.is_weak() {
+                1
+            } else if symbol.is_global() {
+                0
+            } else {
+                2
+            };
+            // Symbols without a size, usually hand written assembly, extend
+            // to the next symbol, but never beyond their section.
+            let end = match symbol.size() {
+                0 => symbol
+                    .section_index()
+                    .and_then(|index| e.section_by_index(index).ok())
+                    .map(|section| section.address() + section.size())
+                    .unwrap_or(symbol.address()),
+                size => symbol.address() + size,
+            };
+
+            symbols.push(SymbolInfo {
+                address: symbol.address(),
+                end,
+                name: name.to_string(),
+                rank,
+            });
         }
 
-        for symbol in elfdbginfo.e.dynamic_symbols() {
-            if let Ok(name) = symbol.name() {
-                symbols.push(SymbolInfo {
-                    address: symbol.address(),
-                    name: name.to_string(),
-                });
-            }
+        symbols.extend(Self::plt_stubs(elfdbginfo));
+
+        symbols.sort_by(|a, b| a.address.cmp(&b.address).then(a.rank.cmp(&b.rank)));
+        symbols.dedup_by_key(|s| s.address);
+
+        for i in 1..symbols.len() {
+            let next = symbols[i].address;
+            let prev = &mut symbols[i - 1];
+            prev.end = prev.end.min(next);
         }
 
-        if let Some(plt_section) = elfdbginfo.e.section_by_name(".plt") {
-            let relocations = plt_section.relocations();
-            for (offset, reloc) in relocations {
-                if let RelocationTarget::Symbol(symbol_index) = reloc.target() {
-                    if let Ok(symbol) = elfdbginfo.e.symbol_by_index(symbol_index) {
-                        if let Ok(name) = symbol.name() {
-                            symbols.push(SymbolInfo {
-                                address: offset,
-                                name: format!("{}@plt", name),
-                            });
-                        }
-                    }
-                }
+        symbols
+    }
+
+    /// Returns a symbol named `<function>@plt` for every PLT stub. The
+    /// stubs are in the order of the JUMP_SLOT relocations, in .plt.sec if
+    /// the binary has one, and otherwise in .plt after its header.
+    fn plt_stubs(elfdbginfo: &'data ElfDebugInfo) -> Vec<SymbolInfo> {
+        let e = &elfdbginfo.e;
+        let (jump_slot, header_size, entry_size) = match e.architecture() {
+            Architecture::X86_64 => (elf::R_X86_64_JUMP_SLOT, 16, 16),
+            Architecture::Aarch64 => (elf::R_AARCH64_JUMP_SLOT, 32, 16),
+            _ => return vec![],
+        };
+        let (base, section) = match (e.section_by_name(".plt.sec"), e.section_by_name(".plt")) {
+            (Some(section), _) => (section.address(), section),
+            (None, Some(section)) => (section.address() + header_size, section),
+            (None, None) => return vec![],
+        };
+        let section_end = section.address() + section.size();
+        let (relocations, dynsym) = match (e.dynamic_relocations(), e.dynamic_symbol_table()) {
+            (Some(relocations), Some(dynsym)) => (relocations, dynsym),
+            _ => return vec![],
+        };
+
+        let mut stubs = vec![];
+        let jump_slots = relocations.filter(|(_, reloc)| {
+            matches!(reloc.flags(), RelocationFlags::Elf { r_type } if r_type == jump_slot)
+        });
+        for (i, (_, reloc)) in jump_slots.enumerate() {
+            let address = base + i as u64 * entry_size;
+            if address + entry_size > section_end {
+                break;
+            }
+            let name = match reloc.target() {
+                RelocationTarget::Symbol(index) => match dynsym.symbol_by_index(index) {
+                    Ok(symbol) => symbol.name().unwrap_or_default().to_string(),
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+            if name.is_empty() {
+                continue;
             }
+            stubs.push(SymbolInfo {
+                address,
+                end: address + entry_size,
+                name: format!("{}@plt", name),
+                rank: u8::MAX,
+            });
         }
 
-        // Sort symbols by address
-        symbols.sort_by_key(|s| s.address);
-
-        symbols
+        stubs
     }
 
+    /// Returns the function containing pc, or no lines if there is none.
     fn source_lines(&self, pc: u64) -> anyhow::Result<Vec<profile::LocationLine>> {
-        let closest_symbol = match self.find_closest_symbol(pc) {
+        let closest_symbol = match self.find_symbol(pc) {
             Some(s) => s,
-            None => {
-                bail!("No symbol found for the given address");
-            }
+            None => return Ok(vec![]),
         };
 
         let mut was_suffixed = false;
@@ -96,7 +162,7 @@ impl<'data> SymbolLiner<'data> {
                 was_suffixed = true;
                 s
             }
-            None => &closest_symbol,
+            None => closest_symbol,
         };
 
         let mut func = self.demangler.demangle(&Function {
@@ -115,13 +181,15 @@ impl<'data> SymbolLiner<'data> {
         }])
     }
 
-    fn find_closest_symbol(&self, pc: u64) -> Option<String> {
-        // Binary search to find the right position
-        match self.symbols.binary_search_by_key(&pc, |s| s.address) {
-            Ok(index) => Some(self.symbols[index].name.clone()),
-            Err(0) => None,
-            Err(index) => Some(self.symbols[index - 1].name.clone()),
+    fn find_symbol(&self, pc: u64) -> Option<&str> {
+        let symbol = match self.symbols.partition_point(|s| s.address <= pc) {
+            0 => return None,
+            i => &self.symbols[i - 1],
+        };
+        if pc >= symbol.end {
+            return None;
         }
+        Some(&symbol.name)
     }
 }
 
@@ -151,6 +219,52 @@ mod tests {
             .unwrap();
     }
 
+    fn names(file: &str, pcs: &[u64]) -> Vec<Option<String>> {
+        let path = PathBuf::from(format!("src/symbols/addr_to_line/testdata/{}", file));
+        let data = std::fs::read(&path).unwrap();
+        let elfdbginfo = ElfDebugInfo {
+            target_path: path,
+            e: object::File::parse(&*data).unwrap(),
+            quality: None,
+        };
+        let demangler = Demangler::new(false);
+        let l = SymbolLiner::try_new(&elfdbginfo, file, &demangler).unwrap();
+        pcs.iter()
+            .map(|pc| {
+                let lines = l.pc_to_lines(NormalizedAddress(*pc)).unwrap();
+                lines.first().map(|l| l.function.clone().unwrap().name)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_size_bounded_lookup() {
+        let names = names(
+            "basic-cpp-inline",
+            &[0x401170, 0x401182, 0x401150, 0x401034, 0x404008],
+        );
+        assert_eq!(
+            names,
+            vec![
+                // compute(int) is 0x21 bytes at 0x401160, followed by padding.
+                Some("compute(int)".into()),
+                None,
+                // frame_dummy has no size, it extends to the next symbol.
+                Some("frame_dummy".into()),
+                // The first stub after the PLT header.
+                Some("printf@plt".into()),
+                // __data_start is a data object.
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plt_sec() {
+        let names = names("basic-cpp-plt-sec", &[0x401044]);
+        assert_eq!(names, vec![Some("printf@plt".into())]);
+    }
+
     #[test]
     fn test_go_symbolizer() {
         let path = PathBuf::from("src/symbols/addr_to_line/testdata/basic-go-with-debuginfo");
@@ -1,5 +1,58 @@
-use object::{File, Object};
+use object::{File, Object, ObjectSection};
+use std::borrow::Cow;
 
 pub fn has_dwarf(e: &File<'_>) -> bool {
     e.has_debug_symbols()
 }
+
+/// Returns the build ID of the supplementary file that a .gnu_debugaltlink
+/// section refers to, if there is one.
+pub fn debug_alt_link(e: &File<'_>) -> Option<String> {
+    let (_, build_id) = e.gnu_debugaltlink().ok()??;
+    if build_id.is_empty() {
+        return None;
+    }
+    Some(build_id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Returns the DWO IDs of the skeleton units, ie. the units whose DWARF was
+/// split out into .dwo files at compile time.
+pub fn dwo_ids(e: &File<'_>) -> Vec<u64> {
+    unit_dwo_ids(e, |id| Some(id.name()))
+}
+
+/// Returns the DWO IDs of the split units of a .dwo file or a DWARF package.
+pub fn split_dwo_ids(e: &File<'_>) -> Vec<u64> {
+    unit_dwo_ids(e, |id| id.dwo_name())
+}
+
+fn unit_dwo_ids(e: &File<'_>, name: impl Fn(gimli::SectionId) -> Option<&'static str>) -> Vec<u64> {
+    let endian = if e.is_little_endian() {
+        gimli::RunTimeEndian::Little
+    } else {
+        gimli::RunTimeEndian::Big
+    };
+
+    let load_section = |id: gimli::SectionId| -> Result<Cow<[u8]>, gimli::Error> {
+        Ok(name(id)
+            .and_then(|name| e.section_by_name(name))
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or(Cow::Borrowed(&[][..])))
+    };
+    let sections = match gimli::DwarfSections::load(load_section) {
+        Ok(sections) => sections,
+        Err(_) => return vec![],
+    };
+    let dwarf = sections.borrow(|section| gimli::EndianSlice::new(section, endian));
+
+    let mut ids = vec![];
+    let mut headers = dwarf.units();
+    while let Ok(Some(header)) = headers.next() {
+        if let Ok(unit) = dwarf.unit(header) {
+            if let Some(id) = unit.dwo_id {
+                ids.push(id.0);
+            }
+        }
+    }
+    ids
+}
@@ -1,6 +1,152 @@
-use object::File;
+use object::{File, Object, ObjectSection, ObjectSymbol};
+use std::borrow::Cow;
 
-pub fn has_go_pcln_tab(_: &File<'_>) -> bool {
-    // e.section_by_name(".gopclntab").is_some()
-    false
+/// Magic numbers at the start of the pclntab, one per table format.
+pub const GO_1_2_MAGIC: u32 = 0xfffffffb;
+pub const GO_1_16_MAGIC: u32 = 0xfffffffa;
+pub const GO_1_18_MAGIC: u32 = 0xfffffff0;
+pub const GO_1_20_MAGIC: u32 = 0xfffffff1;
+
+/// PclnTab is the raw Go line table and the virtual address it is loaded at.
+pub struct PclnTab<'data> {
+    pub address: u64,
+    pub data: Cow<'data, [u8]>,
+}
+
+pub fn has_go_pcln_tab(e: &File<'_>) -> bool {
+    find_pcln_tab(e).is_some()
+}
+
+/// Locates the pclntab of a Go binary. The linker puts it in its own section
+/// for internally linked binaries, and in .data.rel.ro for externally linked
+/// PIE binaries, where after stripping only its header gives it away.
+pub fn find_pcln_tab<'data>(e: &File<'data>) -> Option<PclnTab<'data>> {
+    for name in [".gopclntab", ".data.rel.ro.gopclntab"] {
+        if let Some(section) = e.section_by_name(name) {
+            if let Ok(data) = section.uncompressed_data() {
+                if is_header(e, &data) {
+                    return Some(PclnTab {
+                        address: section.address(),
+                        data,
+                    });
+                }
+            }
+        }
+    }
+
+    if let (Some(start), Some(end)) = (
+        symbol_address(e, "runtime.pclntab"),
+        symbol_address(e, "runtime.epclntab"),
+    ) {
+        if let Some(data) = end
+            .checked_sub(start)
+            .and_then(|size| read_at(e, start, size))
+        {
+            if is_header(e, data) {
+                return Some(PclnTab {
+                    address: start,
+                    data: Cow::Borrowed(data),
+                });
+            }
+        }
+    }
+
+    for name in [".data.rel.ro", ".rodata"] {
+        let section = match e.section_by_name(name) {
+            Some(section) => section,
+            None => continue,
+        };
+        let data = match section.data() {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        // The table is pointer aligned, so it's enough to check every fourth
+        // byte.
+        for offset in (0..data.len()).step_by(4) {
+            if is_header(e, &data[offset..]) {
+                return Some(PclnTab {
+                    address: section.address() + offset as u64,
+                    data: Cow::Borrowed(&data[offset..]),
+                });
+            }
+        }
+    }
+
+    None
+}
+
+/// Reports whether data starts with a plausible pclntab header.
+fn is_header(e: &File<'_>, data: &[u8]) -> bool {
+    if data.len() < 16 {
+        return false;
+    }
+
+    let magic = read_u32(e.is_little_endian(), data);
+    if ![GO_1_2_MAGIC, GO_1_16_MAGIC, GO_1_18_MAGIC, GO_1_20_MAGIC].contains(&magic) {
+        return false;
+    }
+
+    let (pad1, pad2, quantum, ptr_size) = (data[4], data[5], data[6], data[7]);
+    if pad1 != 0 || pad2 != 0 || ![1, 2, 4].contains(&quantum) || ![4, 8].contains(&ptr_size) {
+        return false;
+    }
+
+    // Newer formats start with a list of offsets into the table, which all
+    // have to be in bounds.
+    let words = match magic {
+        GO_1_2_MAGIC => return true,
+        GO_1_16_MAGIC => 7,
+        _ => 8,
+    };
+    let ptr_size = ptr_size as usize;
+    if data.len() < 8 + words * ptr_size {
+        return false;
+    }
+    (2..words).all(|word| {
+        let offset = read_uintptr(e.is_little_endian(), ptr_size, &data[8 + word * ptr_size..]);
+        offset > 0 && (offset as usize) < data.len()
+    }) && read_uintptr(e.is_little_endian(), ptr_size, &data[8..]) > 0
+}
+
+/// Returns the address of the first symbol with the given name.
+pub fn symbol_address(e: &File<'_>, name: &str) -> Option<u64> {
+    e.symbols()
+        .find(|symbol| symbol.name().is_ok_and(|n| n == name))
+        .map(|symbol| symbol.address())
+}
+
+/// Returns the file contents backing the given virtual address range.
+pub fn read_at<'data>(e: &File<'data>, address: u64, size: u64) -> Option<&'data [u8]> {
+    let end = address.checked_add(size)?;
+    for section in e.sections() {
+        let start = section.address();
+        if start == 0 || address < start || end > start.saturating_add(section.size()) {
+            continue;
+        }
+        let data = section.data().ok()?;
+        let offset = usize::try_from(address - start).ok()?;
+        return data.get(offset..offset.checked_add(usize::try_from(size).ok()?)?);
+    }
+    None
+}
+
+pub fn read_u32(little_endian: bool, data: &[u8]) -> u32 {
+    let bytes: [u8; 4] = data[..4].try_into().unwrap();
+    if little_endian {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    }
+}
+
+pub fn read_uintptr(little_endian: bool, ptr_size: usize, data: &[u8]) -> u64 {
+    if ptr_size == 4 {
+        return read_u32(little_endian, data) as u64;
+    }
+    let bytes: [u8; 8] = data[..8].try_into().unwrap();
+    if little_endian {
+        u64::from_le_bytes(bytes)
+    } else {
+        u64::from_be_bytes(bytes)
+    }
 }
@@ -1,9 +1,62 @@
 mod dwarf;
 mod dynsym;
-mod gopclntab;
+pub mod gopclntab;
 mod symtab;
 
-pub use dwarf::has_dwarf;
+pub use dwarf::{debug_alt_link, dwo_ids, has_dwarf, split_dwo_ids};
 pub use dynsym::has_dynsym;
 pub use gopclntab::has_go_pcln_tab;
 pub use symtab::has_symtab;
+
+use crate::debuginfopb::DebuginfoQuality;
+use object::{File, Object, ObjectSection};
+
+/// Assesses what the file can be symbolized with.
+pub fn quality(e: &File<'_>) -> DebuginfoQuality {
+    if !matches!(e, File::Elf32(_) | File::Elf64(_)) {
+        return DebuginfoQuality {
+            not_valid_elf: true,
+            ..Default::default()
+        };
+    }
+
+    DebuginfoQuality {
+        not_valid_elf: false,
+        has_dwarf: has_dwarf(e),
+        has_go_pclntab: has_go_pcln_tab(e),
+        has_symtab: has_symtab(e),
+        has_dynsym: has_dynsym(e),
+    }
+}
+
+/// Returns the hex encoded GNU build ID of the file, if it has one.
+pub fn build_id(e: &File<'_>) -> Option<String> {
+    let id = e.build_id().ok()??;
+    Some(id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The type of the note in .note.go.buildid.
+const GO_BUILD_ID_NOTE: u32 = 4;
+
+/// Returns the Go build ID of the file, if it has one. It is stored as is in
+/// the descriptor of the note in .note.go.buildid.
+pub fn go_build_id(e: &File<'_>) -> Option<String> {
+    let data = e.section_by_name(".note.go.buildid")?.data().ok()?;
+    let word = |offset: usize| -> Option<usize> {
+        let bytes: [u8; 4] = data.get(offset..offset + 4)?.try_into().ok()?;
+        let word = if e.is_little_endian() {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        };
+        Some(word as usize)
+    };
+
+    let (name_size, desc_size) = (word(0)?, word(4)?);
+    if word(8)? != GO_BUILD_ID_NOTE as usize || data.get(12..12 + name_size)? != b"Go\0\0" {
+        return None;
+    }
+    let desc_start = 12 + name_size.next_multiple_of(4);
+    let desc = data.get(desc_start..desc_start + desc_size)?;
+    String::from_utf8(desc.to_vec()).ok()
+}